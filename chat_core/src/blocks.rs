@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    Decode, Encode, Postgres, Type,
+};
+use utoipa::ToSchema;
+
+/// Structured content of a message, shown instead of its plain `content` by
+/// clients which support it. Stored as jsonb, selected as `blocks::text`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Blocks(pub Vec<Block>);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    /// text in markdown, with fields shown in two columns below it
+    Section {
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        fields: Vec<String>,
+    },
+    /// a file uploaded to the workspace, by its url
+    Image {
+        url: String,
+        #[serde(default)]
+        alt: String,
+    },
+    /// buttons and selects, used on messages of bots
+    Actions { elements: Vec<ActionElement> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ActionElement {
+    Button {
+        /// sent to the bot with the value when the button is clicked
+        action_id: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        style: Option<ButtonStyle>,
+    },
+    Select {
+        /// sent to the bot with the value of the option selected
+        action_id: String,
+        placeholder: String,
+        options: Vec<SelectOption>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonStyle {
+    Primary,
+    Danger,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct SelectOption {
+    pub text: String,
+    pub value: String,
+}
+
+impl Blocks {
+    /// The element of an `actions` block with the id
+    pub fn find_action(&self, action_id: &str) -> Option<&ActionElement> {
+        self.0
+            .iter()
+            .filter_map(|block| match block {
+                Block::Actions { elements } => Some(elements),
+                _ => None,
+            })
+            .flatten()
+            .find(|element| element.action_id() == action_id)
+    }
+}
+
+impl ActionElement {
+    pub fn action_id(&self) -> &str {
+        match self {
+            Self::Button { action_id, .. } | Self::Select { action_id, .. } => action_id,
+        }
+    }
+}
+
+impl Type<Postgres> for Blocks {
+    fn type_info() -> PgTypeInfo {
+        <&str as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <&str as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Blocks {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let text = <&str as Decode<Postgres>>::decode(value)?;
+        Ok(serde_json::from_str(text)?)
+    }
+}
+
+/// Bound as text, cast with `$n::jsonb`
+impl Encode<'_, Postgres> for Blocks {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        let text = serde_json::to_string(self).expect("Failed to serialize blocks");
+        <String as Encode<Postgres>>::encode(text, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_should_be_tagged_by_type() {
+        let json = r#"[
+            {"type": "section", "text": "*Deploy* of `v1.2`", "fields": ["env: prod"]},
+            {"type": "image", "url": "/files/1/abc/def/0123.png"},
+            {"type": "actions", "elements": [
+                {"type": "button", "action_id": "approve", "text": "Approve", "value": "v1.2", "style": "primary"},
+                {"type": "select", "action_id": "env", "placeholder": "Environment",
+                 "options": [{"text": "Staging", "value": "staging"}]}
+            ]}
+        ]"#;
+        let blocks: Blocks = serde_json::from_str(json).unwrap();
+        assert_eq!(blocks.0.len(), 3);
+        assert!(matches!(
+            blocks.find_action("approve"),
+            Some(ActionElement::Button { value: Some(v), .. }) if v == "v1.2"
+        ));
+        assert!(matches!(
+            blocks.find_action("env"),
+            Some(ActionElement::Select { options, .. }) if options.len() == 1
+        ));
+        assert!(blocks.find_action("unknown").is_none());
+
+        let bad = r#"[{"type": "video", "url": "/files/1/a.mp4"}]"#;
+        assert!(serde_json::from_str::<Blocks>(bad).is_err());
+    }
+}
@@ -1,11 +1,22 @@
+mod blocks;
+mod markdown;
 mod utils;
 
 pub mod middlewares;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+use std::fmt;
 
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    FromRow,
+};
 
+pub use blocks::{ActionElement, Block, Blocks, ButtonStyle, SelectOption};
+pub use markdown::{render_markdown, render_plain, RenderedMarkdown};
 pub use utils::*;
 use utoipa::ToSchema;
 
@@ -18,6 +29,14 @@ pub struct User {
     #[sqlx(default)]
     #[serde(skip)]
     pub password_hash: Option<String>,
+    /// bumped to revoke the sessions of the user, e.g. on a password reset
+    #[sqlx(default)]
+    #[serde(default)]
+    pub token_version: i32,
+    /// an integration acting through API tokens, it can't sign in
+    #[sqlx(default)]
+    #[serde(default)]
+    pub is_bot: bool,
     pub created_at: DateTime<Local>,
 }
 
@@ -26,6 +45,10 @@ pub struct Workspace {
     pub id: i64,
     pub name: String,
     pub owner_id: i64,
+    /// members must enable two-factor authentication
+    #[sqlx(default)]
+    #[serde(default)]
+    pub require_2fa: bool,
     pub created_at: DateTime<Local>,
 }
 
@@ -55,6 +78,10 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    #[sqlx(default)]
+    pub message_ttl: Option<i64>,
+    #[sqlx(default)]
+    pub topic: Option<String>,
     pub created_at: DateTime<Local>,
 }
 
@@ -64,7 +91,118 @@ pub struct Message {
     pub chat_id: i64,
     pub sender_id: i64,
     pub content: String,
+    /// `content` rendered to sanitized html, older messages are rendered at startup
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_html: Option<String>,
+    /// `content` as plain text, for search and previews
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_text: Option<String>,
     pub files: Option<Vec<String>>,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<Blocks>,
+    #[sqlx(default)]
+    pub expires_at: Option<DateTime<Local>>,
+    /// set when a bot updated the message
+    #[sqlx(default)]
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Local>>,
+    pub created_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "presence_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Dnd,
+    Offline,
+}
+
+/// Role of a user in its workspace, besides its owner
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    sqlx::Type,
+    ToSchema,
+)]
+#[sqlx(type_name = "workspace_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceRole {
+    #[default]
+    Member,
+    /// manages the workspace like its owner
+    Admin,
+}
+
+/// What an API token is allowed to do
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "api_scope")]
+pub enum Scope {
+    #[sqlx(rename = "chat:read")]
+    #[serde(rename = "chat:read")]
+    ChatRead,
+    #[sqlx(rename = "chat:write")]
+    #[serde(rename = "chat:write")]
+    ChatWrite,
+    #[sqlx(rename = "files:write")]
+    #[serde(rename = "files:write")]
+    FilesWrite,
+    #[sqlx(rename = "users:read")]
+    #[serde(rename = "users:read")]
+    UsersRead,
+}
+
+/// Scopes of the token of a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scopes {
+    /// a session of the user signed in, it can do everything
+    All,
+    /// an API token
+    Only(Vec<Scope>),
+}
+
+/// Events outgoing webhooks subscribe to, named after the events of notify_server
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "webhook_event")]
+pub enum WebhookEvent {
+    NewMessage,
+    NewChat,
+    MemberJoined,
+}
+
+/// A member added to a chat, only delivered to webhooks. Clients get the
+/// `UpdateChat` with the new members.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatMember {
+    pub chat: Chat,
+    pub user_id: i64,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct UserPresence {
+    pub user_id: i64,
+    pub status: PresenceStatus,
+    pub last_seen_at: Option<DateTime<Local>>,
+}
+
+/// A message only delivered to a single user over SSE and never stored
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct EphemeralMessage {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub content: String,
     pub created_at: DateTime<Local>,
 }
 
@@ -76,7 +214,53 @@ impl User {
             fullname: fullname.to_string(),
             email: email.to_string(),
             password_hash: None,
+            token_version: 0,
+            is_bot: false,
             created_at: chrono::Local::now(),
         }
     }
 }
+
+impl Message {
+    /// The plain text of the message, its raw content if it wasn't rendered
+    pub fn plain_text(&self) -> &str {
+        self.content_text.as_deref().unwrap_or(&self.content)
+    }
+}
+
+impl PgHasArrayType for Scope {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_api_scope")
+    }
+}
+
+impl PgHasArrayType for WebhookEvent {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_webhook_event")
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::ChatRead => "chat:read",
+            Self::ChatWrite => "chat:write",
+            Self::FilesWrite => "files:write",
+            Self::UsersRead => "users:read",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Scopes {
+    pub fn allows(&self, scope: Scope) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(scopes) => scopes.contains(&scope),
+        }
+    }
+
+    pub fn is_session(&self) -> bool {
+        matches!(self, Self::All)
+    }
+}
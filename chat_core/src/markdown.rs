@@ -0,0 +1,382 @@
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser, Tag, TagEnd};
+
+/// Quotes and lists nested deeper than this are rejected
+const MAX_DEPTH: usize = 8;
+const MAX_LANG_LEN: usize = 32;
+const LINK_SCHEMES: [&str; 3] = ["http://", "https://", "mailto:"];
+
+/// The content of a message rendered in the markdown dialect of the chat:
+/// paragraphs, bold, italic, strikethrough, code, links, quotes and lists.
+/// Anything else, html included, is kept as escaped text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedMarkdown {
+    /// sanitized html, safe to show as is
+    pub html: String,
+    /// plain text, for search and notification previews
+    pub text: String,
+}
+
+/// Render the content of a message, failing if it nests too deep
+pub fn render_markdown(content: &str) -> Result<RenderedMarkdown> {
+    let mut r = Renderer::default();
+    let parser = Parser::new_ext(content, Options::ENABLE_STRIKETHROUGH);
+    for (event, range) in parser.into_offset_iter() {
+        if !matches!(event, Event::Text(_)) {
+            r.flush_text();
+        }
+        if let Some(end) = r.skip_until {
+            if event == Event::End(end) {
+                r.skip_until = None;
+            }
+            continue;
+        }
+        match event {
+            Event::Start(tag) => r.start(tag, &content[range])?,
+            Event::End(end) => r.end(end),
+            Event::Text(text) => r.pending.push_str(&text),
+            Event::Code(code) => {
+                r.html.push_str("<code>");
+                escape(&mut r.html, &code);
+                r.html.push_str("</code>");
+                r.text.push_str(&code);
+            }
+            Event::Html(html) | Event::InlineHtml(html) => r.literal(&html),
+            Event::SoftBreak | Event::HardBreak => {
+                r.html.push_str("<br>");
+                r.text.push('\n');
+            }
+            Event::Rule => r.literal_block(&content[range]),
+            _ => r.literal(&content[range]),
+        }
+    }
+    r.flush_text();
+
+    Ok(RenderedMarkdown {
+        html: r.html,
+        text: r.text.trim().to_string(),
+    })
+}
+
+/// Render the content as plain text, for content which can't be rendered
+/// as markdown
+pub fn render_plain(content: &str) -> RenderedMarkdown {
+    let mut r = Renderer::default();
+    r.literal_block(content);
+    RenderedMarkdown {
+        html: r.html,
+        text: r.text.trim().to_string(),
+    }
+}
+
+#[derive(Default)]
+struct Renderer {
+    html: String,
+    text: String,
+    /// closing html of the open tags, empty for the ones dropped
+    open: Vec<&'static str>,
+    /// text is merged before rendering, the parser splits it on markup chars
+    pending: String,
+    depth: usize,
+    in_code: bool,
+    in_link: bool,
+    /// end of a block rendered from its source, its events are skipped
+    skip_until: Option<TagEnd>,
+}
+
+impl Renderer {
+    fn start(&mut self, tag: Tag, source: &str) -> Result<()> {
+        let close = match tag {
+            Tag::Paragraph => {
+                self.block("<p>");
+                "</p>"
+            }
+            Tag::Heading { .. } | Tag::HtmlBlock => {
+                // not part of the dialect, shown as written
+                self.literal_block(source);
+                self.skip_until = Some(tag.to_end());
+                return Ok(());
+            }
+            Tag::BlockQuote(_) => {
+                self.nest()?;
+                self.block("<blockquote>");
+                "</blockquote>"
+            }
+            Tag::List(start) => {
+                self.nest()?;
+                match start {
+                    Some(1) => self.block("<ol>"),
+                    Some(n) => self.block(&format!("<ol start=\"{}\">", n)),
+                    None => self.block("<ul>"),
+                }
+                if start.is_some() {
+                    "</ol>"
+                } else {
+                    "</ul>"
+                }
+            }
+            Tag::Item => {
+                self.block("<li>");
+                "</li>"
+            }
+            Tag::CodeBlock(kind) => {
+                self.block("<pre><code");
+                if let CodeBlockKind::Fenced(info) = kind {
+                    let lang = info.split_whitespace().next().unwrap_or_default();
+                    if is_valid_lang(lang) {
+                        self.html.push_str(" class=\"language-");
+                        self.html.push_str(lang);
+                        self.html.push('"');
+                    }
+                }
+                self.html.push('>');
+                self.in_code = true;
+                "</code></pre>"
+            }
+            Tag::Emphasis => self.inline("<em>", "</em>"),
+            Tag::Strong => self.inline("<strong>", "</strong>"),
+            Tag::Strikethrough => self.inline("<del>", "</del>"),
+            Tag::Link {
+                link_type,
+                dest_url,
+                ..
+            } => {
+                let href = match link_type {
+                    LinkType::Email => format!("mailto:{}", dest_url),
+                    _ => dest_url.to_string(),
+                };
+                if !is_safe_link(&href) {
+                    // the text of the link is kept, without the link
+                    ""
+                } else {
+                    self.in_link = true;
+                    self.link_open(&href);
+                    "</a>"
+                }
+            }
+            // images are sent as files, only their alt text is kept
+            _ => "",
+        };
+        self.open.push(close);
+        Ok(())
+    }
+
+    fn end(&mut self, end: TagEnd) {
+        match end {
+            TagEnd::BlockQuote | TagEnd::List(_) => self.depth -= 1,
+            TagEnd::CodeBlock => self.in_code = false,
+            TagEnd::Link => self.in_link = false,
+            _ => {}
+        }
+        if let Some(close) = self.open.pop() {
+            self.html.push_str(close);
+        }
+    }
+
+    fn nest(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            bail!("quotes and lists can't be nested deeper than {}", MAX_DEPTH);
+        }
+        Ok(())
+    }
+
+    /// Open a block, on a new line of the plain text
+    fn block(&mut self, open: &str) {
+        if !self.text.is_empty() && !self.text.ends_with('\n') {
+            self.text.push('\n');
+        }
+        self.html.push_str(open);
+    }
+
+    fn inline(&mut self, open: &str, close: &'static str) -> &'static str {
+        self.html.push_str(open);
+        close
+    }
+
+    fn link_open(&mut self, href: &str) {
+        self.html.push_str("<a href=\"");
+        escape(&mut self.html, href);
+        self.html
+            .push_str("\" rel=\"nofollow noopener noreferrer\">");
+    }
+
+    /// Source shown as written, with its line breaks
+    fn literal(&mut self, source: &str) {
+        let source = source.trim_end_matches('\n');
+        for (i, line) in source.split('\n').enumerate() {
+            if i > 0 {
+                self.html.push_str("<br>");
+            }
+            escape(&mut self.html, line);
+        }
+        self.text.push_str(source);
+    }
+
+    fn literal_block(&mut self, source: &str) {
+        self.block("<p>");
+        self.literal(source);
+        self.html.push_str("</p>");
+    }
+
+    fn flush_text(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let text = std::mem::take(&mut self.pending);
+        if self.in_code || self.in_link {
+            escape(&mut self.html, &text);
+        } else {
+            self.linkify(&text);
+        }
+        self.text.push_str(&text);
+    }
+
+    /// Escape text, turning the bare http(s) urls in it into links
+    fn linkify(&mut self, text: &str) {
+        let mut rest = text;
+        while let Some(range) = find_url(rest) {
+            escape(&mut self.html, &rest[..range.start]);
+            let url = &rest[range.clone()];
+            self.link_open(url);
+            escape(&mut self.html, url);
+            self.html.push_str("</a>");
+            rest = &rest[range.end..];
+        }
+        escape(&mut self.html, rest);
+    }
+}
+
+/// The range of the first bare url in the text, without trailing punctuation
+fn find_url(text: &str) -> Option<Range<usize>> {
+    let mut from = 0;
+    while let Some(i) = text[from..].find("http") {
+        let start = from + i;
+        from = start + 4;
+        let s = &text[start..];
+        if !(s.starts_with("http://") || s.starts_with("https://")) {
+            continue;
+        }
+        if text[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric())
+        {
+            continue;
+        }
+        let len = s
+            .find(|c: char| c.is_whitespace() || c == '<' || c == '>')
+            .unwrap_or(s.len());
+        let url = s[..len].trim_end_matches(['.', ',', ':', ';', '!', '?', ')', '\'', '"']);
+        if url.ends_with("//") {
+            continue;
+        }
+        return Some(start..start + url.len());
+    }
+    None
+}
+
+fn is_safe_link(href: &str) -> bool {
+    let href = href.trim_start().to_ascii_lowercase();
+    LINK_SCHEMES.iter().any(|scheme| href.starts_with(scheme))
+}
+
+fn is_valid_lang(lang: &str) -> bool {
+    !lang.is_empty()
+        && lang.len() <= MAX_LANG_LEN
+        && lang
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-#._".contains(c))
+}
+
+fn escape(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(content: &str) -> String {
+        render_markdown(content).unwrap().html
+    }
+
+    #[test]
+    fn dialect_should_render_to_html() {
+        assert_eq!(
+            html("**bold** *it* ~~gone~~ `x < 1`"),
+            "<p><strong>bold</strong> <em>it</em> <del>gone</del> <code>x &lt; 1</code></p>"
+        );
+        assert_eq!(
+            html("```rust\nfn main() {}\n```"),
+            "<pre><code class=\"language-rust\">fn main() {}\n</code></pre>"
+        );
+        assert_eq!(
+            html("> quoted\n\n- a\n- b\n\n3. c"),
+            "<blockquote><p>quoted</p></blockquote><ul><li>a</li><li>b</li></ul><ol start=\"3\"><li>c</li></ol>"
+        );
+        assert_eq!(html("line\nbreak"), "<p>line<br>break</p>");
+        assert_eq!(
+            html("[docs](https://example.com/a?b=1&c=\"2\")"),
+            "<p><a href=\"https://example.com/a?b=1&amp;c=&quot;2&quot;\" rel=\"nofollow noopener noreferrer\">docs</a></p>"
+        );
+        assert_eq!(
+            html("see https://example.com/a_b_c."),
+            "<p>see <a href=\"https://example.com/a_b_c\" rel=\"nofollow noopener noreferrer\">https://example.com/a_b_c</a>.</p>"
+        );
+    }
+
+    #[test]
+    fn markup_outside_the_dialect_should_be_escaped() {
+        assert_eq!(
+            html("<script>\nalert(1)\n</script>"),
+            "<p>&lt;script&gt;<br>alert(1)<br>&lt;/script&gt;</p>"
+        );
+        assert_eq!(
+            html("hi <img src=x onerror=alert(1)>"),
+            "<p>hi &lt;img src=x onerror=alert(1)&gt;</p>"
+        );
+        assert_eq!(
+            html("[x](javascript:alert(1)) ![alt](https://example.com/a.png)"),
+            "<p>x alt</p>"
+        );
+        assert_eq!(html("# title\n\n---"), "<p># title</p><p>---</p>");
+        assert_eq!(
+            html("```\" onload=\"x\n1\n```"),
+            "<pre><code>1\n</code></pre>"
+        );
+        assert_eq!(html("ping <@3>"), "<p>ping &lt;@3&gt;</p>");
+    }
+
+    #[test]
+    fn plain_text_should_drop_markup() {
+        let rendered =
+            render_markdown("**Deploy** of `v1.2`\n\n> [notes](https://example.com)\n\n- a\n- b")
+                .unwrap();
+        assert_eq!(rendered.text, "Deploy of v1.2\nnotes\na\nb");
+    }
+
+    #[test]
+    fn deep_nesting_should_be_rejected() {
+        assert!(render_markdown(&">".repeat(MAX_DEPTH)).is_ok());
+        assert!(render_markdown(&">".repeat(MAX_DEPTH + 1)).is_err());
+    }
+
+    #[test]
+    fn plain_render_should_escape_everything() {
+        let rendered = render_plain("**a**\n<b>b</b>");
+        assert_eq!(rendered.html, "<p>**a**<br>&lt;b&gt;b&lt;/b&gt;</p>");
+        assert_eq!(rendered.text, "**a**\n<b>b</b>");
+    }
+}
@@ -44,10 +44,11 @@ where
             }
         };
 
-    let req = match state.verify(&token) {
-        Ok(user) => {
+    let req = match state.verify(&token).await {
+        Ok((user, scopes)) => {
             let mut req = Request::from_parts(parts, body);
             req.extensions_mut().insert(user);
+            req.extensions_mut().insert(scopes);
             req
         }
         Err(e) => {
@@ -67,14 +68,14 @@ mod tests {
 
     use anyhow::Result;
     use axum::{
-        body::Body, extract::Request, http::StatusCode, middleware::from_fn_with_state,
-        response::IntoResponse, routing::get, Router,
+        async_trait, body::Body, extract::Request, http::StatusCode,
+        middleware::from_fn_with_state, response::IntoResponse, routing::get, Router,
     };
     use tower::ServiceExt;
 
     use crate::{
         middlewares::{verify_token, TokenVerify},
-        DecodingKey, EncodingKey, User,
+        DecodingKey, EncodingKey, Scopes, User,
     };
 
     #[derive(Clone)]
@@ -85,11 +86,13 @@ mod tests {
         dk: DecodingKey,
     }
 
+    #[async_trait]
     impl TokenVerify for AppState {
         type Error = ();
 
-        fn verify(&self, token: &str) -> Result<User, Self::Error> {
-            self.0.dk.verify(token).map_err(|_| ())
+        async fn verify(&self, token: &str) -> Result<(User, Scopes), Self::Error> {
+            let user = self.0.dk.verify(token).map_err(|_| ())?;
+            Ok((user, Scopes::All))
         }
     }
 
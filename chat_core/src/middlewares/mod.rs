@@ -1,13 +1,14 @@
 mod auth;
+mod rate_limit;
 mod request_id;
 mod server_time;
 
 use std::fmt;
 
-use crate::User;
+use crate::{Scopes, User};
 
 use self::{request_id::set_request_id, server_time::ServerTimeLayer};
-use axum::{middleware::from_fn, Router};
+use axum::{async_trait, middleware::from_fn, Router};
 use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
@@ -17,10 +18,14 @@ use tower_http::{
 use tracing::Level;
 
 pub use auth::verify_token;
+pub use rate_limit::{client_ip, RateLimit, RateLimitKey, RateLimitLayer, RateLimitResource};
 
+/// Verify the token of a request, a JWT of a session or an API token
+#[async_trait]
 pub trait TokenVerify {
     type Error: fmt::Debug;
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    /// The user of the token, with what it is allowed to do
+    async fn verify(&self, token: &str) -> Result<(User, Scopes), Self::Error>;
 }
 
 const REQUEST_ID_HEADER: &str = "x-request-id";
@@ -0,0 +1,446 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{header, Extensions, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+use tracing::warn;
+
+use crate::User;
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+const RATE_LIMIT_LIMIT: &str = "ratelimit-limit";
+const RATE_LIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATE_LIMIT_RESET: &str = "ratelimit-reset";
+const RATE_LIMIT_POLICY: &str = "ratelimit-policy";
+/// buckets kept before the least recently used ones are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+/// Who a limit applies to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// the authenticated user, or the IP of anonymous requests
+    #[default]
+    User,
+    Ip,
+    /// all users of the workspace together, or the IP of anonymous requests
+    Workspace,
+    /// the resource set as `RateLimitResource` by an earlier middleware, e.g.
+    /// the webhook of a url carrying its token, or the IP if there is none
+    Resource,
+}
+
+/// What a request with a `RateLimitKey::Resource` limit is counted against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitResource(pub String);
+
+/// A token bucket, `burst` requests at once refilled by `per_minute`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+/// Limits the requests of each user, IP or workspace with a token bucket. The
+/// buckets are shared by the clones of the layer, so one limit can cover
+/// several routes. Keyed by user it must be inside `verify_token`.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    trust_proxy: bool,
+}
+
+#[derive(Clone)]
+pub struct RateLimitMiddleware<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    trust_proxy: bool,
+}
+
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    /// keys by their last use, the oldest first
+    by_use: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// the last use, its key in `Buckets::by_use`
+    used: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    /// seconds until the bucket is full again
+    reset: u64,
+    /// seconds until the next request is allowed
+    retry_after: u64,
+}
+
+impl RateLimitLayer {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limiter: Arc::new(RateLimiter {
+                limit,
+                buckets: Mutex::new(Buckets::default()),
+            }),
+            trust_proxy: false,
+        }
+    }
+
+    /// Take the IP from `X-Forwarded-For`, only behind a reverse proxy
+    pub fn trust_proxy(mut self, trust_proxy: bool) -> Self {
+        self.trust_proxy = trust_proxy;
+        self
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitMiddleware {
+            inner,
+            limiter: self.limiter.clone(),
+            trust_proxy: self.trust_proxy,
+        }
+    }
+}
+
+impl<S> Service<Request> for RateLimitMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let limit = self.limiter.limit;
+        let key = bucket_key(
+            limit.key,
+            request.headers(),
+            request.extensions(),
+            self.trust_proxy,
+        );
+        // requests of unknown origin share no bucket, they are not limited
+        let Some(key) = key else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        let decision = self.limiter.acquire(&key, Instant::now());
+        if !decision.allowed {
+            warn!("rate limit of {} exceeded", key);
+            let mut response = too_many_requests(decision.retry_after);
+            set_headers(response.headers_mut(), &limit, &decision);
+            return Box::pin(async move { Ok(response) });
+        }
+
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            set_headers(response.headers_mut(), &limit, &decision);
+            Ok(response)
+        })
+    }
+}
+
+impl RateLimiter {
+    fn acquire(&self, key: &str, now: Instant) -> Decision {
+        let limit = self.limit;
+        let capacity = limit.burst as f64;
+        let rate = limit.per_minute.max(1) as f64 / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.touch(key, now, capacity);
+        let tokens = bucket.refill(now, rate, capacity);
+        let allowed = tokens >= 1.0;
+        bucket.tokens = if allowed { tokens - 1.0 } else { tokens };
+        bucket.updated = now;
+
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after: ((1.0 - bucket.tokens).max(0.0) / rate).ceil() as u64,
+        }
+    }
+}
+
+impl Buckets {
+    /// The bucket of the key, full if it's new, marked as the latest used.
+    /// The least recently used bucket is dropped once there are too many.
+    fn touch(&mut self, key: &str, now: Instant, capacity: f64) -> &mut Bucket {
+        self.uses += 1;
+        let used = self.uses;
+        match self.by_key.get_mut(key) {
+            Some(bucket) => {
+                self.by_use.remove(&bucket.used);
+                bucket.used = used;
+            }
+            None => {
+                if self.by_key.len() >= MAX_BUCKETS {
+                    if let Some((_, oldest)) = self.by_use.pop_first() {
+                        self.by_key.remove(&oldest);
+                    }
+                }
+                let bucket = Bucket {
+                    tokens: capacity,
+                    updated: now,
+                    used,
+                };
+                self.by_key.insert(key.to_string(), bucket);
+            }
+        }
+        self.by_use.insert(used, key.to_string());
+        self.by_key.get_mut(key).expect("bucket should exist")
+    }
+}
+
+impl Bucket {
+    fn refill(&self, now: Instant, rate: f64, capacity: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(capacity)
+    }
+}
+
+/// IP of the client, from the last hop of `X-Forwarded-For` behind a trusted
+/// reverse proxy, otherwise from the connect info of the server
+pub fn client_ip(
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    trust_proxy: bool,
+) -> Option<IpAddr> {
+    if trust_proxy {
+        // the last hop is the one added by the proxy, the others can be forged
+        let forwarded = headers
+            .get(FORWARDED_FOR)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|v| v.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|v| v.0.ip())
+}
+
+fn bucket_key(
+    key: RateLimitKey,
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    trust_proxy: bool,
+) -> Option<String> {
+    let user = extensions.get::<User>();
+    let resource = extensions.get::<RateLimitResource>();
+    match (key, user, resource) {
+        (RateLimitKey::User, Some(user), _) => Some(format!("user:{}", user.id)),
+        (RateLimitKey::Workspace, Some(user), _) => Some(format!("ws:{}", user.ws_id)),
+        (RateLimitKey::Resource, _, Some(resource)) => Some(format!("resource:{}", resource.0)),
+        _ => client_ip(headers, extensions, trust_proxy).map(|ip| format!("ip:{}", ip)),
+    }
+}
+
+fn set_headers(headers: &mut HeaderMap, limit: &RateLimit, decision: &Decision) {
+    let window = (limit.burst as u64 * 60).div_ceil(limit.per_minute.max(1) as u64);
+    headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(limit.burst));
+    headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(RATE_LIMIT_RESET, HeaderValue::from(decision.reset));
+    if let Ok(v) = HeaderValue::from_str(&format!("{};w={}", limit.burst, window)) {
+        headers.insert(RATE_LIMIT_POLICY, v);
+    }
+}
+
+fn too_many_requests(retry_after: u64) -> Response {
+    let body = format!(
+        r#"{{"error":"too many requests, retry in {} seconds"}}"#,
+        retry_after
+    );
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use axum::{
+        handler::Handler,
+        middleware::{from_fn, Next},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn limit(key: RateLimitKey) -> RateLimit {
+        RateLimit {
+            burst: 2,
+            per_minute: 60,
+            key,
+        }
+    }
+
+    #[test]
+    fn bucket_should_refill_over_time() {
+        let layer = RateLimitLayer::new(limit(RateLimitKey::Ip));
+        let limiter = &layer.limiter;
+        let now = Instant::now();
+
+        let first = limiter.acquire("a", now);
+        assert!(first.allowed);
+        assert_eq!((first.remaining, first.reset), (1, 1));
+        assert!(limiter.acquire("a", now).allowed);
+        let denied = limiter.acquire("a", now);
+        assert!(!denied.allowed);
+        assert_eq!((denied.remaining, denied.retry_after), (0, 1));
+        // other keys have their own bucket
+        assert!(limiter.acquire("b", now).allowed);
+
+        // one token per second, never more than the burst
+        assert!(limiter.acquire("a", now + Duration::from_secs(1)).allowed);
+        let later = limiter.acquire("a", now + Duration::from_secs(60));
+        assert_eq!((later.allowed, later.remaining), (true, 1));
+    }
+
+    #[test]
+    fn least_recently_used_buckets_should_be_dropped() {
+        let layer = RateLimitLayer::new(limit(RateLimitKey::Ip));
+        let limiter = &layer.limiter;
+        let now = Instant::now();
+
+        limiter.acquire("first", now);
+        limiter.acquire("first", now);
+        limiter.acquire("second", now);
+        for i in 0..MAX_BUCKETS - 2 {
+            limiter.acquire(&i.to_string(), now);
+        }
+        // the first bucket was used last among the two, the second is dropped
+        limiter.acquire("first", now);
+        limiter.acquire("new", now);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), MAX_BUCKETS);
+        assert_eq!(buckets.by_use.len(), MAX_BUCKETS);
+        assert!(!buckets.by_key.contains_key("second"));
+        drop(buckets);
+        assert!(!limiter.acquire("first", now).allowed);
+        assert!(limiter.acquire("second", now).allowed);
+    }
+
+    #[test]
+    fn bucket_key_should_fall_back_to_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR, HeaderValue::from_static("1.1.1.1, 10.0.0.1"));
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 8080))));
+
+        let key = bucket_key(RateLimitKey::User, &headers, &extensions, false);
+        assert_eq!(key.as_deref(), Some("ip:127.0.0.1"));
+        let key = bucket_key(RateLimitKey::Ip, &headers, &extensions, true);
+        assert_eq!(key.as_deref(), Some("ip:10.0.0.1"));
+
+        let mut user = User::new(3, "Tyr Chen", "tchen@acme.org");
+        user.ws_id = 7;
+        extensions.insert(user);
+        let key = bucket_key(RateLimitKey::User, &headers, &extensions, false);
+        assert_eq!(key.as_deref(), Some("user:3"));
+        let key = bucket_key(RateLimitKey::Workspace, &headers, &extensions, false);
+        assert_eq!(key.as_deref(), Some("ws:7"));
+        let key = bucket_key(RateLimitKey::Ip, &headers, &Extensions::new(), false);
+        assert_eq!(key, None);
+        let key = bucket_key(RateLimitKey::Resource, &headers, &extensions, false);
+        assert_eq!(key.as_deref(), Some("ip:127.0.0.1"));
+        extensions.insert(RateLimitResource("webhook:2".to_string()));
+        let key = bucket_key(RateLimitKey::Resource, &headers, &extensions, false);
+        assert_eq!(key.as_deref(), Some("resource:webhook:2"));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_layer_should_limit_per_route() -> Result<()> {
+        async fn set_user(mut req: Request, next: Next) -> Response {
+            let id = req.headers()["x-user"].to_str().unwrap().parse().unwrap();
+            req.extensions_mut()
+                .insert(User::new(id, "Tyr Chen", "tchen@acme.org"));
+            next.run(req).await
+        }
+
+        let writes = RateLimitLayer::new(limit(RateLimitKey::User));
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { "ok" }).post((|| async { "ok" }).layer(writes)),
+            )
+            .layer(from_fn(set_user));
+        let call = |method: &str, user: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/")
+                .header("x-user", user)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for remaining in ["1", "0"] {
+            let res = app.clone().oneshot(call("POST", "1")).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[RATE_LIMIT_LIMIT], "2");
+            assert_eq!(res.headers()[RATE_LIMIT_REMAINING], remaining);
+            assert_eq!(res.headers()[RATE_LIMIT_POLICY], "2;w=2");
+        }
+        let res = app.clone().oneshot(call("POST", "1")).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+        assert_eq!(res.headers()[RATE_LIMIT_REMAINING], "0");
+
+        // reads and other users are not limited by it
+        let res = app.clone().oneshot(call("GET", "1")).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(RATE_LIMIT_LIMIT).is_none());
+        let res = app.clone().oneshot(call("POST", "2")).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
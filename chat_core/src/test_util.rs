@@ -0,0 +1,34 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{body::Bytes, http::HeaderMap, response::IntoResponse, routing::post, Router};
+use tokio::net::TcpListener;
+
+/// The requests recorded by a receiver, in the order they arrived
+pub type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+/// Serve `router` on a local port, recording the requests posted to `path`
+/// and answering them with `reply`. Returns the base url of the receiver.
+pub async fn start_receiver<F, R>(
+    path: &str,
+    reply: F,
+    router: Router,
+) -> anyhow::Result<(String, Received)>
+where
+    F: Fn(&Bytes) -> R + Clone + Send + Sync + 'static,
+    R: IntoResponse + Send + 'static,
+{
+    let received: Received = Arc::new(Mutex::new(vec![]));
+    let recorder = received.clone();
+    let app = router.route(
+        path,
+        post(move |headers: HeaderMap, body: Bytes| async move {
+            let res = reply(&body);
+            recorder.lock().unwrap().push((headers, body));
+            res
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Ok((url, received))
+}
@@ -0,0 +1,79 @@
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+
+use crate::{Scope, User};
+
+/// prefix of API tokens, JWTs always start with `eyJ`
+const API_TOKEN_PREFIX: &str = "chat_";
+const SECRET_LEN: usize = 32;
+
+/// A new random API token, only its hash is stored
+pub fn generate_api_token() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    format!("{}{}", API_TOKEN_PREFIX, hex::encode(secret))
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(API_TOKEN_PREFIX)
+}
+
+/// Hash to look the token up by, tokens are random enough not to need a salt
+pub fn hash_api_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug, FromRow)]
+struct TokenUser {
+    #[sqlx(flatten)]
+    user: User,
+    scopes: Vec<Scope>,
+}
+
+/// The user an API token acts as and its scopes, `None` if the token is
+/// unknown, revoked or expired. `last_used_at` is refreshed at most once a
+/// minute, so busy tokens don't write on every request.
+pub async fn verify_api_token(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Option<(User, Vec<Scope>)>, sqlx::Error> {
+    let ret: Option<TokenUser> = sqlx::query_as(
+        r#"
+        WITH t AS (
+          SELECT id, user_id, scopes, last_used_at FROM api_tokens
+          WHERE token_hash = $1 AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > now())
+        ), used AS (
+          UPDATE api_tokens a SET last_used_at = now()
+          FROM t
+          WHERE a.id = t.id
+            AND (t.last_used_at IS NULL OR t.last_used_at < now() - interval '1 minute')
+        )
+        SELECT u.id, u.ws_id, u.fullname, u.email, u.token_version, u.is_bot, u.created_at,
+          t.scopes
+        FROM t JOIN users u ON u.id = t.user_id
+        "#,
+    )
+    .bind(hash_api_token(token))
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(ret.map(|v| (v.user, v.scopes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_token_should_be_told_apart_from_jwt() {
+        let token = generate_api_token();
+        assert!(is_api_token(&token));
+        assert_eq!(token.len(), API_TOKEN_PREFIX.len() + SECRET_LEN * 2);
+        assert_ne!(token, generate_api_token());
+        assert!(!is_api_token("eyJhbGciOiJFZERTQSJ9.e30.sig"));
+        assert_eq!(hash_api_token(&token).len(), 64);
+        assert_eq!(hash_api_token(&token), hash_api_token(&token));
+    }
+}
@@ -1,4 +1,5 @@
 use jwt_simple::prelude::*;
+use uuid::Uuid;
 
 use crate::User;
 
@@ -11,6 +12,24 @@ pub struct EncodingKey(Ed25519KeyPair);
 #[allow(unused)]
 pub struct DecodingKey(Ed25519PublicKey);
 
+/// Claims of a single purpose token, e.g. the link of a password reset email
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ActionClaims {
+    pub user_id: i64,
+    /// token version of the user when the token was issued
+    pub token_version: i32,
+}
+
+/// A verified single purpose token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionToken {
+    /// unique id of the token, to use it only once
+    pub id: String,
+    /// unix timestamp in seconds
+    pub expires_at: u64,
+    pub claims: ActionClaims,
+}
+
 impl EncodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
         Ok(Self(Ed25519KeyPair::from_pem(pem)?))
@@ -21,6 +40,21 @@ impl EncodingKey {
         let claims = claims.with_issuer(JWT_ISS).with_audience(JWT_AUD);
         self.0.sign(claims)
     }
+
+    /// Sign a token valid `ttl` seconds for `action` only, it's never accepted
+    /// as a session token
+    pub fn sign_action(
+        &self,
+        action: &str,
+        claims: ActionClaims,
+        ttl: u64,
+    ) -> Result<String, jwt_simple::Error> {
+        let claims = Claims::with_custom_claims(claims, Duration::from_secs(ttl))
+            .with_issuer(JWT_ISS)
+            .with_audience(action)
+            .with_jwt_id(Uuid::now_v7());
+        self.0.sign(claims)
+    }
 }
 
 impl DecodingKey {
@@ -39,6 +73,28 @@ impl DecodingKey {
         let claims = self.0.verify_token::<User>(token, Some(opts))?;
         Ok(claims.custom)
     }
+
+    pub fn verify_action(
+        &self,
+        token: &str,
+        action: &str,
+    ) -> Result<ActionToken, jwt_simple::Error> {
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
+            allowed_audiences: Some(HashSet::from_strings(&[action])),
+            ..Default::default()
+        };
+
+        let claims = self.0.verify_token::<ActionClaims>(token, Some(opts))?;
+        let id = claims
+            .jwt_id
+            .ok_or_else(|| jwt_simple::Error::msg("token id is missing"))?;
+        Ok(ActionToken {
+            id,
+            expires_at: claims.expires_at.map(|v| v.as_secs()).unwrap_or_default(),
+            claims: claims.custom,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -63,4 +119,22 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn action_token_should_only_verify_for_its_action() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+        let claims = ActionClaims {
+            user_id: 1,
+            token_version: 2,
+        };
+
+        let token = ek.sign_action("password_reset", claims.clone(), 60)?;
+        let ret = dk.verify_action(&token, "password_reset")?;
+        assert_eq!(ret.claims, claims);
+        assert!(!ret.id.is_empty());
+        assert!(dk.verify_action(&token, "email_verify").is_err());
+        assert!(dk.verify(&token).is_err());
+        Ok(())
+    }
 }
@@ -1,3 +1,9 @@
+mod api_token;
 mod jwt;
+mod public_url;
+mod signature;
 
-pub use jwt::{DecodingKey, EncodingKey};
+pub use api_token::{generate_api_token, hash_api_token, is_api_token, verify_api_token};
+pub use jwt::{ActionClaims, ActionToken, DecodingKey, EncodingKey};
+pub use public_url::is_public_url;
+pub use signature::sign_payload;
@@ -0,0 +1,82 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use url::{Host, Url};
+
+/// Whether the server may call the url configured by a user: it must be https,
+/// and not point to a loopback, private or link-local address. Names are not
+/// resolved, only the ones reserved for local hosts are rejected.
+pub fn is_public_url(url: &Url) -> bool {
+    if url.scheme() != "https" {
+        return false;
+    }
+    match url.host() {
+        Some(Host::Domain(name)) => {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            !["localhost", "local", "internal"]
+                .iter()
+                .any(|v| name == *v || name.ends_with(&format!(".{}", v)))
+        }
+        Some(Host::Ipv4(ip)) => is_public_ipv4(ip),
+        Some(Host::Ipv6(ip)) => is_public_ipv6(ip),
+        None => false,
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || a == 0
+        // shared address space of carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(v4);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        // unique local fc00::/7 and link-local fe80::/10
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_https_urls_should_be_accepted() {
+        for url in [
+            "https://example.com/hook",
+            "https://8.8.8.8/hook",
+            "https://[2001:db8::1]/hook",
+        ] {
+            assert!(is_public_url(&Url::parse(url).unwrap()), "{}", url);
+        }
+        for url in [
+            "http://example.com/hook",
+            "https://localhost/hook",
+            "https://api.localhost/hook",
+            "https://metadata.google.internal/",
+            "https://127.0.0.1/hook",
+            "https://10.0.0.1/hook",
+            "https://172.16.3.4/hook",
+            "https://192.168.1.1/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/hook",
+            "https://0.0.0.0/hook",
+            "https://[::1]/hook",
+            "https://[fd00::1]/hook",
+            "https://[fe80::1]/hook",
+            "https://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(!is_public_url(&Url::parse(url).unwrap()), "{}", url);
+        }
+    }
+}
@@ -34,3 +34,6 @@ INSERT INTO messages(chat_id, sender_id, content)
 (1, 3, 'How are you?'),
 (1, 1, 'Hello, world!'),
 (1, 1, 'Hello, world!');
+
+-- fixture users have verified their email
+UPDATE users SET email_verified_at = now();
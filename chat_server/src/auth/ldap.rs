@@ -0,0 +1,390 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chat_core::{User, WorkspaceRole};
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use tracing::{info, warn};
+
+use super::AuthBackend;
+use crate::{config::LdapConfig, AppError, AppState, SigninUser};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// result code of a bind with a wrong password or an unknown DN
+const INVALID_CREDENTIALS: u32 = 49;
+const MEMBER_OF: &str = "memberOf";
+
+/// Binds to an LDAP directory as the user, and syncs the user into `users`
+pub(crate) struct LdapBackend {
+    config: LdapConfig,
+}
+
+/// The entry of a user in the directory
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DirectoryUser {
+    pub(crate) dn: String,
+    pub(crate) fullname: String,
+    pub(crate) email: String,
+    /// DNs of the groups of the user
+    pub(crate) groups: Vec<String>,
+}
+
+impl LdapBackend {
+    pub(crate) fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    /// Bind as the user with the password and read its entry, `None` if the
+    /// credentials are not valid
+    pub(crate) async fn bind_user(
+        &self,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<DirectoryUser>> {
+        // an empty password makes an unauthenticated bind, which always succeeds
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(TIMEOUT)
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        let ret = self.find_user(&mut ldap, login, password).await;
+        let _ = ldap.unbind().await;
+        ret
+    }
+
+    async fn find_user(
+        &self,
+        ldap: &mut Ldap,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<DirectoryUser>> {
+        for dn in self.user_dns(login) {
+            let ret = ldap
+                .with_timeout(TIMEOUT)
+                .simple_bind(&dn, password)
+                .await?;
+            if ret.rc == INVALID_CREDENTIALS {
+                continue;
+            }
+            ret.success()?;
+            return Ok(Some(self.read_user(ldap, &dn, login).await?));
+        }
+        Ok(None)
+    }
+
+    async fn read_user(&self, ldap: &mut Ldap, dn: &str, login: &str) -> Result<DirectoryUser> {
+        let attrs = vec![
+            self.config.fullname_attr.as_str(),
+            self.config.email_attr.as_str(),
+            MEMBER_OF,
+        ];
+        let (entries, _) = ldap
+            .with_timeout(TIMEOUT)
+            .search(dn, Scope::Base, "(objectClass=*)", attrs)
+            .await?
+            .success()?;
+        let entry = entries
+            .into_iter()
+            .next()
+            .map(SearchEntry::construct)
+            .ok_or_else(|| anyhow!("entry {} not found", dn))?;
+
+        let fullname = first_value(&entry, &self.config.fullname_attr)
+            .unwrap_or_else(|| username(login).to_string());
+        let email = first_value(&entry, &self.config.email_attr)
+            .or_else(|| login.contains('@').then(|| login.to_string()))
+            .ok_or_else(|| anyhow!("entry {} has no email", dn))?;
+        let mut groups = values(&entry, MEMBER_OF);
+        if let Some(base) = &self.config.group_base {
+            let filter = format!("(|(member={0})(uniqueMember={0}))", ldap_escape(&entry.dn));
+            let (entries, _) = ldap
+                .with_timeout(TIMEOUT)
+                .search(base, Scope::Subtree, &filter, vec!["1.1"])
+                .await?
+                .success()?;
+            groups.extend(entries.into_iter().map(|v| SearchEntry::construct(v).dn));
+        }
+
+        Ok(DirectoryUser {
+            dn: entry.dn,
+            fullname: fullname.chars().take(64).collect(),
+            email,
+            groups,
+        })
+    }
+
+    fn user_dns(&self, login: &str) -> Vec<String> {
+        let username = dn_escape(username(login));
+        self.config
+            .user_dn
+            .iter()
+            .map(|v| v.replace("{username}", &username))
+            .collect()
+    }
+
+    /// Workspace and role of the user by its groups. The first mapped group
+    /// picks the workspace, the user gets the highest role of its groups there.
+    fn placement(&self, groups: &[String]) -> Option<(String, WorkspaceRole)> {
+        let mut matched = self
+            .config
+            .groups
+            .iter()
+            .filter(|g| groups.iter().any(|v| v.eq_ignore_ascii_case(&g.group)));
+        let Some(first) = matched.next() else {
+            return self
+                .config
+                .workspace
+                .clone()
+                .map(|v| (v, WorkspaceRole::Member));
+        };
+        let role = matched
+            .filter(|g| g.workspace == first.workspace)
+            .map(|g| g.role)
+            .fold(first.role, |a, b| a.max(b));
+        Some((first.workspace.clone(), role))
+    }
+}
+
+#[async_trait]
+impl AuthBackend for LdapBackend {
+    fn name(&self) -> &str {
+        "ldap"
+    }
+
+    async fn authenticate(
+        &self,
+        state: &AppState,
+        input: &SigninUser,
+    ) -> Result<Option<User>, AppError> {
+        // an unreachable directory must not lock out the users with a password
+        let entry = match self.bind_user(&input.email, &input.password).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                warn!("LDAP sign in of {} failed: {}", input.email, e);
+                return Ok(None);
+            }
+        };
+        let Some((workspace, role)) = self.placement(&entry.groups) else {
+            info!("{} is in no group mapped to a workspace", entry.dn);
+            return Ok(None);
+        };
+
+        let user = state
+            .sync_directory_user(&self.config.url, &entry, &workspace, role)
+            .await?;
+        Ok(Some(user))
+    }
+}
+
+/// The part of the login before `@`
+fn username(login: &str) -> &str {
+    login.split('@').next().unwrap_or(login)
+}
+
+/// Values of the attribute, attribute names are case insensitive
+fn values(entry: &SearchEntry, attr: &str) -> Vec<String> {
+    entry
+        .attrs
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(attr))
+        .map(|(_, v)| v.clone())
+        .unwrap_or_default()
+}
+
+fn first_value(entry: &SearchEntry, attr: &str) -> Option<String> {
+    values(entry, attr).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LdapGroupConfig;
+    use std::collections::HashSet;
+
+    const ADMINS: &str = "cn=chat-admins,ou=groups,dc=example,dc=org";
+    const STAFF: &str = "cn=staff,ou=groups,dc=example,dc=org";
+
+    fn test_config(url: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            starttls: false,
+            user_dn: vec![
+                "uid={username},ou=people,dc=example,dc=org".to_string(),
+                "uid={username},dc=example,dc=org".to_string(),
+            ],
+            fullname_attr: "cn".to_string(),
+            email_attr: "mail".to_string(),
+            group_base: Some("dc=example,dc=org".to_string()),
+            groups: vec![
+                LdapGroupConfig {
+                    group: STAFF.to_string(),
+                    workspace: "acme".to_string(),
+                    role: WorkspaceRole::Member,
+                },
+                LdapGroupConfig {
+                    group: ADMINS.to_string(),
+                    workspace: "acme".to_string(),
+                    role: WorkspaceRole::Admin,
+                },
+            ],
+            workspace: None,
+        }
+    }
+
+    #[test]
+    fn user_dns_should_escape_username() {
+        let backend = LdapBackend::new(test_config("ldap://localhost"));
+        assert_eq!(
+            backend.user_dns("eve,ou=admins@acme.org"),
+            vec![
+                "uid=eve\\2cou\\3dadmins,ou=people,dc=example,dc=org",
+                "uid=eve\\2cou\\3dadmins,dc=example,dc=org"
+            ]
+        );
+    }
+
+    #[test]
+    fn placement_should_map_groups() {
+        let mut config = test_config("ldap://localhost");
+        let backend = LdapBackend::new(config.clone());
+        let staff = vec![STAFF.to_uppercase()];
+        assert_eq!(
+            backend.placement(&staff),
+            Some(("acme".to_string(), WorkspaceRole::Member))
+        );
+        let both = vec![STAFF.to_string(), ADMINS.to_string()];
+        assert_eq!(
+            backend.placement(&both),
+            Some(("acme".to_string(), WorkspaceRole::Admin))
+        );
+        assert_eq!(backend.placement(&[]), None);
+
+        config.workspace = Some("guests".to_string());
+        let backend = LdapBackend::new(config);
+        assert_eq!(
+            backend.placement(&[]),
+            Some(("guests".to_string(), WorkspaceRole::Member))
+        );
+    }
+
+    fn entry(uid: &str, fullname: &str, email: &str) -> DirectoryUser {
+        DirectoryUser {
+            dn: format!("uid={},ou=people,dc=example,dc=org", uid),
+            fullname: fullname.to_string(),
+            email: email.to_string(),
+            groups: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn directory_user_should_sync_into_users() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let issuer = "ldap://localhost";
+        let eve = entry("eve", "Eve", "eve@acme.org");
+        let user = state
+            .sync_directory_user(issuer, &eve, "acme", WorkspaceRole::Admin)
+            .await?;
+        let ws = state.find_workspace_by_name("acme").await?.unwrap();
+        assert_eq!(user.ws_id, ws.id);
+        assert!(state.load_session(user.id).await?.unwrap().email_verified);
+
+        // the fullname follows the directory, the workspace is kept
+        let tyr = entry("tchen", "Tyr", "tchen1@acme.org");
+        let user = state
+            .sync_directory_user(issuer, &tyr, "other", WorkspaceRole::Admin)
+            .await?;
+        assert_eq!((user.id, user.ws_id, user.fullname.as_str()), (1, 1, "Tyr"));
+        let input = SigninUser::new("tchen1@acme.org", "123456");
+        assert!(state.verify_user(&input).await?.is_some());
+
+        // the entry stays linked when the directory changes the email
+        let tyr = entry("tchen", "Tyr", "tyr@acme.org");
+        let user = state
+            .sync_directory_user(issuer, &tyr, "acme", WorkspaceRole::Admin)
+            .await?;
+        assert_eq!((user.id, user.email.as_str()), (1, "tyr@acme.org"));
+        assert!(state.find_user_by_email("tchen1@acme.org").await?.is_none());
+
+        // an email of another user is not taken over
+        let tyr = entry("tchen", "Tyr", "alice1@acme.org");
+        let user = state
+            .sync_directory_user(issuer, &tyr, "acme", WorkspaceRole::Admin)
+            .await?;
+        assert_eq!((user.id, user.email.as_str()), (1, "tyr@acme.org"));
+        Ok(())
+    }
+
+    // needs a local OpenLDAP, e.g. `docker run -p 389:389 osixia/openldap` then
+    // LDAP_URL=ldap://localhost:389 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn ldap_bind_should_find_user_and_groups() -> anyhow::Result<()> {
+        let url = std::env::var("LDAP_URL").unwrap_or_else(|_| "ldap://localhost:389".into());
+        let uid = format!("eve{}", uuid_suffix());
+        let dn = format!("uid={},dc=example,dc=org", uid);
+        let group = format!("cn=staff-{},dc=example,dc=org", uid);
+
+        let (conn, mut ldap) = LdapConnAsync::new(&url).await?;
+        ldap3::drive!(conn);
+        ldap.simple_bind("cn=admin,dc=example,dc=org", "admin")
+            .await?
+            .success()?;
+        let email = format!("{}@example.org", uid);
+        ldap.add(
+            &dn,
+            vec![
+                ("objectClass", HashSet::from(["inetOrgPerson"])),
+                ("uid", HashSet::from([uid.as_str()])),
+                ("cn", HashSet::from(["Eve Directory"])),
+                ("sn", HashSet::from(["Directory"])),
+                ("mail", HashSet::from([email.as_str()])),
+                ("userPassword", HashSet::from(["hunter42"])),
+            ],
+        )
+        .await?
+        .success()?;
+        ldap.add(
+            &group,
+            vec![
+                ("objectClass", HashSet::from(["groupOfNames"])),
+                ("member", HashSet::from([dn.as_str()])),
+            ],
+        )
+        .await?
+        .success()?;
+
+        let mut config = test_config(&url);
+        config.groups[0].group = group.clone();
+        let backend = LdapBackend::new(config);
+        let user = backend
+            .bind_user(&email, "hunter42")
+            .await?
+            .expect("user should bind");
+        assert_eq!(user.dn, dn);
+        assert_eq!(user.fullname, "Eve Directory");
+        assert_eq!(user.email, email);
+        assert_eq!(
+            backend.placement(&user.groups),
+            Some(("acme".to_string(), WorkspaceRole::Member))
+        );
+        assert_eq!(backend.bind_user(&email, "wrong").await?, None);
+        assert_eq!(backend.bind_user(&email, "").await?, None);
+
+        ldap.delete(&group).await?;
+        ldap.delete(&dn).await?;
+        ldap.unbind().await?;
+        Ok(())
+    }
+
+    fn uuid_suffix() -> u128 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    }
+}
@@ -0,0 +1,32 @@
+mod ldap;
+mod password;
+
+use async_trait::async_trait;
+use chat_core::User;
+
+use crate::{config::AuthConfig, AppError, AppState, SigninUser};
+
+pub(crate) use ldap::{DirectoryUser, LdapBackend};
+pub(crate) use password::PasswordBackend;
+
+/// Checks the credentials of a sign in against a source of users
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    fn name(&self) -> &str;
+    /// The user of the credentials, `None` if they are not valid for this backend
+    async fn authenticate(
+        &self,
+        state: &AppState,
+        input: &SigninUser,
+    ) -> Result<Option<User>, AppError>;
+}
+
+/// The backends tried in order on sign in: the passwords stored in `users`,
+/// then the directory if one is configured
+pub(crate) fn load_backends(config: &AuthConfig) -> Vec<Box<dyn AuthBackend>> {
+    let mut backends: Vec<Box<dyn AuthBackend>> = vec![Box::new(PasswordBackend)];
+    if let Some(ldap) = &config.ldap {
+        backends.push(Box::new(LdapBackend::new(ldap.clone())));
+    }
+    backends
+}
@@ -0,0 +1,23 @@
+use async_trait::async_trait;
+use chat_core::User;
+
+use super::AuthBackend;
+use crate::{AppError, AppState, SigninUser};
+
+/// The argon2 password hashes stored in `users`
+pub(crate) struct PasswordBackend;
+
+#[async_trait]
+impl AuthBackend for PasswordBackend {
+    fn name(&self) -> &str {
+        "password"
+    }
+
+    async fn authenticate(
+        &self,
+        state: &AppState,
+        input: &SigninUser,
+    ) -> Result<Option<User>, AppError> {
+        state.verify_password_user(input).await
+    }
+}
@@ -0,0 +1,261 @@
+use async_trait::async_trait;
+use chat_core::ChatType;
+
+use super::{CommandContext, CommandReply, SlashCommand};
+use crate::AppError;
+
+pub(super) struct HelpCommand;
+pub(super) struct MeCommand;
+pub(super) struct TopicCommand;
+pub(super) struct InviteCommand;
+pub(super) struct LeaveCommand;
+pub(super) struct RemindCommand;
+
+#[async_trait]
+impl SlashCommand for HelpCommand {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn description(&self) -> &str {
+        "List available commands"
+    }
+
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError> {
+        let mut lines: Vec<String> = ctx
+            .state
+            .commands
+            .iter()
+            .map(|cmd| format!("/{} - {}", cmd.name(), cmd.description()))
+            .collect();
+
+        let external = ctx.state.list_commands(ctx.user.ws_id as _).await?;
+        lines.extend(
+            external
+                .iter()
+                .map(|cmd| format!("/{} - {}", cmd.name, cmd.description)),
+        );
+
+        Ok(CommandReply::Ephemeral(lines.join("\n")))
+    }
+}
+
+#[async_trait]
+impl SlashCommand for MeCommand {
+    fn name(&self) -> &str {
+        "me"
+    }
+
+    fn description(&self) -> &str {
+        "Describe an action, e.g. /me waves"
+    }
+
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError> {
+        if ctx.args.is_empty() {
+            return Ok(CommandReply::Ephemeral("Usage: /me <action>".to_string()));
+        }
+        Ok(CommandReply::Public(format!(
+            "_{} {}_",
+            ctx.user.fullname, ctx.args
+        )))
+    }
+}
+
+#[async_trait]
+impl SlashCommand for TopicCommand {
+    fn name(&self) -> &str {
+        "topic"
+    }
+
+    fn description(&self) -> &str {
+        "Set the topic of the chat"
+    }
+
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError> {
+        if ctx.args.is_empty() {
+            let topic = ctx.chat.topic.as_deref().unwrap_or("(none)");
+            return Ok(CommandReply::Ephemeral(format!("Current topic: {}", topic)));
+        }
+        if ctx.args.chars().count() > 256 {
+            return Ok(CommandReply::Ephemeral(
+                "Topic must be at most 256 characters".to_string(),
+            ));
+        }
+
+        ctx.state
+            .update_chat_topic(ctx.chat.id as _, ctx.args)
+            .await?;
+        Ok(CommandReply::Public(format!(
+            "{} set the topic: {}",
+            ctx.user.fullname, ctx.args
+        )))
+    }
+}
+
+#[async_trait]
+impl SlashCommand for InviteCommand {
+    fn name(&self) -> &str {
+        "invite"
+    }
+
+    fn description(&self) -> &str {
+        "Invite a user by email, e.g. /invite @alice@acme.org"
+    }
+
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError> {
+        let Some(email) = ctx.args.strip_prefix('@').filter(|v| !v.is_empty()) else {
+            return Ok(CommandReply::Ephemeral(
+                "Usage: /invite @<email>".to_string(),
+            ));
+        };
+        if ctx.chat.r#type == ChatType::Single {
+            return Ok(CommandReply::Ephemeral(
+                "Cannot invite users to a direct message".to_string(),
+            ));
+        }
+
+        let user = match ctx.state.find_user_by_email(email).await? {
+            Some(user) if user.ws_id == ctx.user.ws_id => user,
+            _ => return Ok(CommandReply::Ephemeral(format!("User {} not found", email))),
+        };
+        if ctx.chat.members.contains(&user.id) {
+            return Ok(CommandReply::Ephemeral(format!(
+                "{} is already a member",
+                user.fullname
+            )));
+        }
+
+        ctx.state
+            .add_chat_member(ctx.chat.id as _, user.id as _)
+            .await?;
+        Ok(CommandReply::Public(format!(
+            "{} invited {}",
+            ctx.user.fullname, user.fullname
+        )))
+    }
+}
+
+#[async_trait]
+impl SlashCommand for LeaveCommand {
+    fn name(&self) -> &str {
+        "leave"
+    }
+
+    fn description(&self) -> &str {
+        "Leave the chat"
+    }
+
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError> {
+        if ctx.chat.r#type == ChatType::Single {
+            return Ok(CommandReply::Ephemeral(
+                "Cannot leave a direct message".to_string(),
+            ));
+        }
+
+        ctx.state
+            .remove_chat_member(ctx.chat.id as _, ctx.user.id as _)
+            .await?;
+        Ok(CommandReply::Ephemeral("You left the chat".to_string()))
+    }
+}
+
+#[async_trait]
+impl SlashCommand for RemindCommand {
+    fn name(&self) -> &str {
+        "remind"
+    }
+
+    fn description(&self) -> &str {
+        "Remind yourself later, e.g. /remind 10m stand up"
+    }
+
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError> {
+        let usage = || CommandReply::Ephemeral("Usage: /remind <30s|10m|2h|1d> <text>".into());
+        let Some((when, text)) = ctx.args.split_once(char::is_whitespace) else {
+            return Ok(usage());
+        };
+        let (Some(secs), text) = (parse_duration(when), text.trim()) else {
+            return Ok(usage());
+        };
+        if text.is_empty() {
+            return Ok(usage());
+        }
+
+        ctx.state
+            .create_reminder(ctx.user.id as _, ctx.chat.id as _, text, secs)
+            .await?;
+        Ok(CommandReply::Ephemeral(format!(
+            "I will remind you in {}: {}",
+            when, text
+        )))
+    }
+}
+
+/// parse durations like `30s`, `10m`, `2h` or `1d` into seconds
+fn parse_duration(s: &str) -> Option<i64> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        _ => return None,
+    };
+    let n: i64 = s[..s.len() - 1].parse().ok()?;
+    if n <= 0 {
+        return None;
+    }
+    n.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    #[test]
+    fn parse_duration_should_work() {
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("10m"), Some(600));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("m"), None);
+    }
+
+    #[tokio::test]
+    async fn invite_and_leave_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let chat = state.get_chat_by_id(2).await?.expect("chat should exist");
+
+        let ctx = CommandContext {
+            state: &state,
+            user: &user,
+            chat: &chat,
+            args: "@charlie1@acme.org",
+        };
+        let reply = InviteCommand.execute(ctx).await?;
+        assert_eq!(
+            reply,
+            CommandReply::Public("Tyr Chen invited Charlie Chen".to_string())
+        );
+        assert!(state.is_chat_member(2, 4).await?);
+
+        let ctx = CommandContext {
+            state: &state,
+            user: &user,
+            chat: &chat,
+            args: "",
+        };
+        let reply = LeaveCommand.execute(ctx).await?;
+        assert_eq!(
+            reply,
+            CommandReply::Ephemeral("You left the chat".to_string())
+        );
+        assert!(!state.is_chat_member(2, 1).await?);
+
+        Ok(())
+    }
+}
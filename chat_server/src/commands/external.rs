@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use super::{CommandContext, CommandReply, SlashCommand};
+use crate::{AppError, AppState};
+
+const EXTERNAL_COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A slash command registered by a workspace, served by an HTTP endpoint
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ExternalCommand {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub url: String,
+    pub description: String,
+    pub created_at: DateTime<Local>,
+}
+
+/// payload POSTed to the command endpoint
+#[derive(Debug, Serialize)]
+struct ExternalCommandRequest<'a> {
+    command: &'a str,
+    text: &'a str,
+    ws_id: i64,
+    chat_id: i64,
+    user_id: i64,
+    user_name: &'a str,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ResponseType {
+    #[default]
+    Ephemeral,
+    InChannel,
+}
+
+/// response expected from the command endpoint
+#[derive(Debug, Deserialize)]
+struct ExternalCommandResponse {
+    #[serde(default)]
+    response_type: ResponseType,
+    text: String,
+}
+
+impl ExternalCommand {
+    async fn call(
+        &self,
+        state: &AppState,
+        req: &ExternalCommandRequest<'_>,
+    ) -> anyhow::Result<ExternalCommandResponse> {
+        let res = state
+            .http
+            .post(&self.url)
+            .timeout(EXTERNAL_COMMAND_TIMEOUT)
+            .json(req)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let ret = res
+            .json()
+            .await
+            .context("invalid external command response")?;
+        Ok(ret)
+    }
+}
+
+#[async_trait]
+impl SlashCommand for ExternalCommand {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError> {
+        let req = ExternalCommandRequest {
+            command: &self.name,
+            text: ctx.args,
+            ws_id: self.ws_id,
+            chat_id: ctx.chat.id,
+            user_id: ctx.user.id,
+            user_name: &ctx.user.fullname,
+        };
+
+        let ret = match self.call(ctx.state, &req).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                warn!("External command /{} failed: {:?}", self.name, e);
+                return Ok(CommandReply::Ephemeral(format!(
+                    "Command /{} failed, please try again later",
+                    self.name
+                )));
+            }
+        };
+
+        Ok(match ret.response_type {
+            ResponseType::Ephemeral => CommandReply::Ephemeral(ret.text),
+            ResponseType::InChannel => CommandReply::Public(ret.text),
+        })
+    }
+}
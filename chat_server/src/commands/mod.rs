@@ -0,0 +1,113 @@
+mod builtin;
+mod external;
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use async_trait::async_trait;
+use chat_core::{Chat, User};
+
+use crate::{AppError, AppState};
+
+pub(crate) use external::ExternalCommand;
+
+/// How a slash command answers the caller
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandReply {
+    /// posted into the chat as a message from the caller
+    Public(String),
+    /// sent only to the caller over SSE, never stored
+    Ephemeral(String),
+}
+
+pub struct CommandContext<'a> {
+    pub state: &'a AppState,
+    pub user: &'a User,
+    pub chat: &'a Chat,
+    pub args: &'a str,
+}
+
+#[async_trait]
+pub trait SlashCommand: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    async fn execute(&self, ctx: CommandContext<'_>) -> Result<CommandReply, AppError>;
+}
+
+#[derive(Clone)]
+pub struct CommandRegistry {
+    commands: BTreeMap<String, Arc<dyn SlashCommand>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, cmd: impl SlashCommand + 'static) {
+        self.commands.insert(cmd.name().to_string(), Arc::new(cmd));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn SlashCommand>> {
+        self.commands.get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn SlashCommand>> {
+        self.commands.values()
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(builtin::HelpCommand);
+        registry.register(builtin::MeCommand);
+        registry.register(builtin::TopicCommand);
+        registry.register(builtin::InviteCommand);
+        registry.register(builtin::LeaveCommand);
+        registry.register(builtin::RemindCommand);
+        registry
+    }
+}
+
+/// Split `/name args` into the command name and its arguments. Content like
+/// `/usr/bin` is not a valid command name, so it is sent as a normal message.
+pub(crate) fn parse_command(content: &str) -> Option<(&str, &str)> {
+    let s = content.strip_prefix('/')?;
+    let (name, args) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    if !is_valid_command_name(name) {
+        return None;
+    }
+    Some((name, args.trim()))
+}
+
+pub(crate) fn is_valid_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_should_work() {
+        assert_eq!(parse_command("/me waves"), Some(("me", "waves")));
+        assert_eq!(parse_command("/leave"), Some(("leave", "")));
+        assert_eq!(
+            parse_command("/remind  10m  stand up "),
+            Some(("remind", "10m  stand up"))
+        );
+        assert_eq!(parse_command("/usr/bin is a path"), None);
+        assert_eq!(parse_command("/"), None);
+        assert_eq!(parse_command("hello"), None);
+    }
+}
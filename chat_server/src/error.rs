@@ -51,6 +51,51 @@ pub enum AppError {
     #[error("chat file error: {0}")]
     ChatFileError(String),
 
+    #[error("command error: {0}")]
+    CommandError(String),
+
+    #[error("presence error: {0}")]
+    PresenceError(String),
+
+    #[error("email error: {0}")]
+    EmailError(String),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
+    #[error("session revoked, please sign in again")]
+    SessionRevoked,
+
+    #[error("email not verified: {0}")]
+    EmailNotVerified(String),
+
+    #[error("2FA error: {0}")]
+    TwoFactorError(String),
+
+    #[error("the workspace requires 2FA, enable it first")]
+    TwoFactorRequired,
+
+    #[error("single sign-on error: {0}")]
+    OidcError(String),
+
+    #[error("too many failed attempts, retry in {0} seconds")]
+    TooManyAttempts(u64),
+
+    #[error("api token error: {0}")]
+    ApiTokenError(String),
+
+    #[error("webhook error: {0}")]
+    WebhookError(String),
+
+    #[error("interaction error: {0}")]
+    InteractionError(String),
+
+    #[error("bot error: {0}")]
+    BotError(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
     #[error("parse header value: {0}")]
     HeaderError(#[from] header::InvalidHeaderValue),
 }
@@ -79,8 +124,29 @@ impl IntoResponse for AppError {
             Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
             Self::ChatFileError(_) => StatusCode::BAD_REQUEST,
             Self::HeaderError(_) => StatusCode::BAD_REQUEST,
+            Self::CommandError(_) => StatusCode::BAD_REQUEST,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::PresenceError(_) => StatusCode::BAD_REQUEST,
+            Self::EmailError(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidToken(_) => StatusCode::BAD_REQUEST,
+            Self::SessionRevoked => StatusCode::UNAUTHORIZED,
+            Self::EmailNotVerified(_) => StatusCode::FORBIDDEN,
+            Self::TwoFactorError(_) => StatusCode::BAD_REQUEST,
+            Self::TwoFactorRequired => StatusCode::FORBIDDEN,
+            Self::OidcError(_) => StatusCode::BAD_REQUEST,
+            Self::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::ApiTokenError(_) => StatusCode::BAD_REQUEST,
+            Self::WebhookError(_) => StatusCode::BAD_REQUEST,
+            Self::InteractionError(_) => StatusCode::BAD_REQUEST,
+            Self::BotError(_) => StatusCode::BAD_GATEWAY,
         };
 
-        (status, Json(ErrorOutput::new(self.to_string()))).into_response()
+        let body = Json(ErrorOutput::new(self.to_string()));
+        match self {
+            Self::TooManyAttempts(secs) => {
+                (status, [(header::RETRY_AFTER, secs.to_string())], body).into_response()
+            }
+            _ => (status, body).into_response(),
+        }
     }
 }
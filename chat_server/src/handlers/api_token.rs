@@ -0,0 +1,111 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    models::{CreateApiToken, CreateBot},
+    AppError, AppState,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/bots",
+    responses(
+        (status = 200, description = "Bots created by the user", body = Vec<User>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_bots_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let bots = state.list_bots(&user).await?;
+    Ok(Json(bots))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/bots",
+    responses(
+        (status = 201, description = "Bot created", body = User),
+        (status = 403, description = "Not a workspace owner or admin", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_bot_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateBot>,
+) -> Result<impl IntoResponse, AppError> {
+    let bot = state.create_bot(&user, &input).await?;
+    Ok((StatusCode::CREATED, Json(bot)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tokens",
+    responses(
+        (status = 200, description = "API tokens of the user and its bots", body = Vec<ApiToken>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_api_tokens_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let tokens = state.list_api_tokens(&user).await?;
+    Ok(Json(tokens))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/tokens",
+    responses(
+        (status = 201, description = "API token created, shown only once", body = CreatedApiToken),
+        (status = 403, description = "Not the creator of the bot", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_api_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateApiToken>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = state.create_api_token(&user, &input).await?;
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/tokens/{id}",
+    params(
+        ("id" = i64, Path, description = "API token id")
+    ),
+    responses(
+        (status = 204, description = "API token revoked"),
+        (status = 404, description = "API token not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn revoke_api_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.revoke_api_token(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,32 +1,47 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use utoipa::ToSchema;
 
 use crate::{
     error::ErrorOutput,
-    models::{CreateUser, SigninUser},
+    middlewares::ClientIp,
+    models::{CreateUser, ForgotPassword, ResetPassword, SigninUser, VerifyEmail},
     AppError, AppState,
 };
+use chat_core::User;
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct AuthOutput {
-    token: String,
+    pub(crate) token: String,
 }
 
 #[utoipa::path(
     post,
     path = "/api/signin",
     responses(
-        (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 200, description = "User signed in, or a challenge if 2FA is enabled", body = AuthOutput),
+        (status = 403, description = "Invalid email or password", body = ErrorOutput),
+        (status = 429, description = "Too many failed attempts, see Retry-After", body = ErrorOutput),
     )
 )]
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = state.verify_user(&input).await?;
+    let user = state.signin(&input, ip).await?;
 
     match user {
+        Some(user) if state.two_factor_enabled(user.id).await? => {
+            let body = Json(state.signin_challenge(&user)?);
+            Ok((StatusCode::OK, body).into_response())
+        }
         Some(user) => {
             let token = state.ek.sign(user)?;
             let body = Json(AuthOutput { token });
@@ -51,12 +66,91 @@ pub(crate) async fn signup_handler(
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
+    if state.mailer.is_some() {
+        let state = state.clone();
+        let user = user.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.send_email_verification(&user).await {
+                warn!("Failed to send email verification to {}: {}", user.email, e);
+            }
+        });
+    }
     let token = state.ek.sign(user)?;
     let body = Json(AuthOutput { token });
 
     Ok((StatusCode::CREATED, body))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/password/forgot",
+    responses(
+        (status = 202, description = "Reset link sent if the email is known"),
+    )
+)]
+pub(crate) async fn forgot_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ForgotPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.forgot_password(&input).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/password/reset",
+    responses(
+        (status = 204, description = "Password reset, all sessions are signed out"),
+        (status = 400, description = "Invalid or used token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reset_password(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Target of the links in verification emails
+#[utoipa::path(
+    get,
+    path = "/api/email/verify",
+    params(
+        VerifyEmail
+    ),
+    responses(
+        (status = 200, description = "Email verified"),
+        (status = 400, description = "Invalid or used token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Query(input): Query<VerifyEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    state.verify_email(&input.token).await?;
+    Ok("Your email is verified.")
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/email/verification",
+    responses(
+        (status = 202, description = "Verification email sent"),
+        (status = 400, description = "Email already verified", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn resend_verification_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.send_email_verification(&user).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,7 +196,7 @@ mod tests {
         let user = CreateUser::new("none", name, email, password);
         state.create_user(&user).await?;
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), ClientIp(None), Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::OK);
@@ -119,7 +213,7 @@ mod tests {
         let email = "alice@acme.org";
         let password = "Hunter42";
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), ClientIp(None), Json(input))
             .await
             .into_response();
         assert_eq!(ret.status(), StatusCode::FORBIDDEN);
@@ -129,4 +223,22 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn signin_locked_out_should_429_with_retry_after() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ip = ClientIp(Some("10.0.0.3".parse()?));
+        for _ in 0..state.config.auth.signin.email_attempts {
+            let input = SigninUser::new("tchen1@acme.org", "wrong");
+            signin_handler(State(state.clone()), ip, Json(input)).await?;
+        }
+        let input = SigninUser::new("tchen1@acme.org", "123456");
+        let ret = signin_handler(State(state), ip, Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = ret.headers()["retry-after"].to_str()?.parse()?;
+        assert!(retry_after > 0 && retry_after <= 30);
+        Ok(())
+    }
 }
@@ -0,0 +1,49 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+
+use crate::{models::CreateCommand, AppError, AppState};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/commands",
+    responses(
+        (status = 200, description = "List of workspace slash commands", body = Vec<ExternalCommand>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let cmds = state.list_commands(user.ws_id as _).await?;
+    Ok(Json(cmds))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/commands",
+    responses(
+        (status = 201, description = "Slash command registered", body = ExternalCommand),
+        (status = 403, description = "Only the workspace owner can register commands", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateCommand>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.find_workspace_by_id(user.ws_id as _).await?;
+    if ws.map(|ws| ws.owner_id) != Some(user.id) {
+        return Err(AppError::PermissionDenied(
+            "only the workspace owner can register commands".to_string(),
+        ));
+    }
+
+    let cmd = state.create_command(input, user.ws_id as _).await?;
+    Ok((StatusCode::CREATED, Json(cmd)))
+}
@@ -0,0 +1,94 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    models::{MarkRead, Unsubscribe, UpdateEmailPreferences},
+    AppError, AppState,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/users/me/email_preferences",
+    responses(
+        (status = 200, description = "Email preferences of the user", body = EmailPreferences),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_email_preferences_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let prefs = state.get_email_preferences(user.id as _).await?;
+    Ok(Json(prefs))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/users/me/email_preferences",
+    responses(
+        (status = 200, description = "Email preferences updated", body = EmailPreferences),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_email_preferences_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateEmailPreferences>,
+) -> Result<impl IntoResponse, AppError> {
+    let prefs = state.update_email_preferences(user.id as _, input).await?;
+    Ok(Json(prefs))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/read",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 204, description = "Chat marked as read"),
+        (status = 404, description = "Message not found in the chat", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn mark_chat_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+    Json(input): Json<MarkRead>,
+) -> Result<impl IntoResponse, AppError> {
+    state.mark_chat_read(user.id as _, chat_id, input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Target of the unsubscribe links in emails, works without signing in
+#[utoipa::path(
+    get,
+    path = "/api/unsubscribe",
+    params(
+        Unsubscribe
+    ),
+    responses(
+        (status = 200, description = "Unsubscribed from all emails"),
+        (status = 404, description = "Unknown token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn unsubscribe_handler(
+    State(state): State<AppState>,
+    Query(input): Query<Unsubscribe>,
+) -> Result<impl IntoResponse, AppError> {
+    state.unsubscribe(&input.token).await?;
+    Ok("You have been unsubscribed from all emails.")
+}
@@ -0,0 +1,123 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    models::{CreateIncomingWebhook, UpdateIncomingWebhook, WebhookPayload},
+    AppError, AppState,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/webhooks",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Incoming webhooks of the chat", body = Vec<IncomingWebhook>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_incoming_webhooks_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let hooks = state.list_incoming_webhooks(id).await?;
+    Ok(Json(hooks))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/webhooks",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 201, description = "Incoming webhook created, its url is shown only once", body = CreatedIncomingWebhook),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_incoming_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateIncomingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let hook = state.create_incoming_webhook(&user, id, &input).await?;
+    Ok((StatusCode::CREATED, Json(hook)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/webhooks/{id}",
+    params(
+        ("id" = i64, Path, description = "Incoming webhook id")
+    ),
+    responses(
+        (status = 200, description = "Incoming webhook updated", body = IncomingWebhook),
+        (status = 404, description = "Incoming webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_incoming_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<UpdateIncomingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let hook = state.update_incoming_webhook(&user, id, &input).await?;
+    Ok(Json(hook))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/webhooks/{id}",
+    params(
+        ("id" = i64, Path, description = "Incoming webhook id")
+    ),
+    responses(
+        (status = 204, description = "Incoming webhook deleted"),
+        (status = 404, description = "Incoming webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_incoming_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_incoming_webhook(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/hooks/{token}",
+    params(
+        ("token" = String, Path, description = "Secret token of the webhook")
+    ),
+    responses(
+        (status = 201, description = "Message posted as the bot of the webhook", body = Message),
+        (status = 404, description = "Unknown or disabled webhook", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn post_incoming_webhook_handler(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(input): Json<WebhookPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = state.post_incoming_webhook(&token, input).await?;
+    Ok((StatusCode::CREATED, Json(msg)))
+}
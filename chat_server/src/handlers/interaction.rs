@@ -0,0 +1,86 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    models::{Interaction, UpdateBotInteractions},
+    AppError, AppState,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    put,
+    path = "/api/bots/{id}/interactions",
+    params(
+        ("id" = i64, Path, description = "Bot id")
+    ),
+    responses(
+        (status = 200, description = "Interactions url set, with a new secret", body = BotInteractions),
+        (status = 400, description = "Invalid url", body = ErrorOutput),
+        (status = 404, description = "Bot not created by the user", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_bot_interactions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<UpdateBotInteractions>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.update_bot_interactions(&user, id, &input).await?;
+    Ok(Json(settings))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/bots/{id}/interactions",
+    params(
+        ("id" = i64, Path, description = "Bot id")
+    ),
+    responses(
+        (status = 204, description = "Interactions of the bot are no longer sent"),
+        (status = 404, description = "Bot without interactions", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_bot_interactions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_bot_interactions(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/messages/{id}/interactions",
+    params(
+        ("id" = i64, Path, description = "Message id")
+    ),
+    responses(
+        (status = 200, description = "Interaction sent to the bot, the message as the bot left it", body = Message),
+        (status = 400, description = "Unknown action or option", body = ErrorOutput),
+        (status = 404, description = "Not a message of a bot with interactions", body = ErrorOutput),
+        (status = 502, description = "The bot failed", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn interact_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<Interaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = state.interact(&user, id, input).await?;
+    Ok(Json(msg))
+}
@@ -7,10 +7,10 @@ use axum::{
 };
 use tokio::fs;
 use tokio_util::io::ReaderStream;
-use tracing::{info, warn};
+use tracing::warn;
 
 use crate::{
-    models::{ChatFile, CreateMessage, ListMessages},
+    models::{CreateMessage, ListMessages, MessageOutput},
     AppError, AppState,
 };
 use chat_core::User;
@@ -20,6 +20,7 @@ use chat_core::User;
     path = "/api/chats/{id}",
     responses(
         (status = 201, description = "send message", body = Message),
+        (status = 200, description = "ephemeral reply to a slash command", body = EphemeralMessage),
     ),
     security(
         ("token" = [])
@@ -31,8 +32,12 @@ pub(crate) async fn send_message_handler(
     Path(chat_id): Path<u64>,
     Json(input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msg = state.create_message(input, chat_id, user.id as _).await?;
-    Ok((StatusCode::CREATED, Json(msg)))
+    let output = state.send_message(input, chat_id, &user).await?;
+    let status = match output {
+        MessageOutput::Message(_) => StatusCode::CREATED,
+        MessageOutput::Ephemeral(_) => StatusCode::OK,
+    };
+    Ok((status, Json(output)))
 }
 
 #[utoipa::path(
@@ -120,7 +125,6 @@ pub(crate) async fn upload_handler(
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
     let ws_id = user.ws_id as u64;
-    let base_dir = &state.config.server.base_dir;
     let mut files = vec![];
 
     while let Some(field) = multipart.next_field().await? {
@@ -130,16 +134,7 @@ pub(crate) async fn upload_handler(
             continue;
         };
 
-        let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(base_dir);
-        if path.exists() {
-            info!("File {} already exists: {:?}", filename, path);
-        } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exist")).await?;
-            fs::write(path, data).await?;
-        }
-
-        files.push(file.url());
+        files.push(state.store_file(ws_id, &filename, &data).await?);
     }
 
     Ok(Json(files))
@@ -1,13 +1,31 @@
+mod api_token;
 mod auth;
 mod chat;
+mod command;
+mod email;
+mod incoming_webhook;
+mod interaction;
 mod messages;
+mod outgoing_webhook;
+mod presence;
+mod sso;
+mod two_factor;
 mod workspace;
 
 use axum::response::IntoResponse;
 
+pub(crate) use api_token::*;
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use command::*;
+pub(crate) use email::*;
+pub(crate) use incoming_webhook::*;
+pub(crate) use interaction::*;
 pub(crate) use messages::*;
+pub(crate) use outgoing_webhook::*;
+pub(crate) use presence::*;
+pub(crate) use sso::*;
+pub(crate) use two_factor::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
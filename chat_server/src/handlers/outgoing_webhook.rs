@@ -0,0 +1,172 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    models::{CreateOutgoingWebhook, ListDeliveries, UpdateOutgoingWebhook},
+    AppError, AppState,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/outgoing_webhooks",
+    responses(
+        (status = 200, description = "Outgoing webhooks of the workspace", body = Vec<OutgoingWebhook>),
+        (status = 403, description = "Not a workspace admin", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_outgoing_webhooks_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let hooks = state.list_outgoing_webhooks(&user).await?;
+    Ok(Json(hooks))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/outgoing_webhooks",
+    responses(
+        (status = 201, description = "Outgoing webhook created, its secret is shown only once", body = CreatedOutgoingWebhook),
+        (status = 400, description = "Invalid url or events", body = ErrorOutput),
+        (status = 403, description = "Not a workspace admin", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_outgoing_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateOutgoingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let hook = state.create_outgoing_webhook(&user, &input).await?;
+    Ok((StatusCode::CREATED, Json(hook)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/outgoing_webhooks/{id}",
+    params(
+        ("id" = i64, Path, description = "Outgoing webhook id")
+    ),
+    responses(
+        (status = 200, description = "Outgoing webhook updated", body = OutgoingWebhook),
+        (status = 404, description = "Outgoing webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_outgoing_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<UpdateOutgoingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let hook = state.update_outgoing_webhook(&user, id, &input).await?;
+    Ok(Json(hook))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/outgoing_webhooks/{id}",
+    params(
+        ("id" = i64, Path, description = "Outgoing webhook id")
+    ),
+    responses(
+        (status = 204, description = "Outgoing webhook deleted with its deliveries"),
+        (status = 404, description = "Outgoing webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_outgoing_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_outgoing_webhook(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/outgoing_webhooks/{id}/deliveries",
+    params(
+        ("id" = i64, Path, description = "Outgoing webhook id"),
+        ListDeliveries
+    ),
+    responses(
+        (status = 200, description = "Delivery log of the webhook, the latest first", body = Vec<WebhookDelivery>),
+        (status = 404, description = "Outgoing webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_webhook_deliveries_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(input): Query<ListDeliveries>,
+) -> Result<impl IntoResponse, AppError> {
+    let deliveries = state
+        .list_webhook_deliveries(&user, Some(id), &input)
+        .await?;
+    Ok(Json(deliveries))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhook_deliveries",
+    params(
+        ListDeliveries
+    ),
+    responses(
+        (status = 200, description = "Deliveries of all the webhooks, `status=dead` for the dead letters", body = Vec<WebhookDelivery>),
+        (status = 403, description = "Not a workspace admin", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_all_webhook_deliveries_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListDeliveries>,
+) -> Result<impl IntoResponse, AppError> {
+    let deliveries = state.list_webhook_deliveries(&user, None, &input).await?;
+    Ok(Json(deliveries))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/webhook_deliveries/{id}/retry",
+    params(
+        ("id" = i64, Path, description = "Webhook delivery id")
+    ),
+    responses(
+        (status = 200, description = "Dead letter queued again", body = WebhookDelivery),
+        (status = 404, description = "Dead letter not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn retry_webhook_delivery_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let delivery = state.retry_webhook_delivery(&user, id).await?;
+    Ok(Json(delivery))
+}
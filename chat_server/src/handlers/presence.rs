@@ -0,0 +1,52 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    models::{ListPresences, UpdatePresence},
+    AppError, AppState,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/users/presence",
+    params(
+        ListPresences
+    ),
+    responses(
+        (status = 200, description = "Presence of workspace users", body = Vec<UserPresence>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListPresences>,
+) -> Result<impl IntoResponse, AppError> {
+    let presences = state.fetch_presences(input, user.ws_id as _).await?;
+    Ok(Json(presences))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/users/presence",
+    responses(
+        (status = 200, description = "Presence updated", body = UserPresence),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdatePresence>,
+) -> Result<impl IntoResponse, AppError> {
+    let presence = state.update_presence(user.id as _, input).await?;
+    Ok(Json(presence))
+}
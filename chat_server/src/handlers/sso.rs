@@ -0,0 +1,90 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::SET_COOKIE, StatusCode},
+    response::{IntoResponse, Redirect},
+    Json,
+};
+use axum_extra::{headers::Cookie, TypedHeader};
+
+use crate::{
+    handlers::AuthOutput,
+    models::{OidcCallback, OIDC_STATE_COOKIE},
+    AppError, AppState,
+};
+
+/// seconds the browser keeps the binding of an authorization request
+const STATE_COOKIE_MAX_AGE: u32 = 600;
+
+#[utoipa::path(
+    get,
+    path = "/api/oidc",
+    responses(
+        (status = 200, description = "Names of the single sign-on providers", body = Vec<String>),
+    )
+)]
+pub(crate) async fn list_oidc_providers_handler(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    Json(state.list_oidc_providers())
+}
+
+/// Redirects to the provider to sign in
+#[utoipa::path(
+    get,
+    path = "/api/oidc/{provider}/authorize",
+    params(
+        ("provider" = String, Path, description = "Provider name")
+    ),
+    responses(
+        (status = 303, description = "Redirect to the provider, the request is bound to the browser by a cookie"),
+        (status = 404, description = "Unknown provider", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn oidc_authorize_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let start = state.start_oidc(&provider).await?;
+    let cookie = state_cookie(&provider, &start.binding, STATE_COOKIE_MAX_AGE);
+    Ok(([(SET_COOKIE, cookie)], Redirect::to(&start.url)))
+}
+
+/// Target of the redirect back from the provider, the account is linked or
+/// created on first sign in
+#[utoipa::path(
+    get,
+    path = "/api/oidc/{provider}/callback",
+    params(
+        ("provider" = String, Path, description = "Provider name"),
+        OidcCallback
+    ),
+    responses(
+        (status = 200, description = "User signed in, or a challenge if 2FA is enabled", body = AuthOutput),
+        (status = 400, description = "Invalid or expired sign in request, or started in another browser", body = ErrorOutput),
+        (status = 403, description = "No workspace for the user, or the email is not verified", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn oidc_callback_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    cookies: Option<TypedHeader<Cookie>>,
+    Query(input): Query<OidcCallback>,
+) -> Result<impl IntoResponse, AppError> {
+    let binding = cookies.as_ref().and_then(|v| v.get(OIDC_STATE_COOKIE));
+    let user = state.finish_oidc(&provider, &input, binding).await?;
+    let clear = [(SET_COOKIE, state_cookie(&provider, "", 0))];
+    if state.two_factor_enabled(user.id).await? {
+        let body = Json(state.signin_challenge(&user)?);
+        return Ok((StatusCode::OK, clear, body).into_response());
+    }
+    let token = state.ek.sign(user)?;
+    Ok((StatusCode::OK, clear, Json(AuthOutput { token })).into_response())
+}
+
+/// Only sent back to the callback of the provider, and never readable by scripts
+fn state_cookie(provider: &str, value: &str, max_age: u32) -> String {
+    format!(
+        "{}={}; Path=/api/oidc/{}/callback; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        OIDC_STATE_COOKIE, value, provider, max_age
+    )
+}
@@ -0,0 +1,84 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+
+use crate::{
+    handlers::AuthOutput,
+    models::{TwoFactorCode, VerifyTwoFactor},
+    AppError, AppState,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    post,
+    path = "/api/users/me/2fa",
+    responses(
+        (status = 200, description = "Secret to enroll an authenticator app with", body = TwoFactorEnrollment),
+        (status = 400, description = "2FA already enabled", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn enroll_two_factor_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let enrollment = state.enroll_two_factor(&user).await?;
+    Ok(Json(enrollment))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/users/me/2fa/confirm",
+    responses(
+        (status = 200, description = "2FA enabled", body = RecoveryCodes),
+        (status = 400, description = "Invalid code", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn confirm_two_factor_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TwoFactorCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let codes = state.confirm_two_factor(user.id, input).await?;
+    Ok(Json(codes))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/users/me/2fa",
+    responses(
+        (status = 204, description = "2FA disabled"),
+        (status = 400, description = "Invalid code, or 2FA required by the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn disable_two_factor_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TwoFactorCode>,
+) -> Result<impl IntoResponse, AppError> {
+    state.disable_two_factor(&user, input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/signin/2fa",
+    responses(
+        (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 400, description = "Invalid challenge or code", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn verify_two_factor_handler(
+    State(state): State<AppState>,
+    Json(input): Json<VerifyTwoFactor>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.verify_two_factor(&input).await?;
+    let token = state.ek.sign(user)?;
+    Ok(Json(AuthOutput { token }))
+}
@@ -1,6 +1,9 @@
-use axum::{extract::State, response::IntoResponse, Extension, Json};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
 
-use crate::{AppError, AppState};
+use crate::{
+    models::{InviteUser, UpdateWorkspace},
+    AppError, AppState,
+};
 use chat_core::User;
 
 #[utoipa::path(
@@ -20,3 +23,44 @@ pub(crate) async fn list_chat_users_handler(
     let users = state.fetch_chat_users(user.ws_id as _).await?;
     Ok(Json(users))
 }
+
+#[utoipa::path(
+    patch,
+    path = "/api/workspace",
+    responses(
+        (status = 200, description = "Workspace updated", body = Workspace),
+        (status = 403, description = "Not the workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.update_workspace(&user, input).await?;
+    Ok(Json(ws))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspace/invitations",
+    responses(
+        (status = 204, description = "Invitation emailed"),
+        (status = 403, description = "Not the workspace owner or an admin", body = ErrorOutput),
+        (status = 409, description = "The email already has an account", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn invite_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<InviteUser>,
+) -> Result<impl IntoResponse, AppError> {
+    state.invite_user(&user, &input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,50 @@
+use reqwest::{redirect::Policy, Client, Response};
+
+/// The client of the calls to endpoints configured by users: redirects are
+/// not followed, they could lead to an internal service the url check rejects
+pub(crate) fn http_client() -> reqwest::Result<Client> {
+    Client::builder().redirect(Policy::none()).build()
+}
+
+/// Read the body of a response, failing once it's longer than `limit` bytes
+pub(crate) async fn read_body(mut res: Response, limit: usize) -> anyhow::Result<Vec<u8>> {
+    if res.content_length().is_some_and(|len| len > limit as u64) {
+        anyhow::bail!("the body is longer than {} bytes", limit);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            anyhow::bail!("the body is longer than {} bytes", limit);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{response::Redirect, routing::get, Router};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn redirects_and_large_bodies_should_be_refused() -> Result<()> {
+        let app = Router::new()
+            .route("/redirect", get(|| async { Redirect::temporary("/big") }))
+            .route("/big", get(|| async { "a".repeat(2048) }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = http_client()?;
+        let res = client.get(format!("{}/redirect", url)).send().await?;
+        assert_eq!(res.status(), 307);
+
+        let res = client.get(format!("{}/big", url)).send().await?;
+        assert!(read_body(res, 1024).await.is_err());
+        let res = client.get(format!("{}/big", url)).send().await?;
+        assert_eq!(read_body(res, 4096).await?.len(), 2048);
+        Ok(())
+    }
+}
@@ -1,31 +1,50 @@
+mod auth;
+mod commands;
 mod config;
 mod error;
 mod handlers;
+mod http;
+mod mailer;
 mod middlewares;
 mod models;
+mod oidc;
 mod openapi;
+mod sweeper;
+mod totp;
 
-use std::{fmt, ops::Deref, sync::Arc};
+use std::{collections::HashMap, fmt, ops::Deref, sync::Arc};
 
 use anyhow::Context;
+use auth::{load_backends, AuthBackend};
 use axum::{
-    middleware::from_fn_with_state,
-    routing::{get, post},
+    async_trait,
+    handler::Handler,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use chat_core::{
-    middlewares::{set_layer, verify_token, TokenVerify},
-    DecodingKey, EncodingKey, User,
+    is_api_token,
+    middlewares::{set_layer, verify_token, RateLimitLayer, TokenVerify},
+    verify_api_token, DecodingKey, EncodingKey, Scopes, User,
 };
+use commands::CommandRegistry;
 use handlers::*;
-use middlewares::verify_chat;
+use http::http_client;
+use middlewares::{resolve_webhook, verify_access, verify_chat, verify_scope, verify_session};
+use oidc::{load_providers, OidcProvider};
 use openapi::OpenApiRouter;
 use sqlx::PgPool;
 use tokio::fs;
 
-pub use config::AppConfig;
+pub use commands::{CommandContext, CommandReply, SlashCommand};
+pub use config::{
+    AppConfig, EmailConfig, EventMode, LdapConfig, LdapGroupConfig, OidcProviderConfig, RateLimits,
+};
 pub use error::{AppError, ErrorOutput};
+pub use mailer::{Email, EmailTemplate, Mailer};
 pub use models::*;
+pub use sweeper::{spawn_message_backfill, spawn_message_sweeper};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -38,42 +57,145 @@ pub struct AppStateInner {
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKey,
     pub(crate) pool: PgPool,
+    pub(crate) commands: CommandRegistry,
+    pub(crate) auth_backends: Vec<Box<dyn AuthBackend>>,
+    pub(crate) http: reqwest::Client,
+    pub(crate) mailer: Option<Mailer>,
+    pub(crate) oidc: HashMap<String, OidcProvider>,
 }
 
+#[async_trait]
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    async fn verify(&self, token: &str) -> Result<(User, Scopes), Self::Error> {
+        if !is_api_token(token) {
+            return Ok((self.dk.verify(token)?, Scopes::All));
+        }
+        match verify_api_token(&self.pool, token).await? {
+            Some((user, scopes)) => Ok((user, Scopes::Only(scopes))),
+            None => Err(AppError::InvalidToken(
+                "unknown, revoked or expired API token".to_string(),
+            )),
+        }
     }
 }
 
 pub async fn get_router(state: AppState) -> Result<Router, AppError> {
+    let server = &state.config.server;
+    let rate_limit = |limit| RateLimitLayer::new(limit).trust_proxy(server.trust_proxy);
+    let messages = rate_limit(server.rate_limit.messages);
+    let webhooks = rate_limit(server.rate_limit.webhooks);
+
     let chat = Router::new()
         .route(
             "/:id",
             get(get_chat_handler)
                 .patch(update_chat_handler)
                 .delete(delete_chat_handler)
-                .post(send_message_handler),
+                .post(send_message_handler.layer(messages.clone())),
         )
         .route("/:id/messages", get(list_message_handler))
+        .route("/:id/read", post(mark_chat_read_handler))
+        .route(
+            "/:id/webhooks",
+            get(list_incoming_webhooks_handler).post(create_incoming_webhook_handler),
+        )
         .layer(from_fn_with_state(state.clone(), verify_chat))
         .route("/", get(list_chat_handler).post(create_chat_handler));
 
     let api = Router::new()
         .route("/users", get(list_chat_users_handler))
+        .route(
+            "/users/presence",
+            get(list_presence_handler).put(update_presence_handler),
+        )
+        .route(
+            "/users/me/email_preferences",
+            get(get_email_preferences_handler).put(update_email_preferences_handler),
+        )
+        .route(
+            "/commands",
+            get(list_command_handler).post(create_command_handler),
+        )
         .nest("/chats", chat)
-        .route("/upload", post(upload_handler))
+        .route("/upload", post(upload_handler.layer(messages)))
         .route("/files/:ws_id/*path", get(file_handler))
-        .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+        .route("/workspace", patch(update_workspace_handler))
+        .route("/workspace/invitations", post(invite_user_handler))
+        .route("/bots", get(list_bots_handler).post(create_bot_handler))
+        .route(
+            "/bots/:id/interactions",
+            put(update_bot_interactions_handler).delete(delete_bot_interactions_handler),
+        )
+        .route("/messages/:id/interactions", post(interact_handler))
+        .route(
+            "/tokens",
+            get(list_api_tokens_handler).post(create_api_token_handler),
+        )
+        .route("/tokens/:id", delete(revoke_api_token_handler))
+        .route(
+            "/webhooks/:id",
+            patch(update_incoming_webhook_handler).delete(delete_incoming_webhook_handler),
+        )
+        .route(
+            "/outgoing_webhooks",
+            get(list_outgoing_webhooks_handler).post(create_outgoing_webhook_handler),
+        )
+        .route(
+            "/outgoing_webhooks/:id",
+            patch(update_outgoing_webhook_handler).delete(delete_outgoing_webhook_handler),
+        )
+        .route(
+            "/outgoing_webhooks/:id/deliveries",
+            get(list_webhook_deliveries_handler),
+        )
+        .route(
+            "/webhook_deliveries",
+            get(list_all_webhook_deliveries_handler),
+        )
+        .route(
+            "/webhook_deliveries/:id/retry",
+            post(retry_webhook_delivery_handler),
+        )
+        .layer(from_fn_with_state(state.clone(), verify_access))
+        // users held back must still be able to verify their email and enable 2FA
+        .route("/email/verification", post(resend_verification_handler))
+        .route(
+            "/users/me/2fa",
+            post(enroll_two_factor_handler).delete(disable_two_factor_handler),
+        )
+        .route("/users/me/2fa/confirm", post(confirm_two_factor_handler))
+        .layer(from_fn(verify_scope))
+        .layer(from_fn_with_state(state.clone(), verify_session))
+        .layer(rate_limit(server.rate_limit.api))
+        .layer(from_fn_with_state(state.clone(), verify_token::<AppState>));
+
+    let public = Router::new()
         .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler));
+        .route("/signin/2fa", post(verify_two_factor_handler))
+        .route("/signup", post(signup_handler))
+        .route("/password/forgot", post(forgot_password_handler))
+        .route("/password/reset", post(reset_password_handler))
+        .route("/email/verify", get(verify_email_handler))
+        .route("/unsubscribe", get(unsubscribe_handler))
+        .route("/oidc", get(list_oidc_providers_handler))
+        .route("/oidc/:provider/authorize", get(oidc_authorize_handler))
+        .route("/oidc/:provider/callback", get(oidc_callback_handler))
+        .route(
+            "/hooks/:token",
+            post(
+                post_incoming_webhook_handler
+                    .layer(webhooks)
+                    .layer(from_fn_with_state(state.clone(), resolve_webhook)),
+            ),
+        )
+        .layer(rate_limit(server.rate_limit.public));
 
     let app = Router::new()
         .openapi()
         .route("/", get(index_handler))
-        .nest("/api", api)
+        .nest("/api", api.merge(public))
         .with_state(state);
 
     Ok(set_layer(app))
@@ -97,12 +219,26 @@ impl AppState {
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("connect db failed")?;
+        let mailer = config
+            .email
+            .as_ref()
+            .map(Mailer::new)
+            .transpose()
+            .context("create mailer failed")?;
+        let http = http_client().context("create http client failed")?;
+        let oidc = load_providers(&config.oidc, &http);
+        let auth_backends = load_backends(&config.auth);
         Ok(Self {
             inner: Arc::new(AppStateInner {
                 config,
                 dk,
                 ek,
                 pool,
+                commands: CommandRegistry::default(),
+                auth_backends,
+                http,
+                mailer,
+                oidc,
             }),
         })
     }
@@ -130,12 +266,18 @@ mod test_util {
             let post = config.server.db_url.rfind('/').expect("db_url invalid");
             let server_url = &config.server.db_url[..post];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
+            let auth_backends = load_backends(&config.auth);
             let state = Self {
                 inner: Arc::new(AppStateInner {
                     config,
                     ek,
                     dk,
                     pool,
+                    commands: CommandRegistry::default(),
+                    auth_backends,
+                    http: http_client().context("create http client failed")?,
+                    mailer: None,
+                    oidc: HashMap::new(),
                 }),
             };
             Ok((tdb, state))
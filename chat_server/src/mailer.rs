@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::info;
+
+use crate::config::EmailConfig;
+
+const DEFAULT_LOCALE: &str = "en";
+
+/// Emails sent by the server, each with a template per locale
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmailTemplate {
+    Unread,
+    Digest,
+    Invitation,
+    PasswordReset,
+    EmailVerification,
+}
+
+/// A rendered email
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends emails through the configured SMTP relay
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    base_url: String,
+}
+
+impl EmailTemplate {
+    fn source(&self, locale: &str) -> &'static str {
+        match (locale, self) {
+            ("zh", Self::Unread) => include_str!("../templates/email/zh/unread.txt"),
+            ("zh", Self::Digest) => include_str!("../templates/email/zh/digest.txt"),
+            ("zh", Self::Invitation) => include_str!("../templates/email/zh/invitation.txt"),
+            ("zh", Self::PasswordReset) => {
+                include_str!("../templates/email/zh/password_reset.txt")
+            }
+            ("zh", Self::EmailVerification) => {
+                include_str!("../templates/email/zh/email_verification.txt")
+            }
+            (_, Self::Unread) => include_str!("../templates/email/en/unread.txt"),
+            (_, Self::Digest) => include_str!("../templates/email/en/digest.txt"),
+            (_, Self::Invitation) => include_str!("../templates/email/en/invitation.txt"),
+            (_, Self::PasswordReset) => include_str!("../templates/email/en/password_reset.txt"),
+            (_, Self::EmailVerification) => {
+                include_str!("../templates/email/en/email_verification.txt")
+            }
+        }
+    }
+
+    /// Render the template in the locale of the user, english if it's not
+    /// supported. `{{key}}` is replaced by the value of `key`.
+    pub fn render(&self, locale: &str, vars: &HashMap<&str, String>) -> Result<Email> {
+        let locale = match locale.split(['-', '_']).next() {
+            Some(v) if is_supported(v) => v,
+            _ => DEFAULT_LOCALE,
+        };
+        let mut text = render(self.source(locale), vars);
+        if vars.contains_key("unsubscribe_url") {
+            let footer = match locale {
+                "zh" => include_str!("../templates/email/zh/footer.txt"),
+                _ => include_str!("../templates/email/en/footer.txt"),
+            };
+            text.push_str(&render(footer, vars));
+        }
+
+        let (subject, body) = text
+            .strip_prefix("Subject: ")
+            .and_then(|v| v.split_once('\n'))
+            .ok_or_else(|| anyhow!("template {:?} has no subject", self))?;
+        Ok(Email {
+            subject: subject.trim().to_string(),
+            body: body.trim_start().to_string(),
+        })
+    }
+}
+
+impl Mailer {
+    pub fn new(config: &EmailConfig) -> Result<Self> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)?.build();
+        Ok(Self {
+            transport,
+            from: config.from.parse()?,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Public url of the server, for links in emails
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn unsubscribe_url(&self, token: &str) -> String {
+        format!("{}/api/unsubscribe?token={}", self.base_url, token)
+    }
+
+    pub async fn send(&self, to: &str, email: Email) -> Result<()> {
+        let msg = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)?;
+        self.transport.send(msg).await?;
+        info!("Sent email to {}", to);
+        Ok(())
+    }
+
+    /// Invite someone to a workspace
+    pub async fn send_invitation(
+        &self,
+        to: &str,
+        locale: &str,
+        inviter: &str,
+        workspace: &str,
+        link: &str,
+    ) -> Result<()> {
+        let vars = HashMap::from([
+            ("inviter", inviter.to_string()),
+            ("workspace", workspace.to_string()),
+            ("link", link.to_string()),
+        ]);
+        let email = EmailTemplate::Invitation.render(locale, &vars)?;
+        self.send(to, email).await
+    }
+
+    /// Send the link to reset a password, valid for `expires_in` minutes
+    pub async fn send_password_reset(
+        &self,
+        to: &str,
+        locale: &str,
+        name: &str,
+        link: &str,
+        expires_in: u64,
+    ) -> Result<()> {
+        let vars = HashMap::from([
+            ("name", name.to_string()),
+            ("link", link.to_string()),
+            ("expires_in", expires_in.to_string()),
+        ]);
+        let email = EmailTemplate::PasswordReset.render(locale, &vars)?;
+        self.send(to, email).await
+    }
+
+    /// Send the link to verify the email address, valid for `expires_in` minutes
+    pub async fn send_email_verification(
+        &self,
+        to: &str,
+        locale: &str,
+        name: &str,
+        link: &str,
+        expires_in: u64,
+    ) -> Result<()> {
+        let vars = HashMap::from([
+            ("name", name.to_string()),
+            ("link", link.to_string()),
+            ("expires_in", expires_in.to_string()),
+        ]);
+        let email = EmailTemplate::EmailVerification.render(locale, &vars)?;
+        self.send(to, email).await
+    }
+}
+
+pub(crate) fn is_supported(locale: &str) -> bool {
+    matches!(locale, "en" | "zh")
+}
+
+/// Replace the placeholders in one pass, so values are never expanded
+/// themselves. Unknown placeholders are kept as they are.
+fn render(template: &str, vars: &HashMap<&str, String>) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        text.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after
+            .find("}}")
+            .and_then(|end| vars.get(&after[..end]).map(|v| (v, end)));
+        match value {
+            Some((value, end)) => {
+                text.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                text.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    pub(crate) type Inbox = Arc<Mutex<Vec<String>>>;
+
+    /// A local SMTP sink keeping the data of the emails it receives
+    pub(crate) async fn start_smtp_sink() -> Result<(String, Inbox)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("smtp://{}", listener.local_addr()?);
+        let inbox: Inbox = Arc::new(Mutex::new(vec![]));
+        let ret = inbox.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let inbox = inbox.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 localhost ESMTP sink\r\n").await?;
+                    while let Some(line) = lines.next_line().await? {
+                        let reply: &[u8] = match line.to_uppercase() {
+                            v if v.starts_with("EHLO") => b"250 localhost\r\n",
+                            v if v.starts_with("DATA") => {
+                                writer.write_all(b"354 go ahead\r\n").await?;
+                                let mut data = vec![];
+                                while let Some(line) = lines.next_line().await? {
+                                    if line == "." {
+                                        break;
+                                    }
+                                    data.push(line);
+                                }
+                                inbox.lock().unwrap().push(data.join("\n"));
+                                b"250 ok\r\n"
+                            }
+                            v if v.starts_with("QUIT") => {
+                                writer.write_all(b"221 bye\r\n").await?;
+                                break;
+                            }
+                            _ => b"250 ok\r\n",
+                        };
+                        writer.write_all(reply).await?;
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        });
+        Ok((url, ret))
+    }
+
+    pub(crate) fn test_mailer(smtp_url: &str) -> Mailer {
+        Mailer::new(&EmailConfig {
+            smtp_url: smtp_url.to_string(),
+            from: "Chat <noreply@example.com>".to_string(),
+            base_url: "http://localhost:6688/".to_string(),
+            unread_delay: 10,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn template_should_render_in_user_locale() -> Result<()> {
+        let vars = HashMap::from([
+            ("name", "Tyr".to_string()),
+            ("link", "http://localhost/reset".to_string()),
+            ("expires_in", "30".to_string()),
+        ]);
+        let email = EmailTemplate::PasswordReset.render("zh-CN", &vars)?;
+        assert_eq!(email.subject, "重置您的 Chat 密码");
+        assert!(email.body.contains("Tyr 您好"));
+        assert!(email.body.contains("30 分钟"));
+
+        let email = EmailTemplate::PasswordReset.render("fr", &vars)?;
+        assert_eq!(email.subject, "Reset your Chat password");
+        assert!(!email.body.contains("unsubscribe"));
+        Ok(())
+    }
+
+    #[test]
+    fn unsubscribe_link_should_be_appended() -> Result<()> {
+        let vars = HashMap::from([
+            ("name", "Tyr".to_string()),
+            ("count", "2".to_string()),
+            ("messages", "- hi\n".to_string()),
+            ("base_url", "http://localhost".to_string()),
+            ("unsubscribe_url", "http://localhost/unsub".to_string()),
+        ]);
+        let email = EmailTemplate::Unread.render("en", &vars)?;
+        assert_eq!(email.subject, "Unread messages on Chat (2)");
+        assert!(email
+            .body
+            .ends_with("unsubscribe: http://localhost/unsub\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn values_should_not_be_expanded() {
+        let vars = HashMap::from([
+            ("sender", "{{name}}".to_string()),
+            ("name", "Alice".to_string()),
+        ]);
+        assert_eq!(
+            render("{{name}}: {{sender}} {{unknown}} {{", &vars),
+            "Alice: {{name}} {{unknown}} {{"
+        );
+    }
+
+    #[tokio::test]
+    async fn mailer_should_send_through_smtp() -> Result<()> {
+        let (url, inbox) = start_smtp_sink().await?;
+        let mailer = test_mailer(&url);
+        assert_eq!(mailer.base_url(), "http://localhost:6688");
+        mailer
+            .send_invitation(
+                "alice@acme.org",
+                "en",
+                "Tyr",
+                "acme",
+                "http://localhost/join",
+            )
+            .await?;
+
+        let inbox = inbox.lock().unwrap();
+        assert_eq!(inbox.len(), 1);
+        assert!(inbox[0].contains("To: alice@acme.org"));
+        assert!(inbox[0].contains("Subject: Tyr invited you to acme on Chat"));
+        Ok(())
+    }
+}
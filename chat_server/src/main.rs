@@ -1,5 +1,7 @@
+use std::net::SocketAddr;
+
 use anyhow::Result;
-use chat_server::{get_router, AppConfig, AppState};
+use chat_server::{get_router, spawn_message_backfill, spawn_message_sweeper, AppConfig, AppState};
 use tokio::net::TcpListener;
 use tracing::{info, level_filters::LevelFilter};
 use tracing_subscriber::{
@@ -18,10 +20,14 @@ async fn main() -> Result<()> {
     let addr = format!("0.0.0.0:{}", config.server.port);
 
     let state = AppState::try_new(config).await?;
+    spawn_message_sweeper(state.clone());
+    spawn_message_backfill(state.clone());
     let app = get_router(state).await?;
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    // connect info gives the client IP to the sign in lockout
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, app).await?;
     Ok(())
 }
@@ -0,0 +1,25 @@
+use std::{convert::Infallible, net::IpAddr};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use chat_core::middlewares::client_ip;
+
+use crate::AppState;
+
+/// IP of the client, from `X-Forwarded-For` behind a trusted reverse proxy.
+/// It's unknown if the server isn't served with connect info.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        let ip = client_ip(
+            &parts.headers,
+            &parts.extensions,
+            state.config.server.trust_proxy,
+        );
+        Ok(Self(ip))
+    }
+}
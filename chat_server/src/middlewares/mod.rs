@@ -1,3 +1,11 @@
 mod chat;
+mod client_ip;
+mod scope;
+mod session;
+mod webhook;
 
 pub use chat::verify_chat;
+pub use client_ip::ClientIp;
+pub use scope::verify_scope;
+pub use session::{verify_access, verify_session};
+pub use webhook::resolve_webhook;
@@ -0,0 +1,146 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chat_core::{Scope, Scopes};
+
+use crate::AppError;
+
+/// Let API tokens reach only the routes of their scopes. Account settings,
+/// tokens and workspace management are left to the sessions of signed in users.
+pub async fn verify_scope(req: Request, next: Next) -> Response {
+    let scopes = req.extensions().get::<Scopes>().unwrap();
+    if scopes.is_session() {
+        return next.run(req).await;
+    }
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|v| v.as_str())
+        .unwrap_or_else(|| req.uri().path());
+    match required_scope(req.method(), path) {
+        Some(scope) if scopes.allows(scope) => next.run(req).await,
+        Some(scope) => {
+            let msg = format!("the token needs the {} scope", scope);
+            AppError::PermissionDenied(msg).into_response()
+        }
+        None => AppError::PermissionDenied("API tokens can't use this route".to_string())
+            .into_response(),
+    }
+}
+
+fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    let read = method == Method::GET;
+    match path {
+        "/users" | "/users/presence" if read => Some(Scope::UsersRead),
+        "/users/presence" => Some(Scope::ChatWrite),
+        "/upload" => Some(Scope::FilesWrite),
+        "/commands" if read => Some(Scope::ChatRead),
+        p if p.ends_with("/webhooks") => None,
+        p if p.starts_with("/files/") && read => Some(Scope::ChatRead),
+        p if p.starts_with("/chats") && read => Some(Scope::ChatRead),
+        p if p.starts_with("/chats") => Some(Scope::ChatWrite),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateApiToken, AppState};
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::StatusCode,
+        middleware::{from_fn, from_fn_with_state},
+        routing::get,
+        Router,
+    };
+    use chat_core::middlewares::verify_token;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn verify_scope_should_limit_api_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let app = Router::new()
+            .route(
+                "/api/chats/:id",
+                get(|| async { "ok" }).post(|| async { "ok" }),
+            )
+            .route("/api/tokens", get(|| async { "ok" }))
+            .layer(from_fn(verify_scope))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state.clone());
+        let call = |method: Method, uri: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let input = CreateApiToken {
+            name: "reader".to_string(),
+            scopes: vec![Scope::ChatRead],
+            bot_id: None,
+            expires_in: None,
+        };
+        let token = state.create_api_token(&user, &input).await?.token;
+        let res = app
+            .clone()
+            .oneshot(call(Method::GET, "/api/chats/1", &token))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app
+            .clone()
+            .oneshot(call(Method::POST, "/api/chats/1", &token))
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app
+            .clone()
+            .oneshot(call(Method::GET, "/api/tokens", &token))
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // sessions can do everything
+        let jwt = state.ek.sign(user)?;
+        let res = app
+            .oneshot(call(Method::POST, "/api/chats/1", &jwt))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[test]
+    fn required_scope_should_follow_routes() {
+        let cases = [
+            (
+                Method::GET,
+                "/api/chats/:id/messages",
+                Some(Scope::ChatRead),
+            ),
+            (Method::POST, "/api/chats/:id", Some(Scope::ChatWrite)),
+            (Method::DELETE, "/api/chats/:id", Some(Scope::ChatWrite)),
+            (Method::GET, "/api/users", Some(Scope::UsersRead)),
+            (Method::PUT, "/api/users/presence", Some(Scope::ChatWrite)),
+            (Method::POST, "/api/upload", Some(Scope::FilesWrite)),
+            (
+                Method::GET,
+                "/api/files/:ws_id/*path",
+                Some(Scope::ChatRead),
+            ),
+            (Method::POST, "/api/commands", None),
+            (Method::POST, "/api/tokens", None),
+            (Method::GET, "/api/chats/:id/webhooks", None),
+            (Method::GET, "/api/users/me/email_preferences", None),
+        ];
+        for (method, path, scope) in cases {
+            assert_eq!(required_scope(&method, path), scope, "{} {}", method, path);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{models::Session, AppError, AppState, User};
+
+/// Reject the tokens issued before the sessions of the user were revoked
+pub async fn verify_session(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let user = parts.extensions.get::<User>().unwrap();
+    let session = match state.load_session(user.id).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return AppError::SessionRevoked.into_response(),
+        Err(e) => return e.into_response(),
+    };
+    if session.token_version != user.token_version {
+        return AppError::SessionRevoked.into_response();
+    }
+
+    parts.extensions.insert(session);
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Hold users back until they enable 2FA if their workspace requires it, and
+/// only let them read until they verify their email. Runs after `verify_session`.
+pub async fn verify_access(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let session = req.extensions().get::<Session>().unwrap();
+    if session.two_factor_missing {
+        return AppError::TwoFactorRequired.into_response();
+    }
+    // without a mailer there is no way to verify emails
+    if !session.email_verified && state.mailer.is_some() && req.method() != Method::GET {
+        let user = req.extensions().get::<User>().unwrap();
+        let err =
+            AppError::EmailNotVerified(format!("verify {} to do anything but read", user.email));
+        return err.into_response();
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::tests::test_mailer;
+    use crate::middlewares::{verify_access, verify_session};
+    use anyhow::Result;
+    use axum::{
+        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Router,
+    };
+    use chat_core::middlewares::verify_token;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "ok")
+    }
+
+    fn request(method: Method, token: &str) -> Result<Request> {
+        Ok(Request::builder()
+            .method(method)
+            .uri("/")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?)
+    }
+
+    #[tokio::test]
+    async fn session_middlewares_should_work() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        Arc::get_mut(&mut state.inner).unwrap().mailer = Some(test_mailer("smtp://localhost"));
+        let app = Router::new()
+            .route("/", get(handler).post(handler))
+            .layer(from_fn_with_state(state.clone(), verify_access))
+            .layer(from_fn_with_state(state.clone(), verify_session))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state.clone());
+
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+        let res = app.clone().oneshot(request(Method::POST, &token)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // unverified users can only read
+        sqlx::query("UPDATE users SET email_verified_at = NULL WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let res = app.clone().oneshot(request(Method::GET, &token)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.clone().oneshot(request(Method::POST, &token)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // users of a workspace requiring 2FA must enable it first
+        sqlx::query("UPDATE workspaces SET require_2fa = TRUE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let res = app.clone().oneshot(request(Method::GET, &token)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // revoked sessions can't do anything
+        sqlx::query("UPDATE users SET token_version = token_version + 1 WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let res = app.oneshot(request(Method::GET, &token)?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use axum::{
+    extract::{FromRequestParts, Path, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chat_core::middlewares::RateLimitResource;
+use tracing::warn;
+
+use crate::AppState;
+
+/// Count the messages posted to a webhook url against the webhook, whatever
+/// its token. Unknown tokens are left to the limit of the IP.
+pub async fn resolve_webhook(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let Path(token) = Path::<String>::from_request_parts(&mut parts, &state)
+        .await
+        .unwrap();
+
+    match state.find_incoming_webhook_id(&token).await {
+        Ok(Some(id)) => {
+            parts
+                .extensions
+                .insert(RateLimitResource(format!("webhook:{}", id)));
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to resolve webhook: {}", e),
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateIncomingWebhook;
+    use anyhow::Result;
+    use axum::{
+        body::Body, handler::Handler, http::StatusCode, middleware::from_fn_with_state,
+        response::IntoResponse, routing::post, Router,
+    };
+    use chat_core::middlewares::{RateLimit, RateLimitKey, RateLimitLayer};
+    use tower::ServiceExt;
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "ok")
+    }
+
+    #[tokio::test]
+    async fn webhooks_should_be_limited_by_id() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateIncomingWebhook {
+            name: "CI".to_string(),
+        };
+        let hook = state.create_incoming_webhook(&user, 1, &input).await?;
+
+        let limit = RateLimitLayer::new(RateLimit {
+            burst: 1,
+            per_minute: 1,
+            key: RateLimitKey::Resource,
+        });
+        let app = Router::new()
+            .route(
+                "/hooks/:token",
+                post(
+                    handler
+                        .layer(limit)
+                        .layer(from_fn_with_state(state.clone(), resolve_webhook)),
+                ),
+            )
+            .with_state(state);
+        let call = |url: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(url.trim_start_matches("/api"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.clone().oneshot(call(&hook.url)).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.clone().oneshot(call(&hook.url)).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        // unknown tokens fall back to the IP, unknown here, and get no bucket
+        for _ in 0..2 {
+            let res = app.clone().oneshot(call("/hooks/unknown")).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        Ok(())
+    }
+}
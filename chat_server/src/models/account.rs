@@ -0,0 +1,289 @@
+use chat_core::{ActionClaims, ActionToken, User};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, Transaction};
+use utoipa::{IntoParams, ToSchema};
+
+use super::user::hash_password;
+use crate::{AppError, AppState};
+
+const PASSWORD_RESET: &str = "password_reset";
+const EMAIL_VERIFY: &str = "email_verify";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ResetPassword {
+    /// token of the password reset link
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+/// What the session of a user is checked against on every request
+#[derive(Debug, Clone, FromRow, PartialEq)]
+pub(crate) struct Session {
+    pub(crate) token_version: i32,
+    pub(crate) email_verified: bool,
+    /// the workspace requires 2FA and the user hasn't enabled it yet
+    pub(crate) two_factor_missing: bool,
+}
+
+impl AppState {
+    /// Email a password reset link to the user. Unknown emails are ignored
+    /// silently, so they can't be probed.
+    pub async fn forgot_password(&self, input: &ForgotPassword) -> Result<(), AppError> {
+        let Some(mailer) = &self.mailer else {
+            return Err(AppError::EmailError("email is not configured".to_string()));
+        };
+        let Some(user) = self.find_user_by_email(&input.email).await? else {
+            return Ok(());
+        };
+        // bots have no mailbox and must never get a password
+        if user.is_bot {
+            return Ok(());
+        }
+
+        let ttl = self.config.auth.password_reset_ttl;
+        let token = self.sign_action(PASSWORD_RESET, &user, ttl)?;
+        let link = format!("{}/password/reset?token={}", mailer.base_url(), token);
+        let prefs = self.get_email_preferences(user.id as _).await?;
+        mailer
+            .send_password_reset(&user.email, &prefs.locale, &user.fullname, &link, ttl)
+            .await?;
+        Ok(())
+    }
+
+    /// Set a new password with the token of a reset link, and revoke all the
+    /// sessions of the user
+    pub async fn reset_password(&self, input: &ResetPassword) -> Result<(), AppError> {
+        let token = self.verify_action(&input.token, PASSWORD_RESET)?;
+        let password_hash = hash_password(&input.password)?;
+
+        let mut tx = self.pool.begin().await?;
+        use_token(&mut tx, &token).await?;
+        // the link proves the user owns the email as well
+        let ret = sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = $1, token_version = token_version + 1,
+              email_verified_at = COALESCE(email_verified_at, now())
+            WHERE id = $2 AND token_version = $3
+            "#,
+        )
+        .bind(password_hash)
+        .bind(token.claims.user_id)
+        .bind(token.claims.token_version)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::InvalidToken(
+                "token is no longer valid".to_string(),
+            ));
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Email a link to verify the email address of the user
+    pub async fn send_email_verification(&self, user: &User) -> Result<(), AppError> {
+        let Some(mailer) = &self.mailer else {
+            return Err(AppError::EmailError("email is not configured".to_string()));
+        };
+        let session = self.load_session(user.id).await?;
+        if session.is_some_and(|v| v.email_verified) {
+            return Err(AppError::EmailError("email already verified".to_string()));
+        }
+
+        let ttl = self.config.auth.email_verify_ttl;
+        let token = self.sign_action(EMAIL_VERIFY, user, ttl)?;
+        let link = format!("{}/api/email/verify?token={}", mailer.base_url(), token);
+        let prefs = self.get_email_preferences(user.id as _).await?;
+        mailer
+            .send_email_verification(&user.email, &prefs.locale, &user.fullname, &link, ttl)
+            .await?;
+        Ok(())
+    }
+
+    /// Mark the email of the user verified with the token of a verification link
+    pub async fn verify_email(&self, token: &str) -> Result<(), AppError> {
+        let token = self.verify_action(token, EMAIL_VERIFY)?;
+
+        let mut tx = self.pool.begin().await?;
+        use_token(&mut tx, &token).await?;
+        sqlx::query(
+            "UPDATE users SET email_verified_at = COALESCE(email_verified_at, now()) WHERE id = $1",
+        )
+        .bind(token.claims.user_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn load_session(&self, user_id: i64) -> Result<Option<Session>, AppError> {
+        let session = sqlx::query_as(
+            r#"
+            SELECT u.token_version, u.email_verified_at IS NOT NULL AS email_verified,
+              w.require_2fa AND u.totp_enabled_at IS NULL AND NOT u.is_bot AS two_factor_missing
+            FROM users u
+            JOIN workspaces w ON w.id = u.ws_id
+            WHERE u.id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Forget the used tokens which have expired anyway
+    pub async fn delete_expired_tokens(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query("DELETE FROM used_tokens WHERE expires_at <= now()")
+            .execute(&self.pool)
+            .await?;
+        Ok(ret.rows_affected())
+    }
+
+    pub(crate) fn sign_action(
+        &self,
+        action: &str,
+        user: &User,
+        ttl: u64,
+    ) -> Result<String, AppError> {
+        let claims = ActionClaims {
+            user_id: user.id,
+            token_version: user.token_version,
+        };
+        Ok(self.ek.sign_action(action, claims, ttl * 60)?)
+    }
+
+    pub(crate) fn verify_action(&self, token: &str, action: &str) -> Result<ActionToken, AppError> {
+        self.dk
+            .verify_action(token, action)
+            .map_err(|e| AppError::InvalidToken(e.to_string()))
+    }
+}
+
+/// Record the token as used, it fails if it was used before
+pub(crate) async fn use_token(
+    tx: &mut Transaction<'_, Postgres>,
+    token: &ActionToken,
+) -> Result<(), AppError> {
+    let ret = sqlx::query(
+        r#"
+        INSERT INTO used_tokens (id, expires_at)
+        VALUES ($1, to_timestamp($2))
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(&token.id)
+    .bind(token.expires_at as f64)
+    .execute(&mut **tx)
+    .await?;
+    if ret.rows_affected() == 0 {
+        return Err(AppError::InvalidToken("token already used".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::tests::{start_smtp_sink, test_mailer};
+    use crate::{CreateUser, SigninUser};
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    /// the token of the link in the last email
+    fn link_token(email: &str) -> String {
+        let body = email.replace("=\n", "").replace("=3D", "=");
+        let start = body.find("token=").unwrap() + "token=".len();
+        body[start..].split_whitespace().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn password_reset_should_work_once_and_revoke_sessions() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        let (url, inbox) = start_smtp_sink().await?;
+        Arc::get_mut(&mut state.inner).unwrap().mailer = Some(test_mailer(&url));
+
+        let input = ForgotPassword {
+            email: "tchen1@acme.org".to_string(),
+        };
+        state.forgot_password(&input).await?;
+        let input = ForgotPassword {
+            email: "nobody@acme.org".to_string(),
+        };
+        state.forgot_password(&input).await?;
+        let token = {
+            let inbox = inbox.lock().unwrap();
+            assert_eq!(inbox.len(), 1);
+            assert!(inbox[0].contains("Subject: Reset your Chat password"));
+            link_token(&inbox[0])
+        };
+
+        let input = ResetPassword {
+            token: token.clone(),
+            password: "hunter42".to_string(),
+        };
+        state.reset_password(&input).await?;
+        let user = state
+            .verify_user(&SigninUser::new("tchen1@acme.org", "hunter42"))
+            .await?
+            .expect("new password should work");
+        assert_eq!(user.token_version, 1);
+        let session = state.load_session(user.id).await?.unwrap();
+        assert_eq!(session.token_version, 1);
+
+        assert!(matches!(
+            state.reset_password(&input).await,
+            Err(AppError::InvalidToken(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn email_verification_should_work() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        let (url, inbox) = start_smtp_sink().await?;
+        Arc::get_mut(&mut state.inner).unwrap().mailer = Some(test_mailer(&url));
+
+        let input = CreateUser::new("acme", "Eve", "eve@acme.org", "hunter42");
+        let user = state.create_user(&input).await?;
+        assert!(!state.load_session(user.id).await?.unwrap().email_verified);
+
+        state.send_email_verification(&user).await?;
+        let token = link_token(&inbox.lock().unwrap()[0]);
+        // a verification token can't reset the password
+        let input = ResetPassword {
+            token: token.clone(),
+            password: "hunter43".to_string(),
+        };
+        assert!(matches!(
+            state.reset_password(&input).await,
+            Err(AppError::InvalidToken(_))
+        ));
+
+        state.verify_email(&token).await?;
+        assert!(state.load_session(user.id).await?.unwrap().email_verified);
+        assert!(matches!(
+            state.verify_email(&token).await,
+            Err(AppError::InvalidToken(_))
+        ));
+        assert!(matches!(
+            state.send_email_verification(&user).await,
+            Err(AppError::EmailError(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::{generate_api_token, hash_api_token, Scope, User};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+/// domain of the placeholder emails of bots, reserved so it never gets mail
+const BOT_EMAIL_DOMAIN: &str = "bots.invalid";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateBot {
+    pub fullname: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// a bot created by the user the token acts as, the user itself otherwise
+    #[serde(default)]
+    pub bot_id: Option<i64>,
+    /// days the token is valid, it never expires by default
+    #[serde(default)]
+    pub expires_in: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct ApiToken {
+    pub id: i64,
+    /// the user the token acts as
+    pub user_id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<DateTime<Local>>,
+    pub last_used_at: Option<DateTime<Local>>,
+    pub created_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreatedApiToken {
+    /// shown only once, only its hash is stored
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiToken,
+}
+
+impl AppState {
+    /// Create a bot in the workspace of the user, for its owner and admins.
+    /// Bots have no password, they act through the API tokens of their creator.
+    pub async fn create_bot(&self, user: &User, input: &CreateBot) -> Result<User, AppError> {
+        let fullname = input.fullname.trim();
+        if fullname.is_empty() || fullname.chars().count() > 64 {
+            return Err(AppError::ApiTokenError(
+                "the name of a bot must have 1 to 64 characters".to_string(),
+            ));
+        }
+        if !self.is_workspace_admin(user).await? {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner and admins can create bots".to_string(),
+            ));
+        }
+
+        self.insert_bot(user, fullname).await
+    }
+
+    /// Insert a bot created by the user into its workspace
+    pub(crate) async fn insert_bot(&self, user: &User, fullname: &str) -> Result<User, AppError> {
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let email = format!("bot-{}@{}", hex::encode(id), BOT_EMAIL_DOMAIN);
+        let bot = sqlx::query_as(
+            r#"
+            INSERT INTO users (ws_id, email, fullname, is_bot, created_by, email_verified_at)
+            VALUES ($1, $2, $3, TRUE, $4, now())
+            RETURNING id, ws_id, fullname, email, token_version, is_bot, created_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(email)
+        .bind(fullname)
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(bot)
+    }
+
+    /// Bots created by the user
+    pub async fn list_bots(&self, user: &User) -> Result<Vec<User>, AppError> {
+        let bots = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, token_version, is_bot, created_at
+            FROM users
+            WHERE is_bot AND created_by = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(user.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(bots)
+    }
+
+    /// Create an API token acting as the user or one of its bots
+    pub async fn create_api_token(
+        &self,
+        user: &User,
+        input: &CreateApiToken,
+    ) -> Result<CreatedApiToken, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > 64 {
+            return Err(AppError::ApiTokenError(
+                "the name of a token must have 1 to 64 characters".to_string(),
+            ));
+        }
+        if input.scopes.is_empty() {
+            return Err(AppError::ApiTokenError(
+                "a token needs at least one scope".to_string(),
+            ));
+        }
+        let user_id = match input.bot_id {
+            Some(bot_id) => {
+                let (owned,): (bool,) = sqlx::query_as(
+                    "SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND is_bot AND created_by = $2)",
+                )
+                .bind(bot_id)
+                .bind(user.id)
+                .fetch_one(&self.pool)
+                .await?;
+                if !owned {
+                    return Err(AppError::PermissionDenied(format!(
+                        "bot {} was not created by the user",
+                        bot_id
+                    )));
+                }
+                bot_id
+            }
+            None => user.id,
+        };
+
+        let token = generate_api_token();
+        let info: ApiToken = sqlx::query_as(
+            r#"
+            INSERT INTO api_tokens (owner_id, user_id, name, token_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, now() + make_interval(days => $6))
+            RETURNING id, user_id, name, scopes, expires_at, last_used_at, created_at
+            "#,
+        )
+        .bind(user.id)
+        .bind(user_id)
+        .bind(name)
+        .bind(hash_api_token(&token))
+        .bind(&input.scopes)
+        .bind(input.expires_in.map(|v| v.min(i32::MAX as u32) as i32))
+        .fetch_one(&self.pool)
+        .await?;
+
+        let detail = json!({ "token_id": info.id, "user_id": user_id, "scopes": input.scopes });
+        self.audit("api_token_created", Some(user.id), None, detail)
+            .await?;
+        Ok(CreatedApiToken { token, info })
+    }
+
+    /// Tokens of the user and of its bots which are not revoked
+    pub async fn list_api_tokens(&self, user: &User) -> Result<Vec<ApiToken>, AppError> {
+        let tokens = sqlx::query_as(
+            r#"
+            SELECT id, user_id, name, scopes, expires_at, last_used_at, created_at
+            FROM api_tokens
+            WHERE owner_id = $1 AND revoked_at IS NULL
+            ORDER BY id
+            "#,
+        )
+        .bind(user.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    pub async fn revoke_api_token(&self, user: &User, id: i64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE api_tokens SET revoked_at = now()
+            WHERE id = $1 AND owner_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("api token {}", id)));
+        }
+
+        self.audit(
+            "api_token_revoked",
+            Some(user.id),
+            None,
+            json!({ "token_id": id }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_core::{middlewares::TokenVerify, Scopes};
+
+    #[tokio::test]
+    async fn api_tokens_should_verify_until_revoked() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let input = CreateApiToken {
+            name: "ci".to_string(),
+            scopes: vec![Scope::ChatRead],
+            bot_id: None,
+            expires_in: None,
+        };
+        let created = state.create_api_token(&user, &input).await?;
+        assert_eq!(created.info.user_id, 1);
+        assert_eq!(created.info.expires_at, None);
+
+        let (verified, scopes) = state.verify(&created.token).await?;
+        assert_eq!(verified.id, 1);
+        assert_eq!(scopes, Scopes::Only(vec![Scope::ChatRead]));
+        let tokens = state.list_api_tokens(&user).await?;
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].last_used_at.is_some());
+        // the last use is refreshed at most once a minute
+        state.verify(&created.token).await?;
+        assert_eq!(state.list_api_tokens(&user).await?, tokens);
+
+        // tokens of other users can't be revoked
+        let other = state.find_user_by_id(2).await?.unwrap();
+        assert!(matches!(
+            state.revoke_api_token(&other, created.info.id).await,
+            Err(AppError::NotFound(_))
+        ));
+        state.revoke_api_token(&user, created.info.id).await?;
+        assert!(state.verify(&created.token).await.is_err());
+        assert!(state.list_api_tokens(&user).await?.is_empty());
+        assert!(state.verify("chat_unknown").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bots_should_act_through_tokens_of_their_creator() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE users SET role = 'admin' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let member = state.find_user_by_id(2).await?.unwrap();
+        let input = CreateBot {
+            fullname: "Deploy Bot".to_string(),
+        };
+        assert!(matches!(
+            state.create_bot(&member, &input).await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        let bot = state.create_bot(&owner, &input).await?;
+        assert!(bot.is_bot);
+        assert_eq!(bot.ws_id, owner.ws_id);
+        assert_eq!(state.list_bots(&owner).await?, vec![bot.clone()]);
+        let session = state.load_session(bot.id).await?.unwrap();
+        assert!(session.email_verified);
+
+        let input = CreateApiToken {
+            name: "deploys".to_string(),
+            scopes: vec![Scope::ChatWrite, Scope::FilesWrite],
+            bot_id: Some(bot.id),
+            expires_in: Some(30),
+        };
+        assert!(matches!(
+            state.create_api_token(&member, &input).await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        let created = state.create_api_token(&owner, &input).await?;
+        assert!(created.info.expires_at.is_some());
+        let (verified, scopes) = state.verify(&created.token).await?;
+        assert_eq!(verified, bot);
+        assert!(scopes.allows(Scope::FilesWrite));
+        assert!(!scopes.allows(Scope::ChatRead));
+
+        // bots can't sign in
+        let input = crate::SigninUser::new(&bot.email, "");
+        assert!(state.verify_user(&input).await?.is_none());
+        Ok(())
+    }
+}
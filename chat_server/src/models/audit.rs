@@ -0,0 +1,27 @@
+use std::net::IpAddr;
+
+use serde_json::Value;
+
+use crate::{AppError, AppState};
+
+impl AppState {
+    /// Record a security relevant event
+    pub(crate) async fn audit(
+        &self,
+        action: &str,
+        user_id: Option<i64>,
+        ip: Option<IpAddr>,
+        detail: Value,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO audit_log (user_id, action, ip, detail) VALUES ($1, $2, $3, $4::jsonb)",
+        )
+        .bind(user_id)
+        .bind(action)
+        .bind(ip.map(|v| v.to_string()))
+        .bind(detail.to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+use chat_core::{ActionElement, Block, Blocks};
+
+use super::ChatFile;
+use crate::AppError;
+
+const MAX_BLOCKS: usize = 50;
+const MAX_TEXT_LEN: usize = 3000;
+const MAX_FIELDS: usize = 10;
+const MAX_FIELD_LEN: usize = 2000;
+const MAX_ELEMENTS: usize = 5;
+const MAX_OPTIONS: usize = 100;
+const MAX_LABEL_LEN: usize = 75;
+const MAX_ID_LEN: usize = 255;
+const MAX_VALUE_LEN: usize = 2000;
+
+/// Check the blocks of a message against the limits of each kind of block.
+/// Images must be files uploaded to the workspace, and action ids unique.
+pub(crate) fn validate_blocks(blocks: &Blocks, base_dir: &Path) -> Result<(), AppError> {
+    if blocks.0.is_empty() || blocks.0.len() > MAX_BLOCKS {
+        return Err(invalid(format!("a message has 1 to {} blocks", MAX_BLOCKS)));
+    }
+
+    let mut action_ids = HashSet::new();
+    for block in &blocks.0 {
+        match block {
+            Block::Section { text, fields } => {
+                check_len("the text of a section", text, 1, MAX_TEXT_LEN)?;
+                if fields.len() > MAX_FIELDS {
+                    return Err(invalid(format!(
+                        "a section has at most {} fields",
+                        MAX_FIELDS
+                    )));
+                }
+                for field in fields {
+                    check_len("a field", field, 1, MAX_FIELD_LEN)?;
+                }
+            }
+            Block::Image { url, alt } => {
+                check_len("the alt text of an image", alt, 0, MAX_VALUE_LEN)?;
+                let file = ChatFile::from_str(url)?;
+                let is_image = mime_guess::from_ext(&file.ext)
+                    .first()
+                    .is_some_and(|v| v.type_() == mime_guess::mime::IMAGE);
+                if !is_image {
+                    return Err(invalid(format!("{} is not an image", url)));
+                }
+                if !file.path(base_dir).exists() {
+                    return Err(invalid(format!("file not found: {}", url)));
+                }
+            }
+            Block::Actions { elements } => {
+                if elements.is_empty() || elements.len() > MAX_ELEMENTS {
+                    return Err(invalid(format!(
+                        "an actions block has 1 to {} elements",
+                        MAX_ELEMENTS
+                    )));
+                }
+                for element in elements {
+                    validate_element(element)?;
+                    if !action_ids.insert(element.action_id()) {
+                        return Err(invalid(format!(
+                            "action id {} is used twice",
+                            element.action_id()
+                        )));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_element(element: &ActionElement) -> Result<(), AppError> {
+    check_len("an action id", element.action_id(), 1, MAX_ID_LEN)?;
+    match element {
+        ActionElement::Button { text, value, .. } => {
+            check_len("the text of a button", text, 1, MAX_LABEL_LEN)?;
+            if let Some(value) = value {
+                check_len("the value of a button", value, 0, MAX_VALUE_LEN)?;
+            }
+        }
+        ActionElement::Select {
+            placeholder,
+            options,
+            ..
+        } => {
+            check_len("the placeholder of a select", placeholder, 1, MAX_LABEL_LEN)?;
+            if options.is_empty() || options.len() > MAX_OPTIONS {
+                return Err(invalid(format!(
+                    "a select has 1 to {} options",
+                    MAX_OPTIONS
+                )));
+            }
+            for option in options {
+                check_len("the text of an option", &option.text, 1, MAX_LABEL_LEN)?;
+                check_len("the value of an option", &option.value, 1, MAX_ID_LEN)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_len(what: &str, s: &str, min: usize, max: usize) -> Result<(), AppError> {
+    let len = s.trim().chars().count();
+    if len < min || len > max {
+        return Err(invalid(format!(
+            "{} must have {} to {} characters",
+            what, min, max
+        )));
+    }
+    Ok(())
+}
+
+fn invalid(msg: String) -> AppError {
+    AppError::CreateMessageError(format!("invalid blocks: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::SelectOption;
+
+    fn button(action_id: &str) -> ActionElement {
+        ActionElement::Button {
+            action_id: action_id.to_string(),
+            text: "Approve".to_string(),
+            value: Some("v1".to_string()),
+            style: None,
+        }
+    }
+
+    #[test]
+    fn valid_blocks_should_pass() {
+        let blocks = Blocks(vec![
+            Block::Section {
+                text: "Deploy *v1*?".to_string(),
+                fields: vec!["env: prod".to_string()],
+            },
+            Block::Actions {
+                elements: vec![
+                    button("approve"),
+                    ActionElement::Select {
+                        action_id: "env".to_string(),
+                        placeholder: "Environment".to_string(),
+                        options: vec![SelectOption {
+                            text: "Staging".to_string(),
+                            value: "staging".to_string(),
+                        }],
+                    },
+                ],
+            },
+        ]);
+        assert!(validate_blocks(&blocks, Path::new("/tmp")).is_ok());
+    }
+
+    #[test]
+    fn invalid_blocks_should_be_rejected() {
+        let base_dir = Path::new("/tmp/chat_server");
+        let section = |text: &str| Block::Section {
+            text: text.to_string(),
+            fields: vec![],
+        };
+        let image = |url: &str| Block::Image {
+            url: url.to_string(),
+            alt: String::new(),
+        };
+        for blocks in [
+            vec![],
+            vec![section(" ")],
+            vec![section(&"a".repeat(MAX_TEXT_LEN + 1))],
+            vec![image("https://example.com/a.png")],
+            vec![image("/files/1/abc/def/0123.txt")],
+            vec![image("/files/1/abc/def/0123.png")],
+            vec![Block::Actions { elements: vec![] }],
+            vec![Block::Actions {
+                elements: vec![button("approve"), button("approve")],
+            }],
+            vec![Block::Actions {
+                elements: vec![ActionElement::Select {
+                    action_id: "env".to_string(),
+                    placeholder: "Environment".to_string(),
+                    options: vec![],
+                }],
+            }],
+        ] {
+            let ret = validate_blocks(&Blocks(blocks.clone()), base_dir);
+            assert!(ret.is_err(), "{:?} should be rejected", blocks);
+        }
+    }
+}
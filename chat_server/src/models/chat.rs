@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
+use super::{outbox::OutboxEvent, outgoing_webhook::WebhookPayload};
 use crate::{AppError, AppState};
 
 use chat_core::{Chat, ChatType};
@@ -10,12 +11,18 @@ pub struct CreateChat {
     pub name: Option<String>,
     pub members: Vec<i64>,
     pub public: bool,
+    /// Seconds after which messages in this chat are deleted
+    #[serde(default)]
+    pub message_ttl: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct UpdateChat {
     pub name: Option<String>,
     pub members: Option<Vec<i64>>,
+    /// Seconds after which messages in this chat are deleted, 0 to disable
+    #[serde(default)]
+    pub message_ttl: Option<i64>,
 }
 
 impl AppState {
@@ -33,6 +40,12 @@ impl AppState {
             ));
         }
 
+        if input.message_ttl.is_some_and(|ttl| ttl <= 0) {
+            return Err(AppError::CreateChatError(
+                "Message ttl must be positive".to_string(),
+            ));
+        }
+
         // verify if all members exist
         let users = self.fetch_chat_user_by_ids(&input.members).await?;
         if users.len() != len {
@@ -54,19 +67,26 @@ impl AppState {
             }
         };
 
-        let chat = sqlx::query_as(
+        let mut tx = self.begin_events().await?;
+        let chat: Chat = sqlx::query_as(
             r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            INSERT INTO chats (ws_id, name, type, members, message_ttl)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, name, type, members, message_ttl, topic, created_at
             "#,
         )
         .bind(ws_id as i64)
         .bind(input.name)
         .bind(chat_type)
         .bind(&input.members)
-        .fetch_one(&self.pool)
+        .bind(input.message_ttl)
+        .fetch_one(&mut *tx)
         .await?;
+        self.write_outbox(&mut tx, &[OutboxEvent::chat_inserted(chat.id)])
+            .await?;
+        self.queue_webhooks(&mut tx, chat.id, &[WebhookPayload::new_chat(&chat)])
+            .await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
@@ -74,7 +94,7 @@ impl AppState {
     pub async fn fetch_chats(&self, ws_id: u64) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, message_ttl, topic, created_at
             FROM chats
             WHERE ws_id = $1
             "#,
@@ -89,7 +109,7 @@ impl AppState {
     pub async fn get_chat_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, message_ttl, topic, created_at
             FROM chats
             WHERE id = $1 AND deleted_at IS NULL
             "#,
@@ -115,7 +135,13 @@ impl AppState {
 
         let members = match input.members {
             Some(members) => members,
-            None => chat.unwrap().members,
+            None => chat.clone().unwrap().members,
+        };
+
+        let message_ttl = match input.message_ttl {
+            Some(ttl) if ttl > 0 => Some(ttl),
+            Some(_) => None,
+            None => chat.unwrap().message_ttl,
         };
 
         // 校验
@@ -127,19 +153,36 @@ impl AppState {
 
         // TODO: other keys
 
-        let chat = sqlx::query_as(
+        let mut tx = self.begin_events().await?;
+        let (old_members,): (Vec<i64>,) =
+            sqlx::query_as("SELECT members FROM chats WHERE id = $1 FOR UPDATE")
+                .bind(id as i64)
+                .fetch_one(&mut *tx)
+                .await?;
+        let chat: Chat = sqlx::query_as(
             r#"
             UPDATE chats
-            SET name = $1, members = $2
-            WHERE id = $3
-            RETURNING id, ws_id, name, type, members, created_at
+            SET name = $1, members = $2, message_ttl = $3
+            WHERE id = $4
+            RETURNING id, ws_id, name, type, members, message_ttl, topic, created_at
             "#,
         )
         .bind(name)
         .bind(&members)
+        .bind(message_ttl)
         .bind(id as i64)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
+        self.write_outbox(&mut tx, &[OutboxEvent::chat_updated(chat.id)])
+            .await?;
+        let joined: Vec<_> = chat
+            .members
+            .iter()
+            .filter(|v| !old_members.contains(v))
+            .map(|v| WebhookPayload::member_joined(&chat, *v))
+            .collect();
+        self.queue_webhooks(&mut tx, chat.id, &joined).await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
@@ -152,6 +195,7 @@ impl AppState {
             return Err(AppError::NotFound(format!("Chat with id {} not found", id)));
         }
 
+        let mut tx = self.begin_events().await?;
         let chat = sqlx::query(
             r#"
             UPDATE chats
@@ -160,12 +204,93 @@ impl AppState {
             "#,
         )
         .bind(id as i64)
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await?;
+        self.write_outbox(&mut tx, &[OutboxEvent::chat_updated(id as _)])
+            .await?;
+        tx.commit().await?;
 
         Ok(chat.rows_affected() == 1)
     }
 
+    pub async fn update_chat_topic(&self, id: u64, topic: &str) -> Result<Chat, AppError> {
+        let mut tx = self.begin_events().await?;
+        let chat: Option<Chat> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET topic = $1
+            WHERE id = $2 AND deleted_at IS NULL
+            RETURNING id, ws_id, name, type, members, message_ttl, topic, created_at
+            "#,
+        )
+        .bind(topic)
+        .bind(id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if chat.is_some() {
+            self.write_outbox(&mut tx, &[OutboxEvent::chat_updated(id as _)])
+                .await?;
+            tx.commit().await?;
+        }
+
+        chat.ok_or_else(|| AppError::NotFound(format!("Chat with id {} not found", id)))
+    }
+
+    pub async fn add_chat_member(&self, id: u64, user_id: u64) -> Result<Chat, AppError> {
+        let mut tx = self.begin_events().await?;
+        let chat: Option<Chat> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_append(members, $1)
+            WHERE id = $2 AND deleted_at IS NULL AND NOT $1 = ANY(members)
+            RETURNING id, ws_id, name, type, members, message_ttl, topic, created_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(chat) = &chat {
+            self.write_outbox(&mut tx, &[OutboxEvent::chat_updated(id as _)])
+                .await?;
+            let joined = WebhookPayload::member_joined(chat, user_id as _);
+            self.queue_webhooks(&mut tx, chat.id, &[joined]).await?;
+            tx.commit().await?;
+        }
+
+        chat.ok_or_else(|| {
+            AppError::UpdateChatError(format!(
+                "User {} is already a member of chat {}",
+                user_id, id
+            ))
+        })
+    }
+
+    pub async fn remove_chat_member(&self, id: u64, user_id: u64) -> Result<Chat, AppError> {
+        let mut tx = self.begin_events().await?;
+        let chat: Option<Chat> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $1)
+            WHERE id = $2 AND deleted_at IS NULL AND $1 = ANY(members)
+            RETURNING id, ws_id, name, type, members, message_ttl, topic, created_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if chat.is_some() {
+            self.write_outbox(&mut tx, &[OutboxEvent::chat_updated(id as _)])
+                .await?;
+            tx.commit().await?;
+        }
+
+        chat.ok_or_else(|| {
+            AppError::UpdateChatError(format!("User {} is not a member of chat {}", user_id, id))
+        })
+    }
+
     pub async fn is_chat_member(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
         // TODO: 不存在的时候报错？
         let is_member = sqlx::query(
@@ -196,6 +321,7 @@ impl CreateChat {
             name,
             members: members.to_vec(),
             public,
+            message_ttl: None,
         }
     }
 }
@@ -259,6 +385,7 @@ mod tests {
         let input = UpdateChat {
             name: Some("new name".to_string()),
             members: Some(vec![1, 2, 3]),
+            message_ttl: Some(60),
         };
         let chat = state
             .update_chat_by_id(1, input)
@@ -269,6 +396,7 @@ mod tests {
         assert_eq!(chat.name.unwrap(), "new name");
         assert_eq!(chat.ws_id, 1);
         assert_eq!(chat.members.len(), 3);
+        assert_eq!(chat.message_ttl, Some(60));
 
         Ok(())
     }
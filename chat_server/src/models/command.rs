@@ -0,0 +1,238 @@
+use chat_core::{is_public_url, EphemeralMessage};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use utoipa::ToSchema;
+
+use crate::{
+    commands::{is_valid_command_name, ExternalCommand},
+    AppError, AppState,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateCommand {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+impl AppState {
+    pub async fn create_command(
+        &self,
+        input: CreateCommand,
+        ws_id: u64,
+    ) -> Result<ExternalCommand, AppError> {
+        if !is_valid_command_name(&input.name) {
+            return Err(AppError::CommandError(format!(
+                "invalid command name: {}",
+                input.name
+            )));
+        }
+        if self.commands.contains(&input.name) {
+            return Err(AppError::CommandError(format!(
+                "command /{} is a builtin command",
+                input.name
+            )));
+        }
+        let is_public = reqwest::Url::parse(&input.url).is_ok_and(|url| is_public_url(&url));
+        if !is_public {
+            return Err(AppError::CommandError(format!(
+                "invalid command url: {}, it must be a public https url",
+                input.url
+            )));
+        }
+        if self.find_command(ws_id, &input.name).await?.is_some() {
+            return Err(AppError::CommandError(format!(
+                "command /{} already exists",
+                input.name
+            )));
+        }
+
+        let cmd = sqlx::query_as(
+            r#"
+            INSERT INTO commands (ws_id, name, url, description)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, ws_id, name, url, description, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&input.name)
+        .bind(&input.url)
+        .bind(&input.description)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(cmd)
+    }
+
+    pub async fn list_commands(&self, ws_id: u64) -> Result<Vec<ExternalCommand>, AppError> {
+        let cmds = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, url, description, created_at
+            FROM commands
+            WHERE ws_id = $1
+            ORDER BY name
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(cmds)
+    }
+
+    pub async fn find_command(
+        &self,
+        ws_id: u64,
+        name: &str,
+    ) -> Result<Option<ExternalCommand>, AppError> {
+        let cmd = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, url, description, created_at
+            FROM commands
+            WHERE ws_id = $1 AND name = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(cmd)
+    }
+
+    pub async fn create_reminder(
+        &self,
+        user_id: u64,
+        chat_id: u64,
+        content: &str,
+        secs: i64,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO reminders (user_id, chat_id, content, remind_at)
+            VALUES ($1, $2, $3, now() + $4 * interval '1 second')
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(content)
+        .bind(secs)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Send due reminders to their owners as ephemeral messages. They are
+    /// deleted in the transaction of the notifications, which are only sent
+    /// once it commits, so a failure keeps them for the next run.
+    pub async fn deliver_due_reminders(&self) -> Result<u64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let reminders: Vec<(i64, i64, String)> = sqlx::query_as(
+            r#"
+            DELETE FROM reminders
+            WHERE id IN (
+              SELECT id FROM reminders WHERE remind_at <= now() FOR UPDATE SKIP LOCKED
+            )
+            RETURNING chat_id, user_id, content
+            "#,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let count = reminders.len() as u64;
+        for (chat_id, user_id, content) in reminders {
+            let msg = EphemeralMessage {
+                chat_id,
+                user_id,
+                content: format!("Reminder: {}", content),
+                created_at: chrono::Local::now(),
+            };
+            notify_ephemeral(&mut tx, &msg).await?;
+        }
+        tx.commit().await?;
+
+        Ok(count)
+    }
+
+    /// Deliver a message only to its user through notify_server
+    pub async fn send_ephemeral(&self, msg: &EphemeralMessage) -> Result<(), AppError> {
+        let mut conn = self.pool.acquire().await?;
+        notify_ephemeral(&mut conn, msg).await
+    }
+}
+
+async fn notify_ephemeral(conn: &mut PgConnection, msg: &EphemeralMessage) -> Result<(), AppError> {
+    let payload = serde_json::to_string(msg).map_err(anyhow::Error::from)?;
+    sqlx::query("SELECT pg_notify('ephemeral', $1)")
+        .bind(payload)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn create_and_list_commands_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateCommand {
+            name: "deploy".to_string(),
+            url: "https://example.com/deploy".to_string(),
+            description: "Deploy a service".to_string(),
+        };
+        let cmd = state.create_command(input.clone(), 1).await?;
+        assert_eq!(cmd.name, "deploy");
+
+        let err = state.create_command(input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "command error: command /deploy already exists"
+        );
+
+        let input = CreateCommand {
+            name: "me".to_string(),
+            url: "https://example.com/me".to_string(),
+            description: String::new(),
+        };
+        let err = state.create_command(input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "command error: command /me is a builtin command"
+        );
+
+        for url in ["http://example.com/ping", "https://169.254.169.254/latest"] {
+            let input = CreateCommand {
+                name: "ping".to_string(),
+                url: url.to_string(),
+                description: String::new(),
+            };
+            assert!(state.create_command(input, 1).await.is_err(), "{}", url);
+        }
+
+        let cmds = state.list_commands(1).await?;
+        assert_eq!(cmds.len(), 1);
+        assert!(state.list_commands(2).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn due_reminders_should_be_delivered_once() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.create_reminder(1, 1, "stand up", 3600).await?;
+        sqlx::query("UPDATE reminders SET remind_at = now() - interval '1 second'")
+            .execute(&state.pool)
+            .await?;
+
+        assert_eq!(state.deliver_due_reminders().await?, 1);
+        assert_eq!(state.deliver_due_reminders().await?, 0);
+
+        Ok(())
+    }
+}
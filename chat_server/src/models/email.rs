@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{mailer, AppError, AppState, EmailTemplate};
+
+/// characters of a message quoted in an email
+const PREVIEW_LEN: usize = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema, PartialEq)]
+pub struct EmailPreferences {
+    pub locale: String,
+    /// email direct messages left unread
+    pub direct_messages: bool,
+    /// email mentions left unread
+    pub mentions: bool,
+    /// a daily digest of unread chats
+    pub digest: bool,
+    #[serde(skip)]
+    pub unsubscribe_token: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateEmailPreferences {
+    pub locale: Option<String>,
+    pub direct_messages: Option<bool>,
+    pub mentions: Option<bool>,
+    pub digest: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MarkRead {
+    /// the latest message read in the chat
+    pub message_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+pub struct Unsubscribe {
+    pub token: String,
+}
+
+#[derive(Debug, FromRow)]
+struct UnreadMessage {
+    user_id: i64,
+    message_id: i64,
+    email: String,
+    fullname: String,
+    sender: String,
+    content: String,
+}
+
+#[derive(Debug, FromRow)]
+struct DigestRecipient {
+    user_id: i64,
+    email: String,
+    fullname: String,
+    locale: String,
+    unsubscribe_token: String,
+}
+
+#[derive(Debug, FromRow)]
+struct UnreadChat {
+    chat_id: i64,
+    name: Option<String>,
+    count: i64,
+}
+
+impl AppState {
+    pub async fn get_email_preferences(&self, user_id: u64) -> Result<EmailPreferences, AppError> {
+        sqlx::query("INSERT INTO email_preferences (user_id) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        let prefs = sqlx::query_as(
+            r#"
+            SELECT locale, direct_messages, mentions, digest, unsubscribe_token
+            FROM email_preferences
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(prefs)
+    }
+
+    pub async fn update_email_preferences(
+        &self,
+        user_id: u64,
+        input: UpdateEmailPreferences,
+    ) -> Result<EmailPreferences, AppError> {
+        if let Some(locale) = &input.locale {
+            let lang = locale.split(['-', '_']).next().unwrap_or_default();
+            if locale.len() > 16 || !mailer::is_supported(lang) {
+                return Err(AppError::EmailError(format!(
+                    "unsupported locale: {}",
+                    locale
+                )));
+            }
+        }
+
+        let prefs = sqlx::query_as(
+            r#"
+            INSERT INTO email_preferences (user_id, locale, direct_messages, mentions, digest)
+            VALUES ($1, COALESCE($2, 'en'), COALESCE($3, TRUE), COALESCE($4, TRUE), COALESCE($5, FALSE))
+            ON CONFLICT (user_id) DO UPDATE SET
+              locale = COALESCE($2, email_preferences.locale),
+              direct_messages = COALESCE($3, email_preferences.direct_messages),
+              mentions = COALESCE($4, email_preferences.mentions),
+              digest = COALESCE($5, email_preferences.digest),
+              updated_at = now()
+            RETURNING locale, direct_messages, mentions, digest, unsubscribe_token
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.locale)
+        .bind(input.direct_messages)
+        .bind(input.mentions)
+        .bind(input.digest)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(prefs)
+    }
+
+    /// Turn off all emails of the user with the token of an unsubscribe link
+    pub async fn unsubscribe(&self, token: &str) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE email_preferences
+            SET direct_messages = FALSE, mentions = FALSE, digest = FALSE, updated_at = now()
+            WHERE unsubscribe_token = $1
+            "#,
+        )
+        .bind(token)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound("unsubscribe token".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Record the latest message the user read in the chat, reads never go back
+    pub async fn mark_chat_read(
+        &self,
+        user_id: u64,
+        chat_id: u64,
+        input: MarkRead,
+    ) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO chat_reads (user_id, chat_id, last_read_message_id)
+            SELECT $1, $2, $3
+            WHERE EXISTS (SELECT 1 FROM messages WHERE id = $3 AND chat_id = $2)
+            ON CONFLICT (user_id, chat_id) DO UPDATE SET
+              last_read_message_id = GREATEST(chat_reads.last_read_message_id, $3),
+              updated_at = now()
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(input.message_id as i64)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "message {} in chat {}",
+                input.message_id, chat_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Email direct messages and mentions left unread for `unread_delay`
+    /// minutes, one email per user. Returns the number of emails sent.
+    pub async fn send_unread_emails(&self) -> Result<u64, AppError> {
+        let (Some(mailer), Some(config)) = (&self.mailer, &self.config.email) else {
+            return Ok(0);
+        };
+
+        // messages are claimed before they are sent, so each goes out once
+        // even with several servers sweeping
+        let messages: Vec<UnreadMessage> = sqlx::query_as(
+            r#"
+            WITH due AS (
+              SELECT u.id AS user_id, m.id AS message_id
+              FROM messages m
+              JOIN chats c ON c.id = m.chat_id
+              JOIN users u ON u.id = ANY(c.members) AND u.id <> m.sender_id AND NOT u.is_bot
+              LEFT JOIN email_preferences p ON p.user_id = u.id
+              LEFT JOIN chat_reads r ON r.user_id = u.id AND r.chat_id = c.id
+              CROSS JOIN LATERAL (
+                SELECT m.content LIKE '%<@' || u.id || '>%' AS mentioned
+              ) x
+              WHERE c.deleted_at IS NULL
+                AND m.created_at <= now() - make_interval(mins => $1)
+                AND m.created_at > now() - interval '1 day'
+                AND (m.expires_at IS NULL OR m.expires_at > now())
+                AND m.id > COALESCE(r.last_read_message_id, 0)
+                AND (
+                  (x.mentioned AND COALESCE(p.mentions, TRUE))
+                  OR (
+                    c.type = 'single' AND COALESCE(p.direct_messages, TRUE)
+                    AND NOT EXISTS (
+                      SELECT 1 FROM chat_mutes cm WHERE cm.user_id = u.id AND cm.chat_id = c.id
+                    )
+                  )
+                )
+            ), claimed AS (
+              INSERT INTO email_notifications (user_id, message_id)
+              SELECT user_id, message_id FROM due
+              ON CONFLICT DO NOTHING
+              RETURNING user_id, message_id
+            )
+            SELECT cl.user_id, cl.message_id, u.email, u.fullname, s.fullname AS sender,
+              COALESCE(m.content_text, m.content) AS content
+            FROM claimed cl
+            JOIN users u ON u.id = cl.user_id
+            JOIN messages m ON m.id = cl.message_id
+            JOIN users s ON s.id = m.sender_id
+            ORDER BY cl.user_id, cl.message_id
+            "#,
+        )
+        .bind(config.unread_delay.min(i32::MAX as u64) as i32)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut sent = 0;
+        for chunk in messages.chunk_by(|a, b| a.user_id == b.user_id) {
+            let first = &chunk[0];
+            let prefs = self.get_email_preferences(first.user_id as _).await?;
+            let lines: String = chunk
+                .iter()
+                .map(|m| format!("- {}: {}\n", m.sender, preview(&m.content)))
+                .collect();
+            let vars = HashMap::from([
+                ("name", first.fullname.clone()),
+                ("count", chunk.len().to_string()),
+                ("messages", lines),
+                ("base_url", mailer.base_url().to_string()),
+                (
+                    "unsubscribe_url",
+                    mailer.unsubscribe_url(&prefs.unsubscribe_token),
+                ),
+            ]);
+            let email = EmailTemplate::Unread.render(&prefs.locale, &vars)?;
+            match mailer.send(&first.email, email).await {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    warn!("Failed to email user {}: {}", first.user_id, e);
+                    // release the messages so the next sweep tries again
+                    let ids: Vec<i64> = chunk.iter().map(|m| m.message_id).collect();
+                    sqlx::query(
+                        "DELETE FROM email_notifications WHERE user_id = $1 AND message_id = ANY($2)",
+                    )
+                    .bind(first.user_id)
+                    .bind(ids)
+                    .execute(&self.pool)
+                    .await?;
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Email the daily digest of unread chats to the users who asked for it.
+    /// Returns the number of emails sent.
+    pub async fn send_digests(&self) -> Result<u64, AppError> {
+        let Some(mailer) = &self.mailer else {
+            return Ok(0);
+        };
+
+        let recipients: Vec<DigestRecipient> = sqlx::query_as(
+            r#"
+            UPDATE email_preferences p SET last_digest_at = now()
+            FROM users u
+            WHERE u.id = p.user_id AND p.digest
+              AND (p.last_digest_at IS NULL OR p.last_digest_at <= now() - interval '1 day')
+            RETURNING p.user_id, u.email, u.fullname, p.locale, p.unsubscribe_token
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut sent = 0;
+        for r in recipients {
+            let chats: Vec<UnreadChat> = sqlx::query_as(
+                r#"
+                SELECT c.id AS chat_id, c.name, count(*) AS count
+                FROM chats c
+                JOIN messages m ON m.chat_id = c.id
+                LEFT JOIN chat_reads cr ON cr.user_id = $1 AND cr.chat_id = c.id
+                WHERE $1 = ANY(c.members) AND c.deleted_at IS NULL
+                  AND m.sender_id <> $1
+                  AND m.id > COALESCE(cr.last_read_message_id, 0)
+                  AND m.created_at > now() - interval '1 day'
+                  AND (m.expires_at IS NULL OR m.expires_at > now())
+                GROUP BY c.id
+                ORDER BY c.id
+                "#,
+            )
+            .bind(r.user_id)
+            .fetch_all(&self.pool)
+            .await?;
+            if chats.is_empty() {
+                continue;
+            }
+
+            let lines: String = chats
+                .iter()
+                .map(|c| match &c.name {
+                    Some(name) => format!("- #{} ({})\n", name, c.count),
+                    None => format!("- chat {} ({})\n", c.chat_id, c.count),
+                })
+                .collect();
+            let vars = HashMap::from([
+                ("name", r.fullname),
+                ("chats", lines),
+                ("base_url", mailer.base_url().to_string()),
+                (
+                    "unsubscribe_url",
+                    mailer.unsubscribe_url(&r.unsubscribe_token),
+                ),
+            ]);
+            let email = EmailTemplate::Digest.render(&r.locale, &vars)?;
+            match mailer.send(&r.email, email).await {
+                Ok(()) => sent += 1,
+                Err(e) => warn!("Failed to email digest to user {}: {}", r.user_id, e),
+            }
+        }
+
+        Ok(sent)
+    }
+}
+
+fn preview(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default();
+    match line.char_indices().nth(PREVIEW_LEN) {
+        Some((i, _)) => format!("{}…", &line[..i]),
+        None if line.len() < content.len() => format!("{}…", line),
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::tests::{start_smtp_sink, test_mailer};
+    use crate::EmailConfig;
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn unread_dms_and_mentions_should_be_emailed_once() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        let (url, inbox) = start_smtp_sink().await?;
+        let inner = Arc::get_mut(&mut state.inner).unwrap();
+        inner.mailer = Some(test_mailer(&url));
+        inner.config.email = Some(EmailConfig {
+            smtp_url: url,
+            from: "noreply@example.com".to_string(),
+            base_url: "http://localhost".to_string(),
+            unread_delay: 10,
+        });
+
+        // a DM to user 2 and a mention of user 3, both old enough, plus a
+        // recent DM which isn't due yet
+        sqlx::query(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, created_at) VALUES
+            (3, 1, 'are you there?', now() - interval '20 minutes'),
+            (1, 1, 'ping <@3>', now() - interval '20 minutes'),
+            (3, 1, 'just now', now())
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+        state
+            .update_email_preferences(
+                3,
+                UpdateEmailPreferences {
+                    locale: Some("zh-CN".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        assert_eq!(state.send_unread_emails().await?, 2);
+        assert_eq!(state.send_unread_emails().await?, 0);
+        {
+            let inbox = inbox.lock().unwrap();
+            assert_eq!(inbox.len(), 2);
+            assert!(inbox[0].contains("To: alice1@acme.org"));
+            assert!(inbox[0].contains("Tyr Chen: are you there?"));
+            assert!(!inbox[0].contains("just now"));
+            // quoted-printable wraps long lines
+            let body = inbox[0].replace("=\n", "");
+            assert!(body.contains("http://localhost:6688/api/unsubscribe?token="));
+            assert!(inbox[1].contains("To: bob1@acme.org"));
+        }
+
+        // read messages and unsubscribed users get nothing
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO messages (chat_id, sender_id, content, created_at) VALUES (3, 1, 'again', now() - interval '20 minutes') RETURNING id",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        state
+            .mark_chat_read(
+                2,
+                3,
+                MarkRead {
+                    message_id: id as _,
+                },
+            )
+            .await?;
+        let prefs = state.get_email_preferences(3).await?;
+        state.unsubscribe(&prefs.unsubscribe_token).await?;
+        sqlx::query(
+            "INSERT INTO messages (chat_id, sender_id, content, created_at) VALUES (1, 1, 'ping <@3>', now() - interval '20 minutes')",
+        )
+        .execute(&state.pool)
+        .await?;
+        assert_eq!(state.send_unread_emails().await?, 0);
+        assert!(!state.get_email_preferences(3).await?.mentions);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn digest_should_list_unread_chats_daily() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        let (url, inbox) = start_smtp_sink().await?;
+        Arc::get_mut(&mut state.inner).unwrap().mailer = Some(test_mailer(&url));
+
+        let input = UpdateEmailPreferences {
+            digest: Some(true),
+            ..Default::default()
+        };
+        let prefs = state.update_email_preferences(1, input).await?;
+        assert!(prefs.digest && prefs.mentions);
+
+        assert_eq!(state.send_digests().await?, 1);
+        assert_eq!(state.send_digests().await?, 0);
+        let inbox = inbox.lock().unwrap();
+        assert!(inbox[0].contains("Subject: Your daily Chat digest"));
+        assert!(inbox[0].contains("- #general (6)"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_preferences_and_reads_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateEmailPreferences {
+            locale: Some("fr".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            state.update_email_preferences(1, input).await,
+            Err(AppError::EmailError(_))
+        ));
+        // message 1 is in chat 1
+        assert!(matches!(
+            state.mark_chat_read(1, 3, MarkRead { message_id: 1 }).await,
+            Err(AppError::NotFound(_))
+        ));
+        assert!(matches!(
+            state.unsubscribe("nope").await,
+            Err(AppError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn preview_should_truncate_long_messages() {
+        assert_eq!(preview("hi"), "hi");
+        assert_eq!(preview("hi\nthere"), "hi…");
+        assert_eq!(preview(&"a".repeat(300)).chars().count(), PREVIEW_LEN + 1);
+    }
+}
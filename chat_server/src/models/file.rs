@@ -4,8 +4,10 @@ use std::{
 };
 
 use sha1::{Digest, Sha1};
+use tokio::fs;
+use tracing::info;
 
-use crate::AppError;
+use crate::{AppError, AppState};
 
 use super::ChatFile;
 
@@ -14,7 +16,7 @@ impl ChatFile {
         let hash = Sha1::digest(data);
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap_or("txt").to_string(),
+            ext: filename.split('.').next_back().unwrap_or("txt").to_string(),
             hash: hex::encode(hash),
         }
     }
@@ -34,6 +36,27 @@ impl ChatFile {
     }
 }
 
+impl AppState {
+    /// Store a file of the workspace, returns its url. Files are stored by
+    /// content, storing the same file twice is a no-op.
+    pub(crate) async fn store_file(
+        &self,
+        ws_id: u64,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<String, AppError> {
+        let file = ChatFile::new(ws_id, filename, data);
+        let path = file.path(&self.config.server.base_dir);
+        if path.exists() {
+            info!("File {} already exists: {:?}", filename, path);
+        } else {
+            fs::create_dir_all(path.parent().expect("file path parent should exist")).await?;
+            fs::write(path, data).await?;
+        }
+        Ok(file.url())
+    }
+}
+
 impl FromStr for ChatFile {
     type Err = AppError;
 
@@ -0,0 +1,428 @@
+use chat_core::{generate_api_token, hash_api_token, render_markdown, render_plain, Message, User};
+use chrono::{DateTime, Local};
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use super::CreateMessage;
+use crate::{AppError, AppState};
+
+const MAX_ATTACHMENTS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateIncomingWebhook {
+    /// name of the bot the messages are sent as
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateIncomingWebhook {
+    pub name: Option<String>,
+    /// a disabled webhook rejects the messages posted to it
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct IncomingWebhook {
+    pub id: i64,
+    pub chat_id: i64,
+    /// the bot the messages are sent as
+    pub bot_id: i64,
+    pub name: String,
+    pub enabled: bool,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreatedIncomingWebhook {
+    /// path to post messages to, shown only once as it carries the token
+    pub url: String,
+    #[serde(flatten)]
+    pub info: IncomingWebhook,
+}
+
+/// The JSON an external system posts to the url of a webhook
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct WebhookPayload {
+    pub text: String,
+    #[serde(default)]
+    pub format: TextFormat,
+    #[serde(default)]
+    pub attachments: Vec<WebhookAttachment>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFormat {
+    /// shown as is, line by line, markdown is not parsed
+    #[default]
+    Plain,
+    Markdown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct WebhookAttachment {
+    pub filename: String,
+    /// content of the file in base64
+    pub content: String,
+}
+
+#[derive(Debug, FromRow)]
+struct WebhookTarget {
+    chat_id: i64,
+    ws_id: i64,
+    bot_id: i64,
+}
+
+impl AppState {
+    /// Create a webhook posting into the chat as a new bot
+    pub async fn create_incoming_webhook(
+        &self,
+        user: &User,
+        chat_id: u64,
+        input: &CreateIncomingWebhook,
+    ) -> Result<CreatedIncomingWebhook, AppError> {
+        let name = valid_name(&input.name)?;
+        let bot = self.insert_bot(user, name).await?;
+
+        let token = generate_api_token();
+        let info = sqlx::query_as(
+            r#"
+            INSERT INTO incoming_webhooks (chat_id, bot_id, name, token_hash, created_by)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, chat_id, bot_id, name, enabled, created_by, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(bot.id)
+        .bind(name)
+        .bind(hash_api_token(&token))
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(CreatedIncomingWebhook {
+            url: format!("/api/hooks/{}", token),
+            info,
+        })
+    }
+
+    pub async fn list_incoming_webhooks(
+        &self,
+        chat_id: u64,
+    ) -> Result<Vec<IncomingWebhook>, AppError> {
+        let hooks = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, bot_id, name, enabled, created_by, created_at
+            FROM incoming_webhooks
+            WHERE chat_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(hooks)
+    }
+
+    /// Rename, disable or enable a webhook of a chat of the user. The bot is
+    /// renamed with the webhook.
+    pub async fn update_incoming_webhook(
+        &self,
+        user: &User,
+        id: i64,
+        input: &UpdateIncomingWebhook,
+    ) -> Result<IncomingWebhook, AppError> {
+        let name = input.name.as_deref().map(valid_name).transpose()?;
+        let mut tx = self.pool.begin().await?;
+        let hook: Option<IncomingWebhook> = sqlx::query_as(
+            r#"
+            UPDATE incoming_webhooks w
+            SET name = COALESCE($3, w.name), enabled = COALESCE($4, w.enabled)
+            FROM chats c
+            WHERE w.id = $1 AND c.id = w.chat_id AND $2 = ANY(c.members)
+            RETURNING w.id, w.chat_id, w.bot_id, w.name, w.enabled, w.created_by, w.created_at
+            "#,
+        )
+        .bind(id)
+        .bind(user.id)
+        .bind(name)
+        .bind(input.enabled)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(hook) = hook else {
+            return Err(AppError::NotFound(format!("webhook {}", id)));
+        };
+        sqlx::query("UPDATE users SET fullname = $2 WHERE id = $1")
+            .bind(hook.bot_id)
+            .bind(&hook.name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(hook)
+    }
+
+    /// Delete a webhook of a chat of the user, the messages of its bot are kept
+    pub async fn delete_incoming_webhook(&self, user: &User, id: i64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM incoming_webhooks w
+            USING chats c
+            WHERE w.id = $1 AND c.id = w.chat_id AND $2 = ANY(c.members)
+            "#,
+        )
+        .bind(id)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("webhook {}", id)));
+        }
+        Ok(())
+    }
+
+    /// The id of the webhook with the token, enabled or not
+    pub(crate) async fn find_incoming_webhook_id(
+        &self,
+        token: &str,
+    ) -> Result<Option<i64>, AppError> {
+        let id = sqlx::query_scalar("SELECT id FROM incoming_webhooks WHERE token_hash = $1")
+            .bind(hash_api_token(token))
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(id)
+    }
+
+    /// Post a message to the chat of the webhook with the token, as its bot
+    pub async fn post_incoming_webhook(
+        &self,
+        token: &str,
+        input: WebhookPayload,
+    ) -> Result<Message, AppError> {
+        // disabled webhooks look like unknown ones
+        let target: Option<WebhookTarget> = sqlx::query_as(
+            r#"
+            SELECT w.chat_id, c.ws_id, w.bot_id
+            FROM incoming_webhooks w
+            JOIN chats c ON c.id = w.chat_id
+            WHERE w.token_hash = $1 AND w.enabled AND c.deleted_at IS NULL
+            "#,
+        )
+        .bind(hash_api_token(token))
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(target) = target else {
+            return Err(AppError::NotFound("webhook".to_string()));
+        };
+
+        // everything is validated before the files are stored, so a rejected
+        // payload leaves nothing behind
+        let content = input.text.trim().to_string();
+        if content.is_empty() {
+            return Err(AppError::WebhookError("text cannot be empty".to_string()));
+        }
+        let rendered = match input.format {
+            TextFormat::Plain => render_plain(&content),
+            TextFormat::Markdown => render_markdown(&content)
+                .map_err(|e| AppError::WebhookError(format!("invalid text: {}", e)))?,
+        };
+        if input.attachments.len() > MAX_ATTACHMENTS {
+            return Err(AppError::WebhookError(format!(
+                "at most {} attachments",
+                MAX_ATTACHMENTS
+            )));
+        }
+        let attachments = input
+            .attachments
+            .iter()
+            .map(|attachment| {
+                let data = BASE64.decode(attachment.content.as_bytes()).map_err(|e| {
+                    AppError::WebhookError(format!(
+                        "invalid content of {}: {}",
+                        attachment.filename, e
+                    ))
+                })?;
+                Ok((attachment.filename.as_str(), data))
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
+
+        let mut files = Vec::with_capacity(attachments.len());
+        for (filename, data) in attachments {
+            let url = self.store_file(target.ws_id as _, filename, &data).await?;
+            files.push(url);
+        }
+
+        let input = CreateMessage {
+            content,
+            files,
+            ttl: None,
+            blocks: None,
+        };
+        self.create_rendered_message(input, rendered, target.chat_id as _, target.bot_id as _)
+            .await
+    }
+}
+
+fn valid_name(name: &str) -> Result<&str, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return Err(AppError::WebhookError(
+            "the name of a webhook must have 1 to 64 characters".to_string(),
+        ));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ChatFile;
+    use anyhow::Result;
+
+    fn payload(text: &str, format: TextFormat) -> WebhookPayload {
+        WebhookPayload {
+            text: text.to_string(),
+            format,
+            attachments: vec![],
+        }
+    }
+
+    fn token(hook: &CreatedIncomingWebhook) -> &str {
+        hook.url.rsplit('/').next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn plain_payloads_should_not_be_parsed_as_markdown() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let input = CreateIncomingWebhook {
+            name: "CI".to_string(),
+        };
+        let hook = state.create_incoming_webhook(&user, 1, &input).await?;
+
+        let text = "Build #42 failed\n- lint\n1. test\n    at main.rs:1\nSummary\n---";
+        let msg = state
+            .post_incoming_webhook(token(&hook), payload(text, TextFormat::Plain))
+            .await?;
+        assert_eq!(msg.content, text);
+        assert_eq!(
+            msg.content_html.as_deref(),
+            Some("<p>Build #42 failed<br>- lint<br>1. test<br>    at main.rs:1<br>Summary<br>---</p>")
+        );
+        assert_eq!(msg.content_text.as_deref(), Some(text));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_post_as_its_bot() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let input = CreateIncomingWebhook {
+            name: "CI".to_string(),
+        };
+        let hook = state.create_incoming_webhook(&user, 1, &input).await?;
+        assert_eq!(
+            state.list_incoming_webhooks(1).await?,
+            vec![hook.info.clone()]
+        );
+
+        let msg = state
+            .post_incoming_webhook(token(&hook), payload("**green**", TextFormat::Markdown))
+            .await?;
+        assert_eq!((msg.chat_id, msg.sender_id), (1, hook.info.bot_id));
+        assert_eq!(msg.content, "**green**");
+        let bot = state.find_user_by_id(hook.info.bot_id).await?.unwrap();
+        assert!(bot.is_bot);
+        assert_eq!(bot.fullname, "CI");
+
+        let mut input = payload("build *1*", TextFormat::Plain);
+        input.attachments.push(WebhookAttachment {
+            filename: "log.txt".to_string(),
+            content: BASE64.encode(b"all tests passed"),
+        });
+        let msg = state.post_incoming_webhook(token(&hook), input).await?;
+        assert_eq!(msg.content, "build *1*");
+        assert_eq!(msg.content_html.as_deref(), Some("<p>build *1*</p>"));
+        assert_eq!(msg.files.unwrap_or_default().len(), 1);
+
+        // rejected payloads store none of their attachments
+        let data = b"never stored";
+        let mut input = payload(" ", TextFormat::Plain);
+        input.attachments.push(WebhookAttachment {
+            filename: "a.txt".to_string(),
+            content: BASE64.encode(data),
+        });
+        assert!(matches!(
+            state.post_incoming_webhook(token(&hook), input).await,
+            Err(AppError::WebhookError(_))
+        ));
+        let mut input = payload("hi", TextFormat::Plain);
+        input.attachments.push(WebhookAttachment {
+            filename: "a.txt".to_string(),
+            content: BASE64.encode(data),
+        });
+        input.attachments.push(WebhookAttachment {
+            filename: "b.txt".to_string(),
+            content: "not base64!".to_string(),
+        });
+        assert!(matches!(
+            state.post_incoming_webhook(token(&hook), input).await,
+            Err(AppError::WebhookError(_))
+        ));
+        let file = ChatFile::new(user.ws_id as _, "a.txt", data);
+        assert!(!file.path(&state.config.server.base_dir).exists());
+        assert!(matches!(
+            state
+                .post_incoming_webhook("unknown", payload("hi", TextFormat::Plain))
+                .await,
+            Err(AppError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_be_managed_by_chat_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let outsider = state.find_user_by_id(5).await?.unwrap();
+        let input = CreateIncomingWebhook {
+            name: "Monitoring".to_string(),
+        };
+        // chat 2 is private to users 1, 2 and 3
+        let hook = state.create_incoming_webhook(&user, 2, &input).await?;
+        let id = hook.info.id;
+
+        let input = UpdateIncomingWebhook {
+            name: Some("Alerts".to_string()),
+            enabled: Some(false),
+        };
+        assert!(matches!(
+            state.update_incoming_webhook(&outsider, id, &input).await,
+            Err(AppError::NotFound(_))
+        ));
+        let updated = state.update_incoming_webhook(&user, id, &input).await?;
+        assert_eq!((updated.name.as_str(), updated.enabled), ("Alerts", false));
+        let bot = state.find_user_by_id(updated.bot_id).await?.unwrap();
+        assert_eq!(bot.fullname, "Alerts");
+
+        // disabled webhooks reject messages
+        assert!(matches!(
+            state
+                .post_incoming_webhook(token(&hook), payload("down", TextFormat::Plain))
+                .await,
+            Err(AppError::NotFound(_))
+        ));
+
+        assert!(matches!(
+            state.delete_incoming_webhook(&outsider, id).await,
+            Err(AppError::NotFound(_))
+        ));
+        state.delete_incoming_webhook(&user, id).await?;
+        assert!(state.list_incoming_webhooks(2).await?.is_empty());
+        Ok(())
+    }
+}
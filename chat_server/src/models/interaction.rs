@@ -0,0 +1,504 @@
+use std::time::Duration;
+
+use chat_core::{render_markdown, sign_payload, ActionElement, Blocks, Message, User};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use super::{
+    blocks::validate_blocks,
+    outbox::OutboxEvent,
+    outgoing_webhook::{generate_secret, valid_url},
+};
+use crate::{http::read_body, AppError, AppState};
+
+/// the user waits for the bot, it has to answer quickly
+const INTERACTION_TIMEOUT: Duration = Duration::from_secs(3);
+/// a message update, blocks included, is far below this
+const MAX_ANSWER_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateBotInteractions {
+    /// an https url the interactions with the messages of the bot are posted to
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct BotInteractions {
+    pub bot_id: i64,
+    pub url: String,
+    /// key of the `x-webhook-signature` HMAC-SHA256, a new one on every update
+    pub secret: String,
+    pub updated_at: DateTime<Local>,
+}
+
+/// A click on a button or a choice in a select of a message
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Interaction {
+    pub action_id: String,
+    /// the option selected, buttons send their own value
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+/// What the bot receives for an interaction
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InteractionPayload {
+    pub action_id: String,
+    pub value: Option<String>,
+    pub user: InteractionUser,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InteractionUser {
+    pub id: i64,
+    pub fullname: String,
+}
+
+/// The bot answers with the new content or blocks of the message, or with an
+/// empty body to leave it as is
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct InteractionResponse {
+    pub content: Option<String>,
+    pub blocks: Option<Blocks>,
+}
+
+#[derive(Debug, FromRow)]
+struct InteractionTarget {
+    #[sqlx(flatten)]
+    message: Message,
+    url: String,
+    secret: String,
+}
+
+impl AppState {
+    /// Set where the interactions with the messages of a bot of the user are
+    /// sent, with a new secret
+    pub async fn update_bot_interactions(
+        &self,
+        user: &User,
+        bot_id: i64,
+        input: &UpdateBotInteractions,
+    ) -> Result<BotInteractions, AppError> {
+        let url = valid_url(&input.url)?;
+        let ret = sqlx::query_as(
+            r#"
+            INSERT INTO bot_interactions (bot_id, url, secret)
+            SELECT id, $3, $4 FROM users WHERE id = $1 AND is_bot AND created_by = $2
+            ON CONFLICT (bot_id) DO UPDATE
+              SET url = EXCLUDED.url, secret = EXCLUDED.secret, updated_at = now()
+            RETURNING bot_id, url, secret, updated_at
+            "#,
+        )
+        .bind(bot_id)
+        .bind(user.id)
+        .bind(url)
+        .bind(generate_secret())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        ret.ok_or_else(|| AppError::NotFound(format!("bot {}", bot_id)))
+    }
+
+    /// Stop sending the interactions of a bot of the user, its buttons fail
+    pub async fn delete_bot_interactions(&self, user: &User, bot_id: i64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM bot_interactions i
+            USING users u
+            WHERE i.bot_id = $1 AND u.id = i.bot_id AND u.created_by = $2
+            "#,
+        )
+        .bind(bot_id)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "interactions of bot {}",
+                bot_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Forward an interaction of a member of the chat with a message of a bot
+    /// to the bot, and apply the update it answers with. Returns the message as
+    /// it is afterwards.
+    pub async fn interact(
+        &self,
+        user: &User,
+        message_id: i64,
+        input: Interaction,
+    ) -> Result<Message, AppError> {
+        let target: Option<InteractionTarget> = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.content, m.content_html, m.content_text, m.files,
+              m.blocks::text AS blocks, m.expires_at, m.updated_at, m.created_at, i.url, i.secret
+            FROM messages m
+            JOIN bot_interactions i ON i.bot_id = m.sender_id
+            JOIN chats c ON c.id = m.chat_id
+            WHERE m.id = $1 AND $2 = ANY(c.members) AND c.deleted_at IS NULL
+              AND (m.expires_at IS NULL OR m.expires_at > now())
+            "#,
+        )
+        .bind(message_id)
+        .bind(user.id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(target) = target else {
+            return Err(AppError::NotFound(format!(
+                "message {} of a bot with interactions",
+                message_id
+            )));
+        };
+
+        let action = target
+            .message
+            .blocks
+            .as_ref()
+            .and_then(|v| v.find_action(&input.action_id));
+        let value = match action {
+            Some(ActionElement::Button { value, .. }) => value.clone(),
+            Some(ActionElement::Select { options, .. }) => {
+                let value = input
+                    .value
+                    .filter(|v| options.iter().any(|o| &o.value == v));
+                if value.is_none() {
+                    return Err(AppError::InteractionError(
+                        "the value is not an option of the select".to_string(),
+                    ));
+                }
+                value
+            }
+            None => {
+                return Err(AppError::InteractionError(format!(
+                    "unknown action {}",
+                    input.action_id
+                )))
+            }
+        };
+
+        let payload = InteractionPayload {
+            action_id: input.action_id,
+            value,
+            user: InteractionUser {
+                id: user.id,
+                fullname: user.fullname.clone(),
+            },
+            message: target.message,
+        };
+        let body = serde_json::to_string(&payload).expect("Failed to serialize interaction");
+        let timestamp = Utc::now().timestamp().to_string();
+        let res = self
+            .http
+            .post(&target.url)
+            .timeout(INTERACTION_TIMEOUT)
+            .header("content-type", "application/json")
+            .header("x-webhook-event", "Interaction")
+            .header("x-webhook-timestamp", &timestamp)
+            .header(
+                "x-webhook-signature",
+                sign_payload(&target.secret, &timestamp, &body),
+            )
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| AppError::BotError(format!("the bot is unreachable: {}", e)))?;
+        if !res.status().is_success() {
+            return Err(AppError::BotError(format!(
+                "the bot answered {}",
+                res.status()
+            )));
+        }
+        let bytes = read_body(res, MAX_ANSWER_SIZE)
+            .await
+            .map_err(|e| AppError::BotError(format!("invalid answer of the bot: {}", e)))?;
+        if bytes.iter().all(u8::is_ascii_whitespace) {
+            return Ok(payload.message);
+        }
+        let update: InteractionResponse = serde_json::from_slice(&bytes)
+            .map_err(|e| AppError::BotError(format!("invalid answer of the bot: {}", e)))?;
+
+        self.update_bot_message(payload.message, update).await
+    }
+
+    async fn update_bot_message(
+        &self,
+        message: Message,
+        update: InteractionResponse,
+    ) -> Result<Message, AppError> {
+        if update.content.is_none() && update.blocks.is_none() {
+            return Ok(message);
+        }
+        if update.content.as_ref().is_some_and(|v| v.is_empty()) {
+            return Err(AppError::BotError(
+                "the content of a message cannot be empty".to_string(),
+            ));
+        }
+        if let Some(blocks) = &update.blocks {
+            validate_blocks(blocks, &self.config.server.base_dir)
+                .map_err(|e| AppError::BotError(e.to_string()))?;
+        }
+        let rendered = match &update.content {
+            Some(content) => Some(
+                render_markdown(content)
+                    .map_err(|e| AppError::BotError(format!("invalid content: {}", e)))?,
+            ),
+            None => None,
+        };
+
+        let mut tx = self.begin_events().await?;
+        let message: Message = sqlx::query_as(
+            r#"
+            UPDATE messages
+            SET content = COALESCE($2, content), blocks = COALESCE($3::jsonb, blocks),
+              content_html = COALESCE($4, content_html), content_text = COALESCE($5, content_text),
+              updated_at = now()
+            WHERE id = $1
+            RETURNING id, chat_id, sender_id, content, content_html, content_text, files,
+              blocks::text AS blocks, expires_at, updated_at, created_at
+            "#,
+        )
+        .bind(message.id)
+        .bind(update.content)
+        .bind(update.blocks)
+        .bind(rendered.as_ref().map(|v| &v.html))
+        .bind(rendered.as_ref().map(|v| &v.text))
+        .fetch_one(&mut *tx)
+        .await?;
+        self.write_outbox(&mut tx, &[OutboxEvent::message_updated(&message)])
+            .await?;
+        tx.commit().await?;
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CreateMessage;
+    use anyhow::Result;
+    use axum::{body::Bytes, response::Redirect, routing::post, Json, Router};
+    use chat_core::{
+        test_util::{start_receiver, Received},
+        Block, SelectOption,
+    };
+    use serde_json::json;
+
+    /// A local bot, answering clicks on `approve` with an update
+    async fn start_bot() -> Result<(String, Received)> {
+        let reply = |body: &Bytes| {
+            let payload: InteractionPayload = serde_json::from_slice(body).unwrap();
+            match payload.action_id.as_str() {
+                "approve" => Json(json!({ "content": "Approved", "blocks": [
+                    { "type": "section", "text": "Approved by *Tyr*" }
+                ]})),
+                _ => Json(json!({})),
+            }
+        };
+        let redirect = Router::new().route(
+            "/redirect",
+            post(|| async { Redirect::temporary("/interactions") }),
+        );
+        let (url, received) = start_receiver("/interactions", reply, redirect).await?;
+        Ok((format!("{}/interactions", url), received))
+    }
+
+    fn blocks() -> Blocks {
+        Blocks(vec![
+            Block::Section {
+                text: "Deploy v1?".to_string(),
+                fields: vec![],
+            },
+            Block::Actions {
+                elements: vec![
+                    ActionElement::Button {
+                        action_id: "approve".to_string(),
+                        text: "Approve".to_string(),
+                        value: Some("v1".to_string()),
+                        style: None,
+                    },
+                    ActionElement::Select {
+                        action_id: "env".to_string(),
+                        placeholder: "Environment".to_string(),
+                        options: vec![SelectOption {
+                            text: "Staging".to_string(),
+                            value: "staging".to_string(),
+                        }],
+                    },
+                ],
+            },
+        ])
+    }
+
+    #[tokio::test]
+    async fn interactions_should_only_reach_public_bots() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (url, received) = start_bot().await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let bot = state.insert_bot(&owner, "Deploy Bot").await?;
+        for url in [
+            "https://127.0.0.1/interactions",
+            "https://localhost/interactions",
+            "https://10.0.0.5/interactions",
+            "https://169.254.169.254/latest",
+            "https://[fd00::1]/interactions",
+        ] {
+            let input = UpdateBotInteractions {
+                url: url.to_string(),
+            };
+            assert!(matches!(
+                state.update_bot_interactions(&owner, bot.id, &input).await,
+                Err(AppError::WebhookError(_))
+            ));
+        }
+
+        // a bot can't redirect the click to another host
+        let input = UpdateBotInteractions {
+            url: "https://example.com/interactions".to_string(),
+        };
+        state
+            .update_bot_interactions(&owner, bot.id, &input)
+            .await?;
+        sqlx::query("UPDATE bot_interactions SET url = $2 WHERE bot_id = $1")
+            .bind(bot.id)
+            .bind(url.replace("/interactions", "/redirect"))
+            .execute(&state.pool)
+            .await?;
+        let input = CreateMessage {
+            content: "Deploy v1?".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: Some(blocks()),
+        };
+        let msg = state.create_message(input, 1, bot.id as _).await?;
+        let member = state.find_user_by_id(2).await?.unwrap();
+        let click = Interaction {
+            action_id: "approve".to_string(),
+            value: None,
+        };
+        assert!(matches!(
+            state.interact(&member, msg.id, click).await,
+            Err(AppError::BotError(_))
+        ));
+        assert!(received.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interactions_should_reach_the_bot_and_update_the_message() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (url, received) = start_bot().await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let bot = state.insert_bot(&owner, "Deploy Bot").await?;
+        // only public https urls can be set through the API
+        let input = UpdateBotInteractions { url: url.clone() };
+        assert!(state
+            .update_bot_interactions(&owner, bot.id, &input)
+            .await
+            .is_err());
+        let input = UpdateBotInteractions {
+            url: "https://example.com/interactions".to_string(),
+        };
+        let member = state.find_user_by_id(2).await?.unwrap();
+        assert!(matches!(
+            state.update_bot_interactions(&member, bot.id, &input).await,
+            Err(AppError::NotFound(_))
+        ));
+        let settings = state
+            .update_bot_interactions(&owner, bot.id, &input)
+            .await?;
+        sqlx::query("UPDATE bot_interactions SET url = $2 WHERE bot_id = $1")
+            .bind(bot.id)
+            .bind(&url)
+            .execute(&state.pool)
+            .await?;
+
+        let input = CreateMessage {
+            content: "Deploy v1?".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: Some(blocks()),
+        };
+        let msg = state.create_message(input, 1, bot.id as _).await?;
+        assert_eq!(msg.blocks, Some(blocks()));
+
+        let click = |action_id: &str, value: Option<&str>| Interaction {
+            action_id: action_id.to_string(),
+            value: value.map(|v| v.to_string()),
+        };
+        let same = state
+            .interact(&member, msg.id, click("env", Some("staging")))
+            .await?;
+        assert_eq!(same.content, "Deploy v1?");
+        assert!(same.updated_at.is_none());
+        assert!(matches!(
+            state
+                .interact(&member, msg.id, click("env", Some("prod")))
+                .await,
+            Err(AppError::InteractionError(_))
+        ));
+        assert!(matches!(
+            state.interact(&member, msg.id, click("deny", None)).await,
+            Err(AppError::InteractionError(_))
+        ));
+
+        let updated = state
+            .interact(&member, msg.id, click("approve", Some("ignored")))
+            .await?;
+        assert_eq!(updated.content, "Approved");
+        assert_eq!(updated.content_html.as_deref(), Some("<p>Approved</p>"));
+        assert!(updated.updated_at.is_some());
+        assert!(updated.blocks.unwrap().find_action("approve").is_none());
+
+        let (headers, body) = received.lock().unwrap().pop().unwrap();
+        let timestamp = headers["x-webhook-timestamp"].to_str()?;
+        let body = std::str::from_utf8(&body)?;
+        assert_eq!(
+            headers["x-webhook-signature"],
+            sign_payload(&settings.secret, timestamp, body).as_str()
+        );
+        let payload: InteractionPayload = serde_json::from_str(body)?;
+        assert_eq!(payload.value.as_deref(), Some("v1"));
+        assert_eq!(payload.user.id, member.id);
+        assert_eq!(payload.message.id, msg.id);
+
+        // only members of the chat can interact
+        let outsider = state.find_user_by_id(5).await?.unwrap();
+        sqlx::query("UPDATE chats SET members = array_remove(members, 5::bigint) WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        assert!(matches!(
+            state
+                .interact(&outsider, msg.id, click("approve", None))
+                .await,
+            Err(AppError::NotFound(_))
+        ));
+
+        // messages of other users have no interactions
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: Some(blocks()),
+        };
+        let msg = state.create_message(input, 1, 1).await?;
+        assert!(matches!(
+            state
+                .interact(&member, msg.id, click("approve", None))
+                .await,
+            Err(AppError::NotFound(_))
+        ));
+
+        state.delete_bot_interactions(&owner, bot.id).await?;
+        assert!(matches!(
+            state.delete_bot_interactions(&owner, bot.id).await,
+            Err(AppError::NotFound(_))
+        ));
+        Ok(())
+    }
+}
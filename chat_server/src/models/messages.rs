@@ -1,17 +1,43 @@
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::warn;
 use utoipa::{IntoParams, ToSchema};
 
-use crate::{AppError, AppState};
+use crate::{
+    commands::{parse_command, CommandContext, CommandReply, SlashCommand},
+    AppError, AppState,
+};
 
-use super::ChatFile;
-use chat_core::Message;
+use super::{
+    blocks::validate_blocks, outbox::OutboxEvent, outgoing_webhook::WebhookPayload, ChatFile,
+};
+use chat_core::{
+    render_markdown, render_plain, Blocks, EphemeralMessage, Message, RenderedMarkdown, User,
+};
+
+/// messages rendered per transaction by the backfill
+const RENDER_BATCH_SIZE: i64 = 500;
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct CreateMessage {
     pub content: String,
     pub files: Vec<String>,
+    /// Seconds after which the message is deleted, capped by the chat ttl
+    #[serde(default)]
+    pub ttl: Option<i64>,
+    /// structured content, `content` is the fallback for clients and previews
+    #[serde(default)]
+    pub blocks: Option<Blocks>,
+}
+
+/// A stored message, or an ephemeral reply to a slash command
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum MessageOutput {
+    Message(Message),
+    Ephemeral(EphemeralMessage),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
@@ -22,11 +48,74 @@ pub struct ListMessages {
 
 #[allow(dead_code)]
 impl AppState {
+    /// Send a message, dispatching content like `/name args` to slash commands
+    pub async fn send_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user: &User,
+    ) -> Result<MessageOutput, AppError> {
+        let Some((name, args)) = parse_command(&input.content) else {
+            let msg = self.create_message(input, chat_id, user.id as _).await?;
+            return Ok(MessageOutput::Message(msg));
+        };
+
+        let chat = self
+            .get_chat_by_id(chat_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Chat with id {} not found", chat_id)))?;
+        let ctx = CommandContext {
+            state: self,
+            user,
+            chat: &chat,
+            args,
+        };
+        let reply = match self.commands.get(name) {
+            Some(cmd) => cmd.execute(ctx).await?,
+            None => match self.find_command(user.ws_id as _, name).await? {
+                Some(cmd) => cmd.execute(ctx).await?,
+                None => CommandReply::Ephemeral(format!("Unknown command /{}, try /help", name)),
+            },
+        };
+
+        match reply {
+            CommandReply::Public(content) => {
+                let input = CreateMessage { content, ..input };
+                let msg = self.create_message(input, chat_id, user.id as _).await?;
+                Ok(MessageOutput::Message(msg))
+            }
+            CommandReply::Ephemeral(content) => {
+                let msg = EphemeralMessage {
+                    chat_id: chat_id as _,
+                    user_id: user.id,
+                    content,
+                    created_at: chrono::Local::now(),
+                };
+                self.send_ephemeral(&msg).await?;
+                Ok(MessageOutput::Ephemeral(msg))
+            }
+        }
+    }
+
     pub async fn create_message(
         &self,
         input: CreateMessage,
         chat_id: u64,
         user_id: u64,
+    ) -> Result<Message, AppError> {
+        let rendered = render_markdown(&input.content)
+            .map_err(|e| AppError::CreateMessageError(format!("invalid content: {}", e)))?;
+        self.create_rendered_message(input, rendered, chat_id, user_id)
+            .await
+    }
+
+    /// Create a message with its content already rendered, e.g. as plain text
+    pub(crate) async fn create_rendered_message(
+        &self,
+        input: CreateMessage,
+        rendered: RenderedMarkdown,
+        chat_id: u64,
+        user_id: u64,
     ) -> Result<Message, AppError> {
         let base_dir = &self.config.server.base_dir;
         // verity content not empty
@@ -36,6 +125,12 @@ impl AppState {
             ));
         }
 
+        if input.ttl.is_some_and(|ttl| ttl <= 0) {
+            return Err(AppError::CreateMessageError(
+                "ttl must be positive".to_string(),
+            ));
+        }
+
         // verify files exist
         for s in &input.files {
             let file = ChatFile::from_str(s)?;
@@ -47,19 +142,38 @@ impl AppState {
             }
         }
 
-        let message = sqlx::query_as(
+        if let Some(blocks) = &input.blocks {
+            validate_blocks(blocks, base_dir)?;
+        }
+
+        let mut tx = self.begin_events().await?;
+        let message: Message = sqlx::query_as(
             r#"
-            INSERT INTO messages (chat_id, sender_id, content, files)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, chat_id, sender_id, content, files, created_at
+            INSERT INTO messages (chat_id, sender_id, content, files, expires_at, blocks, content_html, content_text)
+            VALUES ($1, $2, $3, $4, now() + LEAST($5, (SELECT message_ttl FROM chats WHERE id = $1)) * interval '1 second', $6::jsonb, $7, $8)
+            RETURNING id, chat_id, sender_id, content, content_html, content_text, files, blocks::text AS blocks,
+              expires_at, updated_at, created_at
             "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
         .bind(input.content)
         .bind(&input.files)
-        .fetch_one(&self.pool)
+        .bind(input.ttl)
+        .bind(input.blocks)
+        .bind(rendered.html)
+        .bind(rendered.text)
+        .fetch_one(&mut *tx)
         .await?;
+        self.write_outbox(&mut tx, &[OutboxEvent::message_added(&message)])
+            .await?;
+        self.queue_webhooks(
+            &mut tx,
+            message.chat_id,
+            &[WebhookPayload::new_message(&message)],
+        )
+        .await?;
+        tx.commit().await?;
 
         Ok(message)
     }
@@ -73,10 +187,12 @@ impl AppState {
 
         let messages = sqlx::query_as(
             r#"
-            SELECT id, chat_id, sender_id, content, files, created_at
+            SELECT id, chat_id, sender_id, content, content_html, content_text, files,
+              blocks::text AS blocks, expires_at, updated_at, created_at
             FROM messages
             WHERE chat_id = $1
             AND id < $2
+            AND (expires_at IS NULL OR expires_at > now())
             ORDER BY id DESC
             LIMIT $3
             "#,
@@ -89,6 +205,102 @@ impl AppState {
 
         Ok(messages)
     }
+
+    /// Render a batch of the messages sent before the content was rendered by
+    /// the server. Returns the number of messages rendered, 0 once done.
+    pub async fn render_legacy_messages(&self) -> Result<u64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            r#"
+            SELECT id, content
+            FROM messages
+            WHERE content_html IS NULL
+            ORDER BY id
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+            "#,
+        )
+        .bind(RENDER_BATCH_SIZE)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut ids = Vec::with_capacity(rows.len());
+        let mut htmls = Vec::with_capacity(rows.len());
+        let mut texts = Vec::with_capacity(rows.len());
+        for (id, content) in rows {
+            // sent before the dialect was enforced, shown as written if too deep
+            let rendered = render_markdown(&content).unwrap_or_else(|_| render_plain(&content));
+            ids.push(id);
+            htmls.push(rendered.html);
+            texts.push(rendered.text);
+        }
+        let ret = sqlx::query(
+            r#"
+            UPDATE messages m
+            SET content_html = t.html, content_text = t.text
+            FROM unnest($1::bigint[], $2::text[], $3::text[]) AS t(id, html, text)
+            WHERE m.id = t.id
+            "#,
+        )
+        .bind(&ids)
+        .bind(&htmls)
+        .bind(&texts)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(ret.rows_affected())
+    }
+
+    /// Hard delete expired messages, and remove the files no other message references
+    pub async fn delete_expired_messages(&self) -> Result<u64, AppError> {
+        let mut tx = self.begin_events().await?;
+        let deleted: Vec<Message> = sqlx::query_as(
+            r#"
+            DELETE FROM messages
+            WHERE expires_at <= now()
+            RETURNING id, chat_id, sender_id, content, files, expires_at, created_at
+            "#,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let events: Vec<_> = deleted.iter().map(OutboxEvent::message_deleted).collect();
+        self.write_outbox(&mut tx, &events).await?;
+        tx.commit().await?;
+
+        let count = deleted.len() as u64;
+        let mut files: Vec<String> = deleted
+            .into_iter()
+            .filter_map(|m| m.files)
+            .flatten()
+            .collect();
+        files.sort();
+        files.dedup();
+
+        let base_dir = &self.config.server.base_dir;
+        for s in files {
+            let referenced = sqlx::query(
+                "SELECT 1 FROM messages WHERE $1 = ANY(files) OR strpos(blocks::text, $1) > 0 LIMIT 1",
+            )
+                .bind(&s)
+                .fetch_optional(&self.pool)
+                .await?;
+            if referenced.is_some() {
+                continue;
+            }
+
+            let Ok(file) = ChatFile::from_str(&s) else {
+                warn!("Invalid file in expired message: {}", s);
+                continue;
+            };
+            let path = file.path(base_dir);
+            if let Err(e) = fs::remove_file(&path).await {
+                warn!("Failed to remove expired file {:?}: {}", path, e);
+            }
+        }
+
+        Ok(count)
+    }
 }
 
 #[cfg(test)]
@@ -102,17 +314,22 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![],
+            ttl: None,
+            blocks: None,
         };
         let message = state
             .create_message(input, 1, 1)
             .await
             .expect("create message failed");
         assert_eq!(message.content, "hello");
+        assert_eq!(message.content_html.as_deref(), Some("<p>hello</p>"));
 
         // invalid files should fail
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec!["1".to_string()],
+            ttl: None,
+            blocks: None,
         };
 
         let err = state.create_message(input, 1, 1).await.unwrap_err();
@@ -126,6 +343,8 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![url],
+            ttl: None,
+            blocks: None,
         };
 
         let message = state
@@ -138,6 +357,73 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_message_should_render_markdown() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage {
+            content: "**ship** it <script>alert(1)</script>".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        assert_eq!(
+            message.content_html.as_deref(),
+            Some("<p><strong>ship</strong> it &lt;script&gt;alert(1)&lt;/script&gt;</p>")
+        );
+        assert_eq!(message.plain_text(), "ship it <script>alert(1)</script>");
+
+        let messages = state
+            .list_messages(
+                ListMessages {
+                    last_id: None,
+                    limit: 1,
+                },
+                1,
+            )
+            .await?;
+        assert_eq!(messages[0].content_html, message.content_html);
+
+        // nesting too deep should fail
+        let input = CreateMessage {
+            content: "> ".repeat(20),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::CreateMessageError(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn legacy_messages_should_be_rendered() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO messages (chat_id, sender_id, content) VALUES (1, 1, '*old* <b>') RETURNING id",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+
+        while state.render_legacy_messages().await? > 0 {}
+        let (html, text): (Option<String>, Option<String>) =
+            sqlx::query_as("SELECT content_html, content_text FROM messages WHERE id = $1")
+                .bind(id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(html.as_deref(), Some("<p><em>old</em> &lt;b&gt;</p>"));
+        assert_eq!(text.as_deref(), Some("old <b>"));
+
+        let (left,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM messages WHERE content_html IS NULL")
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(left, 0);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list_messages_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -162,6 +448,116 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn expired_messages_should_be_hidden_and_deleted() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "secret.txt", b"self destructing");
+        let path = file.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
+        std::fs::write(&path, b"self destructing")?;
+        let url = file.url();
+        let input = CreateMessage {
+            content: "secret".to_string(),
+            files: vec![url.clone()],
+            ttl: Some(3600),
+            blocks: None,
+        };
+        let message = state.create_message(input, 3, 1).await?;
+        assert!(message.expires_at.is_some());
+
+        sqlx::query("UPDATE messages SET expires_at = now() - interval '1 second' WHERE id = $1")
+            .bind(message.id)
+            .execute(&state.pool)
+            .await?;
+
+        // expired rows should never be listed, even before the sweeper runs
+        let input = ListMessages {
+            last_id: None,
+            limit: 10,
+        };
+        let messages = state.list_messages(input, 3).await?;
+        assert!(messages.is_empty());
+
+        let deleted = state.delete_expired_messages().await?;
+        assert_eq!(deleted, 1);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_ttl_should_be_capped_by_chat_ttl() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE chats SET message_ttl = 60 WHERE id = 3")
+            .execute(&state.pool)
+            .await?;
+
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+            ttl: Some(3600),
+            blocks: None,
+        };
+        let message = state.create_message(input, 3, 1).await?;
+        let ttl = message.expires_at.expect("expires_at should be set") - message.created_at;
+        assert!(ttl.num_seconds() <= 60);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_should_dispatch_slash_commands() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+
+        let input = CreateMessage {
+            content: "/me waves".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        let MessageOutput::Message(msg) = state.send_message(input, 1, &user).await? else {
+            panic!("expecting a public message");
+        };
+        assert_eq!(msg.content, "_Tyr Chen waves_");
+
+        let input = CreateMessage {
+            content: "/topic release planning".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        state.send_message(input, 1, &user).await?;
+        let chat = state.get_chat_by_id(1).await?.expect("chat should exist");
+        assert_eq!(chat.topic.as_deref(), Some("release planning"));
+
+        let input = CreateMessage {
+            content: "/nope".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        let MessageOutput::Ephemeral(msg) = state.send_message(input, 1, &user).await? else {
+            panic!("expecting an ephemeral message");
+        };
+        assert_eq!(msg.content, "Unknown command /nope, try /help");
+        assert_eq!(msg.user_id, 1);
+
+        // paths are not commands
+        let input = CreateMessage {
+            content: "/usr/bin is a directory".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        let MessageOutput::Message(msg) = state.send_message(input, 1, &user).await? else {
+            panic!("expecting a plain message");
+        };
+        assert_eq!(msg.content, "/usr/bin is a directory");
+
+        Ok(())
+    }
+
     fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file = ChatFile::new(1, "test.txt", b"hello world");
         let path = file.path(&state.config.server.base_dir);
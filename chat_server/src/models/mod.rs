@@ -1,15 +1,52 @@
+mod account;
+mod api_token;
+mod audit;
+mod blocks;
 mod chat;
+mod command;
+mod email;
 mod file;
+mod incoming_webhook;
+mod interaction;
 mod messages;
+mod outbox;
+mod outgoing_webhook;
+mod presence;
+mod signin_attempt;
+mod sso;
+mod two_factor;
 mod user;
 mod workspace;
 
 use serde::{Deserialize, Serialize};
 
+pub(crate) use account::Session;
+pub use account::{ForgotPassword, ResetPassword, VerifyEmail};
+pub use api_token::{ApiToken, CreateApiToken, CreateBot, CreatedApiToken};
 pub use chat::{CreateChat, UpdateChat};
-pub use messages::{CreateMessage, ListMessages};
+pub use command::CreateCommand;
+pub use email::{EmailPreferences, MarkRead, Unsubscribe, UpdateEmailPreferences};
+pub use incoming_webhook::{
+    CreateIncomingWebhook, CreatedIncomingWebhook, IncomingWebhook, TextFormat,
+    UpdateIncomingWebhook, WebhookAttachment, WebhookPayload,
+};
+pub use interaction::{
+    BotInteractions, Interaction, InteractionPayload, InteractionResponse, InteractionUser,
+    UpdateBotInteractions,
+};
+pub use messages::{CreateMessage, ListMessages, MessageOutput};
+pub use outgoing_webhook::{
+    CreateOutgoingWebhook, CreatedOutgoingWebhook, DeliveryStatus, ListDeliveries, OutgoingWebhook,
+    UpdateOutgoingWebhook, WebhookDelivery,
+};
+pub use presence::{ListPresences, UpdatePresence};
+pub use sso::{OidcCallback, OidcStart, OIDC_STATE_COOKIE};
+pub use two_factor::{
+    RecoveryCodes, SigninChallenge, TwoFactorCode, TwoFactorEnrollment, VerifyTwoFactor,
+};
 pub use user::{CreateUser, SigninUser};
 use utoipa::ToSchema;
+pub use workspace::{InviteUser, UpdateWorkspace};
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ChatFile {
@@ -0,0 +1,213 @@
+use chat_core::Message;
+use serde_json::{json, Value};
+use sqlx::{PgConnection, Postgres, Transaction};
+
+use crate::{AppError, AppState, EventMode};
+
+/// A domain event, with the same channel and payload as the legacy triggers
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct OutboxEvent {
+    chat_id: i64,
+    channel: &'static str,
+    payload: Value,
+}
+
+impl OutboxEvent {
+    pub(crate) fn chat_inserted(chat_id: i64) -> Self {
+        Self::chat_changed("INSERT", chat_id)
+    }
+
+    pub(crate) fn chat_updated(chat_id: i64) -> Self {
+        Self::chat_changed("UPDATE", chat_id)
+    }
+
+    fn chat_changed(op: &str, chat_id: i64) -> Self {
+        Self {
+            chat_id,
+            channel: "chat_change",
+            payload: json!({ "op": op, "chat_id": chat_id }),
+        }
+    }
+
+    pub(crate) fn message_added(msg: &Message) -> Self {
+        Self {
+            chat_id: msg.chat_id,
+            channel: "message_added",
+            payload: json!({
+                "op": "INSERT",
+                "message_id": msg.id,
+                "chat_id": msg.chat_id,
+                "sender_id": msg.sender_id,
+                "created_at": msg.created_at,
+            }),
+        }
+    }
+
+    pub(crate) fn message_updated(msg: &Message) -> Self {
+        Self {
+            chat_id: msg.chat_id,
+            channel: "message_updated",
+            payload: json!({
+                "op": "UPDATE",
+                "message_id": msg.id,
+                "chat_id": msg.chat_id,
+                "sender_id": msg.sender_id,
+                "created_at": msg.created_at,
+            }),
+        }
+    }
+
+    pub(crate) fn message_deleted(msg: &Message) -> Self {
+        Self {
+            chat_id: msg.chat_id,
+            channel: "message_deleted",
+            payload: json!({
+                "op": "DELETE",
+                "message_id": msg.id,
+                "chat_id": msg.chat_id,
+                "sender_id": msg.sender_id,
+                "created_at": msg.created_at,
+            }),
+        }
+    }
+}
+
+impl AppState {
+    /// Begin a transaction for a change which emits events. In `outbox` mode the
+    /// legacy triggers are skipped for this transaction.
+    pub(crate) async fn begin_events(&self) -> Result<Transaction<'static, Postgres>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        if self.config.server.event_mode == EventMode::Outbox {
+            sqlx::query("SELECT set_config('chat.event_mode', 'outbox', true)")
+                .execute(&mut *tx)
+                .await?;
+        }
+        Ok(tx)
+    }
+
+    /// Write events in the transaction of the change. In `triggers` mode they
+    /// are generated by the database instead, and nothing is written.
+    pub(crate) async fn write_outbox(
+        &self,
+        conn: &mut PgConnection,
+        events: &[OutboxEvent],
+    ) -> Result<(), AppError> {
+        if self.config.server.event_mode != EventMode::Outbox || events.is_empty() {
+            return Ok(());
+        }
+
+        let chat_ids: Vec<i64> = events.iter().map(|v| v.chat_id).collect();
+        let channels: Vec<&str> = events.iter().map(|v| v.channel).collect();
+        let payloads: Vec<String> = events.iter().map(|v| v.payload.to_string()).collect();
+        // chat changes refer to the chat before the change, recorded by the trigger
+        // in this transaction
+        sqlx::query(
+            r#"
+            INSERT INTO outbox (chat_id, channel, payload)
+            SELECT chat_id, channel, CASE WHEN channel = 'chat_change' THEN
+                payload::jsonb || jsonb_build_object('change_id', (
+                  SELECT max(c.id) FROM chat_changes c
+                  WHERE c.chat_id = t.chat_id AND c.tx_id = txid_current()))
+              ELSE
+                payload::jsonb
+              END
+            FROM unnest($1::bigint[], $2::text[], $3::text[])
+              WITH ORDINALITY AS t(chat_id, channel, payload, n)
+            ORDER BY n
+            "#,
+        )
+        .bind(&chat_ids)
+        .bind(&channels)
+        .bind(&payloads)
+        .execute(&mut *conn)
+        .await?;
+
+        // wake up the relay once committed
+        sqlx::query("SELECT pg_notify('outbox', '')")
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::CreateMessage;
+    use anyhow::Result;
+    use chat_core::Chat;
+
+    #[tokio::test]
+    async fn outbox_should_be_written_with_the_change() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .config
+            .server
+            .event_mode = EventMode::Outbox;
+
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        let msg = state.create_message(input, 1, 1).await?;
+
+        let rows: Vec<(i64, String, String)> =
+            sqlx::query_as("SELECT chat_id, channel, payload::text FROM outbox ORDER BY id")
+                .fetch_all(&state.pool)
+                .await?;
+        assert_eq!(rows.len(), 1);
+        let (chat_id, channel, payload) = &rows[0];
+        assert_eq!(*chat_id, 1);
+        assert_eq!(channel, "message_added");
+        let payload: Value = serde_json::from_str(payload)?;
+        assert_eq!(payload["message_id"], msg.id);
+        assert_eq!(payload["op"], "INSERT");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_changes_should_refer_to_the_old_chat() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .config
+            .server
+            .event_mode = EventMode::Outbox;
+
+        state.remove_chat_member(2, 3).await?;
+
+        let (payload,): (String,) = sqlx::query_as("SELECT payload::text FROM outbox")
+            .fetch_one(&state.pool)
+            .await?;
+        let payload: Value = serde_json::from_str(&payload)?;
+        assert_eq!(payload["op"], "UPDATE");
+        let (old,): (String,) = sqlx::query_as("SELECT old::text FROM chat_changes WHERE id = $1")
+            .bind(payload["change_id"].as_i64())
+            .fetch_one(&state.pool)
+            .await?;
+        let old: Chat = serde_json::from_str(&old)?;
+        assert_eq!(old.members, vec![1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outbox_should_be_empty_in_triggers_mode() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_chat_topic(1, "news").await?;
+
+        let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM outbox")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(count, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,636 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::{is_public_url, Chat, ChatMember, Message, User, WebhookEvent};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{FromRow, PgConnection};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState};
+
+const SECRET_PREFIX: &str = "whsec_";
+const SECRET_LEN: usize = 24;
+const MAX_URL_LEN: usize = 2048;
+const MAX_DELIVERIES: u64 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateOutgoingWebhook {
+    /// an https url the events are posted to
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateOutgoingWebhook {
+    pub url: Option<String>,
+    pub events: Option<Vec<WebhookEvent>>,
+    /// a disabled webhook gets no new events, its pending deliveries wait
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, ToSchema)]
+pub struct OutgoingWebhook {
+    pub id: i64,
+    pub ws_id: i64,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub enabled: bool,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreatedOutgoingWebhook {
+    /// key of the `x-webhook-signature` HMAC-SHA256, shown only once
+    pub secret: String,
+    #[serde(flatten)]
+    pub info: OutgoingWebhook,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// waiting for its first attempt or a retry
+    Pending,
+    Delivered,
+    /// every attempt failed, it is only retried on request
+    Dead,
+}
+
+/// An event queued for a webhook, with the outcome of its last attempt
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: WebhookEvent,
+    /// the JSON body posted to the webhook
+    pub payload: Value,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Local>,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<DateTime<Local>>,
+    pub created_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct ListDeliveries {
+    pub status: Option<DeliveryStatus>,
+    /// deliveries before this id, the latest first
+    pub last_id: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+/// An event for outgoing webhooks, in the shape notify_server sends its events
+#[derive(Debug, Clone)]
+pub(crate) struct WebhookPayload {
+    event: WebhookEvent,
+    /// identifies the event, it is queued once per webhook
+    key: String,
+    payload: Value,
+}
+
+impl WebhookPayload {
+    pub(crate) fn new_message(msg: &Message) -> Self {
+        Self::tagged(
+            WebhookEvent::NewMessage,
+            format!("NewMessage:{}", msg.id),
+            msg,
+        )
+    }
+
+    pub(crate) fn new_chat(chat: &Chat) -> Self {
+        Self::tagged(WebhookEvent::NewChat, format!("NewChat:{}", chat.id), chat)
+    }
+
+    /// a member can leave and join again, the key gets the transaction id when
+    /// it's queued
+    pub(crate) fn member_joined(chat: &Chat, user_id: i64) -> Self {
+        let member = ChatMember {
+            chat: chat.clone(),
+            user_id,
+        };
+        let key = format!("MemberJoined:{}:{}", chat.id, user_id);
+        Self::tagged(WebhookEvent::MemberJoined, key, &member)
+    }
+
+    fn tagged(event: WebhookEvent, key: String, data: &impl Serialize) -> Self {
+        let mut payload = serde_json::to_value(data).expect("Failed to serialize event");
+        payload["event"] = json!(event);
+        Self {
+            event,
+            key,
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct DeliveryRow {
+    id: i64,
+    webhook_id: i64,
+    event: WebhookEvent,
+    payload: String,
+    status: DeliveryStatus,
+    attempts: i32,
+    next_attempt_at: DateTime<Local>,
+    response_status: Option<i32>,
+    last_error: Option<String>,
+    delivered_at: Option<DateTime<Local>>,
+    created_at: DateTime<Local>,
+}
+
+impl AppState {
+    /// Register an endpoint receiving the events of the workspace, for its
+    /// owner and admins
+    pub async fn create_outgoing_webhook(
+        &self,
+        user: &User,
+        input: &CreateOutgoingWebhook,
+    ) -> Result<CreatedOutgoingWebhook, AppError> {
+        let url = valid_url(&input.url)?;
+        let events = valid_events(&input.events)?;
+        self.ensure_webhook_admin(user).await?;
+
+        let secret = generate_secret();
+        let info: OutgoingWebhook = sqlx::query_as(
+            r#"
+            INSERT INTO outgoing_webhooks (ws_id, url, secret, events, created_by)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, url, events, enabled, created_by, created_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(url)
+        .bind(&secret)
+        .bind(&events)
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let detail = json!({ "webhook_id": info.id, "url": info.url, "events": events });
+        self.audit("outgoing_webhook_created", Some(user.id), None, detail)
+            .await?;
+        Ok(CreatedOutgoingWebhook { secret, info })
+    }
+
+    pub async fn list_outgoing_webhooks(
+        &self,
+        user: &User,
+    ) -> Result<Vec<OutgoingWebhook>, AppError> {
+        self.ensure_webhook_admin(user).await?;
+        let hooks = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, url, events, enabled, created_by, created_at
+            FROM outgoing_webhooks
+            WHERE ws_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(user.ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(hooks)
+    }
+
+    pub async fn update_outgoing_webhook(
+        &self,
+        user: &User,
+        id: i64,
+        input: &UpdateOutgoingWebhook,
+    ) -> Result<OutgoingWebhook, AppError> {
+        let url = input.url.as_deref().map(valid_url).transpose()?;
+        let events = input.events.as_deref().map(valid_events).transpose()?;
+        self.ensure_webhook_admin(user).await?;
+
+        let hook = sqlx::query_as(
+            r#"
+            UPDATE outgoing_webhooks
+            SET url = COALESCE($3, url), events = COALESCE($4, events),
+              enabled = COALESCE($5, enabled)
+            WHERE id = $1 AND ws_id = $2
+            RETURNING id, ws_id, url, events, enabled, created_by, created_at
+            "#,
+        )
+        .bind(id)
+        .bind(user.ws_id)
+        .bind(url)
+        .bind(events)
+        .bind(input.enabled)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        hook.ok_or_else(|| AppError::NotFound(format!("outgoing webhook {}", id)))
+    }
+
+    /// Delete a webhook with its deliveries
+    pub async fn delete_outgoing_webhook(&self, user: &User, id: i64) -> Result<(), AppError> {
+        self.ensure_webhook_admin(user).await?;
+        let ret = sqlx::query("DELETE FROM outgoing_webhooks WHERE id = $1 AND ws_id = $2")
+            .bind(id)
+            .bind(user.ws_id)
+            .execute(&self.pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("outgoing webhook {}", id)));
+        }
+
+        self.audit(
+            "outgoing_webhook_deleted",
+            Some(user.id),
+            None,
+            json!({ "webhook_id": id }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deliveries of the webhooks of the workspace, the latest first. Without a
+    /// webhook, `status: dead` lists the dead letters of the whole workspace.
+    pub async fn list_webhook_deliveries(
+        &self,
+        user: &User,
+        webhook_id: Option<i64>,
+        input: &ListDeliveries,
+    ) -> Result<Vec<WebhookDelivery>, AppError> {
+        self.ensure_webhook_admin(user).await?;
+        if let Some(id) = webhook_id {
+            let (found,): (bool,) = sqlx::query_as(
+                "SELECT EXISTS (SELECT 1 FROM outgoing_webhooks WHERE id = $1 AND ws_id = $2)",
+            )
+            .bind(id)
+            .bind(user.ws_id)
+            .fetch_one(&self.pool)
+            .await?;
+            if !found {
+                return Err(AppError::NotFound(format!("outgoing webhook {}", id)));
+            }
+        }
+
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let limit = input.limit.unwrap_or(MAX_DELIVERIES).min(MAX_DELIVERIES);
+        let rows: Vec<DeliveryRow> = sqlx::query_as(
+            r#"
+            SELECT d.id, d.webhook_id, d.event, d.payload::text AS payload, d.status, d.attempts,
+              d.next_attempt_at, d.response_status, d.last_error, d.delivered_at, d.created_at
+            FROM webhook_deliveries d JOIN outgoing_webhooks w ON w.id = d.webhook_id
+            WHERE w.ws_id = $1 AND ($2::bigint IS NULL OR d.webhook_id = $2)
+              AND ($3::webhook_delivery_status IS NULL OR d.status = $3)
+              AND d.id < $4
+            ORDER BY d.id DESC
+            LIMIT $5
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(webhook_id)
+        .bind(input.status)
+        .bind(last_id as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(TryInto::try_into).collect()
+    }
+
+    /// Queue a dead letter again, with a fresh set of attempts
+    pub async fn retry_webhook_delivery(
+        &self,
+        user: &User,
+        id: i64,
+    ) -> Result<WebhookDelivery, AppError> {
+        self.ensure_webhook_admin(user).await?;
+        let row: Option<DeliveryRow> = sqlx::query_as(
+            r#"
+            UPDATE webhook_deliveries d
+            SET status = 'pending', attempts = 0, next_attempt_at = now()
+            FROM outgoing_webhooks w
+            WHERE d.id = $1 AND w.id = d.webhook_id AND w.ws_id = $2 AND d.status = 'dead'
+            RETURNING d.id, d.webhook_id, d.event, d.payload::text AS payload, d.status,
+              d.attempts, d.next_attempt_at, d.response_status, d.last_error, d.delivered_at,
+              d.created_at
+            "#,
+        )
+        .bind(id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => row.try_into(),
+            None => Err(AppError::NotFound(format!("dead webhook delivery {}", id))),
+        }
+    }
+
+    /// Queue events of the chat for the webhooks of its workspace which
+    /// subscribed to them, in the transaction of the change. Webhooks get the
+    /// events of public channels, and of the other chats their creator is a
+    /// member of.
+    pub(crate) async fn queue_webhooks(
+        &self,
+        conn: &mut PgConnection,
+        chat_id: i64,
+        events: &[WebhookPayload],
+    ) -> Result<u64, AppError> {
+        let mut queued = 0;
+        for event in events {
+            let ret = sqlx::query(
+                r#"
+                INSERT INTO webhook_deliveries (webhook_id, event, event_key, payload)
+                SELECT w.id, $2, CASE WHEN $2 = 'MemberJoined' THEN $3 || ':' || txid_current()
+                    ELSE $3 END, $4::jsonb
+                FROM chats c JOIN outgoing_webhooks w ON w.ws_id = c.ws_id
+                WHERE c.id = $1 AND w.enabled AND $2 = ANY(w.events)
+                  AND (c.type = 'public_channel' OR w.created_by = ANY(c.members))
+                ON CONFLICT (webhook_id, event_key) DO NOTHING
+                "#,
+            )
+            .bind(chat_id)
+            .bind(event.event)
+            .bind(&event.key)
+            .bind(event.payload.to_string())
+            .execute(&mut *conn)
+            .await?;
+            queued += ret.rows_affected();
+        }
+
+        // wake up the senders once committed
+        if queued > 0 {
+            sqlx::query("SELECT pg_notify('webhook_queued', '')")
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(queued)
+    }
+
+    async fn ensure_webhook_admin(&self, user: &User) -> Result<(), AppError> {
+        if !self.is_workspace_admin(user).await? {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner and admins can manage outgoing webhooks".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<DeliveryRow> for WebhookDelivery {
+    type Error = AppError;
+
+    fn try_from(row: DeliveryRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: row.id,
+            webhook_id: row.webhook_id,
+            event: row.event,
+            payload: serde_json::from_str(&row.payload).map_err(anyhow::Error::from)?,
+            status: row.status,
+            attempts: row.attempts,
+            next_attempt_at: row.next_attempt_at,
+            response_status: row.response_status,
+            last_error: row.last_error,
+            delivered_at: row.delivered_at,
+            created_at: row.created_at,
+        })
+    }
+}
+
+/// A key of HMAC-SHA256 signatures, shared with the receiver
+pub(super) fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    format!("{}{}", SECRET_PREFIX, hex::encode(secret))
+}
+
+pub(super) fn valid_url(url: &str) -> Result<&str, AppError> {
+    let url = url.trim();
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| AppError::WebhookError(format!("invalid url: {}", e)))?;
+    if !is_public_url(&parsed) || url.len() > MAX_URL_LEN {
+        return Err(AppError::WebhookError(format!(
+            "the url must be a public https url and have at most {} characters",
+            MAX_URL_LEN
+        )));
+    }
+    Ok(url)
+}
+
+fn valid_events(events: &[WebhookEvent]) -> Result<Vec<WebhookEvent>, AppError> {
+    let mut ret = events.to_vec();
+    ret.sort_by_key(|v| *v as u8);
+    ret.dedup();
+    if ret.is_empty() {
+        return Err(AppError::WebhookError(
+            "a webhook needs at least one event".to_string(),
+        ));
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateChat, CreateMessage, UpdateChat};
+    use anyhow::Result;
+
+    async fn admin(state: &AppState) -> Result<User> {
+        sqlx::query("UPDATE users SET role = 'admin' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        Ok(state.find_user_by_id(1).await?.unwrap())
+    }
+
+    #[tokio::test]
+    async fn outgoing_webhooks_should_be_managed_by_admins() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let admin = admin(&state).await?;
+        let member = state.find_user_by_id(2).await?.unwrap();
+        let input = CreateOutgoingWebhook {
+            url: "https://example.com/hooks".to_string(),
+            events: vec![WebhookEvent::NewMessage, WebhookEvent::NewMessage],
+        };
+        assert!(matches!(
+            state.create_outgoing_webhook(&member, &input).await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        let created = state.create_outgoing_webhook(&admin, &input).await?;
+        assert!(created.secret.starts_with(SECRET_PREFIX));
+        assert_eq!(created.info.events, vec![WebhookEvent::NewMessage]);
+        assert_eq!(
+            state.list_outgoing_webhooks(&admin).await?,
+            vec![created.info.clone()]
+        );
+
+        let input = UpdateOutgoingWebhook {
+            events: Some(vec![WebhookEvent::NewChat, WebhookEvent::MemberJoined]),
+            enabled: Some(false),
+            ..Default::default()
+        };
+        let hook = state
+            .update_outgoing_webhook(&admin, created.info.id, &input)
+            .await?;
+        assert_eq!(hook.url, "https://example.com/hooks");
+        assert_eq!(
+            hook.events,
+            vec![WebhookEvent::NewChat, WebhookEvent::MemberJoined]
+        );
+        assert!(!hook.enabled);
+
+        state.delete_outgoing_webhook(&admin, hook.id).await?;
+        assert!(matches!(
+            state.delete_outgoing_webhook(&admin, hook.id).await,
+            Err(AppError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outgoing_webhooks_should_be_https_with_events() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let admin = admin(&state).await?;
+        for (url, events) in [
+            ("http://example.com/hooks", vec![WebhookEvent::NewChat]),
+            ("example.com", vec![WebhookEvent::NewChat]),
+            ("https://127.0.0.1/hooks", vec![WebhookEvent::NewChat]),
+            ("https://localhost/hooks", vec![WebhookEvent::NewChat]),
+            ("https://10.0.0.5/hooks", vec![WebhookEvent::NewChat]),
+            ("https://192.168.1.1/hooks", vec![WebhookEvent::NewChat]),
+            (
+                "https://169.254.169.254/latest",
+                vec![WebhookEvent::NewChat],
+            ),
+            ("https://[fd00::1]/hooks", vec![WebhookEvent::NewChat]),
+            ("https://[fe80::1]/hooks", vec![WebhookEvent::NewChat]),
+            ("https://example.com/hooks", vec![]),
+        ] {
+            let input = CreateOutgoingWebhook {
+                url: url.to_string(),
+                events,
+            };
+            assert!(matches!(
+                state.create_outgoing_webhook(&admin, &input).await,
+                Err(AppError::WebhookError(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhooks_should_be_queued_with_the_change() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let admin = admin(&state).await?;
+        let input = CreateOutgoingWebhook {
+            url: "https://example.com/hooks".to_string(),
+            events: vec![
+                WebhookEvent::NewMessage,
+                WebhookEvent::NewChat,
+                WebhookEvent::MemberJoined,
+            ],
+        };
+        let hook = state.create_outgoing_webhook(&admin, &input).await?.info;
+
+        let chat = state
+            .create_chat(CreateChat::new("hooks", &[1, 2], false), 1)
+            .await?;
+        // the creator of the webhook is not a member of this private chat
+        let other = state
+            .create_chat(CreateChat::new("others", &[2, 3], false), 1)
+            .await?;
+        state.add_chat_member(other.id as _, 4).await?;
+
+        // leaving and joining again is a new event
+        state.add_chat_member(chat.id as _, 3).await?;
+        state.remove_chat_member(chat.id as _, 3).await?;
+        state.add_chat_member(chat.id as _, 3).await?;
+        let input = UpdateChat {
+            name: None,
+            members: Some(vec![1, 2, 3, 4]),
+            message_ttl: None,
+        };
+        state.update_chat_by_id(chat.id as _, input).await?;
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+            ttl: None,
+            blocks: None,
+        };
+        let msg = state.create_message(input, chat.id as _, 1).await?;
+
+        let mut log = state
+            .list_webhook_deliveries(&admin, Some(hook.id), &ListDeliveries::default())
+            .await?;
+        log.reverse();
+        let events: Vec<_> = log.iter().map(|v| v.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                WebhookEvent::NewChat,
+                WebhookEvent::MemberJoined,
+                WebhookEvent::MemberJoined,
+                WebhookEvent::MemberJoined,
+                WebhookEvent::NewMessage,
+            ]
+        );
+        assert_eq!(log[0].payload["event"], "NewChat");
+        assert_eq!(log[0].payload["id"], chat.id);
+        assert_eq!(log[3].payload["event"], "MemberJoined");
+        assert_eq!(log[3].payload["user_id"], 4);
+        assert_eq!(log[3].payload["chat"]["members"], json!([1, 2, 3, 4]));
+        assert_eq!(log[4].payload["id"], msg.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dead_deliveries_should_be_listed_and_retried() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let admin = admin(&state).await?;
+        let input = CreateOutgoingWebhook {
+            url: "https://example.com/hooks".to_string(),
+            events: vec![WebhookEvent::NewMessage],
+        };
+        let hook = state.create_outgoing_webhook(&admin, &input).await?.info;
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (webhook_id, event, event_key, payload, status, attempts, response_status)
+            VALUES ($1, 'NewMessage', 'NewMessage:1', '{"event":"NewMessage"}', 'delivered', 1, 200),
+              ($1, 'NewMessage', 'NewMessage:2', '{"event":"NewMessage"}', 'dead', 8, 500)
+            "#,
+        )
+        .bind(hook.id)
+        .execute(&state.pool)
+        .await?;
+
+        let log = state
+            .list_webhook_deliveries(&admin, Some(hook.id), &ListDeliveries::default())
+            .await?;
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].status, DeliveryStatus::Dead);
+        assert_eq!(log[0].payload["event"], "NewMessage");
+        assert!(matches!(
+            state
+                .list_webhook_deliveries(&admin, Some(hook.id + 1), &ListDeliveries::default())
+                .await,
+            Err(AppError::NotFound(_))
+        ));
+
+        let dead = ListDeliveries {
+            status: Some(DeliveryStatus::Dead),
+            ..Default::default()
+        };
+        let letters = state.list_webhook_deliveries(&admin, None, &dead).await?;
+        assert_eq!(letters.len(), 1);
+        let retried = state.retry_webhook_delivery(&admin, letters[0].id).await?;
+        assert_eq!(retried.status, DeliveryStatus::Pending);
+        assert_eq!(retried.attempts, 0);
+        assert!(state
+            .list_webhook_deliveries(&admin, None, &dead)
+            .await?
+            .is_empty());
+        // only dead letters are retried
+        assert!(matches!(
+            state.retry_webhook_delivery(&admin, log[1].id).await,
+            Err(AppError::NotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use chat_core::{PresenceStatus, UserPresence};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdatePresence {
+    pub status: PresenceStatus,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct ListPresences {
+    /// comma separated user ids, all users of the workspace if not provided
+    pub ids: Option<String>,
+}
+
+impl AppState {
+    pub async fn fetch_presences(
+        &self,
+        input: ListPresences,
+        ws_id: u64,
+    ) -> Result<Vec<UserPresence>, AppError> {
+        let ids = match input.ids {
+            Some(ids) => {
+                let ids = ids
+                    .split(',')
+                    .filter(|v| !v.trim().is_empty())
+                    .map(|v| v.trim().parse::<i64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| AppError::PresenceError(format!("invalid user ids: {}", e)))?;
+                Some(ids)
+            }
+            None => None,
+        };
+
+        let presences = sqlx::query_as(
+            r#"
+            SELECT user_id, status, last_seen_at
+            FROM presences
+            WHERE ws_id = $1 AND ($2::bigint[] IS NULL OR user_id = ANY($2))
+            ORDER BY user_id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(presences)
+    }
+
+    /// Set the explicit status of the user, effective while the user is connected
+    pub async fn update_presence(
+        &self,
+        user_id: u64,
+        input: UpdatePresence,
+    ) -> Result<UserPresence, AppError> {
+        if input.status == PresenceStatus::Offline {
+            return Err(AppError::PresenceError(
+                "status can only be online, away or dnd".to_string(),
+            ));
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_presence (user_id, status)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET status = $2
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.status)
+        .execute(&self.pool)
+        .await?;
+
+        let presence: UserPresence = sqlx::query_as(
+            "SELECT user_id, status, last_seen_at FROM presences WHERE user_id = $1",
+        )
+        .bind(user_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let payload = serde_json::to_string(&presence).map_err(anyhow::Error::from)?;
+        sqlx::query("SELECT pg_notify('presence_changed', $1)")
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(presence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn presence_should_follow_connections_and_status() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let presences = state.fetch_presences(ListPresences::default(), 1).await?;
+        assert_eq!(presences.len(), 5);
+        assert!(presences
+            .iter()
+            .all(|p| p.status == PresenceStatus::Offline));
+
+        // user 1 connected on two instances
+        sqlx::query(
+            "INSERT INTO presence_connections (instance_id, user_id, connections) VALUES ('a', 1, 1), ('b', 1, 2)",
+        )
+        .execute(&state.pool)
+        .await?;
+        let input = ListPresences {
+            ids: Some("1,2".to_string()),
+        };
+        let presences = state.fetch_presences(input.clone(), 1).await?;
+        assert_eq!(presences[0].status, PresenceStatus::Online);
+        assert_eq!(presences[1].status, PresenceStatus::Offline);
+
+        let input2 = UpdatePresence {
+            status: PresenceStatus::Dnd,
+        };
+        let presence = state.update_presence(1, input2).await?;
+        assert_eq!(presence.status, PresenceStatus::Dnd);
+
+        // stale connections are ignored
+        sqlx::query("UPDATE presence_connections SET updated_at = now() - interval '1 hour'")
+            .execute(&state.pool)
+            .await?;
+        let presences = state.fetch_presences(input, 1).await?;
+        assert_eq!(presences[0].status, PresenceStatus::Offline);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use std::net::IpAddr;
+
+use chat_core::User;
+use serde_json::json;
+
+use crate::{config::SigninLimits, AppError, AppState, SigninUser};
+
+impl AppState {
+    /// Verify the credentials unless the email or the IP is locked out by
+    /// previous failures. Failures are counted, and audited.
+    pub async fn signin(
+        &self,
+        input: &SigninUser,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<User>, AppError> {
+        let limits = &self.config.auth.signin;
+        let email_key = format!("email:{}", input.email.trim().to_lowercase());
+        let ip_key = ip.map(|v| format!("ip:{}", v));
+        // reserved before the password is checked, so concurrent guesses can't
+        // get past the limit and a locked out attacker costs no hashing
+        let mut locked_for = self
+            .reserve_attempt(&email_key, limits.email_attempts)
+            .await?;
+        if let Some(ip_key) = &ip_key {
+            match self.reserve_attempt(ip_key, limits.ip_attempts).await {
+                Ok(secs) => locked_for = locked_for.max(secs),
+                Err(e) => {
+                    self.release_attempt(&email_key, limits.email_attempts)
+                        .await?;
+                    return Err(e);
+                }
+            }
+        }
+
+        let user = self.verify_user(input).await?;
+        if user.is_some() {
+            self.clear_failures(&email_key).await?;
+            // the IP keeps its failures, it only gets its attempt back
+            if let Some(ip_key) = &ip_key {
+                self.release_attempt(ip_key, limits.ip_attempts).await?;
+            }
+            return Ok(user);
+        }
+
+        let user_id = self.find_user_by_email(&input.email).await?.map(|v| v.id);
+        let detail = json!({ "email": input.email, "locked_for": locked_for });
+        self.audit("signin_failed", user_id, ip, detail).await?;
+        Ok(None)
+    }
+
+    /// Count an attempt of the key as a failure until it's released, and lock
+    /// the key out once it has `free` of them. Fails with the seconds to wait
+    /// if the key is locked out already, returns the seconds of the lockout
+    /// the attempt started otherwise.
+    pub(crate) async fn reserve_attempt(
+        &self,
+        key: &str,
+        free: u32,
+    ) -> Result<Option<u64>, AppError> {
+        let limits = &self.config.auth.signin;
+        // the row stays locked until the lockout is set, so concurrent
+        // attempts see it
+        let mut tx = self.pool.begin().await?;
+        let failures: Option<i32> = sqlx::query_scalar(
+            r#"
+            INSERT INTO signin_attempts (key, failures)
+            VALUES ($1, 1)
+            ON CONFLICT (key) DO UPDATE
+            SET failures = CASE
+                WHEN signin_attempts.last_failed_at < now() - make_interval(secs => $2) THEN 1
+                ELSE signin_attempts.failures + 1
+              END,
+              last_failed_at = now()
+            WHERE signin_attempts.locked_until IS NULL OR signin_attempts.locked_until <= now()
+            RETURNING failures
+            "#,
+        )
+        .bind(key)
+        .bind(limits.window as f64)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(failures) = failures else {
+            drop(tx);
+            let wait: Option<f64> = sqlx::query_scalar(
+                r#"
+                SELECT EXTRACT(EPOCH FROM locked_until - now())::float8
+                FROM signin_attempts
+                WHERE key = $1
+                "#,
+            )
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?
+            .flatten();
+            let secs = wait.map(|v| v.ceil().max(1.0) as u64).unwrap_or(1);
+            return Err(AppError::TooManyAttempts(secs));
+        };
+        let secs = lockout_secs(failures as u32, free, limits);
+        if let Some(secs) = secs {
+            sqlx::query(
+                "UPDATE signin_attempts SET locked_until = now() + make_interval(secs => $2) WHERE key = $1",
+            )
+            .bind(key)
+            .bind(secs as f64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(secs)
+    }
+
+    /// Give back an attempt which didn't fail, the key is unlocked if its
+    /// other failures don't lock it out
+    pub(crate) async fn release_attempt(&self, key: &str, free: u32) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE signin_attempts
+            SET failures = failures - 1,
+              locked_until = CASE WHEN failures - 1 < $2 THEN NULL ELSE locked_until END
+            WHERE key = $1 AND failures > 0
+            "#,
+        )
+        .bind(key)
+        .bind(free as i32)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn clear_failures(&self, key: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM signin_attempts WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Forget the failures outside of the window which are no longer locked
+    pub async fn delete_stale_signin_attempts(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM signin_attempts
+            WHERE last_failed_at < now() - make_interval(secs => $1)
+              AND (locked_until IS NULL OR locked_until < now())
+            "#,
+        )
+        .bind(self.config.auth.signin.window as f64)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+}
+
+/// The lockout after `failures` failures, doubled for every failure past `free`
+fn lockout_secs(failures: u32, free: u32, limits: &SigninLimits) -> Option<u64> {
+    if free == 0 || failures < free {
+        return None;
+    }
+    let doublings = (failures - free).min(32);
+    Some(
+        limits
+            .lockout
+            .saturating_mul(1 << doublings)
+            .min(limits.max_lockout),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn lockout_should_back_off_exponentially() {
+        let limits = SigninLimits::default();
+        assert_eq!(lockout_secs(4, 5, &limits), None);
+        assert_eq!(lockout_secs(5, 5, &limits), Some(30));
+        assert_eq!(lockout_secs(6, 5, &limits), Some(60));
+        assert_eq!(lockout_secs(8, 5, &limits), Some(240));
+        assert_eq!(lockout_secs(100, 5, &limits), Some(limits.max_lockout));
+        assert_eq!(lockout_secs(100, 0, &limits), None);
+    }
+
+    #[tokio::test]
+    async fn signin_should_lock_out_email_after_failures() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ip: IpAddr = "10.0.0.1".parse()?;
+        let wrong = SigninUser::new("tchen1@acme.org", "wrong");
+        for _ in 0..4 {
+            assert!(state.signin(&wrong, Some(ip)).await?.is_none());
+        }
+        // a success resets the failures of the email
+        let right = SigninUser::new("tchen1@acme.org", "123456");
+        assert!(state.signin(&right, Some(ip)).await?.is_some());
+        for _ in 0..5 {
+            assert!(state.signin(&wrong, Some(ip)).await?.is_none());
+        }
+
+        // locked out even with the right password, other emails are not
+        match state.signin(&right, Some(ip)).await {
+            Err(AppError::TooManyAttempts(secs)) => assert!(secs > 0 && secs <= 30),
+            other => panic!("expected a lockout, got {:?}", other),
+        }
+        let input = SigninUser::new("alice1@acme.org", "123456");
+        assert!(state.signin(&input, Some(ip)).await?.is_some());
+
+        let (count, user_id): (i64, Option<i64>) = sqlx::query_as(
+            "SELECT count(*), max(user_id) FROM audit_log WHERE action = 'signin_failed'",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        assert_eq!((count, user_id), (9, Some(1)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_signins_should_not_exceed_the_limit() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let tasks: Vec<_> = (0..12)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let wrong = SigninUser::new("tchen1@acme.org", "wrong");
+                    state.signin(&wrong, None).await
+                })
+            })
+            .collect();
+        let mut checked = 0;
+        for task in tasks {
+            match task.await? {
+                Ok(None) => checked += 1,
+                Err(AppError::TooManyAttempts(_)) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(checked, state.config.auth.signin.email_attempts);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_lock_out_ip_across_emails() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ip: IpAddr = "10.0.0.2".parse()?;
+        for i in 0..state.config.auth.signin.ip_attempts {
+            let input = SigninUser::new(&format!("nobody{}@acme.org", i), "wrong");
+            assert!(state.signin(&input, Some(ip)).await?.is_none());
+        }
+        let input = SigninUser::new("tchen1@acme.org", "123456");
+        assert!(matches!(
+            state.signin(&input, Some(ip)).await,
+            Err(AppError::TooManyAttempts(_))
+        ));
+        assert!(state.signin(&input, None).await?.is_some());
+        Ok(())
+    }
+}
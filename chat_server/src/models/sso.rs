@@ -0,0 +1,369 @@
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use utoipa::IntoParams;
+
+use crate::{
+    oidc::{Identity, OidcProvider},
+    AppError, AppState,
+};
+
+/// minutes an authorization request waits for its callback
+const STATE_TTL: i32 = 10;
+
+/// Cookie binding an authorization request to the browser which started it
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+/// Query of the redirect back from the provider
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+pub struct OidcCallback {
+    #[serde(default)]
+    pub code: Option<String>,
+    pub state: String,
+    /// set by the provider when the user denied the request
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub error_description: Option<String>,
+}
+
+/// An authorization request to redirect the user with
+#[derive(Debug, Clone)]
+pub struct OidcStart {
+    pub url: String,
+    /// kept by the browser until the callback, see [`OIDC_STATE_COOKIE`]
+    pub binding: String,
+}
+
+#[derive(Debug, FromRow)]
+struct PendingRequest {
+    nonce: String,
+    code_verifier: String,
+}
+
+impl AppState {
+    /// Names of the configured providers, for the sign in page
+    pub fn list_oidc_providers(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.oidc.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Start signing in with the provider, returns the url to redirect the user
+    /// to and the binding the callback must come back with
+    pub async fn start_oidc(&self, provider: &str) -> Result<OidcStart, AppError> {
+        let provider = self.oidc_provider(provider)?;
+        let req = provider
+            .authorize()
+            .await
+            .map_err(|e| AppError::OidcError(e.to_string()))?;
+
+        sqlx::query(
+            "INSERT INTO oidc_states (state, provider, nonce, code_verifier) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&req.state)
+        .bind(provider.name())
+        .bind(&req.nonce)
+        .bind(&req.code_verifier)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(OidcStart {
+            url: req.url,
+            binding: state_binding(&req.state),
+        })
+    }
+
+    /// Finish signing in with the code the provider redirected back with. The
+    /// user of the identity is linked or provisioned on first sign in.
+    pub async fn finish_oidc(
+        &self,
+        provider: &str,
+        input: &OidcCallback,
+        binding: Option<&str>,
+    ) -> Result<User, AppError> {
+        let provider = self.oidc_provider(provider)?;
+        // a callback from another browser would sign the user in as someone else
+        if binding != Some(state_binding(&input.state).as_str()) {
+            return Err(AppError::InvalidToken(
+                "sign in request was started in another browser".to_string(),
+            ));
+        }
+        // each request is consumed once, whatever the outcome
+        let req: Option<PendingRequest> = sqlx::query_as(
+            r#"
+            DELETE FROM oidc_states
+            WHERE state = $1 AND provider = $2
+              AND created_at > now() - make_interval(mins => $3)
+            RETURNING nonce, code_verifier
+            "#,
+        )
+        .bind(&input.state)
+        .bind(provider.name())
+        .bind(STATE_TTL)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(req) = req else {
+            return Err(AppError::InvalidToken(
+                "unknown or expired sign in request".to_string(),
+            ));
+        };
+
+        if let Some(error) = &input.error {
+            let desc = input.error_description.as_deref().unwrap_or_default();
+            return Err(AppError::OidcError(format!("{} {}", error, desc)));
+        }
+        let Some(code) = &input.code else {
+            return Err(AppError::OidcError("missing code".to_string()));
+        };
+        let identity = provider
+            .exchange(code, &req.code_verifier, &req.nonce)
+            .await
+            .map_err(|e| AppError::OidcError(e.to_string()))?;
+
+        let workspace = identity
+            .email
+            .as_deref()
+            .and_then(|v| provider.workspace_for(v))
+            .map(|v| v.to_string());
+        self.sso_user(&identity, workspace.as_deref()).await
+    }
+
+    /// The user linked to the identity. Otherwise the identity is linked to the
+    /// user with the same email if the provider verified it, or a new user is
+    /// created in the workspace.
+    async fn sso_user(
+        &self,
+        identity: &Identity,
+        workspace: Option<&str>,
+    ) -> Result<User, AppError> {
+        let user = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.token_version, u.created_at
+            FROM user_identities i
+            JOIN users u ON u.id = i.user_id
+            WHERE i.issuer = $1 AND i.subject = $2
+            "#,
+        )
+        .bind(&identity.issuer)
+        .bind(&identity.subject)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(user) = user {
+            return Ok(user);
+        }
+
+        let Some(email) = &identity.email else {
+            return Err(AppError::OidcError(
+                "the provider didn't share the email".to_string(),
+            ));
+        };
+        // an unverified email could take over the account of someone else
+        if !identity.email_verified {
+            return Err(AppError::EmailNotVerified(format!(
+                "{} is not verified by the provider",
+                email
+            )));
+        }
+
+        let user = match self.find_user_by_email(email).await? {
+            Some(user) => user,
+            None => {
+                let Some(workspace) = workspace else {
+                    return Err(AppError::PermissionDenied(format!(
+                        "no workspace for {}",
+                        email
+                    )));
+                };
+                let fullname = match &identity.name {
+                    Some(name) if !name.trim().is_empty() => name.trim(),
+                    _ => email.split('@').next().unwrap_or_default(),
+                };
+                let fullname: String = fullname.chars().take(64).collect();
+                self.insert_user(workspace, email, &fullname, None).await?
+            }
+        };
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO user_identities (issuer, subject, user_id) VALUES ($1, $2, $3)")
+            .bind(&identity.issuer)
+            .bind(&identity.subject)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "UPDATE users SET email_verified_at = COALESCE(email_verified_at, now()) WHERE id = $1",
+        )
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(user)
+    }
+
+    /// Forget the authorization requests which were never completed
+    pub async fn delete_expired_oidc_states(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            "DELETE FROM oidc_states WHERE created_at <= now() - make_interval(mins => $1)",
+        )
+        .bind(STATE_TTL)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+
+    fn oidc_provider(&self, name: &str) -> Result<&OidcProvider, AppError> {
+        self.oidc
+            .get(name)
+            .ok_or_else(|| AppError::NotFound(format!("oidc provider {}", name)))
+    }
+}
+
+fn state_binding(state: &str) -> String {
+    hex::encode(Sha256::digest(state.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        oidc::tests::{start_mock_provider, test_config, MockProvider},
+        SigninUser,
+    };
+    use anyhow::Result;
+    use sqlx_db_tester::TestPg;
+    use std::{collections::HashMap, sync::Arc};
+
+    /// The callback of the provider after the user signed in as `subject`
+    async fn authorize(
+        state: &AppState,
+        mock: &MockProvider,
+        subject: &str,
+        email: &str,
+        verified: bool,
+    ) -> Result<(OidcCallback, String)> {
+        let start = state.start_oidc("acme").await?;
+        let code = mock.grant(&start.url, subject, email, verified);
+        let params: HashMap<_, _> = reqwest::Url::parse(&start.url)?
+            .query_pairs()
+            .into_owned()
+            .collect();
+        let input = OidcCallback {
+            code: Some(code),
+            state: params["state"].clone(),
+            error: None,
+            error_description: None,
+        };
+        Ok((input, start.binding))
+    }
+
+    async fn sign_in(
+        state: &AppState,
+        mock: &MockProvider,
+        subject: &str,
+        email: &str,
+        verified: bool,
+    ) -> Result<User, AppError> {
+        let (input, binding) = authorize(state, mock, subject, email, verified).await?;
+        state.finish_oidc("acme", &input, Some(&binding)).await
+    }
+
+    async fn state_with_provider(mock: &MockProvider) -> Result<(TestPg, AppState)> {
+        let (tdb, mut state) = AppState::new_for_test().await?;
+        let provider = OidcProvider::new(test_config(&mock.issuer), reqwest::Client::new());
+        Arc::get_mut(&mut state.inner)
+            .unwrap()
+            .oidc
+            .insert("acme".to_string(), provider);
+        Ok((tdb, state))
+    }
+
+    #[tokio::test]
+    async fn sso_should_provision_and_link_users() -> Result<()> {
+        let mock = start_mock_provider().await?;
+        let (_tdb, state) = state_with_provider(&mock).await?;
+        assert_eq!(state.list_oidc_providers(), vec!["acme"]);
+
+        // a new user joins the workspace of its domain, without a password
+        let user = sign_in(&state, &mock, "u1", "eve@acme.org", true).await?;
+        let ws = state.find_workspace_by_name("acme").await?.unwrap();
+        assert_eq!(user.ws_id, ws.id);
+        assert_eq!(user.fullname, "SSO User");
+        assert!(state.load_session(user.id).await?.unwrap().email_verified);
+        let input = SigninUser::new("eve@acme.org", "");
+        assert!(state.verify_user(&input).await?.is_none());
+
+        // the identity is linked, later sign ins find the same user
+        let again = sign_in(&state, &mock, "u1", "eve@acme.org", true).await?;
+        assert_eq!(again.id, user.id);
+
+        // an existing account is linked by its verified email
+        let user = sign_in(&state, &mock, "u2", "tchen1@acme.org", true).await?;
+        assert_eq!(user.id, 1);
+        assert!(matches!(
+            sign_in(&state, &mock, "u3", "alice1@acme.org", false).await,
+            Err(AppError::EmailNotVerified(_))
+        ));
+
+        // other domains join the default workspace
+        let user = sign_in(&state, &mock, "u4", "zed@other.org", true).await?;
+        let ws = state.find_workspace_by_name("sso").await?.unwrap();
+        assert_eq!(user.ws_id, ws.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sso_state_should_be_single_use() -> Result<()> {
+        let mock = start_mock_provider().await?;
+        let (_tdb, state) = state_with_provider(&mock).await?;
+
+        let (input, binding) = authorize(&state, &mock, "u1", "eve@acme.org", true).await?;
+        state.finish_oidc("acme", &input, Some(&binding)).await?;
+        assert!(matches!(
+            state.finish_oidc("acme", &input, Some(&binding)).await,
+            Err(AppError::InvalidToken(_))
+        ));
+
+        // a denied request is consumed as well
+        let (mut input, binding) = authorize(&state, &mock, "u1", "eve@acme.org", true).await?;
+        input.code = None;
+        input.error = Some("access_denied".to_string());
+        assert!(matches!(
+            state.finish_oidc("acme", &input, Some(&binding)).await,
+            Err(AppError::OidcError(_))
+        ));
+        input.error = None;
+        assert!(matches!(
+            state.finish_oidc("acme", &input, Some(&binding)).await,
+            Err(AppError::InvalidToken(_))
+        ));
+
+        assert!(matches!(
+            state.start_oidc("other").await,
+            Err(AppError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sso_state_should_be_bound_to_the_browser() -> Result<()> {
+        let mock = start_mock_provider().await?;
+        let (_tdb, state) = state_with_provider(&mock).await?;
+
+        // the callback of someone else's request, opened in the victim's browser
+        let (input, binding) = authorize(&state, &mock, "u1", "eve@acme.org", true).await?;
+        let (_, other) = authorize(&state, &mock, "u2", "zed@acme.org", true).await?;
+        for binding in [None, Some(other.as_str())] {
+            assert!(matches!(
+                state.finish_oidc("acme", &input, binding).await,
+                Err(AppError::InvalidToken(_))
+            ));
+        }
+        // the request is still usable by the browser which started it
+        let user = state.finish_oidc("acme", &input, Some(&binding)).await?;
+        assert_eq!(user.email, "eve@acme.org");
+        Ok(())
+    }
+}
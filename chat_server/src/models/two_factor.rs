@@ -0,0 +1,413 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::User;
+use data_encoding::BASE32_NOPAD;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use utoipa::ToSchema;
+
+use super::account::use_token;
+use crate::{totp::Totp, AppError, AppState};
+
+const SIGNIN_2FA: &str = "signin_2fa";
+/// minutes to enter the code after the password
+const CHALLENGE_TTL: u64 = 5;
+const RECOVERY_CODES: usize = 10;
+const ISSUER: &str = "Chat";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TwoFactorEnrollment {
+    /// base32 secret, for apps which can't scan the uri
+    pub secret: String,
+    /// `otpauth://` uri to show as a QR code
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TwoFactorCode {
+    /// a TOTP code, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodes {
+    /// shown only once, each can be used once instead of a TOTP code
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SigninChallenge {
+    /// exchanged with a code for a token at `/api/signin/2fa`
+    pub challenge: String,
+    /// seconds the challenge is valid
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct VerifyTwoFactor {
+    pub challenge: String,
+    /// a TOTP code, or a recovery code
+    pub code: String,
+}
+
+impl AppState {
+    /// Start enrolling the user, 2FA is enabled once a first code is confirmed
+    pub async fn enroll_two_factor(&self, user: &User) -> Result<TwoFactorEnrollment, AppError> {
+        let totp = Totp::generate();
+        let ret = sqlx::query(
+            "UPDATE users SET totp_secret = $2 WHERE id = $1 AND totp_enabled_at IS NULL",
+        )
+        .bind(user.id)
+        .bind(totp.to_base32())
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::TwoFactorError("2FA already enabled".to_string()));
+        }
+
+        Ok(TwoFactorEnrollment {
+            secret: totp.to_base32(),
+            uri: totp.uri(ISSUER, &user.email)?,
+        })
+    }
+
+    /// Enable 2FA with a first code from the enrolled app, and generate the
+    /// recovery codes
+    pub async fn confirm_two_factor(
+        &self,
+        user_id: i64,
+        input: TwoFactorCode,
+    ) -> Result<RecoveryCodes, AppError> {
+        let row: Option<(Option<String>, bool)> = sqlx::query_as(
+            "SELECT totp_secret, totp_enabled_at IS NOT NULL FROM users WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let secret = match row {
+            Some((_, true)) => {
+                return Err(AppError::TwoFactorError("2FA already enabled".to_string()))
+            }
+            Some((Some(secret), false)) => secret,
+            _ => return Err(AppError::TwoFactorError("enroll 2FA first".to_string())),
+        };
+        let totp = Totp::from_base32(&secret)?;
+        let Some(step) = totp.verify(&input.code, now()) else {
+            return Err(AppError::TwoFactorError("invalid code".to_string()));
+        };
+
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| recovery_code()).collect();
+        let hashes: Vec<String> = codes.iter().map(|v| hash_recovery_code(v)).collect();
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            UPDATE users SET totp_enabled_at = now(), totp_last_step = $2
+            WHERE id = $1 AND totp_enabled_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(step as i64)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::TwoFactorError("2FA already enabled".to_string()));
+        }
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, unnest($2::text[])",
+        )
+        .bind(user_id)
+        .bind(hashes)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(RecoveryCodes {
+            recovery_codes: codes,
+        })
+    }
+
+    /// Turn 2FA off with a current code, unless the workspace requires it
+    pub async fn disable_two_factor(
+        &self,
+        user: &User,
+        input: TwoFactorCode,
+    ) -> Result<(), AppError> {
+        let ws = self.find_workspace_by_id(user.ws_id as _).await?;
+        if ws.is_some_and(|ws| ws.require_2fa) {
+            return Err(AppError::TwoFactorError(
+                "2FA is required by the workspace".to_string(),
+            ));
+        }
+        let mut tx = self.pool.begin().await?;
+        if !check_second_factor(&mut tx, user.id, &input.code).await? {
+            return Err(AppError::TwoFactorError("invalid code".to_string()));
+        }
+        sqlx::query(
+            r#"
+            UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = 0
+            WHERE id = $1
+            "#,
+        )
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn two_factor_enabled(&self, user_id: i64) -> Result<bool, AppError> {
+        let enabled: Option<(bool,)> =
+            sqlx::query_as("SELECT totp_enabled_at IS NOT NULL FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(enabled.is_some_and(|(v,)| v))
+    }
+
+    /// The challenge a user with 2FA gets for the right password, instead of a token
+    pub fn signin_challenge(&self, user: &User) -> Result<SigninChallenge, AppError> {
+        Ok(SigninChallenge {
+            challenge: self.sign_action(SIGNIN_2FA, user, CHALLENGE_TTL)?,
+            expires_in: CHALLENGE_TTL * 60,
+        })
+    }
+
+    /// Check the code for the challenge, and return the user to sign in
+    pub async fn verify_two_factor(&self, input: &VerifyTwoFactor) -> Result<User, AppError> {
+        let token = self.verify_action(&input.challenge, SIGNIN_2FA)?;
+        let user = self
+            .find_user_by_id(token.claims.user_id)
+            .await?
+            .filter(|user| user.token_version == token.claims.token_version)
+            .ok_or_else(|| AppError::InvalidToken("challenge is no longer valid".to_string()))?;
+        // codes are guessed against the user, whatever the challenge
+        let key = format!("2fa:{}", user.id);
+        let attempts = self.config.auth.signin.email_attempts;
+        let locked_for = self.reserve_attempt(&key, attempts).await?;
+
+        // the challenge and the code are used up together, a wrong code rolls
+        // back and leaves the challenge usable
+        let mut tx = self.pool.begin().await?;
+        use_token(&mut tx, &token).await?;
+        if !check_second_factor(&mut tx, user.id, &input.code).await? {
+            drop(tx);
+            let detail = json!({ "locked_for": locked_for });
+            self.audit("two_factor_failed", Some(user.id), None, detail)
+                .await?;
+            return Err(AppError::TwoFactorError("invalid code".to_string()));
+        }
+        tx.commit().await?;
+        self.clear_failures(&key).await?;
+        Ok(user)
+    }
+}
+
+/// Check a TOTP code, or a recovery code, and use it up
+async fn check_second_factor(
+    conn: &mut PgConnection,
+    user_id: i64,
+    code: &str,
+) -> Result<bool, AppError> {
+    let code = code.trim();
+    if code.len() == 6 {
+        let secret: Option<(Option<String>,)> = sqlx::query_as(
+            "SELECT totp_secret FROM users WHERE id = $1 AND totp_enabled_at IS NOT NULL",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+        let Some((Some(secret),)) = secret else {
+            return Ok(false);
+        };
+        let Some(step) = Totp::from_base32(&secret)?.verify(code, now()) else {
+            return Ok(false);
+        };
+        let ret = sqlx::query(
+            "UPDATE users SET totp_last_step = $2 WHERE id = $1 AND totp_last_step < $2",
+        )
+        .bind(user_id)
+        .bind(step as i64)
+        .execute(&mut *conn)
+        .await?;
+        return Ok(ret.rows_affected() == 1);
+    }
+
+    let ret = sqlx::query(
+        r#"
+        UPDATE recovery_codes SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(hash_recovery_code(code))
+    .execute(&mut *conn)
+    .await?;
+    Ok(ret.rows_affected() == 1)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+/// 10 random base32 characters, as `xxxxx-xxxxx`
+fn recovery_code() -> String {
+    let mut bytes = [0u8; 7];
+    OsRng.fill_bytes(&mut bytes);
+    let code = BASE32_NOPAD.encode(&bytes).to_lowercase();
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
+/// codes are compared without the dash and case insensitively
+fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    /// Enable 2FA, returns the code it was confirmed with
+    async fn enable(state: &AppState, user: &User) -> Result<(String, RecoveryCodes)> {
+        let enrollment = state.enroll_two_factor(user).await?;
+        assert!(enrollment.uri.starts_with("otpauth://totp/Chat:"));
+        let code = Totp::from_base32(&enrollment.secret)?.code_at(now());
+        let input = TwoFactorCode { code: code.clone() };
+        let codes = state.confirm_two_factor(user.id, input).await?;
+        Ok((code, codes))
+    }
+
+    #[tokio::test]
+    async fn two_factor_signin_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        assert!(!state.two_factor_enabled(1).await?);
+        let (code, codes) = enable(&state, &user).await?;
+        assert!(state.two_factor_enabled(1).await?);
+        assert_eq!(codes.recovery_codes.len(), RECOVERY_CODES);
+
+        // the code used to confirm can't be used again
+        let challenge = state.signin_challenge(&user)?;
+        let input = VerifyTwoFactor {
+            challenge: challenge.challenge.clone(),
+            code,
+        };
+        assert!(matches!(
+            state.verify_two_factor(&input).await,
+            Err(AppError::TwoFactorError(_))
+        ));
+
+        // a recovery code works once, in any case
+        let input = VerifyTwoFactor {
+            challenge: challenge.challenge.clone(),
+            code: codes.recovery_codes[0].to_uppercase(),
+        };
+        assert_eq!(state.verify_two_factor(&input).await?.id, 1);
+        let input = VerifyTwoFactor {
+            challenge: state.signin_challenge(&user)?.challenge,
+            code: codes.recovery_codes[0].clone(),
+        };
+        assert!(matches!(
+            state.verify_two_factor(&input).await,
+            Err(AppError::TwoFactorError(_))
+        ));
+
+        // the challenge is single use too
+        let input = VerifyTwoFactor {
+            challenge: challenge.challenge,
+            code: codes.recovery_codes[1].clone(),
+        };
+        assert!(matches!(
+            state.verify_two_factor(&input).await,
+            Err(AppError::InvalidToken(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn two_factor_codes_should_not_be_brute_forced() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let (_, codes) = enable(&state, &user).await?;
+
+        let challenge = state.signin_challenge(&user)?.challenge;
+        for _ in 0..state.config.auth.signin.email_attempts {
+            let input = VerifyTwoFactor {
+                challenge: challenge.clone(),
+                code: "000000".to_string(),
+            };
+            assert!(matches!(
+                state.verify_two_factor(&input).await,
+                Err(AppError::TwoFactorError(_))
+            ));
+        }
+        let input = VerifyTwoFactor {
+            challenge,
+            code: codes.recovery_codes[0].clone(),
+        };
+        assert!(matches!(
+            state.verify_two_factor(&input).await,
+            Err(AppError::TooManyAttempts(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn two_factor_should_only_be_disabled_when_allowed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let (_, codes) = enable(&state, &user).await?;
+        assert!(matches!(
+            state.enroll_two_factor(&user).await,
+            Err(AppError::TwoFactorError(_))
+        ));
+
+        sqlx::query("UPDATE workspaces SET require_2fa = TRUE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let input = TwoFactorCode {
+            code: codes.recovery_codes[0].clone(),
+        };
+        assert!(matches!(
+            state.disable_two_factor(&user, input.clone()).await,
+            Err(AppError::TwoFactorError(_))
+        ));
+
+        sqlx::query("UPDATE workspaces SET require_2fa = FALSE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        state.disable_two_factor(&user, input).await?;
+        assert!(!state.two_factor_enabled(2).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_codes_should_be_normalized() {
+        let code = recovery_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(
+            hash_recovery_code(&code),
+            hash_recovery_code(&code.replace('-', "").to_uppercase())
+        );
+    }
+}
@@ -5,11 +5,12 @@ use argon2::{
     Argon2, PasswordHash, PasswordVerifier,
 };
 use serde::{Deserialize, Serialize};
+use tracing::info;
 use utoipa::ToSchema;
 
-use crate::{AppError, AppState, User};
+use crate::{auth::DirectoryUser, AppError, AppState, User};
 
-use chat_core::ChatUser;
+use chat_core::{ChatUser, WorkspaceRole};
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct CreateUser {
@@ -30,7 +31,7 @@ impl AppState {
     /// find a user by email
     pub async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, fullname, email, token_version, is_bot, created_at FROM users WHERE email = $1",
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -43,7 +44,7 @@ impl AppState {
     // find a user by id
     pub async fn find_user_by_id(&self, id: i64) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1",
+            "SELECT id, ws_id, fullname, email, token_version, is_bot, created_at FROM users WHERE id = $1",
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -52,29 +53,54 @@ impl AppState {
     }
 
     pub async fn create_user(&self, input: &CreateUser) -> Result<User, AppError> {
+        if let Err(e) = input.email.parse::<lettre::Address>() {
+            return Err(AppError::EmailError(format!(
+                "invalid email {}: {}",
+                input.email, e
+            )));
+        }
+
         // check if user with email already exists
         let user = self.find_user_by_email(&input.email).await?;
         if user.is_some() {
             return Err(AppError::EmailAlreadyExists(input.email.clone()));
         }
 
+        let password_hash = hash_password(&input.password)?;
+        self.insert_user(
+            &input.workspace,
+            &input.email,
+            &input.fullname,
+            Some(password_hash),
+        )
+        .await
+    }
+
+    /// Insert a user into the workspace, which is created if it doesn't exist.
+    /// Users without a password can only sign in through single sign-on.
+    pub(crate) async fn insert_user(
+        &self,
+        workspace: &str,
+        email: &str,
+        fullname: &str,
+        password_hash: Option<String>,
+    ) -> Result<User, AppError> {
         // check if workspace exists, if not create it
-        let ws = match self.find_workspace_by_name(&input.workspace).await? {
+        let ws = match self.find_workspace_by_name(workspace).await? {
             Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
+            None => self.create_workspace(workspace, 0).await?,
         };
 
-        let password_hash = hash_password(&input.password)?;
         let user: User = sqlx::query_as(
             r#"
             INSERT INTO users (ws_id, email, fullname, password_hash)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, fullname, email, created_at
+            RETURNING id, ws_id, fullname, email, token_version, created_at
             "#,
         )
         .bind(ws.id)
-        .bind(&input.email)
-        .bind(&input.fullname)
+        .bind(email)
+        .bind(fullname)
         .bind(password_hash)
         .fetch_one(&self.pool)
         .await?;
@@ -87,19 +113,36 @@ impl AppState {
         Ok(user)
     }
 
-    /// Verify email and password
+    /// Verify email and password with the auth backends, the first one which
+    /// knows the user wins
     pub async fn verify_user(&self, input: &SigninUser) -> Result<Option<User>, AppError> {
+        for backend in &self.auth_backends {
+            if let Some(user) = backend.authenticate(self, input).await? {
+                info!("{} signed in with {}", user.email, backend.name());
+                return Ok(Some(user));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Verify email and password against the password hash of the user
+    pub(crate) async fn verify_password_user(
+        &self,
+        input: &SigninUser,
+    ) -> Result<Option<User>, AppError> {
         let user: Option<User> = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, fullname, email, password_hash, token_version, created_at FROM users WHERE email = $1",
         )
         .bind(&input.email)
         .fetch_optional(&self.pool)
         .await?;
         match user {
             Some(mut user) => {
-                let password_hash = mem::take(&mut user.password_hash);
-                let is_valid =
-                    verify_password(&input.password, &password_hash.unwrap_or_default())?;
+                // users of single sign-on have no password
+                let Some(password_hash) = mem::take(&mut user.password_hash) else {
+                    return Ok(None);
+                };
+                let is_valid = verify_password(&input.password, &password_hash)?;
                 if is_valid {
                     Ok(Some(user))
                 } else {
@@ -110,6 +153,74 @@ impl AppState {
         }
     }
 
+    /// Create or update the user of an external directory on sign in. The user
+    /// is linked to its entry, so the email and fullname follow the directory,
+    /// and the role too while the user stays in the workspace; users aren't
+    /// moved across workspaces.
+    pub(crate) async fn sync_directory_user(
+        &self,
+        issuer: &str,
+        entry: &DirectoryUser,
+        workspace: &str,
+        role: WorkspaceRole,
+    ) -> Result<User, AppError> {
+        let linked: Option<(i64,)> = sqlx::query_as(
+            "SELECT user_id FROM user_identities WHERE issuer = $1 AND subject = $2",
+        )
+        .bind(issuer)
+        .bind(&entry.dn)
+        .fetch_optional(&self.pool)
+        .await?;
+        let user_id = match linked {
+            Some((id,)) => id,
+            None => {
+                // the first sign in links the account with the email of the entry
+                let user = match self.find_user_by_email(&entry.email).await? {
+                    Some(user) => user,
+                    None => {
+                        self.insert_user(workspace, &entry.email, &entry.fullname, None)
+                            .await?
+                    }
+                };
+                sqlx::query(
+                    r#"
+                    INSERT INTO user_identities (issuer, subject, user_id) VALUES ($1, $2, $3)
+                    ON CONFLICT (issuer, subject) DO NOTHING
+                    "#,
+                )
+                .bind(issuer)
+                .bind(&entry.dn)
+                .bind(user.id)
+                .execute(&self.pool)
+                .await?;
+                user.id
+            }
+        };
+
+        // the directory vouches for the email, it's kept if another user has it
+        let user = sqlx::query_as(
+            r#"
+            UPDATE users u
+            SET fullname = $2, email_verified_at = COALESCE(email_verified_at, now()),
+              email = CASE WHEN EXISTS (SELECT 1 FROM users o WHERE o.email = $3 AND o.id <> u.id)
+                THEN u.email ELSE $3 END,
+              role = CASE WHEN w.name = $4 THEN $5 ELSE u.role END
+            FROM workspaces w
+            WHERE u.id = $1 AND w.id = u.ws_id
+            RETURNING u.id, u.ws_id, u.fullname, u.email, u.token_version, u.created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(&entry.fullname)
+        .bind(&entry.email)
+        .bind(workspace)
+        .bind(role)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
     pub async fn fetch_chat_user_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             "SELECT id, fullname, email FROM users WHERE id = ANY($1) ORDER BY fullname",
@@ -131,7 +242,7 @@ impl AppState {
     }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
+pub(crate) fn hash_password(password: &str) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
 
     let argon2 = Argon2::default();
@@ -1,14 +1,28 @@
-use chat_core::Workspace;
+use chat_core::{User, Workspace};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
 
 use crate::{AppError, AppState};
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateWorkspace {
+    /// members must enable 2FA before they can do anything else
+    pub require_2fa: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InviteUser {
+    pub email: String,
+}
+
 impl AppState {
     pub async fn create_workspace(&self, name: &str, user_id: u64) -> Result<Workspace, AppError> {
         let ws = sqlx::query_as(
             r#"
               INSERT INTO workspaces (name, owner_id)
               VALUES ($1, $2)
-              RETURNING id, name, owner_id, created_at
+              RETURNING id, name, owner_id, require_2fa, created_at
               "#,
         )
         .bind(name)
@@ -22,7 +36,7 @@ impl AppState {
     pub async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
-          SELECT id, name, owner_id, created_at
+          SELECT id, name, owner_id, require_2fa, created_at
           FROM workspaces
           WHERE name = $1
           "#,
@@ -38,7 +52,7 @@ impl AppState {
     pub async fn find_workspace_by_id(&self, id: u64) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
-        SELECT id, name, owner_id, created_at
+        SELECT id, name, owner_id, require_2fa, created_at
         FROM workspaces
         WHERE id = $1
         "#,
@@ -61,7 +75,7 @@ impl AppState {
             UPDATE workspaces
             SET owner_id = $1
             WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
-            RETURNING id, name, owner_id, created_at
+            RETURNING id, name, owner_id, require_2fa, created_at
             "#,
         )
         .bind(owner_id as i64)
@@ -71,12 +85,108 @@ impl AppState {
 
         Ok(ws)
     }
+
+    /// Update the settings of the workspace of the user, for its owner and admins
+    pub async fn update_workspace(
+        &self,
+        user: &User,
+        input: UpdateWorkspace,
+    ) -> Result<Workspace, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET require_2fa = COALESCE($3, require_2fa)
+            WHERE id = $1 AND (owner_id = $2 OR EXISTS (
+              SELECT 1 FROM users WHERE id = $2 AND ws_id = $1 AND role = 'admin'
+            ))
+            RETURNING id, name, owner_id, require_2fa, created_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(input.require_2fa)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        ws.ok_or_else(|| {
+            AppError::PermissionDenied(
+                "only the workspace owner and admins can update it".to_string(),
+            )
+        })
+    }
+
+    /// Email a link to sign up to the workspace, for its owner and admins
+    pub async fn invite_user(&self, user: &User, input: &InviteUser) -> Result<(), AppError> {
+        let Some(mailer) = &self.mailer else {
+            return Err(AppError::EmailError("email is not configured".to_string()));
+        };
+        if !self.is_workspace_admin(user).await? {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner and admins can invite users".to_string(),
+            ));
+        }
+        let email = input.email.trim();
+        if email.parse::<lettre::Address>().is_err() {
+            return Err(AppError::EmailError(format!("invalid email: {}", email)));
+        }
+        if self.find_user_by_email(email).await?.is_some() {
+            return Err(AppError::EmailAlreadyExists(email.to_string()));
+        }
+        let ws = self
+            .find_workspace_by_id(user.ws_id as _)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Workspace {} not found", user.ws_id)))?;
+
+        let mut link = reqwest::Url::parse(&format!("{}/signup", mailer.base_url()))
+            .map_err(anyhow::Error::from)?;
+        link.query_pairs_mut()
+            .append_pair("workspace", &ws.name)
+            .append_pair("email", email);
+        // the invitee has no preferences yet, the email is in the language of the inviter
+        let prefs = self.get_email_preferences(user.id as _).await?;
+        mailer
+            .send_invitation(
+                email,
+                &prefs.locale,
+                &user.fullname,
+                &ws.name,
+                link.as_str(),
+            )
+            .await?;
+        self.audit(
+            "user_invited",
+            Some(user.id),
+            None,
+            json!({ "email": email }),
+        )
+        .await
+    }
+
+    /// Whether the user owns its workspace or is one of its admins
+    pub(crate) async fn is_workspace_admin(&self, user: &User) -> Result<bool, AppError> {
+        let (admin,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT EXISTS (
+              SELECT 1 FROM workspaces w JOIN users u ON u.ws_id = w.id
+              WHERE u.id = $1 AND (w.owner_id = u.id OR u.role = 'admin')
+            )
+            "#,
+        )
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(admin)
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use crate::auth::DirectoryUser;
+    use crate::mailer::tests::{start_smtp_sink, test_mailer};
     use crate::models::CreateUser;
+    use chat_core::WorkspaceRole;
+    use std::sync::Arc;
 
     use super::*;
     use anyhow::{Ok, Result};
@@ -112,6 +222,79 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn workspace_should_only_be_updated_by_owner_and_admins() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("ws", "Tyr Chen", "tchen@ws.org", "Hunter42");
+        let owner = state.create_user(&input).await?;
+        let input = CreateUser::new("ws", "Alice Chen", "alice@ws.org", "Hunter42");
+        let member = state.create_user(&input).await?;
+
+        let input = UpdateWorkspace {
+            require_2fa: Some(true),
+        };
+        assert!(matches!(
+            state.update_workspace(&member, input.clone()).await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        let ws = state.update_workspace(&owner, input).await?;
+        assert!(ws.require_2fa);
+        let session = state.load_session(member.id).await?.unwrap();
+        assert!(session.two_factor_missing);
+
+        let entry = DirectoryUser {
+            dn: "uid=alice,dc=ws,dc=org".to_string(),
+            fullname: "Alice Chen".to_string(),
+            email: member.email.clone(),
+            groups: vec![],
+        };
+        let member = state
+            .sync_directory_user("ldap://localhost", &entry, "ws", WorkspaceRole::Admin)
+            .await?;
+        let input = UpdateWorkspace {
+            require_2fa: Some(false),
+        };
+        let ws = state.update_workspace(&member, input).await?;
+        assert!(!ws.require_2fa);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invitation_should_be_sent_by_admins_to_new_emails() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        let (url, inbox) = start_smtp_sink().await?;
+        Arc::get_mut(&mut state.inner).unwrap().mailer = Some(test_mailer(&url));
+        let input = CreateUser::new("ws", "Tyr Chen", "tchen@ws.org", "Hunter42");
+        let owner = state.create_user(&input).await?;
+        let input = CreateUser::new("ws", "Alice Chen", "alice@ws.org", "Hunter42");
+        let member = state.create_user(&input).await?;
+
+        let input = InviteUser {
+            email: "bob@ws.org".to_string(),
+        };
+        assert!(matches!(
+            state.invite_user(&member, &input).await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        state.invite_user(&owner, &input).await?;
+        {
+            let inbox = inbox.lock().unwrap();
+            assert_eq!(inbox.len(), 1);
+            let body = inbox[0].replace("=\n", "").replace("=3D", "=");
+            assert!(body.contains("Tyr Chen"));
+            assert!(body.contains("/signup?workspace=ws&email=bob%40ws.org"));
+        }
+
+        let input = InviteUser {
+            email: member.email.clone(),
+        };
+        assert!(matches!(
+            state.invite_user(&owner, &input).await,
+            Err(AppError::EmailAlreadyExists(_))
+        ));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn workspace_should_fetch_all_chat_users() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -0,0 +1,640 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use data_encoding::BASE64URL_NOPAD;
+use jwt_simple::{
+    prelude::{
+        Clock, Duration, ECDSAP256PublicKeyLike, ES256PublicKey, JWTClaims, UnixTimeStamp,
+        VerificationOptions,
+    },
+    token::Token,
+};
+use rsa::{pkcs1v15, signature::Verifier, BigUint, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::config::OidcProviderConfig;
+
+/// seconds an id_token is still accepted after it expired, for clock drift
+const CLOCK_SKEW: u64 = 60;
+
+/// The parts of the discovery document the authorization code flow needs
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// A public key of the provider, RSA and P-256 keys are supported
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The claims of an id_token the sign in relies on, besides the registered ones
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct IdTokenClaims {
+    #[serde(default)]
+    azp: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// The user as asserted by a validated id_token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+/// A new authorization request, `state`, `nonce` and `code_verifier` must be
+/// kept until the callback
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+/// An OpenID Connect provider, used with the authorization code flow and PKCE.
+/// The discovery document and the keys are fetched on first use.
+pub struct OidcProvider {
+    config: OidcProviderConfig,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    keys: RwLock<Vec<Jwk>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcProviderConfig, http: reqwest::Client) -> Self {
+        Self {
+            config,
+            http,
+            metadata: RwLock::new(None),
+            keys: RwLock::new(vec![]),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Workspace new users with this email join, by domain first
+    pub fn workspace_for(&self, email: &str) -> Option<&str> {
+        let domain = email.rsplit_once('@').map(|(_, v)| v.to_lowercase());
+        domain
+            .and_then(|v| self.config.domains.get(&v))
+            .or(self.config.workspace.as_ref())
+            .map(|v| v.as_str())
+    }
+
+    pub async fn authorize(&self) -> Result<AuthRequest> {
+        let metadata = self.metadata().await?;
+        let state = random_string(24);
+        let nonce = random_string(24);
+        let code_verifier = random_string(48);
+
+        let mut url = reqwest::Url::parse(&metadata.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge(&code_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(AuthRequest {
+            url: url.to_string(),
+            state,
+            nonce,
+            code_verifier,
+        })
+    }
+
+    /// Exchange the code of the callback for an id_token, and validate it
+    pub async fn exchange(&self, code: &str, code_verifier: &str, nonce: &str) -> Result<Identity> {
+        let metadata = self.metadata().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let res = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            bail!("token endpoint returned {}: {}", status, res.text().await?);
+        }
+        let res: TokenResponse = res.json().await?;
+        self.verify_id_token(&res.id_token, nonce).await
+    }
+
+    async fn verify_id_token(&self, token: &str, nonce: &str) -> Result<Identity> {
+        let metadata = Token::decode_metadata(token)?;
+        let kid = metadata.key_id();
+        let key = match self.find_key(kid).await {
+            Some(key) => key,
+            // the provider may have rotated its keys
+            None => {
+                self.refresh_keys().await?;
+                self.find_key(kid)
+                    .await
+                    .ok_or_else(|| anyhow!("no key {:?} in the provider keys", kid))?
+            }
+        };
+        self.verify_with_key(&key, token, metadata.algorithm(), nonce)
+    }
+
+    /// Verify the signature and the claims of the id_token
+    fn verify_with_key(&self, key: &Jwk, token: &str, alg: &str, nonce: &str) -> Result<Identity> {
+        let claims = key.verify(token, alg)?;
+        self.validate_claims(&claims, nonce, Clock::now_since_epoch())?;
+        Ok(Identity {
+            issuer: self.config.issuer.clone(),
+            subject: claims.subject.unwrap_or_default(),
+            email: claims.custom.email,
+            email_verified: claims.custom.email_verified.unwrap_or(false),
+            name: claims.custom.name,
+        })
+    }
+
+    fn validate_claims(
+        &self,
+        claims: &JWTClaims<IdTokenClaims>,
+        nonce: &str,
+        now: UnixTimeStamp,
+    ) -> Result<()> {
+        if claims.issuer.as_ref() != Some(&self.config.issuer) {
+            bail!("id_token is issued by {:?}", claims.issuer);
+        }
+        let client_id = &self.config.client_id;
+        let for_client = claims
+            .audiences
+            .as_ref()
+            .is_some_and(|v| v.contains(&HashSet::from([client_id.clone()])));
+        if !for_client || claims.custom.azp.as_ref().is_some_and(|v| v != client_id) {
+            bail!("id_token is not for this client");
+        }
+        match claims.expires_at {
+            Some(exp) if exp + Duration::from_secs(CLOCK_SKEW) >= now => {}
+            _ => bail!("id_token has expired"),
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            bail!("id_token nonce doesn't match");
+        }
+        if claims.subject.is_none() {
+            bail!("id_token has no subject");
+        }
+        Ok(())
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = self
+            .http
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if metadata.issuer != self.config.issuer {
+            bail!(
+                "discovery document of {} is for issuer {}",
+                self.config.issuer,
+                metadata.issuer
+            );
+        }
+        *self.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = self.keys.read().await;
+        match kid {
+            Some(kid) => keys.iter().find(|k| k.kid.as_deref() == Some(kid)).cloned(),
+            // without a kid the provider must have a single key
+            None if keys.len() == 1 => keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn refresh_keys(&self) -> Result<()> {
+        let metadata = self.metadata().await?;
+        let jwks: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        *self.keys.write().await = jwks.keys;
+        Ok(())
+    }
+}
+
+/// The providers by name
+pub(crate) fn load_providers(
+    configs: &[OidcProviderConfig],
+    http: &reqwest::Client,
+) -> HashMap<String, OidcProvider> {
+    configs
+        .iter()
+        .map(|c| (c.name.clone(), OidcProvider::new(c.clone(), http.clone())))
+        .collect()
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn random_string(len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    OsRng.fill_bytes(&mut buf);
+    BASE64URL_NOPAD.encode(&buf)
+}
+
+impl Jwk {
+    /// Verify the signature of the token with the key, and decode its claims
+    fn verify(&self, token: &str, alg: &str) -> Result<JWTClaims<IdTokenClaims>> {
+        match (alg, self.kty.as_str()) {
+            ("RS256", "RSA") => {
+                let (message, signature) = token
+                    .rsplit_once('.')
+                    .ok_or_else(|| anyhow!("malformed id_token"))?;
+                let n = BigUint::from_bytes_be(&decode_b64(self.n.as_deref())?);
+                let e = BigUint::from_bytes_be(&decode_b64(self.e.as_deref())?);
+                let key = pkcs1v15::VerifyingKey::<Sha256>::new(RsaPublicKey::new(n, e)?);
+                let signature = decode_b64(Some(signature))?;
+                let signature = pkcs1v15::Signature::try_from(signature.as_slice())?;
+                key.verify(message.as_bytes(), &signature)?;
+
+                let payload = message
+                    .split_once('.')
+                    .map(|(_, v)| v)
+                    .ok_or_else(|| anyhow!("malformed id_token"))?;
+                Ok(serde_json::from_slice(&decode_b64(Some(payload))?)?)
+            }
+            ("ES256", "EC") if self.crv.as_deref() == Some("P-256") => {
+                let mut point = vec![0x04];
+                point.extend(decode_b64(self.x.as_deref())?);
+                point.extend(decode_b64(self.y.as_deref())?);
+                // the claims are validated by the provider, with the same skew
+                let options = VerificationOptions {
+                    time_tolerance: Some(Duration::from_secs(CLOCK_SKEW)),
+                    ..Default::default()
+                };
+                Ok(ES256PublicKey::from_bytes(&point)?.verify_token(token, Some(options))?)
+            }
+            (alg, kty) => bail!("unsupported id_token algorithm {} for a {} key", alg, kty),
+        }
+    }
+}
+
+fn decode_b64(value: Option<&str>) -> Result<Vec<u8>> {
+    let value = value.ok_or_else(|| anyhow!("incomplete key"))?;
+    Ok(BASE64URL_NOPAD.decode(value.as_bytes())?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::{
+        extract::State,
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use jwt_simple::prelude::{Claims, ECDSAP256KeyPairLike, ES256KeyPair};
+    use rsa::{
+        pkcs1v15::SigningKey,
+        signature::{SignatureEncoding, Signer},
+        traits::PublicKeyParts,
+        RsaPrivateKey,
+    };
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct MockClaims {
+        email: String,
+        email_verified: bool,
+        name: String,
+    }
+
+    /// What the mock provider puts in the id_token of a code
+    #[derive(Debug, Clone)]
+    pub(crate) struct Grant {
+        pub(crate) subject: String,
+        pub(crate) email: String,
+        pub(crate) email_verified: bool,
+        pub(crate) nonce: String,
+        pub(crate) code_challenge: String,
+    }
+
+    /// A local OpenID Connect provider, codes are granted by the tests
+    pub(crate) struct MockProvider {
+        pub(crate) issuer: String,
+        key: ES256KeyPair,
+        grants: Mutex<HashMap<String, Grant>>,
+    }
+
+    impl MockProvider {
+        /// Grant a code for the authorization request at `url`
+        pub(crate) fn grant(
+            &self,
+            url: &str,
+            subject: &str,
+            email: &str,
+            verified: bool,
+        ) -> String {
+            let url = reqwest::Url::parse(url).unwrap();
+            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+            assert_eq!(params["code_challenge_method"], "S256");
+            let code = random_string(16);
+            let grant = Grant {
+                subject: subject.to_string(),
+                email: email.to_string(),
+                email_verified: verified,
+                nonce: params["nonce"].clone(),
+                code_challenge: params["code_challenge"].clone(),
+            };
+            self.grants.lock().unwrap().insert(code.clone(), grant);
+            code
+        }
+    }
+
+    pub(crate) async fn start_mock_provider() -> Result<Arc<MockProvider>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let provider = Arc::new(MockProvider {
+            issuer: format!("http://{}", listener.local_addr()?),
+            key: ES256KeyPair::generate().with_key_id("test"),
+            grants: Mutex::new(HashMap::new()),
+        });
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(provider.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok(provider)
+    }
+
+    pub(crate) fn test_config(issuer: &str) -> OidcProviderConfig {
+        OidcProviderConfig {
+            name: "acme".to_string(),
+            issuer: issuer.to_string(),
+            client_id: "chat".to_string(),
+            client_secret: Some("secret".to_string()),
+            redirect_uri: "http://localhost:6688/api/oidc/acme/callback".to_string(),
+            scopes: vec!["openid".into(), "email".into()],
+            workspace: Some("sso".to_string()),
+            domains: HashMap::from([("acme.org".to_string(), "acme".to_string())]),
+        }
+    }
+
+    async fn discovery(State(p): State<Arc<MockProvider>>) -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "issuer": p.issuer,
+            "authorization_endpoint": format!("{}/authorize", p.issuer),
+            "token_endpoint": format!("{}/token", p.issuer),
+            "jwks_uri": format!("{}/jwks", p.issuer),
+        }))
+    }
+
+    async fn jwks(State(p): State<Arc<MockProvider>>) -> Json<serde_json::Value> {
+        let point = p.key.public_key().public_key().to_bytes_uncompressed();
+        Json(serde_json::json!({
+            "keys": [{
+                "kty": "EC",
+                "kid": "test",
+                "crv": "P-256",
+                "x": BASE64URL_NOPAD.encode(&point[1..33]),
+                "y": BASE64URL_NOPAD.encode(&point[33..]),
+            }]
+        }))
+    }
+
+    async fn token(
+        State(p): State<Arc<MockProvider>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, &'static str)> {
+        let bad = (axum::http::StatusCode::BAD_REQUEST, "invalid_grant");
+        let grant = p.grants.lock().unwrap().remove(&form["code"]).ok_or(bad)?;
+        if code_challenge(&form["code_verifier"]) != grant.code_challenge
+            || form.get("client_secret").map(|v| v.as_str()) != Some("secret")
+        {
+            return Err(bad);
+        }
+
+        let custom = MockClaims {
+            email: grant.email,
+            email_verified: grant.email_verified,
+            name: "SSO User".to_string(),
+        };
+        let claims = Claims::with_custom_claims(custom, Duration::from_mins(5))
+            .with_issuer(&p.issuer)
+            .with_audience("chat")
+            .with_subject(grant.subject)
+            .with_nonce(grant.nonce);
+        let id_token = p.key.sign(claims).unwrap();
+        Ok(Json(serde_json::json!({
+            "access_token": "access",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        })))
+    }
+
+    #[test]
+    fn workspace_should_be_mapped_by_domain() {
+        let provider = OidcProvider::new(test_config("http://idp"), reqwest::Client::new());
+        assert_eq!(provider.workspace_for("alice@ACME.org"), Some("acme"));
+        assert_eq!(provider.workspace_for("bob@other.org"), Some("sso"));
+    }
+
+    #[test]
+    fn code_challenge_should_be_s256() {
+        // base64url of the sha256 digest, as `openssl dgst -sha256 -binary` gives
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOBjXk"),
+            "jCuPkTvxOxWSyB6e8dxJTu_2YioCDxJajnuD959epdo"
+        );
+    }
+
+    #[tokio::test]
+    async fn exchange_should_validate_id_token() -> Result<()> {
+        let mock = start_mock_provider().await?;
+        let provider = OidcProvider::new(test_config(&mock.issuer), reqwest::Client::new());
+
+        let req = provider.authorize().await?;
+        assert!(req.url.starts_with(&format!("{}/authorize?", mock.issuer)));
+        let code = mock.grant(&req.url, "u1", "alice@acme.org", true);
+        let identity = provider
+            .exchange(&code, &req.code_verifier, &req.nonce)
+            .await?;
+        assert_eq!(identity.issuer, mock.issuer);
+        assert_eq!(identity.subject, "u1");
+        assert_eq!(identity.email.as_deref(), Some("alice@acme.org"));
+        assert!(identity.email_verified);
+
+        // codes are single use, the nonce must match and the verifier must be the one of the challenge
+        assert!(provider
+            .exchange(&code, &req.code_verifier, &req.nonce)
+            .await
+            .is_err());
+        let code = mock.grant(&req.url, "u1", "alice@acme.org", true);
+        assert!(provider
+            .exchange(&code, &req.code_verifier, "other")
+            .await
+            .is_err());
+        let code = mock.grant(&req.url, "u1", "alice@acme.org", true);
+        assert!(provider.exchange(&code, "other", &req.nonce).await.is_err());
+        Ok(())
+    }
+
+    fn test_claims() -> JWTClaims<IdTokenClaims> {
+        let mut claims =
+            Claims::with_custom_claims(IdTokenClaims::default(), Duration::from_secs(0))
+                .with_issuer("http://idp")
+                .with_audiences(HashSet::from(["other".to_string(), "chat".to_string()]))
+                .with_subject("u1")
+                .with_nonce("n");
+        claims.issued_at = None;
+        claims.invalid_before = None;
+        claims.expires_at = Some(Duration::from_secs(1_000));
+        claims
+    }
+
+    #[test]
+    fn rs256_id_token_should_verify() -> Result<()> {
+        let sk = RsaPrivateKey::new(&mut OsRng, 1024)?;
+        let pk = sk.to_public_key();
+        let jwk = Jwk {
+            kty: "RSA".to_string(),
+            kid: None,
+            n: Some(BASE64URL_NOPAD.encode(&pk.n().to_bytes_be())),
+            e: Some(BASE64URL_NOPAD.encode(&pk.e().to_bytes_be())),
+            crv: None,
+            x: None,
+            y: None,
+        };
+        let header = BASE64URL_NOPAD.encode(br#"{"alg":"RS256"}"#);
+        let payload = BASE64URL_NOPAD.encode(&serde_json::to_vec(&test_claims())?);
+        let message = format!("{}.{}", header, payload);
+        let signature = SigningKey::<Sha256>::new(sk).sign(message.as_bytes());
+        let token = format!(
+            "{}.{}",
+            message,
+            BASE64URL_NOPAD.encode(&signature.to_bytes())
+        );
+
+        assert_eq!(jwk.verify(&token, "RS256")?.subject.as_deref(), Some("u1"));
+        // the algorithm must match the key
+        assert!(jwk.verify(&token, "ES256").is_err());
+        let tampered = token.replacen(&payload, &BASE64URL_NOPAD.encode(b"{}"), 1);
+        assert!(jwk.verify(&tampered, "RS256").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn es256_id_token_should_verify() -> Result<()> {
+        let key = ES256KeyPair::generate();
+        let point = key.public_key().public_key().to_bytes_uncompressed();
+        let jwk = Jwk {
+            kty: "EC".to_string(),
+            kid: None,
+            n: None,
+            e: None,
+            crv: Some("P-256".to_string()),
+            x: Some(BASE64URL_NOPAD.encode(&point[1..33])),
+            y: Some(BASE64URL_NOPAD.encode(&point[33..])),
+        };
+        let claims = Claims::with_custom_claims(IdTokenClaims::default(), Duration::from_mins(5))
+            .with_subject("u1");
+        let token = key.sign(claims)?;
+
+        assert_eq!(jwk.verify(&token, "ES256")?.subject.as_deref(), Some("u1"));
+        assert!(jwk.verify(&token, "RS256").is_err());
+        let other = ES256KeyPair::generate().sign(Claims::create(Duration::from_mins(5)))?;
+        let (message, _) = token.rsplit_once('.').unwrap();
+        let (_, signature) = other.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", message, signature);
+        assert!(jwk.verify(&forged, "ES256").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn id_token_claims_should_be_validated() {
+        let provider = OidcProvider::new(test_config("http://idp"), reqwest::Client::new());
+        let now = Duration::from_secs(1_000);
+        let claims = test_claims();
+        assert!(provider.validate_claims(&claims, "n", now).is_ok());
+        let later = now + Duration::from_secs(CLOCK_SKEW + 1);
+        assert!(provider.validate_claims(&claims, "n", later).is_err());
+        assert!(provider.validate_claims(&claims, "other", now).is_err());
+
+        let mut other = claims.clone();
+        other.custom.azp = Some("other".to_string());
+        assert!(provider.validate_claims(&other, "n", now).is_err());
+        let other = claims.clone().with_audience("other");
+        assert!(provider.validate_claims(&other, "n", now).is_err());
+        let mut other = claims.clone();
+        other.expires_at = None;
+        assert!(provider.validate_claims(&other, "n", now).is_err());
+        let other = claims.with_issuer("http://evil");
+        assert!(provider.validate_claims(&other, "n", now).is_err());
+    }
+}
@@ -1,9 +1,20 @@
-use crate::{handlers::*, ChatFile};
+use crate::{commands::ExternalCommand, handlers::*, ChatFile};
 use crate::{
-    AppState, CreateChat, CreateMessage, CreateUser, ErrorOutput, ListMessages, SigninUser,
+    ApiToken, AppState, BotInteractions, CreateApiToken, CreateBot, CreateChat, CreateCommand,
+    CreateIncomingWebhook, CreateMessage, CreateOutgoingWebhook, CreateUser, CreatedApiToken,
+    CreatedIncomingWebhook, CreatedOutgoingWebhook, DeliveryStatus, EmailPreferences, ErrorOutput,
+    ForgotPassword, IncomingWebhook, Interaction, InteractionPayload, InteractionResponse,
+    InteractionUser, InviteUser, ListDeliveries, ListMessages, ListPresences, MarkRead,
+    MessageOutput, OutgoingWebhook, RecoveryCodes, ResetPassword, SigninChallenge, SigninUser,
+    TextFormat, TwoFactorCode, TwoFactorEnrollment, UpdateBotInteractions, UpdateEmailPreferences,
+    UpdateIncomingWebhook, UpdateOutgoingWebhook, UpdatePresence, UpdateWorkspace, VerifyTwoFactor,
+    WebhookAttachment, WebhookDelivery, WebhookPayload,
 };
 use axum::Router;
-use chat_core::{Chat, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{
+    ActionElement, Block, Blocks, ButtonStyle, Chat, ChatType, ChatUser, EphemeralMessage, Message,
+    PresenceStatus, Scope, SelectOption, User, UserPresence, WebhookEvent, Workspace,
+};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -31,9 +42,50 @@ pub(crate) trait OpenApiRouter {
             send_message_handler,
             file_handler,
             upload_handler,
+            list_command_handler,
+            create_command_handler,
+            list_presence_handler,
+            update_presence_handler,
+            get_email_preferences_handler,
+            update_email_preferences_handler,
+            mark_chat_read_handler,
+            unsubscribe_handler,
+            forgot_password_handler,
+            reset_password_handler,
+            verify_email_handler,
+            resend_verification_handler,
+            enroll_two_factor_handler,
+            confirm_two_factor_handler,
+            disable_two_factor_handler,
+            verify_two_factor_handler,
+            update_workspace_handler,
+            invite_user_handler,
+            list_oidc_providers_handler,
+            oidc_authorize_handler,
+            oidc_callback_handler,
+            list_bots_handler,
+            create_bot_handler,
+            list_api_tokens_handler,
+            create_api_token_handler,
+            revoke_api_token_handler,
+            list_incoming_webhooks_handler,
+            create_incoming_webhook_handler,
+            update_incoming_webhook_handler,
+            delete_incoming_webhook_handler,
+            post_incoming_webhook_handler,
+            list_outgoing_webhooks_handler,
+            create_outgoing_webhook_handler,
+            update_outgoing_webhook_handler,
+            delete_outgoing_webhook_handler,
+            list_webhook_deliveries_handler,
+            list_all_webhook_deliveries_handler,
+            retry_webhook_delivery_handler,
+            update_bot_interactions_handler,
+            delete_bot_interactions_handler,
+            interact_handler,
         ),
         components(
-            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, ErrorOutput, ChatFile),
+            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, ErrorOutput, ChatFile, EphemeralMessage, MessageOutput, CreateCommand, ExternalCommand, PresenceStatus, UserPresence, ListPresences, UpdatePresence, EmailPreferences, UpdateEmailPreferences, MarkRead, ForgotPassword, ResetPassword, TwoFactorEnrollment, TwoFactorCode, RecoveryCodes, SigninChallenge, VerifyTwoFactor, UpdateWorkspace, InviteUser, Scope, CreateBot, CreateApiToken, ApiToken, CreatedApiToken, IncomingWebhook, CreateIncomingWebhook, CreatedIncomingWebhook, UpdateIncomingWebhook, WebhookPayload, WebhookAttachment, TextFormat, WebhookEvent, OutgoingWebhook, CreateOutgoingWebhook, CreatedOutgoingWebhook, UpdateOutgoingWebhook, DeliveryStatus, WebhookDelivery, ListDeliveries, Blocks, Block, ActionElement, ButtonStyle, SelectOption, BotInteractions, UpdateBotInteractions, Interaction, InteractionPayload, InteractionUser, InteractionResponse),
         ),
         modifiers(&SecurityAddon),
         tags(
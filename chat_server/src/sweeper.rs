@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Periodically hard delete expired messages and the files only they reference,
+/// deliver due reminders, and email unread messages and digests
+pub fn spawn_message_sweeper(state: AppState) {
+    let period = Duration::from_secs(state.config.server.sweep_interval.max(1));
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        loop {
+            interval.tick().await;
+            match state.delete_expired_messages().await {
+                Ok(0) => {}
+                Ok(n) => info!("Deleted {} expired messages", n),
+                Err(e) => warn!("Failed to delete expired messages: {}", e),
+            }
+            match state.deliver_due_reminders().await {
+                Ok(0) => {}
+                Ok(n) => info!("Delivered {} reminders", n),
+                Err(e) => warn!("Failed to deliver reminders: {}", e),
+            }
+            if let Err(e) = state.delete_expired_tokens().await {
+                warn!("Failed to delete expired tokens: {}", e);
+            }
+            if let Err(e) = state.delete_stale_signin_attempts().await {
+                warn!("Failed to delete stale sign in attempts: {}", e);
+            }
+            if let Err(e) = state.delete_expired_oidc_states().await {
+                warn!("Failed to delete expired sign in requests: {}", e);
+            }
+            if state.mailer.is_none() {
+                continue;
+            }
+            match state.send_unread_emails().await {
+                Ok(0) => {}
+                Ok(n) => info!("Emailed unread messages to {} users", n),
+                Err(e) => warn!("Failed to email unread messages: {}", e),
+            }
+            match state.send_digests().await {
+                Ok(0) => {}
+                Ok(n) => info!("Emailed {} digests", n),
+                Err(e) => warn!("Failed to email digests: {}", e),
+            }
+        }
+    });
+}
+
+/// Render once the messages sent before the server rendered their content
+pub fn spawn_message_backfill(state: AppState) {
+    tokio::spawn(async move {
+        let mut total = 0;
+        loop {
+            match state.render_legacy_messages().await {
+                Ok(0) => break,
+                Ok(n) => total += n,
+                Err(e) => {
+                    warn!("Failed to render legacy messages: {}", e);
+                    return;
+                }
+            }
+        }
+        if total > 0 {
+            info!("Rendered {} legacy messages", total);
+        }
+    });
+}
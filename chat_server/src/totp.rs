@@ -0,0 +1,127 @@
+use anyhow::Result;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// seconds a code is valid
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// steps before and after the current one still accepted, for clock drift
+const SKEW: u64 = 1;
+const SECRET_LEN: usize = 20;
+
+/// Time-based one-time passwords (RFC 6238), with the defaults authenticator
+/// apps expect: SHA1, 6 digits, 30 seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totp {
+    secret: Vec<u8>,
+}
+
+impl Totp {
+    pub fn generate() -> Self {
+        let mut secret = vec![0u8; SECRET_LEN];
+        OsRng.fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    pub fn from_base32(secret: &str) -> Result<Self> {
+        let secret = BASE32_NOPAD.decode(secret.trim_end_matches('=').as_bytes())?;
+        Ok(Self { secret })
+    }
+
+    pub fn to_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.secret)
+    }
+
+    /// The `otpauth://` URI authenticator apps enroll with, usually as a QR code
+    pub fn uri(&self, issuer: &str, account: &str) -> Result<String> {
+        let mut url = reqwest::Url::parse("otpauth://totp/")?;
+        url.set_path(&format!("{}:{}", issuer, account));
+        url.query_pairs_mut()
+            .append_pair("secret", &self.to_base32())
+            .append_pair("issuer", issuer)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &STEP.to_string());
+        Ok(url.to_string())
+    }
+
+    #[cfg(test)]
+    pub fn code_at(&self, unix_secs: u64) -> String {
+        self.code_for_step(unix_secs / STEP)
+    }
+
+    /// Check the code against the steps around `unix_secs`. Returns the step
+    /// it matched, later codes must be for later steps so none is used twice.
+    pub fn verify(&self, code: &str, unix_secs: u64) -> Option<u64> {
+        let code = code.trim();
+        if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let now = unix_secs / STEP;
+        (now.saturating_sub(SKEW)..=now + SKEW).find(|step| self.code_for_step(*step) == code)
+    }
+
+    fn code_for_step(&self, step: u64) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.secret).expect("hmac accepts keys of any length");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+        // dynamic truncation
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let value = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            value % 10u32.pow(DIGITS),
+            width = DIGITS as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the SHA1 secret of the RFC 6238 test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_should_match_rfc_vectors() {
+        let totp = Totp {
+            secret: RFC_SECRET.to_vec(),
+        };
+        // the RFC lists 8 digit codes, these are their last 6 digits
+        assert_eq!(totp.code_at(59), "287082");
+        assert_eq!(totp.code_at(1111111109), "081804");
+        assert_eq!(totp.code_at(1234567890), "005924");
+        assert_eq!(totp.code_at(2000000000), "279037");
+    }
+
+    #[test]
+    fn totp_should_verify_with_skew() -> Result<()> {
+        let totp = Totp::from_base32(&Totp::generate().to_base32())?;
+        let now = 1_720_000_000;
+        let code = totp.code_at(now);
+        assert_eq!(totp.verify(&code, now), Some(now / STEP));
+        assert_eq!(totp.verify(&code, now + STEP), Some(now / STEP));
+        assert_eq!(totp.verify(&code, now + 3 * STEP), None);
+        assert_eq!(totp.verify("12345", now), None);
+        Ok(())
+    }
+
+    #[test]
+    fn totp_uri_should_work() -> Result<()> {
+        let totp = Totp::from_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ")?;
+        assert_eq!(
+            totp.uri("Chat", "tchen@acme.org")?,
+            "otpauth://totp/Chat:tchen@acme.org?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Chat&algorithm=SHA1&digits=6&period=30"
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use chat_core::{Chat, ChatType, Message};
+use chat_core::{Chat, ChatType, Message, UserPresence, WebhookEvent};
 use futures::StreamExt;
 use reqwest::{
     multipart::{Form, Part},
@@ -8,7 +8,7 @@ use reqwest::{
 use reqwest_eventsource::{Event, EventSource};
 use serde::Deserialize;
 use serde_json::json;
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{net::TcpListener, time::sleep};
 
 /*
@@ -58,6 +58,298 @@ async fn chat_server_should_work() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn revoked_sessions_should_be_rejected_by_notify_server() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let addr = NotifyServer::start(&tdb.url()).await?;
+
+    let url = format!("http://{}/chats/1/mute", addr);
+    let res = chat_server
+        .client
+        .put(&url)
+        .bearer_auth(&chat_server.token)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    sqlx::query("UPDATE users SET token_version = token_version + 1 WHERE id = 1")
+        .execute(&tdb.get_pool().await)
+        .await?;
+    let res = chat_server
+        .client
+        .put(&url)
+        .bearer_auth(&chat_server.token)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = chat_server
+        .client
+        .get(format!(
+            "http://{}/events?access_token={}",
+            addr, chat_server.token
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    Ok(())
+}
+
+#[tokio::test]
+async fn removed_members_should_be_notified() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    // a fresh instance never cached the chat before the change
+    let addr = NotifyServer::start(&tdb.url()).await?;
+
+    let token = chat_server.signin("bob1@acme.org").await?;
+    let mut es = EventSource::get(format!("http://{}/events?access_token={}", addr, token));
+    assert!(matches!(es.next().await, Some(Ok(Event::Open))));
+
+    let res = chat_server
+        .client
+        .patch(format!("http://{}/api/chats/2", chat_server.addr))
+        .bearer_auth(&chat_server.token)
+        .json(&json!({ "members": [1, 2] }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let chat = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = es.next().await {
+            if let Ok(Event::Message(message)) = event {
+                if message.event == "UpdateChat" {
+                    return serde_json::from_str::<Chat>(&message.data).ok();
+                }
+            }
+        }
+        None
+    })
+    .await?
+    .expect("bob should be told about the removal");
+    assert_eq!(chat.id, 2);
+    assert_eq!(chat.members, vec![1, 2]);
+    es.close();
+    Ok(())
+}
+
+#[tokio::test]
+async fn events_should_be_logged_once_by_all_instances() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+
+    let mut streams = vec![];
+    for _ in 0..2 {
+        let addr = NotifyServer::start(&tdb.url()).await?;
+        let url = format!("http://{}/events?access_token={}", addr, chat_server.token);
+        let mut es = EventSource::get(url);
+        assert!(matches!(es.next().await, Some(Ok(Event::Open))));
+        streams.push(es);
+    }
+    let msg = chat_server.create_message(1).await?;
+
+    let mut ids = vec![];
+    for es in &mut streams {
+        let id = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(event) = es.next().await {
+                if let Ok(Event::Message(message)) = event {
+                    if message.event == "NewMessage" {
+                        return Some(message.id);
+                    }
+                }
+            }
+            None
+        })
+        .await?
+        .expect("the message should be delivered");
+        ids.push(id);
+        es.close();
+    }
+    assert_eq!(ids[0], ids[1]);
+
+    let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM event_log WHERE event_key = $1")
+        .bind(format!("new_message:{}", msg.id))
+        .fetch_one(&tdb.get_pool().await)
+        .await?;
+    assert_eq!(count, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_bus_should_deliver_chat_server_events() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let addr = NotifyServer::start_with_bus(&tdb.url(), notify_server::BusConfig::Memory).await?;
+
+    let url = format!("http://{}/events?access_token={}", addr, chat_server.token);
+    let mut es = EventSource::get(url);
+    assert!(matches!(es.next().await, Some(Ok(Event::Open))));
+    // the notifications of chat_server are forwarded once the bridge listens
+    sleep(Duration::from_millis(500)).await;
+    let msg = chat_server.create_message(1).await?;
+
+    let received = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = es.next().await {
+            if let Ok(Event::Message(message)) = event {
+                if message.event == "NewMessage" {
+                    return serde_json::from_str::<Message>(&message.data).ok();
+                }
+            }
+        }
+        None
+    })
+    .await?
+    .expect("the message should be delivered");
+    assert_eq!(received.id, msg.id);
+    es.close();
+    Ok(())
+}
+
+#[tokio::test]
+async fn typing_should_reach_users_on_other_instances() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let bus: Arc<dyn notify_server::EventBus> = Arc::new(notify_server::MemoryBus::default());
+    let mut addrs = vec![];
+    for _ in 0..2 {
+        let mut config = notify_server::AppConfig::load()?;
+        config.server.db_url = tdb.url();
+        let state = notify_server::AppState::with_bus(config, bus.clone())?;
+        addrs.push(NotifyServer::start_with_state(state).await?);
+    }
+
+    let token = chat_server.signin("alice1@acme.org").await?;
+    let url = format!("http://{}/events?access_token={}", addrs[1], token);
+    let mut es = EventSource::get(url);
+    assert!(matches!(es.next().await, Some(Ok(Event::Open))));
+
+    let typing_url = format!("http://{}/chats/1/typing", addrs[0]);
+    let expected = notify_server::Typing {
+        chat_id: 1,
+        user_id: 1,
+    };
+    let res = chat_server
+        .client
+        .post(&typing_url)
+        .bearer_auth(&chat_server.token)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let data = next_event(&mut es, "TypingStarted").await?;
+    assert_eq!(
+        serde_json::from_str::<notify_server::Typing>(&data)?,
+        expected
+    );
+
+    let res = chat_server
+        .client
+        .delete(&typing_url)
+        .bearer_auth(&chat_server.token)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let data = next_event(&mut es, "TypingStopped").await?;
+    assert_eq!(
+        serde_json::from_str::<notify_server::Typing>(&data)?,
+        expected
+    );
+    es.close();
+    Ok(())
+}
+
+#[tokio::test]
+async fn connections_should_be_visible_to_admins() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let addr = NotifyServer::start(&tdb.url()).await?;
+    let pool = tdb.get_pool().await;
+    sqlx::query("UPDATE workspaces SET owner_id = 1 WHERE id = 1")
+        .execute(&pool)
+        .await?;
+    sqlx::query("UPDATE users SET role = 'admin' WHERE id = 2")
+        .execute(&pool)
+        .await?;
+
+    let url = format!("http://{}/connections", addr);
+    for (email, status) in [
+        ("tchen1@acme.org", StatusCode::OK),
+        ("alice1@acme.org", StatusCode::OK),
+        ("bob1@acme.org", StatusCode::FORBIDDEN),
+    ] {
+        let token = chat_server.signin(email).await?;
+        let res = chat_server
+            .client
+            .get(&url)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        assert_eq!(res.status(), status, "{}", email);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn webhook_payloads_should_be_notify_server_events() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    sqlx::query("UPDATE users SET role = 'admin' WHERE id = 1")
+        .execute(&tdb.get_pool().await)
+        .await?;
+    let admin = state.find_user_by_id(1).await?.unwrap();
+    let input = chat_server::CreateOutgoingWebhook {
+        url: "https://example.com/hooks".to_string(),
+        events: vec![
+            WebhookEvent::NewMessage,
+            WebhookEvent::NewChat,
+            WebhookEvent::MemberJoined,
+        ],
+    };
+    let hook = state.create_outgoing_webhook(&admin, &input).await?.info;
+
+    let input = chat_server::CreateChat {
+        name: Some("hooks".to_string()),
+        members: vec![1, 2],
+        public: false,
+        message_ttl: None,
+    };
+    let chat = state.create_chat(input, 1).await?;
+    state.add_chat_member(chat.id as _, 3).await?;
+    let input = chat_server::CreateMessage {
+        content: "hello".to_string(),
+        files: vec![],
+        ttl: None,
+        blocks: None,
+    };
+    state.create_message(input, chat.id as _, 1).await?;
+
+    let list = chat_server::ListDeliveries::default();
+    let log = state
+        .list_webhook_deliveries(&admin, Some(hook.id), &list)
+        .await?;
+    assert_eq!(log.len(), 3);
+    for delivery in log {
+        let event: notify_server::AppEvent = serde_json::from_value(delivery.payload)?;
+        assert_eq!(event.name(), format!("{:?}", delivery.event));
+        assert_eq!(event.chat_id(), Some(chat.id));
+    }
+    Ok(())
+}
+
+/// The data of the next event with the given name
+async fn next_event(es: &mut EventSource, name: &str) -> Result<String> {
+    let data = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = es.next().await {
+            if let Ok(Event::Message(message)) = event {
+                if message.event == name {
+                    return Some(message.data);
+                }
+            }
+        }
+        None
+    })
+    .await?;
+    data.ok_or_else(|| anyhow::anyhow!("no {} event", name))
+}
+
 impl ChatServer {
     async fn new(state: chat_server::AppState) -> Result<Self> {
         let app = chat_server::get_router(state).await?;
@@ -78,17 +370,16 @@ impl ChatServer {
             token: String::new(),
         };
 
-        ret.token = ret.signin().await?;
+        ret.token = ret.signin("tchen1@acme.org").await?;
 
         Ok(ret)
     }
 
-    async fn signin(&self) -> Result<String> {
+    async fn signin(&self, email: &str) -> Result<String> {
         let res = self
             .client
-            .post(&format!("http://{}/api/signin", self.addr))
-            .header("Content-Type", "application/json")
-            .body(r#"{"email": "tchen1@acme.org","password":"123456"}"#)
+            .post(format!("http://{}/api/signin", self.addr))
+            .json(&json!({ "email": email, "password": "123456" }))
             .send()
             .await?;
 
@@ -124,7 +415,7 @@ impl ChatServer {
 
         let res = self
             .client
-            .post(&format!("http://{}/api/upload", self.addr))
+            .post(format!("http://{}/api/upload", self.addr))
             .header("Authorization", format!("Bearer {}", self.token))
             .multipart(form)
             .send()
@@ -155,18 +446,7 @@ impl ChatServer {
 
 impl NotifyServer {
     async fn new(db_url: &str, token: &str) -> Result<Self> {
-        let mut config = notify_server::AppConfig::load()?;
-        config.server.db_url = db_url.to_string();
-        let app = notify_server::get_router(config).await?;
-        let listener = TcpListener::bind(WILD_ADDR).await?;
-        let addr = listener.local_addr()?;
-
-        tokio::spawn(async move {
-            axum::serve(listener, app.into_make_service())
-                .await
-                .unwrap();
-        });
-
+        let addr = Self::start(db_url).await?;
         let mut es = EventSource::get(format!("http://{}/events?access_token={}", addr, token));
 
         tokio::spawn(async move {
@@ -189,6 +469,11 @@ impl NotifyServer {
                             assert_eq!(msg.files.unwrap().len(), 1);
                             assert_eq!(msg.sender_id, 1);
                         }
+                        "PresenceChanged" => {
+                            let presence: UserPresence =
+                                serde_json::from_str(&message.data).unwrap();
+                            println!("presence {:?}", presence);
+                        }
                         _ => {
                             panic!("unexpected event: {:?}", message);
                         }
@@ -203,4 +488,29 @@ impl NotifyServer {
 
         Ok(Self)
     }
+
+    async fn start(db_url: &str) -> Result<SocketAddr> {
+        Self::start_with_bus(db_url, notify_server::BusConfig::Postgres).await
+    }
+
+    async fn start_with_bus(db_url: &str, bus: notify_server::BusConfig) -> Result<SocketAddr> {
+        let mut config = notify_server::AppConfig::load()?;
+        config.server.db_url = db_url.to_string();
+        config.server.bus = bus;
+        let state = notify_server::AppState::try_new(config).await?;
+        Self::start_with_state(state).await
+    }
+
+    async fn start_with_state(state: notify_server::AppState) -> Result<SocketAddr> {
+        let app = notify_server::get_router_with_state(state).await?;
+        let listener = TcpListener::bind(WILD_ADDR).await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+        Ok(addr)
+    }
 }
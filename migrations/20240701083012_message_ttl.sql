@@ -0,0 +1,38 @@
+-- Add migration script here
+-- per-chat retention, in seconds. NULL means messages never expire
+ALTER TABLE chats ADD COLUMN message_ttl bigint NULL DEFAULT NULL;
+
+-- per-message expiration, computed from the message or chat ttl on insert
+ALTER TABLE messages ADD COLUMN expires_at timestamptz NULL DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS expires_at_index ON messages(expires_at)
+WHERE
+  expires_at IS NOT NULL;
+
+-- if message deleted, notify with message data
+CREATE OR REPLACE FUNCTION notify_message_deleted()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    RAISE NOTICE 'message_deleted: %', OLD;
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = OLD.chat_id;
+    PERFORM
+      pg_notify('message_deleted', json_build_object('message', OLD, 'members', USERS)::text);
+  END IF;
+  RETURN OLD;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER message_deleted_trigger
+AFTER DELETE ON messages
+FOR EACH ROW
+EXECUTE FUNCTION notify_message_deleted();
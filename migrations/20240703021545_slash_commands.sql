@@ -0,0 +1,26 @@
+-- Add migration script here
+ALTER TABLE chats ADD COLUMN topic varchar(256) NULL DEFAULT NULL;
+
+-- external slash commands registered by a workspace
+CREATE TABLE IF NOT EXISTS commands(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  name varchar(32) NOT NULL,
+  url text NOT NULL,
+  description text NOT NULL DEFAULT '',
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS commands_ws_id_name_index ON commands(ws_id, name);
+
+-- reminders created by /remind, delivered as ephemeral messages
+CREATE TABLE IF NOT EXISTS reminders(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  content text NOT NULL,
+  remind_at timestamptz NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS remind_at_index ON reminders(remind_at);
@@ -0,0 +1,49 @@
+-- Add migration script here
+CREATE TYPE presence_status AS ENUM (
+  'online',
+  'away',
+  'dnd',
+  'offline'
+);
+
+-- status explicitly set by the user, and the last time the user was seen online
+CREATE TABLE IF NOT EXISTS user_presence(
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  status presence_status NOT NULL DEFAULT 'online',
+  last_seen_at timestamptz
+);
+
+-- live SSE connections of a user on a notify_server instance,
+-- refreshed by the instance heartbeat
+CREATE TABLE IF NOT EXISTS presence_connections(
+  instance_id varchar(64) NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id),
+  connections int NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (instance_id, user_id)
+);
+
+-- effective presence: offline without a live connection on any instance
+CREATE OR REPLACE VIEW presences AS
+SELECT
+  u.id AS user_id,
+  u.ws_id,
+  CASE WHEN COALESCE(c.connections, 0) > 0 THEN
+    COALESCE(p.status, 'online')
+  ELSE
+    'offline'
+  END AS status,
+  p.last_seen_at
+FROM
+  users u
+  LEFT JOIN user_presence p ON p.user_id = u.id
+  LEFT JOIN (
+    SELECT
+      user_id,
+      sum(connections) AS connections
+    FROM
+      presence_connections
+    WHERE
+      updated_at > now() - interval '90 seconds'
+    GROUP BY
+      user_id) c ON c.user_id = u.id;
@@ -0,0 +1,12 @@
+-- Add migration script here
+-- events delivered by notify_server, kept for a while so clients can replay
+-- what they missed after a reconnect. The id is the event id sent to clients.
+CREATE TABLE IF NOT EXISTS event_log(
+  id bigserial PRIMARY KEY,
+  user_ids bigint[] NOT NULL,
+  event jsonb NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS event_log_user_ids_idx ON event_log USING GIN(user_ids);
+CREATE INDEX IF NOT EXISTS event_log_created_at_idx ON event_log(created_at);
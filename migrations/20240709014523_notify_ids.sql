@@ -0,0 +1,44 @@
+-- Add migration script here
+-- notifications only carry ids, so the payload stays far below the 8000 bytes
+-- limit of pg_notify. notify_server loads the rows and members itself.
+CREATE OR REPLACE FUNCTION notify_chat_change()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  PERFORM
+    pg_notify('chat_change', json_build_object('op', TG_OP, 'chat_id', CASE WHEN TG_OP = 'DELETE' THEN
+        OLD.id
+      ELSE
+        NEW.id
+      END)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_message_added()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('message_added', json_build_object('op', TG_OP, 'message_id', NEW.id, 'chat_id', NEW.chat_id, 'sender_id', NEW.sender_id, 'created_at', NEW.created_at)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+-- the row is gone once deleted, so the few fields clients need are kept in the payload
+CREATE OR REPLACE FUNCTION notify_message_deleted()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    PERFORM
+      pg_notify('message_deleted', json_build_object('op', TG_OP, 'message_id', OLD.id, 'chat_id', OLD.chat_id, 'sender_id', OLD.sender_id, 'created_at', OLD.created_at)::text);
+  END IF;
+  RETURN OLD;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,63 @@
+-- Add migration script here
+-- domain events written by chat_server in the transaction of the change, and
+-- relayed by notify_server. The payload has the same format as the triggers.
+CREATE TABLE IF NOT EXISTS outbox(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL,
+  channel varchar(64) NOT NULL,
+  payload jsonb NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- the triggers are the legacy way to generate events, they are skipped for
+-- transactions of a chat_server running in outbox mode
+CREATE OR REPLACE FUNCTION notify_chat_change()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF current_setting('chat.event_mode', TRUE) = 'outbox' THEN
+    RETURN NEW;
+  END IF;
+  PERFORM
+    pg_notify('chat_change', json_build_object('op', TG_OP, 'chat_id', CASE WHEN TG_OP = 'DELETE' THEN
+        OLD.id
+      ELSE
+        NEW.id
+      END)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_message_added()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF current_setting('chat.event_mode', TRUE) = 'outbox' THEN
+    RETURN NEW;
+  END IF;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('message_added', json_build_object('op', TG_OP, 'message_id', NEW.id, 'chat_id', NEW.chat_id, 'sender_id', NEW.sender_id, 'created_at', NEW.created_at)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+-- the row is gone once deleted, so the few fields clients need are kept in the payload
+CREATE OR REPLACE FUNCTION notify_message_deleted()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF current_setting('chat.event_mode', TRUE) = 'outbox' THEN
+    RETURN OLD;
+  END IF;
+  IF TG_OP = 'DELETE' THEN
+    PERFORM
+      pg_notify('message_deleted', json_build_object('op', TG_OP, 'message_id', OLD.id, 'chat_id', OLD.chat_id, 'sender_id', OLD.sender_id, 'created_at', OLD.created_at)::text);
+  END IF;
+  RETURN OLD;
+END;
+$$
+LANGUAGE plpgsql;
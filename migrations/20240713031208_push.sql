@@ -0,0 +1,35 @@
+-- Add migration script here
+CREATE TYPE push_kind AS ENUM (
+  'web_push',
+  'gateway'
+);
+
+-- push endpoints of the devices of a user. For web push the endpoint is the
+-- url of the push service, for the gateway it's the device token.
+CREATE TABLE IF NOT EXISTS push_subscriptions(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  kind push_kind NOT NULL,
+  endpoint text NOT NULL,
+  -- web push keys of the browser, base64url
+  p256dh varchar(128),
+  auth varchar(64),
+  -- platform passed to the gateway, e.g. fcm or apns
+  platform varchar(32),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (user_id, endpoint)
+);
+
+-- chats a user doesn't get pushes for, except mentions
+CREATE TABLE IF NOT EXISTS chat_mutes(
+  user_id bigint NOT NULL REFERENCES users(id),
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
+
+-- messages already pushed, so only one notify_server instance pushes them
+CREATE TABLE IF NOT EXISTS message_pushes(
+  message_id bigint PRIMARY KEY,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
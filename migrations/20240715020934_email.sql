@@ -0,0 +1,32 @@
+-- Add migration script here
+-- the latest message a user has read in a chat
+CREATE TABLE IF NOT EXISTS chat_reads(
+  user_id bigint NOT NULL REFERENCES users(id),
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  last_read_message_id bigint NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
+
+-- what a user gets emails about, created on first use
+CREATE TABLE IF NOT EXISTS email_preferences(
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  locale varchar(16) NOT NULL DEFAULT 'en',
+  direct_messages boolean NOT NULL DEFAULT TRUE,
+  mentions boolean NOT NULL DEFAULT TRUE,
+  digest boolean NOT NULL DEFAULT FALSE,
+  -- identifies the user in unsubscribe links, which work without signing in
+  unsubscribe_token varchar(64) NOT NULL UNIQUE DEFAULT replace(gen_random_uuid()::text, '-', ''),
+  last_digest_at timestamptz,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- unread messages already emailed to a user
+CREATE TABLE IF NOT EXISTS email_notifications(
+  user_id bigint NOT NULL REFERENCES users(id),
+  message_id bigint NOT NULL,
+  sent_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, message_id)
+);
+
+CREATE INDEX IF NOT EXISTS email_notifications_sent_at_idx ON email_notifications(sent_at);
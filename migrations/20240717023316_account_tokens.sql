@@ -0,0 +1,16 @@
+-- Add migration script here
+-- accounts created before verification existed are trusted
+ALTER TABLE users
+  ADD COLUMN email_verified_at timestamptz,
+  ADD COLUMN token_version int NOT NULL DEFAULT 0;
+
+UPDATE users SET email_verified_at = created_at;
+
+-- single purpose tokens already used, kept until they expire
+CREATE TABLE IF NOT EXISTS used_tokens(
+  id varchar(64) PRIMARY KEY,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS used_tokens_expires_at_idx ON used_tokens(expires_at);
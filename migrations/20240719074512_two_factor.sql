@@ -0,0 +1,19 @@
+-- Add migration script here
+ALTER TABLE users
+  -- base32 TOTP secret, set on enrollment
+  ADD COLUMN totp_secret varchar(64),
+  -- set once the enrollment is confirmed with a first code
+  ADD COLUMN totp_enabled_at timestamptz,
+  -- time step of the last code used, codes can't be used twice
+  ADD COLUMN totp_last_step bigint NOT NULL DEFAULT 0;
+
+-- sha256 of the recovery codes, each usable once instead of a TOTP code
+CREATE TABLE IF NOT EXISTS recovery_codes(
+  user_id bigint NOT NULL REFERENCES users(id),
+  code_hash char(64) NOT NULL,
+  used_at timestamptz,
+  PRIMARY KEY (user_id, code_hash)
+);
+
+ALTER TABLE workspaces
+  ADD COLUMN require_2fa boolean NOT NULL DEFAULT FALSE;
@@ -0,0 +1,24 @@
+-- Add migration script here
+-- users signed up through single sign-on have no password
+ALTER TABLE users
+  ALTER COLUMN password_hash DROP NOT NULL;
+
+-- pending authorization requests, consumed by the callback
+CREATE TABLE IF NOT EXISTS oidc_states(
+  state varchar(64) PRIMARY KEY,
+  provider varchar(64) NOT NULL,
+  nonce varchar(64) NOT NULL,
+  code_verifier varchar(128) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- accounts of the identity providers linked to users
+CREATE TABLE IF NOT EXISTS user_identities(
+  issuer varchar(256) NOT NULL,
+  subject varchar(256) NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identities_user_id_idx ON user_identities(user_id);
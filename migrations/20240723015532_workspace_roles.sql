@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TYPE workspace_role AS ENUM (
+  'member',
+  'admin'
+);
+
+ALTER TABLE users
+  ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
@@ -0,0 +1,20 @@
+-- Add migration script here
+-- failed sign ins by email, IP or 2FA challenge, shared by all the replicas
+CREATE TABLE IF NOT EXISTS signin_attempts(
+  key varchar(320) PRIMARY KEY,
+  failures integer NOT NULL DEFAULT 0,
+  last_failed_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  locked_until timestamptz
+);
+
+-- security relevant events, e.g. failed sign ins
+CREATE TABLE IF NOT EXISTS audit_log(
+  id bigserial PRIMARY KEY,
+  user_id bigint REFERENCES users(id),
+  action varchar(64) NOT NULL,
+  ip varchar(45),
+  detail jsonb NOT NULL DEFAULT '{}',
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user_id_idx ON audit_log(user_id, created_at);
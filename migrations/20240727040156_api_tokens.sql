@@ -0,0 +1,25 @@
+-- Add migration script here
+CREATE TYPE api_scope AS ENUM ('chat:read', 'chat:write', 'files:write', 'users:read');
+
+-- bots sign in with API tokens only, managed by the user who created them
+ALTER TABLE users
+  ADD COLUMN is_bot boolean NOT NULL DEFAULT FALSE,
+  ADD COLUMN created_by bigint REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE TABLE IF NOT EXISTS api_tokens(
+  id bigserial PRIMARY KEY,
+  -- the user who manages the token
+  owner_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- the user the token acts as, the owner itself or one of its bots
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name varchar(64) NOT NULL,
+  -- sha256 of the token in hex
+  token_hash char(64) NOT NULL UNIQUE,
+  scopes api_scope[] NOT NULL,
+  expires_at timestamptz,
+  last_used_at timestamptz,
+  revoked_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS api_tokens_owner_id_idx ON api_tokens(owner_id);
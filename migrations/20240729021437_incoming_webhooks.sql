@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS incoming_webhooks(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  -- the bot the messages are sent as
+  bot_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name varchar(64) NOT NULL,
+  -- sha256 of the token of the url in hex
+  token_hash char(64) NOT NULL UNIQUE,
+  enabled boolean NOT NULL DEFAULT TRUE,
+  created_by bigint REFERENCES users(id) ON DELETE SET NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS incoming_webhooks_chat_id_idx ON incoming_webhooks(chat_id);
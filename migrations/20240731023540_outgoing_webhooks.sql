@@ -0,0 +1,40 @@
+-- Add migration script here
+CREATE TYPE webhook_event AS ENUM ('NewMessage', 'NewChat', 'MemberJoined');
+
+CREATE TYPE webhook_delivery_status AS ENUM ('pending', 'delivered', 'dead');
+
+CREATE TABLE IF NOT EXISTS outgoing_webhooks(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  url varchar(2048) NOT NULL,
+  -- key of the HMAC-SHA256 signatures, shared with the receiver
+  secret varchar(64) NOT NULL,
+  events webhook_event[] NOT NULL,
+  enabled boolean NOT NULL DEFAULT TRUE,
+  created_by bigint REFERENCES users(id) ON DELETE SET NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS outgoing_webhooks_ws_id_idx ON outgoing_webhooks(ws_id);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries(
+  id bigserial PRIMARY KEY,
+  webhook_id bigint NOT NULL REFERENCES outgoing_webhooks(id) ON DELETE CASCADE,
+  event webhook_event NOT NULL,
+  -- identifies the event, every notify_server instance sees it
+  event_key varchar(128) NOT NULL,
+  payload jsonb NOT NULL,
+  status webhook_delivery_status NOT NULL DEFAULT 'pending',
+  attempts int NOT NULL DEFAULT 0,
+  next_attempt_at timestamptz NOT NULL DEFAULT now(),
+  -- response status of the last attempt, null if it failed before
+  response_status int,
+  last_error text,
+  delivered_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id_idx ON webhook_deliveries(webhook_id, id DESC);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_event_key_idx ON webhook_deliveries(webhook_id, event_key);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_pending_idx ON webhook_deliveries(next_attempt_at)
+  WHERE status = 'pending';
@@ -0,0 +1,32 @@
+-- Add migration script here
+ALTER TABLE messages
+  ADD COLUMN blocks jsonb,
+  ADD COLUMN updated_at timestamptz;
+
+-- where the clicks on the buttons and selects of the messages of a bot are sent
+CREATE TABLE IF NOT EXISTS bot_interactions(
+  bot_id bigint PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  url varchar(2048) NOT NULL,
+  -- key of the HMAC-SHA256 signatures, shared with the bot
+  secret varchar(64) NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE OR REPLACE FUNCTION notify_message_updated()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF current_setting('chat.event_mode', TRUE) = 'outbox' THEN
+    RETURN NEW;
+  END IF;
+  PERFORM
+    pg_notify('message_updated', json_build_object('op', TG_OP, 'message_id', NEW.id, 'chat_id', NEW.chat_id, 'sender_id', NEW.sender_id, 'created_at', NEW.created_at)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER message_updated_trigger
+AFTER UPDATE OF content, blocks ON messages
+FOR EACH ROW
+EXECUTE FUNCTION notify_message_updated();
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- content rendered by the server, older messages are rendered by a job of chat_server
+ALTER TABLE messages
+  ADD COLUMN content_html text,
+  ADD COLUMN content_text text;
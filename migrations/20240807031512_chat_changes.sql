@@ -0,0 +1,40 @@
+-- Add migration script here
+-- the chat before each update or delete. notify_server tells the removed members
+-- and the members of a deleted chat even if it never cached the chat before.
+CREATE TABLE IF NOT EXISTS chat_changes(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL,
+  old jsonb NOT NULL,
+  tx_id bigint NOT NULL DEFAULT txid_current(),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS chat_changes_created_at_idx ON chat_changes(created_at);
+
+-- the change is recorded in outbox mode as well, chat_server refers to it in the
+-- outbox event
+CREATE OR REPLACE FUNCTION notify_chat_change()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  change_id bigint;
+BEGIN
+  IF TG_OP <> 'INSERT' THEN
+    INSERT INTO chat_changes(chat_id, old)
+      VALUES (OLD.id, to_jsonb(OLD))
+    RETURNING
+      id INTO change_id;
+  END IF;
+  IF current_setting('chat.event_mode', TRUE) = 'outbox' THEN
+    RETURN NEW;
+  END IF;
+  PERFORM
+    pg_notify('chat_change', json_build_object('op', TG_OP, 'chat_id', CASE WHEN TG_OP = 'DELETE' THEN
+        OLD.id
+      ELSE
+        NEW.id
+      END, 'change_id', change_id)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
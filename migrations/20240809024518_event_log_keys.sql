@@ -0,0 +1,7 @@
+-- Add migration script here
+-- every notify_server instance delivers the same events, the key identifies an
+-- event so it is logged once with a single id
+ALTER TABLE event_log
+  ADD COLUMN IF NOT EXISTS event_key text;
+
+CREATE UNIQUE INDEX IF NOT EXISTS event_log_event_key_idx ON event_log(event_key);
@@ -0,0 +1,22 @@
+-- Add migration script here
+-- deliveries are queued by chat_server in the transaction of the change, once
+-- per event. Keys queued twice before are made unique to keep their history.
+UPDATE
+  webhook_deliveries d
+SET
+  event_key = d.event_key || ':' || d.id
+WHERE
+  EXISTS (
+    SELECT
+      1
+    FROM
+      webhook_deliveries e
+    WHERE
+      e.webhook_id = d.webhook_id
+      AND e.event_key = d.event_key
+      AND e.id < d.id);
+
+DROP INDEX IF EXISTS webhook_deliveries_event_key_idx;
+
+ALTER TABLE webhook_deliveries
+  ADD CONSTRAINT webhook_deliveries_event_key_key UNIQUE (webhook_id, event_key);
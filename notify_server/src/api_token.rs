@@ -0,0 +1,50 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chat_core::{Scope, Scopes};
+
+use crate::AppError;
+
+/// Let API tokens receive events and send typing notifications only, the
+/// other routes are left to the sessions of signed in users
+pub(crate) async fn verify_scope(req: Request, next: Next) -> Response {
+    let scopes = req.extensions().get::<Scopes>().unwrap();
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|v| v.as_str())
+        .unwrap_or_else(|| req.uri().path());
+    match required_scope(path) {
+        _ if scopes.is_session() => next.run(req).await,
+        Some(scope) if scopes.allows(scope) => next.run(req).await,
+        Some(scope) => {
+            let msg = format!("the token needs the {} scope", scope);
+            AppError::PermissionDenied(msg).into_response()
+        }
+        None => AppError::PermissionDenied("API tokens can't use this route".to_string())
+            .into_response(),
+    }
+}
+
+fn required_scope(path: &str) -> Option<Scope> {
+    match path {
+        "/events" | "/ws" => Some(Scope::ChatRead),
+        "/chats/:id/typing" => Some(Scope::ChatWrite),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_scope_should_follow_routes() {
+        assert_eq!(required_scope("/events"), Some(Scope::ChatRead));
+        assert_eq!(required_scope("/chats/:id/typing"), Some(Scope::ChatWrite));
+        assert_eq!(required_scope("/chats/:id/mute"), None);
+        assert_eq!(required_scope("/push/subscriptions"), None);
+    }
+}
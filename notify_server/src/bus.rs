@@ -0,0 +1,303 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::{stream::SelectAll, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// messages kept for slow in-memory subscribers before they lag
+const MEMORY_BUS_CAPACITY: usize = 4096;
+const DEFAULT_NATS_PREFIX: &str = "chat.";
+
+/// Where notify_server instances exchange notifications. Only the fan-out goes
+/// through the bus, chats, the event log and presence are still in Postgres.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BusConfig {
+    /// Postgres LISTEN/NOTIFY on the server database
+    #[default]
+    Postgres,
+    /// in-process only, for tests and single instance deployments. The database
+    /// notifications of chat_server are forwarded to it.
+    Memory,
+    /// a NATS server, subjects are `<prefix><channel>`
+    Nats {
+        url: String,
+        #[serde(default = "default_nats_prefix")]
+        prefix: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusMessage {
+    pub channel: String,
+    pub payload: String,
+}
+
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    async fn publish(&self, channel: &str, payload: &str) -> anyhow::Result<()>;
+
+    async fn subscribe(&self, channels: &[&str]) -> anyhow::Result<Box<dyn Subscription>>;
+
+    /// Whether the database notifications reach the bus by themselves. Other
+    /// buses need them to be forwarded.
+    fn is_postgres(&self) -> bool {
+        false
+    }
+
+    /// Whether the bus is shared by several notify_server instances
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+#[async_trait]
+pub trait Subscription: Send {
+    /// The next message. `Ok(None)` means the subscription was interrupted and
+    /// messages may have been missed, the caller should subscribe again.
+    async fn recv(&mut self) -> anyhow::Result<Option<BusMessage>>;
+}
+
+fn default_nats_prefix() -> String {
+    DEFAULT_NATS_PREFIX.to_string()
+}
+
+impl BusConfig {
+    pub async fn connect(&self, db_url: &str, pool: PgPool) -> anyhow::Result<Arc<dyn EventBus>> {
+        let bus: Arc<dyn EventBus> = match self {
+            Self::Postgres => Arc::new(PgBus::new(db_url, pool)),
+            Self::Memory => Arc::new(MemoryBus::default()),
+            Self::Nats { url, prefix } => Arc::new(NatsBus::connect(url, prefix).await?),
+        };
+        Ok(bus)
+    }
+}
+
+/// Postgres LISTEN/NOTIFY
+pub struct PgBus {
+    db_url: String,
+    pool: PgPool,
+}
+
+struct PgSubscription(PgListener);
+
+impl PgBus {
+    pub fn new(db_url: impl Into<String>, pool: PgPool) -> Self {
+        Self {
+            db_url: db_url.into(),
+            pool,
+        }
+    }
+}
+
+#[async_trait]
+impl EventBus for PgBus {
+    async fn publish(&self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(channel)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, channels: &[&str]) -> anyhow::Result<Box<dyn Subscription>> {
+        let mut listener = PgListener::connect(&self.db_url).await?;
+        listener.listen_all(channels.iter().copied()).await?;
+        Ok(Box::new(PgSubscription(listener)))
+    }
+
+    fn is_postgres(&self) -> bool {
+        true
+    }
+}
+
+#[async_trait]
+impl Subscription for PgSubscription {
+    async fn recv(&mut self) -> anyhow::Result<Option<BusMessage>> {
+        Ok(self.0.try_recv().await?.map(|notif| BusMessage {
+            channel: notif.channel().to_string(),
+            payload: notif.payload().to_string(),
+        }))
+    }
+}
+
+/// In-process bus, messages don't leave the instance
+pub struct MemoryBus {
+    tx: broadcast::Sender<Arc<BusMessage>>,
+}
+
+struct MemorySubscription {
+    rx: broadcast::Receiver<Arc<BusMessage>>,
+    channels: Vec<String>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(MEMORY_BUS_CAPACITY).0,
+        }
+    }
+}
+
+#[async_trait]
+impl EventBus for MemoryBus {
+    async fn publish(&self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        let msg = BusMessage {
+            channel: channel.to_string(),
+            payload: payload.to_string(),
+        };
+        // no subscriber is not an error, nobody is listening yet
+        let _ = self.tx.send(Arc::new(msg));
+        Ok(())
+    }
+
+    async fn subscribe(&self, channels: &[&str]) -> anyhow::Result<Box<dyn Subscription>> {
+        Ok(Box::new(MemorySubscription {
+            rx: self.tx.subscribe(),
+            channels: channels.iter().map(|v| v.to_string()).collect(),
+        }))
+    }
+
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl Subscription for MemorySubscription {
+    async fn recv(&mut self) -> anyhow::Result<Option<BusMessage>> {
+        loop {
+            match self.rx.recv().await {
+                Ok(msg) if self.channels.contains(&msg.channel) => return Ok(Some((*msg).clone())),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => return Ok(None),
+                Err(RecvError::Closed) => anyhow::bail!("memory bus closed"),
+            }
+        }
+    }
+}
+
+/// NATS core pub/sub, shared by all instances connected to the same server
+pub struct NatsBus {
+    client: async_nats::Client,
+    prefix: String,
+}
+
+struct NatsSubscription {
+    subscribers: SelectAll<async_nats::Subscriber>,
+    prefix: String,
+}
+
+impl NatsBus {
+    pub async fn connect(url: &str, prefix: &str) -> anyhow::Result<Self> {
+        let client = async_nats::connect(url).await?;
+        Ok(Self {
+            client,
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EventBus for NatsBus {
+    async fn publish(&self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        let subject = format!("{}{}", self.prefix, channel);
+        self.client
+            .publish(subject, payload.to_string().into())
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, channels: &[&str]) -> anyhow::Result<Box<dyn Subscription>> {
+        let mut subscribers = SelectAll::new();
+        for channel in channels {
+            let subject = format!("{}{}", self.prefix, channel);
+            subscribers.push(self.client.subscribe(subject).await?);
+        }
+        // make sure the server knows the subscriptions before anything is published
+        self.client.flush().await?;
+        Ok(Box::new(NatsSubscription {
+            subscribers,
+            prefix: self.prefix.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Subscription for NatsSubscription {
+    async fn recv(&mut self) -> anyhow::Result<Option<BusMessage>> {
+        let Some(msg) = self.subscribers.next().await else {
+            return Ok(None);
+        };
+        let channel = msg
+            .subject
+            .strip_prefix(self.prefix.as_str())
+            .unwrap_or(&msg.subject)
+            .to_string();
+        let payload = String::from_utf8(msg.payload.to_vec())?;
+        Ok(Some(BusMessage { channel, payload }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn publish_and_receive(bus: &dyn EventBus) -> anyhow::Result<()> {
+        let mut sub = bus.subscribe(&["ephemeral", "outbox"]).await?;
+        bus.publish("chat_change", "{}").await?;
+        bus.publish("ephemeral", r#"{"user_id":1}"#).await?;
+        bus.publish("outbox", "").await?;
+
+        let msg = sub.recv().await?.expect("subscription interrupted");
+        assert_eq!(msg.channel, "ephemeral");
+        assert_eq!(msg.payload, r#"{"user_id":1}"#);
+        let msg = sub.recv().await?.expect("subscription interrupted");
+        assert_eq!(msg.channel, "outbox");
+        assert_eq!(msg.payload, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_bus_should_deliver_subscribed_channels() -> anyhow::Result<()> {
+        publish_and_receive(&MemoryBus::default()).await
+    }
+
+    #[tokio::test]
+    async fn memory_bus_should_report_lagged_subscription() -> anyhow::Result<()> {
+        let bus = MemoryBus::default();
+        let mut sub = bus.subscribe(&["outbox"]).await?;
+        for _ in 0..=MEMORY_BUS_CAPACITY {
+            bus.publish("outbox", "").await?;
+        }
+        assert_eq!(sub.recv().await?, None);
+        Ok(())
+    }
+
+    // needs a local nats-server, e.g. NATS_URL=nats://localhost:4222 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn nats_bus_should_deliver_subscribed_channels() -> anyhow::Result<()> {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".into());
+        let prefix = format!("test-{}.", uuid::Uuid::now_v7());
+        publish_and_receive(&NatsBus::connect(&url, &prefix).await?).await
+    }
+
+    #[test]
+    fn bus_config_should_parse() {
+        let config: BusConfig = serde_yaml::from_str("type: memory").unwrap();
+        assert_eq!(config, BusConfig::Memory);
+        let config: BusConfig =
+            serde_yaml::from_str("type: nats\nurl: nats://localhost:4222").unwrap();
+        assert_eq!(
+            config,
+            BusConfig::Nats {
+                url: "nats://localhost:4222".into(),
+                prefix: "chat.".into()
+            }
+        );
+    }
+}
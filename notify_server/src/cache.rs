@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use chat_core::Chat;
+use dashmap::DashMap;
+
+use crate::{error::AppError, AppState};
+
+/// the cache is simply emptied when it grows beyond this
+const MAX_CACHED_CHATS: usize = 10_000;
+
+/// Chats loaded by notify_server, kept up to date by chat_change notifications,
+/// so the members of a large chat are not loaded for every message.
+#[derive(Debug, Default)]
+pub struct ChatCache {
+    chats: DashMap<i64, Arc<Chat>>,
+}
+
+impl ChatCache {
+    pub(crate) fn get(&self, chat_id: i64) -> Option<Arc<Chat>> {
+        self.chats.get(&chat_id).map(|v| v.clone())
+    }
+
+    pub(crate) fn insert(&self, chat: Arc<Chat>) {
+        if self.chats.len() >= MAX_CACHED_CHATS && !self.chats.contains_key(&chat.id) {
+            self.chats.clear();
+        }
+        self.chats.insert(chat.id, chat);
+    }
+
+    pub(crate) fn remove(&self, chat_id: i64) -> Option<Arc<Chat>> {
+        self.chats.remove(&chat_id).map(|(_, v)| v)
+    }
+
+    pub(crate) fn clear(&self) {
+        self.chats.clear();
+    }
+}
+
+impl AppState {
+    /// Get a chat from the cache, or load it from db
+    pub(crate) async fn get_chat(&self, chat_id: i64) -> Result<Option<Arc<Chat>>, AppError> {
+        if let Some(chat) = self.chats.get(chat_id) {
+            return Ok(Some(chat));
+        }
+        let Some(chat) = self.load_chats(&[chat_id]).await?.pop() else {
+            return Ok(None);
+        };
+        let chat = Arc::new(chat);
+        self.chats.insert(chat.clone());
+        Ok(Some(chat))
+    }
+
+    /// Load chats from db in one query, bypassing the cache
+    pub(crate) async fn load_chats(&self, chat_ids: &[i64]) -> Result<Vec<Chat>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, message_ttl, topic, deleted_at, created_at
+            FROM chats
+            WHERE id = ANY($1)
+            "#,
+        )
+        .bind(chat_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::ChatType;
+
+    fn chat(id: i64) -> Arc<Chat> {
+        Arc::new(Chat {
+            id,
+            ws_id: 1,
+            deleted_at: None,
+            name: None,
+            r#type: ChatType::Group,
+            members: vec![1, 2, 3],
+            message_ttl: None,
+            topic: None,
+            created_at: chrono::Local::now(),
+        })
+    }
+
+    #[test]
+    fn chat_cache_should_be_bounded() {
+        let cache = ChatCache::default();
+        for id in 0..MAX_CACHED_CHATS as i64 {
+            cache.insert(chat(id));
+        }
+        assert!(cache.get(0).is_some());
+
+        // replacing a cached chat keeps the others
+        cache.insert(chat(0));
+        assert!(cache.get(1).is_some());
+
+        cache.insert(chat(MAX_CACHED_CHATS as i64));
+        assert!(cache.get(0).is_none());
+        assert!(cache.get(MAX_CACHED_CHATS as i64).is_some());
+
+        assert!(cache.remove(MAX_CACHED_CHATS as i64).is_some());
+        assert!(cache.get(MAX_CACHED_CHATS as i64).is_none());
+    }
+}
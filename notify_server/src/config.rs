@@ -1,11 +1,17 @@
 use anyhow::{bail, Result};
+use chat_core::middlewares::{RateLimit, RateLimitKey};
 use serde::{Deserialize, Serialize};
 use std::{env, fs::File};
 
+use crate::BusConfig;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    /// pushes to offline users, disabled when nothing is configured
+    #[serde(default)]
+    pub push: PushConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,10 +19,78 @@ pub struct AuthConfig {
     pub pk: String,
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PushConfig {
+    /// VAPID private key for web push, in PEM
+    pub vapid_sk: Option<String>,
+    /// contact of the server for push services
+    #[serde(default = "default_vapid_subject")]
+    pub vapid_subject: String,
+    pub gateway: Option<GatewayConfig>,
+}
+
+/// A generic HTTP push gateway, e.g. in front of FCM or APNs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GatewayConfig {
+    pub url: String,
+    /// sent as a bearer token
+    pub token: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// how long in seconds events are kept for replay
+    #[serde(default = "default_event_retention")]
+    pub event_retention: u64,
+    /// SSE and websocket connections of a user to one instance
+    #[serde(default = "default_max_connections_per_user")]
+    pub max_connections_per_user: usize,
+    /// how notifications reach the notify_server instances
+    #[serde(default)]
+    pub bus: BusConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimits,
+}
+
+/// Token buckets of a user, `burst` requests at once refilled by `per_minute`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// SSE and websocket connections opened, reconnects included
+    pub connect: RateLimit,
+    /// typing, mutes and push subscriptions
+    pub api: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            connect: RateLimit {
+                burst: 10,
+                per_minute: 30,
+                key: RateLimitKey::User,
+            },
+            api: RateLimit {
+                burst: 60,
+                per_minute: 300,
+                key: RateLimitKey::User,
+            },
+        }
+    }
+}
+
+fn default_event_retention() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_max_connections_per_user() -> usize {
+    16
+}
+
+fn default_vapid_subject() -> String {
+    "mailto:admin@example.com".to_string()
 }
 
 impl AppConfig {
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse, Extension, Json};
+use chat_core::User;
+use serde::Serialize;
+use sqlx::FromRow;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::{error::AppError, AppState, EventEnvelope, UserMap};
+
+/// events buffered per user, a connection further behind lags and must resync
+const CHANNEL_CAPACITY: usize = 256;
+
+/// The channel of a user, shared by all their connections to this instance
+#[derive(Debug)]
+pub struct UserChannel {
+    pub(crate) tx: broadcast::Sender<Arc<EventEnvelope>>,
+    connections: usize,
+}
+
+/// Close the connection when the SSE stream or the websocket is dropped
+pub(crate) struct ConnectionGuard {
+    state: AppState,
+    user_id: u64,
+}
+
+/// Connections of a user summed over all notify_server instances
+#[derive(Debug, Serialize, FromRow, PartialEq)]
+pub struct UserConnections {
+    pub user_id: i64,
+    pub connections: i64,
+}
+
+impl UserChannel {
+    pub(crate) fn connections(&self) -> usize {
+        self.connections
+    }
+}
+
+impl AppState {
+    /// Open a connection of the user, and subscribe to their events
+    pub(crate) async fn connect(
+        &self,
+        user_id: u64,
+    ) -> Result<(broadcast::Receiver<Arc<EventEnvelope>>, ConnectionGuard), AppError> {
+        let max = self.config.server.max_connections_per_user;
+        let (rx, count) = add_connection(&self.users, user_id, max)?;
+        // built before any await, the slot is released even if the request is cancelled
+        let guard = ConnectionGuard {
+            state: self.clone(),
+            user_id,
+        };
+        info!("User {} connected, {} connections", user_id, count);
+        if let Err(e) = self.sync_connections(user_id, count).await {
+            warn!("Failed to update presence of user {}: {}", user_id, e);
+        }
+        Ok((rx, guard))
+    }
+
+    async fn load_connections(&self, ws_id: i64) -> Result<Vec<UserConnections>, AppError> {
+        let connections = sqlx::query_as(
+            r#"
+            SELECT pc.user_id, sum(pc.connections)::bigint AS connections
+            FROM presence_connections pc
+            JOIN users u ON u.id = pc.user_id
+            WHERE u.ws_id = $1
+            GROUP BY pc.user_id
+            ORDER BY pc.user_id
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(connections)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let user_id = self.user_id;
+        let count = remove_connection(&state.users, user_id);
+        info!("User {} disconnected, {} connections left", user_id, count);
+        tokio::spawn(async move {
+            if let Err(e) = state.sync_connections(user_id, count).await {
+                warn!("Failed to update presence of user {}: {}", user_id, e);
+            }
+        });
+    }
+}
+
+/// Add a connection of the user, up to `max`. Returns the receiver of the
+/// connection and the number of connections of the user.
+fn add_connection(
+    users: &UserMap,
+    user_id: u64,
+    max: usize,
+) -> Result<(broadcast::Receiver<Arc<EventEnvelope>>, usize), AppError> {
+    let mut channel = users.entry(user_id).or_insert_with(|| UserChannel {
+        tx: broadcast::channel(CHANNEL_CAPACITY).0,
+        connections: 0,
+    });
+    if channel.connections >= max {
+        return Err(AppError::TooManyConnections(max));
+    }
+    channel.connections += 1;
+    Ok((channel.tx.subscribe(), channel.connections))
+}
+
+/// Remove a connection of the user, the channel goes with the last one.
+/// Returns the number of connections left.
+fn remove_connection(users: &UserMap, user_id: u64) -> usize {
+    let count = match users.get_mut(&user_id) {
+        Some(mut channel) => {
+            channel.connections = channel.connections.saturating_sub(1);
+            channel.connections
+        }
+        None => 0,
+    };
+    if count == 0 {
+        // a new connection may have come in between
+        users.remove_if(&user_id, |_, v| v.connections == 0);
+    }
+    count
+}
+
+/// Connection counts of the users of the workspace, for its owner and admins
+pub(crate) async fn connections_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let (admin,): (bool,) = sqlx::query_as(
+        r#"
+        SELECT EXISTS (
+          SELECT 1 FROM workspaces w JOIN users u ON u.ws_id = w.id
+          WHERE u.id = $1 AND (w.owner_id = u.id OR u.role = 'admin')
+        )
+        "#,
+    )
+    .bind(user.id)
+    .fetch_one(&state.pool)
+    .await?;
+    if !admin {
+        return Err(AppError::PermissionDenied(
+            "only workspace admins can see connections".to_string(),
+        ));
+    }
+
+    let connections = state.load_connections(user.ws_id).await?;
+    Ok(Json(connections))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dashmap::DashMap;
+
+    #[test]
+    fn connections_should_be_counted_and_cleaned_up() {
+        let users: UserMap = Arc::new(DashMap::new());
+        let (_rx1, count) = add_connection(&users, 1, 2).unwrap();
+        assert_eq!(count, 1);
+        let (_rx2, count) = add_connection(&users, 1, 2).unwrap();
+        assert_eq!(count, 2);
+        assert!(matches!(
+            add_connection(&users, 1, 2),
+            Err(AppError::TooManyConnections(2))
+        ));
+
+        assert_eq!(remove_connection(&users, 1), 1);
+        assert!(users.contains_key(&1));
+        assert_eq!(remove_connection(&users, 1), 0);
+        assert!(users.is_empty());
+        assert_eq!(remove_connection(&users, 1), 0);
+    }
+
+    #[test]
+    fn connections_should_share_the_user_channel() {
+        let users: UserMap = Arc::new(DashMap::new());
+        let (mut rx1, _) = add_connection(&users, 1, 2).unwrap();
+        let (mut rx2, _) = add_connection(&users, 1, 2).unwrap();
+        let tx = users.get(&1).unwrap().tx.clone();
+        tx.send(EventEnvelope::resync()).unwrap();
+        assert!(rx1.try_recv().is_ok());
+        assert!(rx2.try_recv().is_ok());
+    }
+}
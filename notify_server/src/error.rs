@@ -1,4 +1,4 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::Json;
 use axum::response::{IntoResponse, Response};
 use serde::{Deserialize, Serialize};
@@ -16,6 +16,33 @@ pub enum AppError {
 
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
+
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("event bus error: {0}")]
+    BusError(String),
+
+    #[error("chat error: {0}")]
+    ChatError(String),
+
+    #[error("invalid filter: {0}")]
+    InvalidFilter(String),
+
+    #[error("push error: {0}")]
+    PushError(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("session revoked, please sign in again")]
+    SessionRevoked,
+
+    #[error("too many connections, at most {0} per user")]
+    TooManyConnections(usize),
+
+    #[error("too many requests, retry after {0}s")]
+    TooManyRequests(u64),
 }
 
 impl ErrorOutput {
@@ -31,8 +58,23 @@ impl IntoResponse for AppError {
         let status = match &self {
             Self::JwtError(_) => StatusCode::FORBIDDEN,
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::BusError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ChatError(_) => StatusCode::FORBIDDEN,
+            Self::InvalidFilter(_) => StatusCode::BAD_REQUEST,
+            Self::PushError(_) => StatusCode::BAD_REQUEST,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::SessionRevoked => StatusCode::UNAUTHORIZED,
+            Self::TooManyConnections(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
         };
 
-        (status, Json(ErrorOutput::new(self.to_string()))).into_response()
+        let body = Json(ErrorOutput::new(self.to_string()));
+        match self {
+            Self::TooManyRequests(secs) => {
+                (status, [(header::RETRY_AFTER, secs.to_string())], body).into_response()
+            }
+            _ => (status, body).into_response(),
+        }
     }
 }
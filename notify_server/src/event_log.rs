@@ -0,0 +1,216 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{error::AppError, AppEvent, AppState};
+
+const EVENT_LOG_TRIM_INTERVAL: Duration = Duration::from_secs(60);
+/// advisory lock taken by every append, so ids are committed in order
+const EVENT_LOG_LOCK_ID: i64 = 0x6576656e746c6f67;
+/// clients missing more events than this must resync
+const MAX_REPLAY_EVENTS: usize = 500;
+
+/// An event as delivered to clients. Transient events (typing, presence, resync)
+/// are not logged and have no id.
+#[derive(Debug, Serialize)]
+pub struct EventEnvelope {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    #[serde(flatten)]
+    pub event: AppEvent,
+}
+
+impl From<AppEvent> for EventEnvelope {
+    fn from(event: AppEvent) -> Self {
+        Self { id: None, event }
+    }
+}
+
+impl EventEnvelope {
+    pub(crate) fn resync() -> Arc<Self> {
+        Arc::new(AppEvent::Resync.into())
+    }
+}
+
+impl AppState {
+    /// Store the event in the event log, and return its id. An event with the
+    /// key of a logged one, appended by another instance, gets the same id.
+    pub(crate) async fn append_event(
+        &self,
+        user_ids: &HashSet<u64>,
+        event: &AppEvent,
+        key: Option<&str>,
+    ) -> Result<i64, AppError> {
+        let user_ids: Vec<i64> = user_ids.iter().map(|id| *id as i64).collect();
+        let event = serde_json::to_string(event).expect("Failed to serialize event");
+
+        // ids are taken under the lock, a replay after `id` never misses an event
+        // committed later with a smaller id
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(EVENT_LOG_LOCK_ID)
+            .execute(&mut *tx)
+            .await?;
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO event_log (user_ids, event, event_key)
+            VALUES ($1, $2::jsonb, $3)
+            ON CONFLICT (event_key) DO UPDATE
+              SET user_ids = ARRAY(SELECT DISTINCT unnest(event_log.user_ids || EXCLUDED.user_ids))
+            RETURNING id
+            "#,
+        )
+        .bind(&user_ids)
+        .bind(event)
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(id)
+    }
+
+    /// Load the events of the user after `last_id`. If some of them were already
+    /// trimmed, or there are too many of them, a single `Resync` is returned.
+    pub(crate) async fn replay_events(
+        &self,
+        user_id: u64,
+        last_id: i64,
+    ) -> Result<Vec<Arc<EventEnvelope>>, AppError> {
+        // the oldest id still in the log, or the next id if the log is empty
+        let (oldest,): (i64,) = sqlx::query_as(
+            r#"
+            SELECT COALESCE(
+              (SELECT min(id) FROM event_log),
+              (SELECT CASE WHEN is_called THEN last_value + 1 ELSE last_value END
+               FROM event_log_id_seq)
+            )
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if last_id + 1 < oldest {
+            return Ok(vec![EventEnvelope::resync()]);
+        }
+
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            r#"
+            SELECT id, event::text
+            FROM event_log
+            WHERE id > $1 AND user_ids @> ARRAY[$2]::bigint[]
+            ORDER BY id
+            LIMIT $3
+            "#,
+        )
+        .bind(last_id)
+        .bind(user_id as i64)
+        .bind(MAX_REPLAY_EVENTS as i64 + 1)
+        .fetch_all(&self.pool)
+        .await?;
+        if rows.len() > MAX_REPLAY_EVENTS {
+            return Ok(vec![EventEnvelope::resync()]);
+        }
+
+        let mut events = Vec::with_capacity(rows.len());
+        for (id, event) in rows {
+            match serde_json::from_str(&event) {
+                Ok(event) => events.push(Arc::new(EventEnvelope {
+                    id: Some(id),
+                    event,
+                })),
+                Err(e) => {
+                    warn!("Failed to load event {}: {}", id, e);
+                    return Ok(vec![EventEnvelope::resync()]);
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    async fn trim_event_log(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            "DELETE FROM event_log WHERE created_at < now() - $1 * interval '1 second'",
+        )
+        .bind(self.config.server.event_retention as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(ret.rows_affected())
+    }
+
+    /// Old chats are only needed until their change is delivered
+    async fn trim_chat_changes(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            "DELETE FROM chat_changes WHERE created_at < now() - $1 * interval '1 second'",
+        )
+        .bind(self.config.server.event_retention as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(ret.rows_affected())
+    }
+}
+
+/// Periodically remove events older than the configured retention
+pub(crate) fn setup_event_log_trim(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVENT_LOG_TRIM_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.trim_event_log().await {
+                Ok(0) => {}
+                Ok(n) => info!("Trimmed {} events", n),
+                Err(e) => warn!("Failed to trim event log: {}", e),
+            }
+            if let Err(e) = state.trim_chat_changes().await {
+                warn!("Failed to trim chat changes: {}", e);
+            }
+            if let Err(e) = state.trim_message_pushes().await {
+                warn!("Failed to trim message pushes: {}", e);
+            }
+        }
+    });
+}
+
+/// Drop live events which were already replayed
+pub(crate) fn is_replayed(event: &EventEnvelope, last_id: Option<i64>) -> bool {
+    matches!((event.id, last_id), (Some(id), Some(last_id)) if id <= last_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Typing;
+
+    #[test]
+    fn event_envelope_should_serialize_with_id() {
+        let event = EventEnvelope {
+            id: Some(42),
+            event: AppEvent::TypingStarted(Typing {
+                chat_id: 1,
+                user_id: 2,
+            }),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"id":42,"event":"TypingStarted","chat_id":1,"user_id":2}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&*EventEnvelope::resync()).unwrap(),
+            r#"{"event":"Resync"}"#
+        );
+    }
+
+    #[test]
+    fn is_replayed_should_work() {
+        let event = |id| EventEnvelope {
+            id,
+            event: AppEvent::Resync,
+        };
+        assert!(is_replayed(&event(Some(3)), Some(3)));
+        assert!(!is_replayed(&event(Some(4)), Some(3)));
+        assert!(!is_replayed(&event(Some(4)), None));
+        assert!(!is_replayed(&event(None), Some(3)));
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+use crate::{error::AppError, AppEvent};
+
+/// Which events a connection receives. Events which don't match are not sent,
+/// except `Resync` which is always sent.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct EventFilter {
+    /// only events of these chats, or of all chats if `None`
+    chat_ids: Option<HashSet<i64>>,
+    /// only these event types, e.g. `NewMessage`, or all of them if `None`
+    events: Option<HashSet<String>>,
+    /// never events of these chats
+    muted: HashSet<i64>,
+}
+
+/// Filters as query params, comma separated lists, e.g.
+/// `/events?chat_ids=1,2&events=NewMessage,MessageDeleted&muted=3`
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct FilterParams {
+    chat_ids: Option<String>,
+    events: Option<String>,
+    muted: Option<String>,
+}
+
+impl EventFilter {
+    /// An empty list of chats or events means all of them
+    pub(crate) fn new(
+        chat_ids: Vec<i64>,
+        events: Vec<String>,
+        muted: Vec<i64>,
+    ) -> Result<Self, AppError> {
+        if let Some(name) = events
+            .iter()
+            .find(|v| !AppEvent::NAMES.contains(&v.as_str()))
+        {
+            return Err(AppError::InvalidFilter(format!("unknown event {}", name)));
+        }
+        Ok(Self {
+            chat_ids: (!chat_ids.is_empty()).then(|| chat_ids.into_iter().collect()),
+            events: (!events.is_empty()).then(|| events.into_iter().collect()),
+            muted: muted.into_iter().collect(),
+        })
+    }
+
+    pub(crate) fn accepts(&self, event: &AppEvent) -> bool {
+        if matches!(event, AppEvent::Resync) {
+            return true;
+        }
+        if let Some(events) = &self.events {
+            if !events.contains(event.name()) {
+                return false;
+            }
+        }
+        match (event.chat_id(), &self.chat_ids) {
+            (Some(chat_id), _) if self.muted.contains(&chat_id) => false,
+            (Some(chat_id), Some(chat_ids)) => chat_ids.contains(&chat_id),
+            _ => true,
+        }
+    }
+}
+
+impl TryFrom<FilterParams> for EventFilter {
+    type Error = AppError;
+
+    fn try_from(params: FilterParams) -> Result<Self, Self::Error> {
+        let chat_ids = parse_ids(params.chat_ids.as_deref())?;
+        let events = split(params.events.as_deref()).map(String::from).collect();
+        let muted = parse_ids(params.muted.as_deref())?;
+        Self::new(chat_ids, events, muted)
+    }
+}
+
+fn split(v: Option<&str>) -> impl Iterator<Item = &str> {
+    v.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+fn parse_ids(v: Option<&str>) -> Result<Vec<i64>, AppError> {
+    split(v)
+        .map(|v| {
+            v.parse()
+                .map_err(|_| AppError::InvalidFilter(format!("invalid chat id {}", v)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Typing;
+
+    fn typing(chat_id: i64) -> AppEvent {
+        AppEvent::TypingStarted(Typing {
+            chat_id,
+            user_id: 1,
+        })
+    }
+
+    #[test]
+    fn empty_filter_should_accept_everything() {
+        let filter = EventFilter::default();
+        assert!(filter.accepts(&typing(1)));
+        assert!(filter.accepts(&AppEvent::Resync));
+    }
+
+    #[test]
+    fn filter_should_match_chats_events_and_muted_chats() {
+        let filter = EventFilter::new(vec![1, 2], vec!["TypingStarted".into()], vec![2]).unwrap();
+        assert!(filter.accepts(&typing(1)));
+        // muted
+        assert!(!filter.accepts(&typing(2)));
+        // not subscribed
+        assert!(!filter.accepts(&typing(3)));
+        // other event type
+        assert!(!filter.accepts(&AppEvent::TypingStopped(Typing {
+            chat_id: 1,
+            user_id: 1
+        })));
+        assert!(filter.accepts(&AppEvent::Resync));
+    }
+
+    #[test]
+    fn filter_params_should_parse() {
+        let params = FilterParams {
+            chat_ids: Some("1, 2".into()),
+            events: Some("NewMessage,MessageDeleted".into()),
+            muted: Some("".into()),
+        };
+        let filter = EventFilter::try_from(params).unwrap();
+        assert_eq!(
+            filter,
+            EventFilter::new(
+                vec![1, 2],
+                vec!["NewMessage".into(), "MessageDeleted".into()],
+                vec![]
+            )
+            .unwrap()
+        );
+
+        let params = FilterParams {
+            chat_ids: Some("a".into()),
+            ..Default::default()
+        };
+        assert!(EventFilter::try_from(params).is_err());
+        let params = FilterParams {
+            events: Some("Unknown".into()),
+            ..Default::default()
+        };
+        assert!(EventFilter::try_from(params).is_err());
+    }
+}
@@ -1,29 +1,72 @@
+mod api_token;
+mod bus;
+mod cache;
 mod config;
+mod connection;
 mod error;
+mod event_log;
+mod filter;
+mod listener;
 mod notif;
+mod outbox;
+mod presence;
+mod push;
 mod see;
+mod typing;
+mod webhook;
+mod webpush;
+mod ws;
 
 use std::{ops::Deref, sync::Arc};
 
+use api_token::verify_scope;
+use async_trait::async_trait;
 use axum::{
-    middleware::from_fn_with_state,
+    handler::Handler,
+    middleware::{from_fn, from_fn_with_state},
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{delete, get, post, put},
     Router,
 };
 use chat_core::{
-    middlewares::{verify_token, TokenVerify},
-    DecodingKey,
+    is_api_token,
+    middlewares::{verify_token, RateLimitLayer, TokenVerify},
+    verify_api_token, DecodingKey, Scopes, User,
 };
-pub use config::AppConfig;
+pub use config::{AppConfig, GatewayConfig, PushConfig, RateLimits};
+use connection::connections_handler;
 use dashmap::DashMap;
 use error::AppError;
+use event_log::setup_event_log_trim;
+use listener::health_handler;
+use outbox::setup_outbox_relay;
+use presence::setup_presence_heartbeat;
+use push::{
+    create_push_subscription_handler, delete_push_subscription_handler, mute_chat_handler,
+    unmute_chat_handler, vapid_key_handler,
+};
 use see::sse_handler;
-use tokio::sync::broadcast;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::sync::Notify;
+use typing::{setup_typing_expiry, typing_start_handler, typing_stop_handler};
+use webhook::setup_webhook_delivery;
+use ws::ws_handler;
 
+pub use bus::{BusConfig, BusMessage, EventBus, MemoryBus, NatsBus, PgBus, Subscription};
+pub use cache::ChatCache;
+pub use connection::{UserChannel, UserConnections};
+pub use event_log::EventEnvelope;
+pub use listener::{setup_pg_listener, ListenerStatus};
 pub use notif::*;
+pub use presence::PresenceTracker;
+pub use push::{
+    CreatePushSubscription, GatewayRequest, PushKeys, PushKind, PushNotification, PushSender,
+    PushSubscription,
+};
+pub use typing::{Typing, TypingTracker};
+pub use webhook::WebhookSender;
 
-pub type UserMap = Arc<DashMap<u64, broadcast::Sender<Arc<AppEvent>>>>;
+pub type UserMap = Arc<DashMap<u64, UserChannel>>;
 
 #[derive(Clone)]
 pub struct AppState(Arc<AppStateInner>);
@@ -32,17 +75,62 @@ pub struct AppStateInner {
     pub config: AppConfig,
     users: UserMap,
     dk: DecodingKey,
+    pool: PgPool,
+    bus: Arc<dyn EventBus>,
+    typing: TypingTracker,
+    presence: PresenceTracker,
+    listener: ListenerStatus,
+    chats: ChatCache,
+    push: PushSender,
+    /// woken up when chat_server writes to the outbox
+    outbox: Notify,
+    webhooks: WebhookSender,
 }
 
 const INDEX_HTML: &str = include_str!("../index.html");
 
 pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
-    let state = AppState::new(config);
-    notif::setup_pg_listener(state.clone()).await?;
+    let state = AppState::try_new(config).await?;
+    get_router_with_state(state).await
+}
+
+/// The router of an instance with the given state, e.g. one of several
+/// instances sharing a bus
+pub async fn get_router_with_state(state: AppState) -> anyhow::Result<Router> {
+    setup_pg_listener(state.clone()).await?;
+    setup_typing_expiry(state.clone());
+    setup_presence_heartbeat(state.clone());
+    setup_event_log_trim(state.clone());
+    setup_outbox_relay(state.clone());
+    setup_webhook_delivery(state.clone());
+    let limits = &state.config.server.rate_limit;
+    let connect = RateLimitLayer::new(limits.connect);
     let app = Router::new()
-        .route("/events", get(sse_handler))
+        .route("/events", get(sse_handler.layer(connect.clone())))
+        .route("/ws", get(ws_handler.layer(connect)))
+        .route("/connections", get(connections_handler))
+        .route(
+            "/chats/:id/typing",
+            post(typing_start_handler).delete(typing_stop_handler),
+        )
+        .route(
+            "/chats/:id/mute",
+            put(mute_chat_handler).delete(unmute_chat_handler),
+        )
+        .route("/push/vapid_key", get(vapid_key_handler))
+        .route(
+            "/push/subscriptions",
+            post(create_push_subscription_handler),
+        )
+        .route(
+            "/push/subscriptions/:id",
+            delete(delete_push_subscription_handler),
+        )
+        .layer(from_fn(verify_scope))
+        .layer(RateLimitLayer::new(limits.api))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/", get(index_handler))
+        .route("/health", get(health_handler))
         .with_state(state.clone());
 
     Ok(app)
@@ -53,18 +141,90 @@ async fn index_handler() -> impl IntoResponse {
 }
 
 impl AppState {
-    pub fn new(config: AppConfig) -> Self {
+    pub async fn try_new(config: AppConfig) -> anyhow::Result<Self> {
+        let pool = connect_pool(&config);
+        let bus = config
+            .server
+            .bus
+            .connect(&config.server.db_url, pool.clone())
+            .await?;
+        Self::new(config, pool, bus)
+    }
+
+    /// A state on an existing bus, instead of the one of the config
+    pub fn with_bus(config: AppConfig, bus: Arc<dyn EventBus>) -> anyhow::Result<Self> {
+        let pool = connect_pool(&config);
+        Self::new(config, pool, bus)
+    }
+
+    fn new(config: AppConfig, pool: PgPool, bus: Arc<dyn EventBus>) -> anyhow::Result<Self> {
         let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load public key");
         let users = Arc::new(DashMap::new());
-        Self(Arc::new(AppStateInner { config, dk, users }))
+        let push = PushSender::new(&config.push)?;
+        Ok(Self(Arc::new(AppStateInner {
+            config,
+            dk,
+            users,
+            pool,
+            bus,
+            typing: TypingTracker::default(),
+            presence: PresenceTracker::new(),
+            listener: ListenerStatus::default(),
+            chats: ChatCache::default(),
+            push,
+            outbox: Notify::new(),
+            webhooks: WebhookSender::new()?,
+        })))
+    }
+
+    /// Get members of a chat, and verify the user is one of them
+    pub(crate) async fn get_chat_members(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<Vec<i64>, AppError> {
+        match self.get_chat(chat_id).await? {
+            Some(chat) if chat.deleted_at.is_none() && chat.members.contains(&user_id) => {
+                Ok(chat.members.clone())
+            }
+            _ => Err(AppError::ChatError(format!(
+                "User {} is not a member of chat {}",
+                user_id, chat_id
+            ))),
+        }
     }
 }
 
+fn connect_pool(config: &AppConfig) -> PgPool {
+    PgPoolOptions::new()
+        .connect_lazy(&config.server.db_url)
+        .expect("Failed to parse db_url")
+}
+
+#[async_trait]
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<chat_core::User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    async fn verify(&self, token: &str) -> Result<(User, Scopes), Self::Error> {
+        if !is_api_token(token) {
+            let user = self.dk.verify(token)?;
+            // tokens issued before the sessions of the user were revoked are rejected
+            let version: Option<(i32,)> =
+                sqlx::query_as("SELECT token_version FROM users WHERE id = $1")
+                    .bind(user.id)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            if version != Some((user.token_version,)) {
+                return Err(AppError::SessionRevoked);
+            }
+            return Ok((user, Scopes::All));
+        }
+        match verify_api_token(&self.pool, token).await? {
+            Some((user, scopes)) => Ok((user, Scopes::Only(scopes))),
+            None => Err(AppError::PermissionDenied(
+                "unknown, revoked or expired API token".to_string(),
+            )),
+        }
     }
 }
 
@@ -0,0 +1,351 @@
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicBool, AtomicI64, Ordering},
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use chat_core::{Chat, Message};
+use serde::Serialize;
+use sqlx::{Connection, FromRow, PgConnection};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{
+    bus::{EventBus, PgBus, Subscription},
+    error::AppError,
+    notif::Notification,
+    AppEvent, AppState,
+};
+
+const LISTEN_CHANNELS: [&str; 10] = [
+    "chat_change",
+    "message_added",
+    "message_updated",
+    "message_deleted",
+    "ephemeral",
+    "presence_changed",
+    "outbox",
+    "webhook_queued",
+    "typing_started",
+    "typing_stopped",
+];
+/// notifications waiting to be loaded, the listener waits when it's full
+const NOTIFICATION_QUEUE_SIZE: usize = 1024;
+/// notifications loaded together, so a busy chat doesn't issue a query per message
+const MAX_BATCH_SIZE: usize = 128;
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+/// advisory lock of the instance forwarding Postgres notifications to the bus
+const BRIDGE_LOCK_ID: i64 = 0x627269646765;
+const BRIDGE_LOCK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Health of the event bus subscription, and the latest chat and message seen, so
+/// what was created while it was disconnected can be caught up.
+#[derive(Debug, Default)]
+pub struct ListenerStatus {
+    healthy: AtomicBool,
+    last_chat_id: AtomicI64,
+    last_message_id: AtomicI64,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthOutput {
+    pg_listener: bool,
+}
+
+#[derive(Debug, FromRow)]
+struct MessageWithMembers {
+    #[sqlx(flatten)]
+    message: Message,
+    members: Vec<i64>,
+}
+
+/// Exponential backoff between reconnect attempts
+#[derive(Debug)]
+struct Backoff {
+    current: Duration,
+}
+
+impl ListenerStatus {
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// keep track of the latest chat and message delivered
+    pub(crate) fn observe(&self, event: &AppEvent) {
+        match event {
+            AppEvent::NewChat(chat) => {
+                self.last_chat_id.fetch_max(chat.id, Ordering::Relaxed);
+            }
+            AppEvent::NewMessage(msg) => {
+                self.last_message_id.fetch_max(msg.id, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            current: MIN_RECONNECT_BACKOFF,
+        }
+    }
+}
+
+impl Backoff {
+    fn next(&mut self) -> Duration {
+        let ret = self.current;
+        self.current = (self.current * 2).min(MAX_RECONNECT_BACKOFF);
+        ret
+    }
+}
+
+pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
+    if !state.bus.is_postgres() {
+        setup_pg_bridge(state.clone());
+    }
+    let subscription = state.bus.subscribe(&LISTEN_CHANNELS).await?;
+    state.init_watermarks().await?;
+    state.listener.set_healthy(true);
+
+    let (tx, rx) = mpsc::channel(NOTIFICATION_QUEUE_SIZE);
+    tokio::spawn(run_loader(state.clone(), rx));
+    tokio::spawn(run_listener(state, subscription, tx));
+    Ok(())
+}
+
+async fn run_listener(
+    state: AppState,
+    mut subscription: Box<dyn Subscription>,
+    tx: mpsc::Sender<Notification>,
+) {
+    loop {
+        match subscription.recv().await {
+            Ok(Some(msg)) => {
+                info!("Received notification: {:?}", msg);
+                // a bad payload must not stop the delivery of the others
+                let notification = match Notification::parse(&msg.channel, &msg.payload) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!(
+                            "Skipping bad notification on {}: {:?}, payload: {}",
+                            msg.channel, e, msg.payload
+                        );
+                        continue;
+                    }
+                };
+                if tx.send(notification).await.is_err() {
+                    warn!("Notification loader stopped");
+                    return;
+                }
+            }
+            Ok(None) => {
+                warn!("Lost the event bus subscription");
+                state.listener.set_healthy(false);
+                subscription = reconnect(&state).await;
+            }
+            Err(e) => {
+                warn!("Event bus subscription failed: {}", e);
+                state.listener.set_healthy(false);
+                subscription = reconnect(&state).await;
+            }
+        }
+    }
+}
+
+/// Load and deliver the queued notifications in batches
+async fn run_loader(state: AppState, mut rx: mpsc::Receiver<Notification>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+    while rx.recv_many(&mut batch, MAX_BATCH_SIZE).await > 0 {
+        let len = batch.len();
+        if let Err(e) = state
+            .deliver_notifications(std::mem::take(&mut batch))
+            .await
+        {
+            warn!("Failed to deliver {} notifications: {}", len, e);
+        }
+    }
+}
+
+/// Subscribe again until it succeeds, then deliver what was missed in between
+async fn reconnect(state: &AppState) -> Box<dyn Subscription> {
+    let mut backoff = Backoff::default();
+    loop {
+        tokio::time::sleep(backoff.next()).await;
+        let subscription = match state.bus.subscribe(&LISTEN_CHANNELS).await {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                warn!("Failed to subscribe to the event bus: {}", e);
+                continue;
+            }
+        };
+        // chat changes were missed as well, reload the chats on demand
+        state.chats.clear();
+        // subscribed again before catching up, so nothing falls in between
+        if let Err(e) = state.catch_up().await {
+            warn!("Failed to catch up missed events: {}", e);
+            continue;
+        }
+        state.listener.set_healthy(true);
+        info!("Event bus subscription restored");
+        return subscription;
+    }
+}
+
+/// Forward the database notifications of chat_server to a bus which isn't
+/// Postgres. On a shared bus only the instance holding the advisory lock
+/// forwards them, the others wait to take over.
+fn setup_pg_bridge(state: AppState) {
+    tokio::spawn(async move {
+        let mut backoff = Backoff::default();
+        loop {
+            if let Err(e) = run_pg_bridge(&state).await {
+                warn!("Postgres bridge failed: {}", e);
+            }
+            tokio::time::sleep(backoff.next()).await;
+        }
+    });
+}
+
+/// Only returns when a connection is lost
+async fn run_pg_bridge(state: &AppState) -> anyhow::Result<()> {
+    let mut lock_conn = PgConnection::connect(&state.config.server.db_url).await?;
+    if state.bus.is_shared() {
+        loop {
+            let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock($1)")
+                .bind(BRIDGE_LOCK_ID)
+                .fetch_one(&mut lock_conn)
+                .await?;
+            if locked {
+                break;
+            }
+            tokio::time::sleep(BRIDGE_LOCK_INTERVAL).await;
+        }
+    }
+
+    let pg = PgBus::new(&state.config.server.db_url, state.pool.clone());
+    let mut subscription = pg.subscribe(&LISTEN_CHANNELS).await?;
+    info!("Forwarding Postgres notifications to the event bus");
+    // the events chat_server wrote while nobody was forwarding are in the outbox
+    state.outbox.notify_one();
+
+    let mut check = tokio::time::interval(BRIDGE_LOCK_INTERVAL);
+    loop {
+        tokio::select! {
+            msg = subscription.recv() => {
+                let Some(msg) = msg? else {
+                    anyhow::bail!("lost connection of the Postgres listener");
+                };
+                state.bus.publish(&msg.channel, &msg.payload).await?;
+            }
+            // the lock is released with the connection, don't keep forwarding without it
+            _ = check.tick() => {
+                sqlx::query("SELECT 1").execute(&mut lock_conn).await?;
+            }
+        }
+    }
+}
+
+impl AppState {
+    async fn init_watermarks(&self) -> Result<(), AppError> {
+        let (chat_id, message_id): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COALESCE((SELECT max(id) FROM chats), 0),
+                   COALESCE((SELECT max(id) FROM messages), 0)
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.listener.last_chat_id.store(chat_id, Ordering::Relaxed);
+        self.listener
+            .last_message_id
+            .store(message_id, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Deliver chats and messages created while the listener was disconnected.
+    /// Updates and deletions in between are not recovered.
+    async fn catch_up(&self) -> Result<(), AppError> {
+        let chats: Vec<Chat> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, message_ttl, topic, deleted_at, created_at
+            FROM chats
+            WHERE id > $1
+            ORDER BY id
+            "#,
+        )
+        .bind(self.listener.last_chat_id.load(Ordering::Relaxed))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let messages: Vec<MessageWithMembers> = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.content, m.content_html, m.content_text,
+                   m.files, m.blocks::text AS blocks, m.expires_at, m.updated_at, m.created_at,
+                   c.members
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE m.id > $1 AND (m.expires_at IS NULL OR m.expires_at > now())
+            ORDER BY m.id
+            "#,
+        )
+        .bind(self.listener.last_message_id.load(Ordering::Relaxed))
+        .fetch_all(&self.pool)
+        .await?;
+
+        info!(
+            "Catching up {} chats and {} messages",
+            chats.len(),
+            messages.len()
+        );
+        for chat in chats {
+            let user_ids: HashSet<_> = chat.members.iter().map(|v| *v as u64).collect();
+            self.deliver(user_ids, AppEvent::NewChat(chat)).await;
+        }
+        for v in messages {
+            let user_ids: HashSet<_> = v.members.iter().map(|v| *v as u64).collect();
+            self.deliver(user_ids, AppEvent::NewMessage(v.message))
+                .await;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn health_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let healthy = state.listener.is_healthy();
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(HealthOutput {
+            pg_listener: healthy,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_should_grow_up_to_max() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next(), Duration::from_millis(100));
+        assert_eq!(backoff.next(), Duration::from_millis(200));
+        assert_eq!(backoff.next(), Duration::from_millis(400));
+        for _ in 0..20 {
+            backoff.next();
+        }
+        assert_eq!(backoff.next(), MAX_RECONNECT_BACKOFF);
+    }
+}
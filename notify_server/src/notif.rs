@@ -1,12 +1,15 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
-use chat_core::{Chat, Message};
-use futures::StreamExt;
+use chat_core::{Chat, ChatMember, EphemeralMessage, Message, UserPresence};
+use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
+use sqlx::types::Json;
 use tracing::{info, warn};
 
-use crate::AppState;
+use crate::{error::AppError, typing::TypingStarted, AppState, EventEnvelope, Typing, UserMap};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "event")]
@@ -15,90 +18,405 @@ pub enum AppEvent {
     UpdateChat(Chat),
     RemoveFromChat(Chat),
     NewMessage(Message),
+    /// a bot changed the content or blocks of the message
+    MessageUpdated(Message),
+    MessageDeleted(Message),
+    Ephemeral(EphemeralMessage),
+    TypingStarted(Typing),
+    TypingStopped(Typing),
+    PresenceChanged(UserPresence),
+    /// only queued by chat_server for outgoing webhooks, never sent to clients
+    MemberJoined(ChatMember),
+    /// missed events can't be replayed, the client should reload its state
+    Resync,
 }
 
+impl AppEvent {
+    /// names of the event types, as sent to clients
+    pub const NAMES: [&'static str; 11] = [
+        "NewChat",
+        "UpdateChat",
+        "RemoveFromChat",
+        "NewMessage",
+        "MessageUpdated",
+        "MessageDeleted",
+        "Ephemeral",
+        "TypingStarted",
+        "TypingStopped",
+        "PresenceChanged",
+        "Resync",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewChat(_) => "NewChat",
+            AppEvent::UpdateChat(_) => "UpdateChat",
+            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::MessageUpdated(_) => "MessageUpdated",
+            AppEvent::MessageDeleted(_) => "MessageDeleted",
+            AppEvent::Ephemeral(_) => "Ephemeral",
+            AppEvent::TypingStarted(_) => "TypingStarted",
+            AppEvent::TypingStopped(_) => "TypingStopped",
+            AppEvent::PresenceChanged(_) => "PresenceChanged",
+            AppEvent::MemberJoined(_) => "MemberJoined",
+            AppEvent::Resync => "Resync",
+        }
+    }
+
+    /// the chat the event belongs to, if any
+    pub fn chat_id(&self) -> Option<i64> {
+        match self {
+            AppEvent::NewChat(chat)
+            | AppEvent::UpdateChat(chat)
+            | AppEvent::RemoveFromChat(chat) => Some(chat.id),
+            AppEvent::NewMessage(msg)
+            | AppEvent::MessageUpdated(msg)
+            | AppEvent::MessageDeleted(msg) => Some(msg.chat_id),
+            AppEvent::MemberJoined(v) => Some(v.chat.id),
+            AppEvent::Ephemeral(msg) => Some(msg.chat_id),
+            AppEvent::TypingStarted(typing) | AppEvent::TypingStopped(typing) => {
+                Some(typing.chat_id)
+            }
+            AppEvent::PresenceChanged(_) | AppEvent::Resync => None,
+        }
+    }
+
+    /// Identifies the event across instances, all of them deliver it. Chat
+    /// updates are keyed by their change instead.
+    pub(crate) fn log_key(&self) -> Option<String> {
+        let key = match self {
+            AppEvent::NewChat(chat) => format!("new_chat:{}", chat.id),
+            AppEvent::NewMessage(msg) => format!("new_message:{}", msg.id),
+            AppEvent::MessageUpdated(msg) => format!(
+                "message_updated:{}:{}",
+                msg.id,
+                msg.updated_at
+                    .map(|v| v.timestamp_micros())
+                    .unwrap_or_default()
+            ),
+            AppEvent::MessageDeleted(msg) => format!("message_deleted:{}", msg.id),
+            AppEvent::Ephemeral(msg) => format!(
+                "ephemeral:{}:{}:{}",
+                msg.chat_id,
+                msg.user_id,
+                msg.created_at.timestamp_micros()
+            ),
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+/// A Postgres notification. Triggers only send ids, the rows are loaded by
+/// `deliver_notifications` for a whole batch at once.
 #[derive(Debug)]
-struct Notification {
-    // users being impacted, so we should send the notification to them
-    user_ids: HashSet<u64>,
-    event: Arc<AppEvent>,
+pub(crate) enum Notification {
+    ChatChanged(ChatChanged),
+    MessageAdded(MessageChanged),
+    MessageUpdated(MessageChanged),
+    MessageDeleted(MessageChanged),
+    // ephemeral messages are sent by chat_server with pg_notify('ephemeral', <EphemeralMessage>)
+    Ephemeral(EphemeralMessage),
+    // presence_changed is sent by notify_server and chat_server with a UserPresence
+    PresenceChanged(UserPresence),
+    // chat_server wrote events to the outbox, pg_notify('outbox', '')
+    Outbox,
+    // chat_server queued deliveries for outgoing webhooks, pg_notify('webhook_queued', '')
+    WebhookQueued,
+    // typing_started and typing_stopped are published by notify_server
+    TypingStarted(TypingStarted),
+    TypingStopped(Typing),
 }
 
-// pg_notify('chat_change', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
-#[derive(Debug, Serialize, Deserialize)]
-struct ChatUpdated {
-    op: String,
-    old: Option<Chat>,
-    new: Option<Chat>,
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) enum ChatOp {
+    Insert,
+    Update,
+    Delete,
 }
 
-// pg_notify('message_added', row_to_json(NEW)::text);
-#[derive(Debug, Serialize, Deserialize)]
-struct ChatMessageCreated {
-    message: Message,
-    members: Vec<i64>,
+// pg_notify('chat_change', json_build_object('op', TG_OP, 'chat_id', id, 'change_id', change_id)::text);
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChatChanged {
+    op: ChatOp,
+    chat_id: i64,
+    /// the row of `chat_changes` with the chat before an update or delete
+    #[serde(default)]
+    change_id: Option<i64>,
 }
 
-pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
-    let mut listener = PgListener::connect(&state.config.server.db_url).await?;
-    listener.listen("chat_change").await?;
-    listener.listen("message_added").await?;
-
-    let mut stream = listener.into_stream();
-
-    tokio::spawn(async move {
-        while let Some(Ok(notif)) = stream.next().await {
-            info!("Received notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
-            let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user: {}", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {}", user_id, e);
-                        // 用户退出sse连接， 进行删除
-                        users.remove(&user_id);
+// pg_notify('message_added', json_build_object('op', TG_OP, 'message_id', NEW.id, 'chat_id', NEW.chat_id, ...)::text);
+// pg_notify('message_updated', json_build_object('op', TG_OP, 'message_id', NEW.id, 'chat_id', NEW.chat_id, ...)::text);
+// pg_notify('message_deleted', json_build_object('op', TG_OP, 'message_id', OLD.id, 'chat_id', OLD.chat_id, ...)::text);
+#[derive(Debug, Deserialize)]
+pub(crate) struct MessageChanged {
+    message_id: i64,
+    chat_id: i64,
+    sender_id: i64,
+    created_at: DateTime<Local>,
+}
+
+impl AppState {
+    /// Load the rows of a batch of notifications, and deliver them in order
+    pub(crate) async fn deliver_notifications(
+        &self,
+        batch: Vec<Notification>,
+    ) -> Result<(), AppError> {
+        // changed chats are always reloaded, the cached version is the old one
+        let mut changed_ids = HashSet::new();
+        let mut change_ids = vec![];
+        let mut chat_ids = HashSet::new();
+        let mut message_ids = vec![];
+        for notification in &batch {
+            match notification {
+                Notification::ChatChanged(v) => {
+                    if v.op != ChatOp::Delete {
+                        changed_ids.insert(v.chat_id);
                     }
+                    change_ids.extend(v.change_id);
+                }
+                Notification::MessageAdded(v) | Notification::MessageUpdated(v) => {
+                    message_ids.push(v.message_id);
+                    chat_ids.insert(v.chat_id);
+                }
+                Notification::MessageDeleted(v) => {
+                    chat_ids.insert(v.chat_id);
                 }
+                Notification::TypingStarted(TypingStarted { typing, .. })
+                | Notification::TypingStopped(typing) => {
+                    chat_ids.insert(typing.chat_id);
+                }
+                _ => {}
             }
         }
+        chat_ids.retain(|id| !changed_ids.contains(id) && self.chats.get(*id).is_none());
+        chat_ids.extend(&changed_ids);
 
-        Ok::<_, anyhow::Error>(())
-    });
+        let chat_ids: Vec<_> = chat_ids.into_iter().collect();
+        let mut chats = HashMap::new();
+        for chat in self.load_chats(&chat_ids).await? {
+            let chat = Arc::new(chat);
+            if !changed_ids.contains(&chat.id) {
+                self.chats.insert(chat.clone());
+            }
+            chats.insert(chat.id, chat);
+        }
+        let mut messages: HashMap<_, _> = self
+            .load_messages(&message_ids)
+            .await?
+            .into_iter()
+            .map(|v| (v.id, v))
+            .collect();
+        let mut old_chats = self.load_chat_changes(&change_ids).await?;
 
-    Ok(())
-}
+        let members = |chat_id| -> HashSet<u64> {
+            self.chats
+                .get(chat_id)
+                .or_else(|| chats.get(&chat_id).cloned())
+                .map(|chat| chat.members.iter().map(|v| *v as u64).collect())
+                .unwrap_or_default()
+        };
 
-impl Notification {
-    fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
-        match r#type {
-            "chat_change" => {
-                let payload: ChatUpdated = serde_json::from_str(payload).unwrap();
-                info!("chat_change: {:?}", payload);
-                let user_ids =
-                    get_affected_chat_user_ids(payload.old.as_ref(), payload.new.as_ref());
-                let event = match payload.op.as_str() {
-                    "INSERT" => AppEvent::NewChat(payload.new.expect("New should exist")),
-                    "UPDATE" => AppEvent::UpdateChat(payload.new.expect("New should exist")),
-                    "DELETE" => AppEvent::RemoveFromChat(payload.old.expect("Old should exist")),
-                    _ => return Err(anyhow::anyhow!("Invalid operation")),
-                };
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(event),
-                })
+        for notification in batch {
+            match notification {
+                Notification::ChatChanged(ChatChanged {
+                    op,
+                    chat_id,
+                    change_id,
+                }) => {
+                    // the cache only has the old chat if this instance loaded it
+                    let cached = match op {
+                        ChatOp::Delete => self.chats.remove(chat_id),
+                        _ => self.chats.get(chat_id),
+                    };
+                    let old = change_id
+                        .and_then(|id| old_chats.remove(&id))
+                        .map(Arc::new)
+                        .or(cached);
+                    let new = match op {
+                        ChatOp::Delete => None,
+                        _ => chats.get(&chat_id).cloned(),
+                    };
+                    if let Some(new) = &new {
+                        self.chats.insert(new.clone());
+                    }
+                    let user_ids = get_affected_chat_user_ids(old.as_deref(), new.as_deref());
+                    let event = match (op, old, new) {
+                        (ChatOp::Insert, _, Some(new)) => AppEvent::NewChat((*new).clone()),
+                        (ChatOp::Update, _, Some(new)) => AppEvent::UpdateChat((*new).clone()),
+                        (ChatOp::Delete, Some(old), _) => AppEvent::RemoveFromChat((*old).clone()),
+                        _ => {
+                            warn!("Chat {} not found for {:?}", chat_id, op);
+                            continue;
+                        }
+                    };
+                    let key = change_id.map(|id| format!("chat_change:{}", id));
+                    self.deliver_as(user_ids, event, key).await;
+                }
+                Notification::MessageAdded(v) => {
+                    // deleted in the meantime, nothing to deliver
+                    let Some(msg) = messages.remove(&v.message_id) else {
+                        continue;
+                    };
+                    let user_ids = members(v.chat_id);
+                    if self.push.is_enabled() {
+                        let state = self.clone();
+                        let msg = msg.clone();
+                        let members: Vec<_> = user_ids.iter().map(|v| *v as i64).collect();
+                        tokio::spawn(async move {
+                            if let Err(e) = state.push_message(&msg, &members).await {
+                                warn!("Failed to push message {}: {}", msg.id, e);
+                            }
+                        });
+                    }
+                    self.deliver(user_ids, AppEvent::NewMessage(msg)).await;
+                }
+                Notification::MessageUpdated(v) => {
+                    // the latest version is loaded, updates in a batch send it twice
+                    let Some(msg) = messages.get(&v.message_id).cloned() else {
+                        continue;
+                    };
+                    self.deliver(members(v.chat_id), AppEvent::MessageUpdated(msg))
+                        .await;
+                }
+                Notification::MessageDeleted(v) => {
+                    let msg = Message {
+                        id: v.message_id,
+                        chat_id: v.chat_id,
+                        sender_id: v.sender_id,
+                        content: String::new(),
+                        content_html: None,
+                        content_text: None,
+                        files: None,
+                        blocks: None,
+                        expires_at: None,
+                        updated_at: None,
+                        created_at: v.created_at,
+                    };
+                    self.deliver(members(v.chat_id), AppEvent::MessageDeleted(msg))
+                        .await;
+                }
+                Notification::Ephemeral(msg) => {
+                    let user_ids = HashSet::from([msg.user_id as u64]);
+                    self.deliver(user_ids, AppEvent::Ephemeral(msg)).await;
+                }
+                Notification::PresenceChanged(presence) => {
+                    // recipients are loaded from db, don't block the others
+                    let state = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = state.broadcast_presence(presence).await {
+                            warn!("Failed to broadcast presence: {}", e);
+                        }
+                    });
+                }
+                Notification::Outbox => self.outbox.notify_one(),
+                Notification::WebhookQueued => self.webhooks.notify_queued(),
+                Notification::TypingStarted(v) => {
+                    let members = members(v.typing.chat_id);
+                    self.typing_started(v, members);
+                }
+                Notification::TypingStopped(typing) => {
+                    let members = members(typing.chat_id);
+                    self.typing_stopped(typing, members);
+                }
             }
-            "message_added" => {
-                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
-                info!("message_added: {:?}", payload);
-                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(AppEvent::NewMessage(payload.message)),
-                })
+        }
+        Ok(())
+    }
+
+    /// Log the event and send it to the users
+    pub(crate) async fn deliver(&self, user_ids: HashSet<u64>, event: AppEvent) {
+        let key = event.log_key();
+        self.deliver_as(user_ids, event, key).await
+    }
+
+    /// Log the event with the given key, and send it to the users
+    async fn deliver_as(&self, user_ids: HashSet<u64>, event: AppEvent, key: Option<String>) {
+        self.listener.observe(&event);
+        if user_ids.is_empty() {
+            return;
+        }
+        let id = match self.append_event(&user_ids, &event, key.as_deref()).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                warn!("Failed to log event: {}", e);
+                None
             }
-            _ => Err(anyhow::anyhow!("Invalid notification type")),
+        };
+        send_event(&self.users, user_ids, Arc::new(EventEnvelope { id, event }));
+    }
+
+    /// Load the chats as they were before the changes
+    async fn load_chat_changes(&self, change_ids: &[i64]) -> Result<HashMap<i64, Chat>, AppError> {
+        if change_ids.is_empty() {
+            return Ok(HashMap::new());
         }
+        let rows: Vec<(i64, Json<Chat>)> =
+            sqlx::query_as("SELECT id, old FROM chat_changes WHERE id = ANY($1)")
+                .bind(change_ids)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|(id, v)| (id, v.0)).collect())
+    }
+
+    async fn load_messages(&self, message_ids: &[i64]) -> Result<Vec<Message>, AppError> {
+        if message_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let messages = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, content_html, content_text, files,
+              blocks::text AS blocks, expires_at, updated_at, created_at
+            FROM messages
+            WHERE id = ANY($1)
+            "#,
+        )
+        .bind(message_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+}
+
+/// Send an event to the given users who are connected to this instance
+pub(crate) fn send_event(
+    users: &UserMap,
+    user_ids: impl IntoIterator<Item = u64>,
+    event: Arc<EventEnvelope>,
+) {
+    for user_id in user_ids {
+        let Some(tx) = users.get(&user_id).map(|v| v.tx.clone()) else {
+            continue;
+        };
+        info!("Sending notification to user: {}", user_id);
+        // the channel is removed with the last connection, not here
+        if let Err(e) = tx.send(event.clone()) {
+            warn!("Failed to send notification to user {}: {}", user_id, e);
+        }
+    }
+}
+
+impl Notification {
+    pub(crate) fn parse(channel: &str, payload: &str) -> anyhow::Result<Self> {
+        let ret = match channel {
+            "chat_change" => Self::ChatChanged(serde_json::from_str(payload)?),
+            "message_added" => Self::MessageAdded(serde_json::from_str(payload)?),
+            "message_updated" => Self::MessageUpdated(serde_json::from_str(payload)?),
+            "message_deleted" => Self::MessageDeleted(serde_json::from_str(payload)?),
+            "ephemeral" => Self::Ephemeral(serde_json::from_str(payload)?),
+            "presence_changed" => Self::PresenceChanged(serde_json::from_str(payload)?),
+            "outbox" => Self::Outbox,
+            "webhook_queued" => Self::WebhookQueued,
+            "typing_started" => Self::TypingStarted(serde_json::from_str(payload)?),
+            "typing_stopped" => Self::TypingStopped(serde_json::from_str(payload)?),
+            _ => return Err(anyhow::anyhow!("Invalid notification type: {}", channel)),
+        };
+        info!("{}: {:?}", channel, ret);
+        Ok(ret)
     }
 }
 
@@ -108,9 +426,8 @@ fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet
             let old_user_ids: HashSet<_> = old.members.iter().map(|v| *v as u64).collect();
             let new_user_ids: HashSet<_> = new.members.iter().map(|v| *v as u64).collect();
             if old_user_ids == new_user_ids {
-                // 删除 或者 名字更改 需要通知所有用户
-                // TODO: DELETE
-                if old.name != new.name || old.deleted_at != new.deleted_at {
+                // name, topic, ttl or deletion changed, the members see it
+                if old != new {
                     return new_user_ids;
                 }
                 HashSet::new()
@@ -123,3 +440,150 @@ fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet
         _ => HashSet::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notification_should_parse() {
+        let ret = Notification::parse("chat_change", r#"{"op":"UPDATE","chat_id":1}"#).unwrap();
+        assert!(matches!(
+            ret,
+            Notification::ChatChanged(ChatChanged {
+                op: ChatOp::Update,
+                chat_id: 1,
+                change_id: None,
+            })
+        ));
+        let payload = r#"{"op":"DELETE","chat_id":1,"change_id":7}"#;
+        let ret = Notification::parse("chat_change", payload).unwrap();
+        assert!(matches!(
+            ret,
+            Notification::ChatChanged(ChatChanged {
+                op: ChatOp::Delete,
+                change_id: Some(7),
+                ..
+            })
+        ));
+
+        let payload = r#"{"op":"INSERT","message_id":2,"chat_id":1,"sender_id":3,"created_at":"2024-07-09T10:00:00.123456+00:00"}"#;
+        let ret = Notification::parse("message_added", payload).unwrap();
+        assert!(matches!(
+            ret,
+            Notification::MessageAdded(MessageChanged {
+                message_id: 2,
+                chat_id: 1,
+                sender_id: 3,
+                ..
+            })
+        ));
+        let ret = Notification::parse("message_updated", payload).unwrap();
+        assert!(matches!(
+            ret,
+            Notification::MessageUpdated(MessageChanged { message_id: 2, .. })
+        ));
+
+        let ret = Notification::parse("typing_started", r#"{"chat_id":1,"user_id":2}"#).unwrap();
+        assert!(matches!(
+            ret,
+            Notification::TypingStarted(TypingStarted {
+                typing: Typing {
+                    chat_id: 1,
+                    user_id: 2
+                },
+                refreshed: false,
+            })
+        ));
+        let ret = Notification::parse("typing_stopped", r#"{"chat_id":1,"user_id":2}"#).unwrap();
+        assert!(matches!(
+            ret,
+            Notification::TypingStopped(Typing { user_id: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn chat_updates_should_reach_the_members() {
+        let old = Chat {
+            id: 1,
+            ws_id: 1,
+            name: Some("general".to_string()),
+            r#type: chat_core::ChatType::PublicChannel,
+            members: vec![1, 2],
+            message_ttl: None,
+            topic: None,
+            deleted_at: None,
+            created_at: Local::now(),
+        };
+        let members = HashSet::from([1, 2]);
+        assert!(get_affected_chat_user_ids(Some(&old), Some(&old)).is_empty());
+
+        let new = Chat {
+            topic: Some("news".to_string()),
+            ..old.clone()
+        };
+        assert_eq!(get_affected_chat_user_ids(Some(&old), Some(&new)), members);
+        let new = Chat {
+            message_ttl: Some(60),
+            ..old.clone()
+        };
+        assert_eq!(get_affected_chat_user_ids(Some(&old), Some(&new)), members);
+        let new = Chat {
+            members: vec![2, 3],
+            ..old.clone()
+        };
+        assert_eq!(
+            get_affected_chat_user_ids(Some(&old), Some(&new)),
+            HashSet::from([1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn events_should_have_a_log_key() {
+        let msg = Message {
+            id: 2,
+            chat_id: 1,
+            sender_id: 3,
+            content: String::new(),
+            content_html: None,
+            content_text: None,
+            files: None,
+            blocks: None,
+            expires_at: None,
+            updated_at: None,
+            created_at: Local::now(),
+        };
+        let key = AppEvent::NewMessage(msg.clone()).log_key();
+        assert_eq!(key.as_deref(), Some("new_message:2"));
+        let key = AppEvent::MessageDeleted(msg.clone()).log_key();
+        assert_eq!(key.as_deref(), Some("message_deleted:2"));
+
+        // each edit of the message is a new event
+        let updated = |updated_at| {
+            AppEvent::MessageUpdated(Message {
+                updated_at,
+                ..msg.clone()
+            })
+            .log_key()
+        };
+        let now = Local::now();
+        assert_ne!(
+            updated(Some(now)),
+            updated(Some(now + chrono::Duration::seconds(1)))
+        );
+
+        let typing = Typing {
+            chat_id: 1,
+            user_id: 2,
+        };
+        assert!(AppEvent::TypingStarted(typing).log_key().is_none());
+    }
+
+    #[test]
+    fn bad_notification_should_be_rejected() {
+        assert!(Notification::parse("chat_change", "not json").is_err());
+        assert!(Notification::parse("chat_change", r#"{"op":"TRUNCATE","chat_id":1}"#).is_err());
+        assert!(Notification::parse("message_added", "{}").is_err());
+        assert!(Notification::parse("unknown", "{}").is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::{error::AppError, AppState};
+
+/// the relay is woken up by `outbox` notifications, polling is a fallback
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const OUTBOX_BATCH_SIZE: i64 = 500;
+/// advisory lock shared by all notify_server instances, so only one relays
+const OUTBOX_LOCK_ID: i64 = 0x6f7574626f78;
+
+impl AppState {
+    /// Publish a batch of events from the outbox to the event bus, in id order.
+    /// Rows are deleted once published, so an event may be published twice if
+    /// the relay fails in between, never lost.
+    async fn relay_outbox(&self) -> Result<i64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_xact_lock($1)")
+            .bind(OUTBOX_LOCK_ID)
+            .fetch_one(&mut *tx)
+            .await?;
+        if !locked {
+            return Ok(0);
+        }
+
+        let rows: Vec<(i64, String, String)> = sqlx::query_as(
+            r#"
+            SELECT id, channel, payload::text
+            FROM outbox
+            ORDER BY id
+            LIMIT $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(OUTBOX_BATCH_SIZE)
+        .fetch_all(&mut *tx)
+        .await?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let mut ids = Vec::with_capacity(rows.len());
+        for (id, channel, payload) in rows {
+            self.bus
+                .publish(&channel, &payload)
+                .await
+                .map_err(|e| AppError::BusError(e.to_string()))?;
+            ids.push(id);
+        }
+        sqlx::query("DELETE FROM outbox WHERE id = ANY($1)")
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(ids.len() as i64)
+    }
+}
+
+pub(crate) fn setup_outbox_relay(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            match state.relay_outbox().await {
+                Ok(0) => {}
+                Ok(n) => {
+                    info!("Relayed {} outbox events", n);
+                    if n >= OUTBOX_BATCH_SIZE {
+                        continue;
+                    }
+                }
+                Err(e) => warn!("Failed to relay outbox: {}", e),
+            }
+            tokio::select! {
+                _ = state.outbox.notified() => {}
+                _ = tokio::time::sleep(OUTBOX_POLL_INTERVAL) => {}
+            }
+        }
+    });
+}
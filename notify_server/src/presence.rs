@@ -0,0 +1,174 @@
+use std::{sync::Arc, time::Duration};
+
+use chat_core::UserPresence;
+use tracing::warn;
+
+use crate::{error::AppError, notif::send_event, AppEvent, AppState};
+
+const PRESENCE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Presence of the users connected to this notify_server instance. Connection
+/// counts are mirrored to `presence_connections` so presence is summed across
+/// instances.
+#[derive(Debug)]
+pub struct PresenceTracker {
+    instance_id: String,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self {
+            instance_id: uuid::Uuid::now_v7().to_string(),
+        }
+    }
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppState {
+    /// Store the connection count of this instance, and publish the presence
+    /// when the user goes online or offline on this instance
+    pub(crate) async fn sync_connections(
+        &self,
+        user_id: u64,
+        count: usize,
+    ) -> Result<(), AppError> {
+        let instance_id = &self.presence.instance_id;
+        if count == 0 {
+            sqlx::query("DELETE FROM presence_connections WHERE instance_id = $1 AND user_id = $2")
+                .bind(instance_id)
+                .bind(user_id as i64)
+                .execute(&self.pool)
+                .await?;
+            sqlx::query(
+                r#"
+                INSERT INTO user_presence (user_id, last_seen_at)
+                VALUES ($1, now())
+                ON CONFLICT (user_id) DO UPDATE SET last_seen_at = now()
+                "#,
+            )
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        } else {
+            sqlx::query(
+                r#"
+                INSERT INTO presence_connections (instance_id, user_id, connections)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (instance_id, user_id)
+                DO UPDATE SET connections = $3, updated_at = now()
+                "#,
+            )
+            .bind(instance_id)
+            .bind(user_id as i64)
+            .bind(count as i32)
+            .execute(&self.pool)
+            .await?;
+        }
+
+        if count <= 1 {
+            self.publish_presence(user_id as _).await?;
+        }
+        Ok(())
+    }
+
+    /// Notify all notify_server instances that the presence of the user changed
+    async fn publish_presence(&self, user_id: i64) -> Result<(), AppError> {
+        let presence: Option<UserPresence> = sqlx::query_as(
+            "SELECT user_id, status, last_seen_at FROM presences WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(presence) = presence {
+            let payload = serde_json::to_string(&presence).expect("Failed to serialize presence");
+            self.bus
+                .publish("presence_changed", &payload)
+                .await
+                .map_err(|e| AppError::BusError(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Send the presence to connected users who share a chat with the user
+    pub(crate) async fn broadcast_presence(&self, presence: UserPresence) -> Result<(), AppError> {
+        let user_ids: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT unnest(members)
+            FROM chats
+            WHERE $1 = ANY(members) AND deleted_at IS NULL
+            "#,
+        )
+        .bind(presence.user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let user_ids = user_ids.into_iter().map(|(id,)| id as u64);
+        send_event(
+            &self.users,
+            user_ids,
+            Arc::new(AppEvent::PresenceChanged(presence).into()),
+        );
+        Ok(())
+    }
+
+    /// Refresh connections of this instance, and expire the ones of dead instances
+    async fn presence_heartbeat(&self) -> Result<(), AppError> {
+        let (user_ids, counts): (Vec<i64>, Vec<i32>) = self
+            .users
+            .iter()
+            .map(|v| (*v.key() as i64, v.connections() as i32))
+            .unzip();
+        sqlx::query(
+            r#"
+            INSERT INTO presence_connections (instance_id, user_id, connections)
+            SELECT $1, * FROM unnest($2::bigint[], $3::int[])
+            ON CONFLICT (instance_id, user_id)
+            DO UPDATE SET connections = EXCLUDED.connections, updated_at = now()
+            "#,
+        )
+        .bind(&self.presence.instance_id)
+        .bind(&user_ids)
+        .bind(&counts)
+        .execute(&self.pool)
+        .await?;
+
+        let expired: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            WITH stale AS (
+                DELETE FROM presence_connections
+                WHERE updated_at <= now() - interval '90 seconds'
+                RETURNING user_id, updated_at
+            )
+            INSERT INTO user_presence (user_id, last_seen_at)
+            SELECT user_id, max(updated_at) FROM stale GROUP BY user_id
+            ON CONFLICT (user_id)
+            DO UPDATE SET last_seen_at = GREATEST(user_presence.last_seen_at, EXCLUDED.last_seen_at)
+            RETURNING user_id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        for (user_id,) in expired {
+            self.publish_presence(user_id).await?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn setup_presence_heartbeat(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRESENCE_HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = state.presence_heartbeat().await {
+                warn!("Presence heartbeat failed: {}", e);
+            }
+        }
+    });
+}
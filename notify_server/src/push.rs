@@ -0,0 +1,615 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{is_public_url, Message, User};
+use chrono::{DateTime, Local};
+use futures::future::join_all;
+use reqwest::{header, redirect::Policy};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::{info, warn};
+
+use crate::{config::PushConfig, error::AppError, webpush, AppState};
+
+/// how long in seconds push services keep a notification for an offline device
+const PUSH_TTL: u32 = 24 * 60 * 60;
+/// the body of a notification is truncated to this many chars
+const MAX_BODY_CHARS: usize = 200;
+/// a push service that doesn't answer in time is given up on
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "push_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PushKind {
+    /// a browser subscription, encrypted and signed with VAPID
+    WebPush,
+    /// a device token sent to the configured push gateway, e.g. for FCM or APNs
+    Gateway,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow, PartialEq)]
+pub struct PushSubscription {
+    pub id: i64,
+    pub user_id: i64,
+    pub kind: PushKind,
+    pub endpoint: String,
+    #[serde(skip)]
+    pub p256dh: Option<String>,
+    #[serde(skip)]
+    pub auth: Option<String>,
+    pub platform: Option<String>,
+    pub created_at: DateTime<Local>,
+}
+
+/// A push subscription registered by a device. Browsers can send their
+/// `PushSubscription` as is, with `kind` added.
+#[derive(Debug, Deserialize)]
+pub struct CreatePushSubscription {
+    pub kind: PushKind,
+    pub endpoint: String,
+    pub keys: Option<PushKeys>,
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// The payload of a push
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PushNotification {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub sender_id: i64,
+    pub title: String,
+    pub body: String,
+    /// the user was mentioned in the message
+    pub mention: bool,
+}
+
+/// What the push gateway receives
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct GatewayRequest {
+    pub token: String,
+    pub platform: Option<String>,
+    pub notification: PushNotification,
+}
+
+#[derive(Debug, Serialize)]
+struct VapidKeyOutput {
+    public_key: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum PushOutcome {
+    Sent,
+    /// the subscription expired or was revoked, it should be removed
+    Gone,
+}
+
+/// Sends pushes to push services and the push gateway
+pub struct PushSender {
+    client: reqwest::Client,
+    vapid: Option<webpush::Vapid>,
+    gateway: Option<crate::config::GatewayConfig>,
+}
+
+impl PushSender {
+    pub fn new(config: &PushConfig) -> anyhow::Result<Self> {
+        Self::with_timeout(config, PUSH_TIMEOUT)
+    }
+
+    /// Redirects are not followed, endpoints were only checked to be public
+    /// when they were registered
+    fn with_timeout(config: &PushConfig, timeout: Duration) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(Policy::none())
+            .build()?;
+        let vapid = match &config.vapid_sk {
+            Some(pem) => Some(webpush::Vapid::from_pem(pem, &config.vapid_subject)?),
+            None => None,
+        };
+        Ok(Self {
+            client,
+            vapid,
+            gateway: config.gateway.clone(),
+        })
+    }
+
+    fn supports(&self, kind: PushKind) -> bool {
+        match kind {
+            PushKind::WebPush => self.vapid.is_some(),
+            PushKind::Gateway => self.gateway.is_some(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.vapid.is_some() || self.gateway.is_some()
+    }
+
+    pub(crate) async fn send(
+        &self,
+        sub: &PushSubscription,
+        notification: &PushNotification,
+    ) -> anyhow::Result<PushOutcome> {
+        let req = match sub.kind {
+            PushKind::WebPush => {
+                let vapid = self
+                    .vapid
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("web push is not configured"))?;
+                let (Some(p256dh), Some(auth)) = (&sub.p256dh, &sub.auth) else {
+                    return Err(anyhow!("web push subscription {} has no keys", sub.id));
+                };
+                let payload = serde_json::to_vec(notification)?;
+                self.client
+                    .post(&sub.endpoint)
+                    .header(header::AUTHORIZATION, vapid.authorization(&sub.endpoint)?)
+                    .header(header::CONTENT_ENCODING, "aes128gcm")
+                    .header(header::CONTENT_TYPE, "application/octet-stream")
+                    .header("TTL", PUSH_TTL)
+                    .header(
+                        "Urgency",
+                        if notification.mention {
+                            "high"
+                        } else {
+                            "normal"
+                        },
+                    )
+                    .body(webpush::encrypt(&payload, p256dh, auth)?)
+            }
+            PushKind::Gateway => {
+                let gateway = self
+                    .gateway
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("push gateway is not configured"))?;
+                let req = self.client.post(&gateway.url).json(&GatewayRequest {
+                    token: sub.endpoint.clone(),
+                    platform: sub.platform.clone(),
+                    notification: notification.clone(),
+                });
+                match &gateway.token {
+                    Some(token) => req.bearer_auth(token),
+                    None => req,
+                }
+            }
+        };
+
+        let res = req.send().await?;
+        match res.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(PushOutcome::Gone),
+            status if status.is_success() => Ok(PushOutcome::Sent),
+            status => Err(anyhow!("push to {} failed: {}", sub.endpoint, status)),
+        }
+    }
+}
+
+impl AppState {
+    /// Push a new message to the members who are offline on every instance.
+    /// Muted chats are skipped unless the user is mentioned, users in DND are
+    /// skipped. Only one instance pushes a message.
+    pub(crate) async fn push_message(
+        &self,
+        msg: &Message,
+        members: &[i64],
+    ) -> Result<(), AppError> {
+        let recipients: Vec<i64> = members
+            .iter()
+            .copied()
+            .filter(|id| *id != msg.sender_id)
+            .collect();
+        if recipients.is_empty() {
+            return Ok(());
+        }
+        let mentions = mentions(&msg.content);
+        let subs: Vec<PushSubscription> = sqlx::query_as(
+            r#"
+            WITH claimed AS (
+              INSERT INTO message_pushes (message_id)
+              VALUES ($1)
+              ON CONFLICT DO NOTHING
+              RETURNING message_id
+            )
+            SELECT s.id, s.user_id, s.kind, s.endpoint, s.p256dh, s.auth, s.platform, s.created_at
+            FROM push_subscriptions s
+            JOIN presences p ON p.user_id = s.user_id
+            WHERE EXISTS (SELECT 1 FROM claimed)
+              AND s.user_id = ANY($2)
+              AND p.status = 'offline'
+              AND NOT EXISTS (
+                SELECT 1 FROM user_presence up WHERE up.user_id = s.user_id AND up.status = 'dnd'
+              )
+              AND (s.user_id = ANY($3) OR NOT EXISTS (
+                SELECT 1 FROM chat_mutes m WHERE m.user_id = s.user_id AND m.chat_id = $4
+              ))
+            "#,
+        )
+        .bind(msg.id)
+        .bind(&recipients)
+        .bind(&mentions)
+        .bind(msg.chat_id)
+        .fetch_all(&self.pool)
+        .await?;
+        if subs.is_empty() {
+            return Ok(());
+        }
+
+        let notification = self.push_notification(msg).await?;
+        let sends = subs
+            .iter()
+            .filter(|v| self.push.supports(v.kind))
+            .map(|sub| {
+                let notification = PushNotification {
+                    mention: mentions.contains(&sub.user_id),
+                    ..notification.clone()
+                };
+                async move { (sub, self.push.send(sub, &notification).await) }
+            });
+        for (sub, res) in join_all(sends).await {
+            match res {
+                Ok(PushOutcome::Sent) => info!("Pushed message {} to user {}", msg.id, sub.user_id),
+                Ok(PushOutcome::Gone) => {
+                    info!("Push subscription {} is gone, removing it", sub.id);
+                    sqlx::query("DELETE FROM push_subscriptions WHERE id = $1")
+                        .bind(sub.id)
+                        .execute(&self.pool)
+                        .await?;
+                }
+                Err(e) => warn!("Failed to push message {}: {}", msg.id, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn push_notification(&self, msg: &Message) -> Result<PushNotification, AppError> {
+        let (fullname,): (String,) = sqlx::query_as("SELECT fullname FROM users WHERE id = $1")
+            .bind(msg.sender_id)
+            .fetch_one(&self.pool)
+            .await?;
+        let chat_name = self.chats.get(msg.chat_id).and_then(|v| v.name.clone());
+        let title = match chat_name {
+            Some(name) => format!("{} in {}", fullname, name),
+            None => fullname,
+        };
+        Ok(PushNotification {
+            chat_id: msg.chat_id,
+            message_id: msg.id,
+            sender_id: msg.sender_id,
+            title,
+            body: msg.plain_text().chars().take(MAX_BODY_CHARS).collect(),
+            mention: false,
+        })
+    }
+
+    async fn create_push_subscription(
+        &self,
+        user_id: i64,
+        input: CreatePushSubscription,
+    ) -> Result<PushSubscription, AppError> {
+        if !self.push.supports(input.kind) {
+            return Err(AppError::PushError(format!(
+                "{:?} is not configured",
+                input.kind
+            )));
+        }
+        let (p256dh, auth) = match (input.kind, input.keys) {
+            (PushKind::WebPush, Some(keys)) => (Some(keys.p256dh), Some(keys.auth)),
+            (PushKind::WebPush, None) => {
+                return Err(AppError::PushError("web push needs keys".to_string()))
+            }
+            (PushKind::Gateway, _) => (None, None),
+        };
+        if input.kind == PushKind::WebPush {
+            valid_endpoint(&input.endpoint)?;
+        }
+
+        let sub = sqlx::query_as(
+            r#"
+            INSERT INTO push_subscriptions (user_id, kind, endpoint, p256dh, auth, platform)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_id, endpoint)
+            DO UPDATE SET kind = $2, p256dh = $4, auth = $5, platform = $6
+            RETURNING id, user_id, kind, endpoint, p256dh, auth, platform, created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(input.kind)
+        .bind(&input.endpoint)
+        .bind(p256dh)
+        .bind(auth)
+        .bind(input.platform)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(sub)
+    }
+
+    async fn delete_push_subscription(&self, id: i64, user_id: i64) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM push_subscriptions WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_chat_muted(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        muted: bool,
+    ) -> Result<(), AppError> {
+        self.get_chat_members(chat_id, user_id).await?;
+        let sql = if muted {
+            "INSERT INTO chat_mutes (user_id, chat_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM chat_mutes WHERE user_id = $1 AND chat_id = $2"
+        };
+        sqlx::query(sql)
+            .bind(user_id)
+            .bind(chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Forget pushed messages older than the event retention
+    pub(crate) async fn trim_message_pushes(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            "DELETE FROM message_pushes WHERE created_at < now() - $1 * interval '1 second'",
+        )
+        .bind(self.config.server.event_retention as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(ret.rows_affected())
+    }
+}
+
+/// Users mentioned in a message, written as `<@user_id>`
+fn mentions(content: &str) -> Vec<i64> {
+    let ids: HashSet<i64> = content
+        .split("<@")
+        .skip(1)
+        .filter_map(|v| v.split_once('>'))
+        .filter_map(|(id, _)| id.parse().ok())
+        .collect();
+    ids.into_iter().collect()
+}
+
+pub(crate) async fn vapid_key_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let vapid = state
+        .push
+        .vapid
+        .as_ref()
+        .ok_or_else(|| AppError::PushError("web push is not configured".to_string()))?;
+    Ok(Json(VapidKeyOutput {
+        public_key: vapid.public_key(),
+    }))
+}
+
+pub(crate) async fn create_push_subscription_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreatePushSubscription>,
+) -> Result<impl IntoResponse, AppError> {
+    let sub = state.create_push_subscription(user.id, input).await?;
+    Ok((StatusCode::CREATED, Json(sub)))
+}
+
+pub(crate) async fn delete_push_subscription_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_push_subscription(id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn mute_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.set_chat_muted(chat_id, user.id, true).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn unmute_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.set_chat_muted(chat_id, user.id, false).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Web push endpoints are called by the server, they must be public https urls
+fn valid_endpoint(endpoint: &str) -> Result<(), AppError> {
+    match reqwest::Url::parse(endpoint) {
+        Ok(url) if is_public_url(&url) => Ok(()),
+        _ => Err(AppError::PushError(
+            "the endpoint must be a public https url".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{response::Redirect, routing::post, Router};
+    use chat_core::test_util::{self, Received};
+    use jwt_simple::prelude::*;
+
+    use super::*;
+    use crate::{config::GatewayConfig, webpush::tests::UserAgent};
+
+    /// A local push receiver, recording what it gets
+    async fn start_receiver() -> anyhow::Result<(String, Received)> {
+        let others = Router::new()
+            .route("/gone", post(|| async { StatusCode::GONE }))
+            .route(
+                "/redirect",
+                post(|| async { Redirect::temporary("/push/redirected") }),
+            )
+            .route("/hang", post(std::future::pending::<StatusCode>));
+        test_util::start_receiver("/push/:id", |_| StatusCode::CREATED, others).await
+    }
+
+    #[test]
+    fn web_push_endpoints_should_be_public() {
+        assert!(valid_endpoint("https://fcm.googleapis.com/fcm/send/abc").is_ok());
+        for endpoint in [
+            "not a url",
+            "http://fcm.googleapis.com/fcm/send/abc",
+            "https://localhost/push",
+            "https://10.1.2.3/push",
+            "https://169.254.169.254/latest",
+        ] {
+            assert!(valid_endpoint(endpoint).is_err(), "{}", endpoint);
+        }
+    }
+
+    fn subscription(kind: PushKind, endpoint: String) -> PushSubscription {
+        PushSubscription {
+            id: 1,
+            user_id: 2,
+            kind,
+            endpoint,
+            p256dh: None,
+            auth: None,
+            platform: Some("fcm".to_string()),
+            created_at: Local::now(),
+        }
+    }
+
+    fn notification() -> PushNotification {
+        PushNotification {
+            chat_id: 1,
+            message_id: 10,
+            sender_id: 1,
+            title: "Tyr Chen".to_string(),
+            body: "hello".to_string(),
+            mention: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn web_push_should_be_encrypted_and_signed() -> anyhow::Result<()> {
+        let (url, received) = start_receiver().await?;
+        let key = ES256KeyPair::generate();
+        let sender = PushSender::new(&PushConfig {
+            vapid_sk: Some(key.to_pem()?),
+            ..Default::default()
+        })?;
+        let ua = UserAgent::new();
+        let sub = PushSubscription {
+            p256dh: Some(ua.p256dh()),
+            auth: Some(ua.auth()),
+            ..subscription(PushKind::WebPush, format!("{}/push/abc", url))
+        };
+
+        assert_eq!(sender.send(&sub, &notification()).await?, PushOutcome::Sent);
+        let (headers, body) = received.lock().unwrap().pop().unwrap();
+        assert_eq!(headers[header::CONTENT_ENCODING], "aes128gcm");
+        assert_eq!(headers["urgency"], "high");
+        assert!(headers[header::AUTHORIZATION]
+            .to_str()?
+            .starts_with("vapid t="));
+        let payload: PushNotification = serde_json::from_slice(&ua.decrypt(&body)?)?;
+        assert_eq!(payload, notification());
+
+        let sub = PushSubscription {
+            endpoint: format!("{}/gone", url),
+            ..sub
+        };
+        assert_eq!(sender.send(&sub, &notification()).await?, PushOutcome::Gone);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gateway_push_should_send_token_and_notification() -> anyhow::Result<()> {
+        let (url, received) = start_receiver().await?;
+        let sender = PushSender::new(&PushConfig {
+            gateway: Some(GatewayConfig {
+                url: format!("{}/push/gateway", url),
+                token: Some("secret".to_string()),
+            }),
+            ..Default::default()
+        })?;
+        assert!(!sender.supports(PushKind::WebPush));
+
+        let sub = subscription(PushKind::Gateway, "device-token".to_string());
+        assert_eq!(sender.send(&sub, &notification()).await?, PushOutcome::Sent);
+        let (headers, body) = received.lock().unwrap().pop().unwrap();
+        assert_eq!(headers[header::AUTHORIZATION], "Bearer secret");
+        let req: GatewayRequest = serde_json::from_slice(&body)?;
+        assert_eq!(
+            req,
+            GatewayRequest {
+                token: "device-token".to_string(),
+                platform: Some("fcm".to_string()),
+                notification: notification(),
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_should_not_follow_redirects() -> anyhow::Result<()> {
+        let (url, received) = start_receiver().await?;
+        let sender = PushSender::new(&PushConfig {
+            gateway: Some(GatewayConfig {
+                url: format!("{}/redirect", url),
+                token: None,
+            }),
+            ..Default::default()
+        })?;
+
+        let sub = subscription(PushKind::Gateway, "device-token".to_string());
+        let err = sender.send(&sub, &notification()).await.unwrap_err();
+        assert!(err.to_string().contains("307"), "{}", err);
+        assert!(received.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_should_give_up_on_endpoints_that_never_answer() -> anyhow::Result<()> {
+        let (url, _) = start_receiver().await?;
+        let config = PushConfig {
+            gateway: Some(GatewayConfig {
+                url: format!("{}/hang", url),
+                token: None,
+            }),
+            ..Default::default()
+        };
+        let sender = PushSender::with_timeout(&config, Duration::from_millis(200))?;
+
+        let sub = subscription(PushKind::Gateway, "device-token".to_string());
+        let res = tokio::time::timeout(Duration::from_secs(5), sender.send(&sub, &notification()))
+            .await?;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn mentions_should_be_parsed() {
+        let mut ids = mentions("hi <@1> and <@23>, not <@x> or <@4");
+        ids.sort();
+        assert_eq!(ids, vec![1, 23]);
+        assert!(mentions("no mention").is_empty());
+    }
+}
@@ -1,48 +1,95 @@
 use axum::{
-    extract::State,
+    extract::{Query, State},
+    http::HeaderMap,
     response::{sse::Event, Sse},
     Extension,
 };
 use chat_core::User;
-use std::{convert::Infallible, time::Duration};
-use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
-use tracing::info;
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+use tracing::{info, warn};
 
-use crate::{AppEvent, AppState};
+use crate::{
+    error::AppError,
+    event_log::is_replayed,
+    filter::{EventFilter, FilterParams},
+    AppState, EventEnvelope,
+};
 
-const CHANNEL_CAPACITY: usize = 256;
+const LAST_EVENT_ID: &str = "last-event-id";
 
 pub(crate) async fn sse_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Query(params): Query<FilterParams>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let filter = EventFilter::try_from(params)?;
     let user_id = user.id as u64;
-    let users = &state.users;
-
-    let rx = if let Some(tx) = users.get(&user_id) {
-        tx.subscribe()
-    } else {
-        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
-        state.users.insert(user_id, tx);
-        rx
+    // subscribe before loading the missed events, so nothing falls in between
+    let (rx, guard) = state.connect(user_id).await?;
+
+    let last_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok());
+    let replayed = match last_id {
+        Some(last_id) => state.replay_missed(user_id, last_id).await,
+        None => vec![],
     };
-    info!("User {} subscribed", user_id);
-
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::UpdateChat(_) => "UpdateChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
-        };
-        let v = serde_json::to_string(&v).expect("Failed to serialize event");
-        Ok(Event::default().data(v).event(name))
+    let last_id = replayed.iter().filter_map(|v| v.id).max().or(last_id);
+
+    let replayed = replayed
+        .into_iter()
+        .filter(|v| filter.accepts(&v.event))
+        .collect::<Vec<_>>();
+    let live = BroadcastStream::new(rx).filter_map(move |v| match v {
+        Ok(v) if !filter.accepts(&v.event) => None,
+        Ok(v) if is_replayed(&v, last_id) => None,
+        Ok(v) => Some(v),
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            warn!("User {} lagged, {} events skipped", user_id, n);
+            Some(EventEnvelope::resync())
+        }
     });
 
-    Sse::new(stream).keep_alive(
+    let stream = tokio_stream::iter(replayed).chain(live).map(move |v| {
+        // keep the guard alive as long as the stream
+        let _guard = &guard;
+        let data = serde_json::to_string(&v.event).expect("Failed to serialize event");
+        let event = Event::default().data(data).event(v.event.name());
+        Ok(match v.id {
+            Some(id) => event.id(id.to_string()),
+            None => event,
+        })
+    });
+
+    Ok(Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(1))
             .text("keep-alive-text"),
-    )
+    ))
+}
+
+impl AppState {
+    /// Events missed since `last_id`, or a `Resync` if they can't be loaded
+    pub(crate) async fn replay_missed(
+        &self,
+        user_id: u64,
+        last_id: i64,
+    ) -> Vec<Arc<EventEnvelope>> {
+        match self.replay_events(user_id, last_id).await {
+            Ok(events) => {
+                info!("Replaying {} events to user {}", events.len(), user_id);
+                events
+            }
+            Err(e) => {
+                warn!("Failed to replay events to user {}: {}", user_id, e);
+                vec![EventEnvelope::resync()]
+            }
+        }
+    }
 }
@@ -0,0 +1,251 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
+use chat_core::User;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{error::AppError, notif::send_event, AppEvent, AppState};
+
+/// typing state expires if the client doesn't refresh it in time
+const TYPING_TTL: Duration = Duration::from_secs(6);
+/// minimal interval between two typing-start of the same user in the same chat
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Typing {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+/// A typing start published on the bus, every instance keeps the typing state
+/// and notifies its own connections
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct TypingStarted {
+    #[serde(flatten)]
+    pub(crate) typing: Typing,
+    /// the user is still typing, the members were already notified
+    #[serde(default)]
+    pub(crate) refreshed: bool,
+}
+
+/// In-memory typing state, keyed by (chat_id, user_id). Nothing is stored in db,
+/// the instances share it through the bus.
+#[derive(Debug, Default)]
+pub struct TypingTracker {
+    // last accepted typing-start time
+    active: DashMap<(i64, i64), Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum TypingStart {
+    /// first typing-start, notify other members
+    Started,
+    /// refresh of an active typing state
+    Refreshed,
+    /// too many requests, retry later
+    Throttled(Duration),
+}
+
+impl TypingTracker {
+    pub(crate) fn start(&self, typing: &Typing, now: Instant) -> TypingStart {
+        let key = (typing.chat_id, typing.user_id);
+        let mut ret = TypingStart::Started;
+        self.active
+            .entry(key)
+            .and_modify(|last| {
+                let elapsed = now.saturating_duration_since(*last);
+                if elapsed < TYPING_THROTTLE {
+                    ret = TypingStart::Throttled(TYPING_THROTTLE - elapsed);
+                } else {
+                    if elapsed < TYPING_TTL {
+                        ret = TypingStart::Refreshed;
+                    }
+                    *last = now;
+                }
+            })
+            .or_insert(now);
+        ret
+    }
+
+    /// keep a typing state published by any instance
+    pub(crate) fn touch(&self, typing: &Typing, now: Instant) {
+        self.active.insert((typing.chat_id, typing.user_id), now);
+    }
+
+    /// returns true if the user was typing
+    pub(crate) fn stop(&self, typing: &Typing) -> bool {
+        self.active
+            .remove(&(typing.chat_id, typing.user_id))
+            .is_some()
+    }
+
+    /// remove and return all expired typing states
+    pub(crate) fn expire(&self, now: Instant) -> Vec<Typing> {
+        let mut expired = vec![];
+        self.active.retain(|(chat_id, user_id), last| {
+            if now.saturating_duration_since(*last) >= TYPING_TTL {
+                expired.push(Typing {
+                    chat_id: *chat_id,
+                    user_id: *user_id,
+                });
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+}
+
+pub(crate) async fn typing_start_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.typing_start(chat_id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn typing_stop_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.typing_stop(chat_id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl AppState {
+    /// Publish the typing state to all instances. Refreshes are published too,
+    /// so it doesn't expire on the others.
+    pub(crate) async fn typing_start(&self, chat_id: i64, user_id: i64) -> Result<(), AppError> {
+        self.get_chat_members(chat_id, user_id).await?;
+        let typing = Typing { chat_id, user_id };
+
+        let refreshed = match self.typing.start(&typing, Instant::now()) {
+            TypingStart::Started => {
+                info!("User {} started typing in chat {}", user_id, chat_id);
+                false
+            }
+            TypingStart::Refreshed => true,
+            TypingStart::Throttled(retry_after) => {
+                return Err(AppError::TooManyRequests(retry_after.as_secs().max(1)));
+            }
+        };
+        self.publish_typing("typing_started", &TypingStarted { typing, refreshed })
+            .await
+    }
+
+    pub(crate) async fn typing_stop(&self, chat_id: i64, user_id: i64) -> Result<(), AppError> {
+        self.get_chat_members(chat_id, user_id).await?;
+        let typing = Typing { chat_id, user_id };
+
+        if self.typing.stop(&typing) {
+            info!("User {} stopped typing in chat {}", user_id, chat_id);
+            self.publish_typing("typing_stopped", &typing).await?;
+        }
+        Ok(())
+    }
+
+    async fn publish_typing(
+        &self,
+        channel: &str,
+        payload: &impl Serialize,
+    ) -> Result<(), AppError> {
+        let payload = serde_json::to_string(payload).expect("Failed to serialize typing");
+        self.bus
+            .publish(channel, &payload)
+            .await
+            .map_err(|e| AppError::BusError(e.to_string()))
+    }
+
+    /// Keep a typing start received from the bus, and notify the members
+    /// connected to this instance unless it's a refresh
+    pub(crate) fn typing_started(&self, started: TypingStarted, members: HashSet<u64>) {
+        self.typing.touch(&started.typing, Instant::now());
+        if !started.refreshed {
+            notify_others(self, members, AppEvent::TypingStarted(started.typing));
+        }
+    }
+
+    /// Drop a typing state stopped on any instance, and notify the members
+    /// connected to this instance
+    pub(crate) fn typing_stopped(&self, typing: Typing, members: HashSet<u64>) {
+        self.typing.stop(&typing);
+        notify_others(self, members, AppEvent::TypingStopped(typing));
+    }
+}
+
+/// Periodically expire typing states which were not refreshed by the client.
+/// Every instance keeps them, so each one notifies its own connections.
+pub(crate) fn setup_typing_expiry(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TYPING_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            for typing in state.typing.expire(Instant::now()) {
+                let Ok(members) = state.get_chat_members(typing.chat_id, typing.user_id).await
+                else {
+                    continue;
+                };
+                let members = members.into_iter().map(|id| id as u64);
+                notify_others(&state, members, AppEvent::TypingStopped(typing));
+            }
+        }
+    });
+}
+
+fn notify_others(state: &AppState, members: impl IntoIterator<Item = u64>, event: AppEvent) {
+    let sender = match &event {
+        AppEvent::TypingStarted(typing) | AppEvent::TypingStopped(typing) => typing.user_id as u64,
+        _ => return,
+    };
+    let user_ids = members.into_iter().filter(|id| *id != sender);
+    send_event(&state.users, user_ids, Arc::new(event.into()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_tracker_should_throttle_and_expire() {
+        let tracker = TypingTracker::default();
+        let typing = Typing {
+            chat_id: 1,
+            user_id: 1,
+        };
+        let now = Instant::now();
+
+        assert_eq!(tracker.start(&typing, now), TypingStart::Started);
+        assert!(matches!(
+            tracker.start(&typing, now + Duration::from_millis(500)),
+            TypingStart::Throttled(_)
+        ));
+        assert_eq!(
+            tracker.start(&typing, now + TYPING_THROTTLE),
+            TypingStart::Refreshed
+        );
+
+        // not expired yet, refreshed at now + TYPING_THROTTLE
+        assert!(tracker.expire(now + TYPING_TTL).is_empty());
+        let expired = tracker.expire(now + TYPING_THROTTLE + TYPING_TTL);
+        assert_eq!(expired, vec![typing.clone()]);
+
+        assert!(!tracker.stop(&typing));
+        assert_eq!(tracker.start(&typing, now), TypingStart::Started);
+        assert!(tracker.stop(&typing));
+    }
+}
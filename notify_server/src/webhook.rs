@@ -0,0 +1,271 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use chat_core::sign_payload;
+use chrono::Utc;
+use futures::future::join_all;
+use reqwest::redirect::Policy;
+use sqlx::FromRow;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::{error::AppError, AppState};
+
+/// deliveries are woken up when chat_server queues them, polling picks up the
+/// retries
+const WEBHOOK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const WEBHOOK_BATCH_SIZE: i64 = 50;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// a delivery failing this many times is dead, after about 8 hours of retries
+const MAX_ATTEMPTS: i32 = 10;
+const BACKOFF_BASE_SECS: i64 = 30;
+const BACKOFF_MAX_SECS: i64 = 6 * 60 * 60;
+const MAX_ERROR_LEN: usize = 512;
+
+/// Posts the events queued for outgoing webhooks, signed with their secret
+pub struct WebhookSender {
+    client: reqwest::Client,
+    /// woken up when chat_server queued deliveries
+    queued: Notify,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct Delivery {
+    id: i64,
+    event: String,
+    payload: String,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+impl WebhookSender {
+    pub fn new() -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .redirect(Policy::none())
+            .build()?;
+        Ok(Self {
+            client,
+            queued: Notify::new(),
+        })
+    }
+
+    pub(crate) fn notify_queued(&self) {
+        self.queued.notify_one();
+    }
+
+    /// Post the payload of a delivery, it succeeded with a 2xx status
+    pub(crate) async fn send(&self, delivery: &Delivery) -> Result<StatusCode, reqwest::Error> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let res = self
+            .client
+            .post(&delivery.url)
+            .header("content-type", "application/json")
+            .header("x-webhook-id", delivery.id)
+            .header("x-webhook-event", &delivery.event)
+            .header("x-webhook-timestamp", &timestamp)
+            .header(
+                "x-webhook-signature",
+                sign_payload(&delivery.secret, &timestamp, &delivery.payload),
+            )
+            .body(delivery.payload.clone())
+            .send()
+            .await?;
+        Ok(res.status())
+    }
+}
+
+impl AppState {
+    /// Post a batch of due deliveries. They are claimed for a while, so that
+    /// other instances skip them until they succeed or are rescheduled.
+    async fn deliver_webhooks(&self) -> Result<usize, AppError> {
+        let deliveries: Vec<Delivery> = sqlx::query_as(
+            r#"
+            UPDATE webhook_deliveries d
+            SET attempts = d.attempts + 1, next_attempt_at = now() + $2 * interval '1 second'
+            FROM outgoing_webhooks w
+            WHERE w.id = d.webhook_id AND d.id IN (
+              SELECT d.id
+              FROM webhook_deliveries d JOIN outgoing_webhooks w ON w.id = d.webhook_id
+              WHERE d.status = 'pending' AND d.next_attempt_at <= now() AND w.enabled
+              ORDER BY d.next_attempt_at
+              LIMIT $1
+              FOR UPDATE OF d SKIP LOCKED
+            )
+            RETURNING d.id, d.event::text AS event, d.payload::text AS payload, d.attempts,
+              w.url, w.secret
+            "#,
+        )
+        .bind(WEBHOOK_BATCH_SIZE)
+        .bind(2 * WEBHOOK_TIMEOUT.as_secs() as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let results = join_all(deliveries.iter().map(|v| self.webhooks.send(v))).await;
+        for (delivery, result) in deliveries.iter().zip(results) {
+            let (status, error) = match result {
+                Ok(status) if status.is_success() => {
+                    sqlx::query(
+                        r#"
+                        UPDATE webhook_deliveries
+                        SET status = 'delivered', delivered_at = now(), response_status = $2,
+                          last_error = NULL
+                        WHERE id = $1
+                        "#,
+                    )
+                    .bind(delivery.id)
+                    .bind(status.as_u16() as i32)
+                    .execute(&self.pool)
+                    .await?;
+                    continue;
+                }
+                Ok(status) => (Some(status.as_u16() as i32), format!("status {}", status)),
+                Err(e) => (None, e.to_string()),
+            };
+            let dead = delivery.attempts >= MAX_ATTEMPTS;
+            if dead {
+                warn!(
+                    "Webhook delivery {} is dead after {} attempts: {}",
+                    delivery.id, delivery.attempts, error
+                );
+            }
+            sqlx::query(
+                r#"
+                UPDATE webhook_deliveries
+                SET status = CASE WHEN $2 THEN 'dead' ELSE 'pending' END::webhook_delivery_status,
+                  next_attempt_at = now() + $3 * interval '1 second',
+                  response_status = $4, last_error = $5
+                WHERE id = $1
+                "#,
+            )
+            .bind(delivery.id)
+            .bind(dead)
+            .bind(backoff(delivery.attempts))
+            .bind(status)
+            .bind(truncate(&error, MAX_ERROR_LEN))
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(deliveries.len())
+    }
+}
+
+pub(crate) fn setup_webhook_delivery(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            match state.deliver_webhooks().await {
+                Ok(0) => {}
+                Ok(n) => {
+                    info!("Delivered {} webhook events", n);
+                    if n as i64 >= WEBHOOK_BATCH_SIZE {
+                        continue;
+                    }
+                }
+                Err(e) => warn!("Failed to deliver webhooks: {}", e),
+            }
+            tokio::select! {
+                _ = state.webhooks.queued.notified() => {}
+                _ = tokio::time::sleep(WEBHOOK_POLL_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+/// seconds before the next attempt, doubling from 30s up to 6h
+fn backoff(attempts: i32) -> i64 {
+    let exp = attempts.clamp(1, 20) as u32 - 1;
+    (BACKOFF_BASE_SECS << exp).min(BACKOFF_MAX_SECS)
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Router};
+    use chat_core::{
+        test_util::{self, Received},
+        Chat, ChatMember, ChatType,
+    };
+    use chrono::Local;
+
+    use super::*;
+    use crate::AppEvent;
+
+    async fn start_receiver() -> anyhow::Result<(String, Received)> {
+        let failing = Router::new().route(
+            "/failing",
+            post(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+        );
+        test_util::start_receiver("/hooks", |_| StatusCode::NO_CONTENT, failing).await
+    }
+
+    fn chat(members: Vec<i64>) -> Chat {
+        Chat {
+            id: 1,
+            ws_id: 1,
+            deleted_at: None,
+            name: Some("general".to_string()),
+            r#type: ChatType::PublicChannel,
+            members,
+            message_ttl: None,
+            topic: None,
+            created_at: Local::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_should_be_signed_with_its_secret() -> anyhow::Result<()> {
+        let (url, received) = start_receiver().await?;
+        let sender = WebhookSender::new()?;
+        let event = AppEvent::MemberJoined(ChatMember {
+            chat: chat(vec![1, 2]),
+            user_id: 2,
+        });
+        let delivery = Delivery {
+            id: 7,
+            event: event.name().to_string(),
+            payload: serde_json::to_string(&event)?,
+            attempts: 1,
+            url: format!("{}/hooks", url),
+            secret: "whsec_test".to_string(),
+        };
+
+        assert_eq!(sender.send(&delivery).await?, StatusCode::NO_CONTENT);
+        let (headers, body) = received.lock().unwrap().pop().unwrap();
+        assert_eq!(headers["x-webhook-id"], "7");
+        assert_eq!(headers["x-webhook-event"], "MemberJoined");
+        let timestamp = headers["x-webhook-timestamp"].to_str()?;
+        let expected = sign_payload("whsec_test", timestamp, std::str::from_utf8(&body)?);
+        assert_eq!(headers["x-webhook-signature"], expected.as_str());
+        let payload: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(payload["event"], "MemberJoined");
+        assert_eq!(payload["user_id"], 2);
+        assert_eq!(payload["chat"]["members"], serde_json::json!([1, 2]));
+
+        let delivery = Delivery {
+            url: format!("{}/failing", url),
+            ..delivery
+        };
+        assert_eq!(
+            sender.send(&delivery).await?,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_should_double_up_to_a_limit() {
+        assert_eq!(backoff(1), 30);
+        assert_eq!(backoff(2), 60);
+        assert_eq!(backoff(5), 480);
+        assert_eq!(backoff(MAX_ATTEMPTS), 15360);
+        assert_eq!(backoff(MAX_ATTEMPTS + 1), 6 * 60 * 60);
+        assert_eq!(backoff(100), 6 * 60 * 60);
+    }
+}
@@ -0,0 +1,201 @@
+use aes_gcm::{aead::Aead, Aes128Gcm, KeyInit, Nonce};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hkdf::Hkdf;
+use jwt_simple::prelude::*;
+use p256::{ecdh::EphemeralSecret, elliptic_curve::sec1::ToEncodedPoint, PublicKey};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+
+/// the payload is sent as a single record of at most this size
+const RECORD_SIZE: u32 = 4096;
+const VAPID_TOKEN_HOURS: u64 = 12;
+
+/// Key pair identifying the server to push services (RFC 8292)
+pub struct Vapid {
+    key: ES256KeyPair,
+    subject: String,
+}
+
+impl Vapid {
+    pub fn from_pem(pem: &str, subject: impl Into<String>) -> Result<Self> {
+        Ok(Self {
+            key: ES256KeyPair::from_pem(pem)?,
+            subject: subject.into(),
+        })
+    }
+
+    /// The key browsers subscribe with, as `applicationServerKey`
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.key.public_key().public_key().to_bytes_uncompressed())
+    }
+
+    /// The `Authorization` header for a push service endpoint
+    pub fn authorization(&self, endpoint: &str) -> Result<String> {
+        let url = reqwest::Url::parse(endpoint)?;
+        let claims = Claims::create(Duration::from_hours(VAPID_TOKEN_HOURS))
+            .with_audience(url.origin().ascii_serialization())
+            .with_subject(&self.subject);
+        let token = self.key.sign(claims)?;
+        Ok(format!("vapid t={}, k={}", token, self.public_key()))
+    }
+}
+
+/// Encrypt the payload with the keys of the browser subscription
+/// (RFC 8291, `aes128gcm` content encoding)
+pub fn encrypt(payload: &[u8], p256dh: &str, auth: &str) -> Result<Vec<u8>> {
+    let ua_public = decode(p256dh)?;
+    let auth = decode(auth)?;
+    let ua_key = PublicKey::from_sec1_bytes(&ua_public)?;
+
+    let as_secret = EphemeralSecret::random(&mut OsRng);
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let shared = as_secret.diffie_hellman(&ua_key);
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+
+    let (cek, nonce) = derive_keys(
+        shared.raw_secret_bytes(),
+        &auth,
+        &ua_public,
+        as_public.as_bytes(),
+        &salt,
+    )?;
+    // a single record, ended by the 0x02 delimiter, without padding
+    let mut record = payload.to_vec();
+    record.push(2);
+    if record.len() + 16 > RECORD_SIZE as usize {
+        return Err(anyhow!("push payload too large"));
+    }
+    let cipher = Aes128Gcm::new_from_slice(&cek).map_err(|_| anyhow!("invalid key"))?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), record.as_slice())
+        .map_err(|_| anyhow!("failed to encrypt push payload"))?;
+
+    let mut body = Vec::with_capacity(86 + ciphertext.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+/// The content encryption key and nonce, from the ECDH secret
+fn derive_keys(
+    ecdh_secret: &[u8],
+    auth: &[u8],
+    ua_public: &[u8],
+    as_public: &[u8],
+    salt: &[u8],
+) -> Result<([u8; 16], [u8; 12])> {
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(ua_public);
+    key_info.extend_from_slice(as_public);
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth), ecdh_secret)
+        .expand(&key_info, &mut ikm)
+        .map_err(|_| anyhow!("invalid key length"))?;
+
+    let hk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    hk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .and_then(|_| hk.expand(b"Content-Encoding: nonce\0", &mut nonce))
+        .map_err(|_| anyhow!("invalid key length"))?;
+    Ok((cek, nonce))
+}
+
+/// browsers may send the keys padded or not
+fn decode(v: &str) -> Result<Vec<u8>> {
+    Ok(URL_SAFE_NO_PAD.decode(v.trim_end_matches('='))?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use p256::SecretKey;
+
+    /// Keys of a browser subscription
+    pub(crate) struct UserAgent {
+        secret: SecretKey,
+        auth: [u8; 16],
+    }
+
+    impl UserAgent {
+        pub(crate) fn new() -> Self {
+            let mut auth = [0u8; 16];
+            OsRng.fill_bytes(&mut auth);
+            Self {
+                secret: SecretKey::random(&mut OsRng),
+                auth,
+            }
+        }
+
+        pub(crate) fn p256dh(&self) -> String {
+            let public = self.secret.public_key().to_encoded_point(false);
+            URL_SAFE_NO_PAD.encode(public.as_bytes())
+        }
+
+        pub(crate) fn auth(&self) -> String {
+            URL_SAFE_NO_PAD.encode(self.auth)
+        }
+
+        /// Decrypt a payload the way a browser does
+        pub(crate) fn decrypt(&self, body: &[u8]) -> Result<Vec<u8>> {
+            let (salt, rest) = body.split_at(16);
+            let id_len = rest[4] as usize;
+            let (as_public, ciphertext) = rest[5..].split_at(id_len);
+
+            let as_key = PublicKey::from_sec1_bytes(as_public)?;
+            let shared =
+                p256::ecdh::diffie_hellman(self.secret.to_nonzero_scalar(), as_key.as_affine());
+            let ua_public = self.secret.public_key().to_encoded_point(false);
+            let (cek, nonce) = derive_keys(
+                shared.raw_secret_bytes(),
+                &self.auth,
+                ua_public.as_bytes(),
+                as_public,
+                salt,
+            )?;
+            let cipher = Aes128Gcm::new_from_slice(&cek).map_err(|_| anyhow!("invalid key"))?;
+            let mut record = cipher
+                .decrypt(Nonce::from_slice(&nonce), ciphertext)
+                .map_err(|_| anyhow!("failed to decrypt"))?;
+            assert_eq!(record.pop(), Some(2));
+            Ok(record)
+        }
+    }
+
+    #[test]
+    fn encrypted_payload_should_decrypt() -> Result<()> {
+        let ua = UserAgent::new();
+        let body = encrypt(b"hello world", &ua.p256dh(), &ua.auth())?;
+        assert_eq!(&body[16..20], &RECORD_SIZE.to_be_bytes());
+        assert_eq!(body[20], 65);
+        assert_eq!(ua.decrypt(&body)?, b"hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn vapid_authorization_should_verify() -> Result<()> {
+        let key = ES256KeyPair::generate();
+        let vapid = Vapid::from_pem(&key.to_pem()?, "mailto:admin@example.com")?;
+        let auth = vapid.authorization("https://push.example.com/send/abc")?;
+        let (token, public_key) = auth
+            .strip_prefix("vapid t=")
+            .and_then(|v| v.split_once(", k="))
+            .unwrap();
+        assert_eq!(public_key, vapid.public_key());
+
+        let options = VerificationOptions {
+            allowed_audiences: Some(HashSet::from_strings(&["https://push.example.com"])),
+            ..Default::default()
+        };
+        let claims = key
+            .public_key()
+            .verify_token::<NoCustomClaims>(token, Some(options))?;
+        assert_eq!(claims.subject.as_deref(), Some("mailto:admin@example.com"));
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Query, State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use chat_core::User;
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{info, warn};
+
+use crate::{
+    connection::ConnectionGuard,
+    error::AppError,
+    event_log::is_replayed,
+    filter::{EventFilter, FilterParams},
+    AppState, EventEnvelope,
+};
+
+const WS_PING_INTERVAL: Duration = Duration::from_secs(15);
+/// close the connection if the client is silent for this long
+const WS_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Commands sent by the client over the websocket, e.g.
+/// `{"type": "typing", "chat_id": 1, "typing": true}`
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ClientCommand {
+    /// replace the filter of the connection, empty lists of chats or events
+    /// receive all of them
+    Subscribe {
+        chat_ids: Vec<i64>,
+        #[serde(default)]
+        events: Vec<String>,
+        #[serde(default)]
+        muted: Vec<i64>,
+    },
+    Typing {
+        chat_id: i64,
+        typing: bool,
+    },
+    /// the client received the events up to `id`
+    Ack {
+        id: i64,
+    },
+}
+
+/// Replies to client commands, framed like `AppEvent`
+#[derive(Debug, Serialize)]
+#[serde(tag = "event")]
+enum CommandReply {
+    Subscribed {
+        chat_ids: Vec<i64>,
+        events: Vec<String>,
+        muted: Vec<i64>,
+    },
+    CommandError {
+        error: String,
+    },
+}
+
+/// Per connection state
+#[derive(Debug, Default)]
+struct Connection {
+    filter: EventFilter,
+    /// the latest event acknowledged by the client, the events after it are
+    /// redelivered from the event log if the connection lags behind
+    last_ack: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WsParams {
+    /// browsers can't set headers on websockets, so the last event id is a query param
+    last_event_id: Option<i64>,
+}
+
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(params): Query<WsParams>,
+    Query(filter): Query<FilterParams>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, AppError> {
+    let filter = EventFilter::try_from(filter)?;
+    // subscribe before loading the missed events, so nothing falls in between,
+    // and before the upgrade, so a rejected connection gets an http error
+    let conn = state.connect(user.id as u64).await?;
+    Ok(ws.on_upgrade(move |socket| {
+        handle_socket(socket, state, user, conn, filter, params.last_event_id)
+    }))
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    user: User,
+    (mut rx, _guard): (Receiver<Arc<EventEnvelope>>, ConnectionGuard),
+    filter: EventFilter,
+    last_id: Option<i64>,
+) {
+    let user_id = user.id as u64;
+    info!("User {} connected through websocket", user_id);
+
+    let (mut sender, mut receiver) = socket.split();
+    let mut last_id = match last_id {
+        Some(last_id) => {
+            match send_replayed(&mut sender, &state, user_id, &filter, last_id).await {
+                Ok(id) => id.max(Some(last_id)),
+                Err(_) => return,
+            }
+        }
+        None => None,
+    };
+
+    let mut conn = Connection {
+        filter,
+        ..Default::default()
+    };
+    let mut ping = tokio::time::interval(WS_PING_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let event = match event {
+                    Ok(event) if is_replayed(&event, last_id) => continue,
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("User {} websocket lagged, {} events skipped", user_id, n);
+                        match conn.last_ack {
+                            // the events after the last ack are redelivered from the log
+                            Some(ack) => {
+                                let ret = send_replayed(&mut sender, &state, user_id, &conn.filter, ack).await;
+                                match ret {
+                                    Ok(id) => last_id = last_id.max(id),
+                                    Err(_) => break,
+                                }
+                                continue;
+                            }
+                            // without an ack the client can only resync
+                            None => EventEnvelope::resync(),
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if !conn.filter.accepts(&event.event) {
+                    continue;
+                }
+                let v = serde_json::to_string(&event).expect("Failed to serialize event");
+                if sender.send(Message::Text(v)).await.is_err() {
+                    break;
+                }
+            }
+            msg = receiver.next() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    _ => break,
+                };
+                last_seen = Instant::now();
+                let reply = match msg {
+                    Message::Text(text) => conn.handle(&state, &user, &text).await,
+                    Message::Close(_) => break,
+                    // pings are answered by axum, pongs only refresh last_seen
+                    Message::Ping(_) | Message::Pong(_) | Message::Binary(_) => None,
+                };
+                if let Some(reply) = reply {
+                    let v = serde_json::to_string(&reply).expect("Failed to serialize reply");
+                    if sender.send(Message::Text(v)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > WS_IDLE_TIMEOUT {
+                    info!("User {} websocket timed out", user_id);
+                    break;
+                }
+                if sender.send(Message::Ping(vec![])).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    info!("User {} websocket closed", user_id);
+}
+
+/// Send the logged events of the user after `last_id`, returning the id of the
+/// last one sent
+async fn send_replayed(
+    sender: &mut SplitSink<WebSocket, Message>,
+    state: &AppState,
+    user_id: u64,
+    filter: &EventFilter,
+    last_id: i64,
+) -> Result<Option<i64>, axum::Error> {
+    let replayed = state.replay_missed(user_id, last_id).await;
+    for event in replayed.iter().filter(|v| filter.accepts(&v.event)) {
+        let v = serde_json::to_string(&event).expect("Failed to serialize event");
+        sender.send(Message::Text(v)).await?;
+    }
+    Ok(replayed.iter().filter_map(|v| v.id).max())
+}
+
+impl Connection {
+    async fn handle(&mut self, state: &AppState, user: &User, text: &str) -> Option<CommandReply> {
+        let cmd = match serde_json::from_str::<ClientCommand>(text) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                return Some(CommandReply::CommandError {
+                    error: format!("invalid command: {}", e),
+                })
+            }
+        };
+
+        let ret = match cmd {
+            ClientCommand::Subscribe {
+                chat_ids,
+                events,
+                muted,
+            } => {
+                let reply = match EventFilter::new(chat_ids.clone(), events.clone(), muted.clone())
+                {
+                    Ok(filter) => {
+                        self.filter = filter;
+                        CommandReply::Subscribed {
+                            chat_ids,
+                            events,
+                            muted,
+                        }
+                    }
+                    Err(e) => CommandReply::CommandError {
+                        error: e.to_string(),
+                    },
+                };
+                return Some(reply);
+            }
+            ClientCommand::Typing {
+                chat_id,
+                typing: true,
+            } => state.typing_start(chat_id, user.id).await,
+            ClientCommand::Typing {
+                chat_id,
+                typing: false,
+            } => state.typing_stop(chat_id, user.id).await,
+            ClientCommand::Ack { id } => {
+                self.last_ack = self.last_ack.max(Some(id));
+                return None;
+            }
+        };
+
+        ret.err().map(|e| CommandReply::CommandError {
+            error: e.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppEvent, Typing};
+
+    #[test]
+    fn client_command_should_parse() {
+        let cmd: ClientCommand =
+            serde_json::from_str(r#"{"type": "subscribe", "chat_ids": [1, 2]}"#).unwrap();
+        assert_eq!(
+            cmd,
+            ClientCommand::Subscribe {
+                chat_ids: vec![1, 2],
+                events: vec![],
+                muted: vec![],
+            }
+        );
+
+        let cmd: ClientCommand = serde_json::from_str(
+            r#"{"type": "subscribe", "chat_ids": [], "events": ["NewMessage"], "muted": [3]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            cmd,
+            ClientCommand::Subscribe {
+                chat_ids: vec![],
+                events: vec!["NewMessage".into()],
+                muted: vec![3],
+            }
+        );
+
+        let cmd: ClientCommand =
+            serde_json::from_str(r#"{"type": "typing", "chat_id": 1, "typing": false}"#).unwrap();
+        assert_eq!(
+            cmd,
+            ClientCommand::Typing {
+                chat_id: 1,
+                typing: false
+            }
+        );
+
+        let cmd: ClientCommand = serde_json::from_str(r#"{"type": "ack", "id": 42}"#).unwrap();
+        assert_eq!(cmd, ClientCommand::Ack { id: 42 });
+
+        assert!(serde_json::from_str::<ClientCommand>(r#"{"type": "unknown"}"#).is_err());
+    }
+
+    #[test]
+    fn connection_should_filter_subscribed_chats() {
+        let typing = |chat_id| {
+            AppEvent::TypingStarted(Typing {
+                chat_id,
+                user_id: 1,
+            })
+        };
+        let mut conn = Connection::default();
+        assert!(conn.filter.accepts(&typing(1)));
+
+        conn.filter = EventFilter::new(vec![2], vec![], vec![]).unwrap();
+        assert!(!conn.filter.accepts(&typing(1)));
+        assert!(conn.filter.accepts(&typing(2)));
+    }
+}